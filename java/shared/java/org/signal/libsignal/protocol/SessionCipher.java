@@ -14,6 +14,7 @@ import org.signal.libsignal.protocol.message.PreKeySignalMessage;
 import org.signal.libsignal.protocol.message.SignalMessage;
 import org.signal.libsignal.protocol.state.SignalProtocolStore;
 import org.signal.libsignal.protocol.state.IdentityKeyStore;
+import org.signal.libsignal.protocol.state.KyberPreKeyStore;
 import org.signal.libsignal.protocol.state.PreKeyStore;
 import org.signal.libsignal.protocol.state.SessionRecord;
 import org.signal.libsignal.protocol.state.SessionStore;
@@ -39,6 +40,7 @@ public class SessionCipher {
   private final IdentityKeyStore      identityKeyStore;
   private final PreKeyStore           preKeyStore;
   private final SignedPreKeyStore     signedPreKeyStore;
+  private final KyberPreKeyStore      kyberPreKeyStore;
   private final SignalProtocolAddress remoteAddress;
 
   /**
@@ -50,18 +52,19 @@ public class SessionCipher {
    * @param  remoteAddress  The remote address that messages will be encrypted to or decrypted from.
    */
   public SessionCipher(SessionStore sessionStore, PreKeyStore preKeyStore,
-                       SignedPreKeyStore signedPreKeyStore, IdentityKeyStore identityKeyStore,
-                       SignalProtocolAddress remoteAddress)
+                       SignedPreKeyStore signedPreKeyStore, KyberPreKeyStore kyberPreKeyStore,
+                       IdentityKeyStore identityKeyStore, SignalProtocolAddress remoteAddress)
   {
     this.sessionStore     = sessionStore;
     this.preKeyStore      = preKeyStore;
     this.identityKeyStore = identityKeyStore;
     this.remoteAddress    = remoteAddress;
     this.signedPreKeyStore = signedPreKeyStore;
+    this.kyberPreKeyStore = kyberPreKeyStore;
   }
 
   public SessionCipher(SignalProtocolStore store, SignalProtocolAddress remoteAddress) {
-    this(store, store, store, store, remoteAddress);
+    this(store, store, store, store, store, remoteAddress);
   }
 
   /**
@@ -106,6 +109,7 @@ public class SessionCipher {
                                                              identityKeyStore,
                                                              preKeyStore,
                                                              signedPreKeyStore,
+                                                             kyberPreKeyStore,
                                                              null);
     }
   }
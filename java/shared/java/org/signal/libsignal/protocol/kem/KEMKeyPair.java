@@ -0,0 +1,45 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.protocol.kem;
+
+import org.signal.libsignal.internal.Native;
+import org.signal.libsignal.internal.NativeHandleGuard;
+
+public class KEMKeyPair implements NativeHandleGuard.Owner {
+  private final long unsafeHandle;
+
+  public static KEMKeyPair generate() {
+    return new KEMKeyPair(Native.KyberKeyPair_Generate());
+  }
+
+  public KEMKeyPair(long nativeHandle) {
+    if (nativeHandle == 0) {
+      throw new NullPointerException();
+    }
+    this.unsafeHandle = nativeHandle;
+  }
+
+  @Override @SuppressWarnings("deprecation")
+  protected void finalize() {
+    Native.KyberKeyPair_Destroy(this.unsafeHandle);
+  }
+
+  public KEMPublicKey getPublicKey() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return new KEMPublicKey(Native.KyberKeyPair_GetPublicKey(guard.nativeHandle()));
+    }
+  }
+
+  public KEMSecretKey getSecretKey() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return new KEMSecretKey(Native.KyberKeyPair_GetSecretKey(guard.nativeHandle()));
+    }
+  }
+
+  public long unsafeNativeHandleWithoutGuard() {
+    return this.unsafeHandle;
+  }
+}
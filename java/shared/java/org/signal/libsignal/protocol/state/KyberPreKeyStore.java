@@ -0,0 +1,59 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.protocol.state;
+
+import org.signal.libsignal.protocol.InvalidKeyIdException;
+
+import java.util.List;
+
+/**
+ * An interface describing the local storage of signed Kyber pre-keys ({@link KyberPreKeyRecord}s).
+ *
+ * A Kyber pre-key is either "one-time", used for a single session, or "last-resort", used whenever
+ * no one-time key is available. It is up to the store to remember which is which.
+ */
+public interface KyberPreKeyStore {
+
+  /**
+   * Load a local KyberPreKeyRecord.
+   *
+   * @param kyberPreKeyId the ID of the local KyberPreKeyRecord.
+   * @return the corresponding KyberPreKeyRecord.
+   * @throws InvalidKeyIdException when there is no corresponding KyberPreKeyRecord.
+   */
+  public KyberPreKeyRecord loadKyberPreKey(int kyberPreKeyId) throws InvalidKeyIdException;
+
+  /**
+   * Load all local KyberPreKeyRecords.
+   *
+   * @return All stored KyberPreKeyRecords.
+   */
+  public List<KyberPreKeyRecord> loadKyberPreKeys();
+
+  /**
+   * Store a local KyberPreKeyRecord.
+   *
+   * @param kyberPreKeyId the ID of the KyberPreKeyRecord to store.
+   * @param record the KyberPreKeyRecord.
+   */
+  public void         storeKyberPreKey(int kyberPreKeyId, KyberPreKeyRecord record);
+
+  /**
+   * @param kyberPreKeyId A KyberPreKeyRecord ID.
+   * @return true if the store has a record for the kyberPreKeyId, otherwise false.
+   */
+  public boolean      containsKyberPreKey(int kyberPreKeyId);
+
+  /**
+   * Mark a KyberPreKeyRecord in the local storage as used.
+   *
+   * One-time pre-keys should be removed at this point; last-resort pre-keys should be kept.
+   *
+   * @param kyberPreKeyId The ID of the KyberPreKeyRecord that was used.
+   */
+  public void         markKyberPreKeyUsed(int kyberPreKeyId);
+
+}
@@ -9,6 +9,7 @@ import org.signal.libsignal.internal.Native;
 import org.signal.libsignal.internal.NativeHandleGuard;
 import org.signal.libsignal.protocol.IdentityKey;
 import org.signal.libsignal.protocol.ecc.ECPublicKey;
+import org.signal.libsignal.protocol.kem.KEMPublicKey;
 
 /**
  * A class that contains a remote PreKey and collection
@@ -27,11 +28,23 @@ public class PreKeyBundle implements NativeHandleGuard.Owner {
   public PreKeyBundle(int registrationId, int deviceId, int preKeyId, ECPublicKey preKeyPublic,
                       int signedPreKeyId, ECPublicKey signedPreKeyPublic, byte[] signedPreKeySignature,
                       IdentityKey identityKey)
+  {
+    this(registrationId, deviceId, preKeyId, preKeyPublic,
+         signedPreKeyId, signedPreKeyPublic, signedPreKeySignature,
+         identityKey,
+         -1, null, new byte[0]);
+  }
+
+  public PreKeyBundle(int registrationId, int deviceId, int preKeyId, ECPublicKey preKeyPublic,
+                      int signedPreKeyId, ECPublicKey signedPreKeyPublic, byte[] signedPreKeySignature,
+                      IdentityKey identityKey,
+                      int kyberPreKeyId, KEMPublicKey kyberPreKeyPublic, byte[] kyberPreKeySignature)
   {
     try (
       NativeHandleGuard preKeyPublicGuard = new NativeHandleGuard(preKeyPublic);
       NativeHandleGuard signedPreKeyPublicGuard = new NativeHandleGuard(signedPreKeyPublic);
       NativeHandleGuard identityKeyGuard = new NativeHandleGuard(identityKey.getPublicKey());
+      NativeHandleGuard kyberPreKeyPublicGuard = new NativeHandleGuard(kyberPreKeyPublic);
     ) {
       this.unsafeHandle = Native.PreKeyBundle_New(
         registrationId,
@@ -41,7 +54,10 @@ public class PreKeyBundle implements NativeHandleGuard.Owner {
         signedPreKeyId,
         signedPreKeyPublicGuard.nativeHandle(),
         signedPreKeySignature,
-        identityKeyGuard.nativeHandle());
+        identityKeyGuard.nativeHandle(),
+        kyberPreKeyId,
+        kyberPreKeyPublicGuard.nativeHandle(),
+        kyberPreKeySignature);
     }
   }
 
@@ -112,6 +128,37 @@ public class PreKeyBundle implements NativeHandleGuard.Owner {
     }
   }
 
+  /**
+   * @return the unique key ID for the Kyber prekey, or -1 if the bundle has none.
+   */
+  public int getKyberPreKeyId() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return Native.PreKeyBundle_GetKyberPreKeyId(guard.nativeHandle());
+    }
+  }
+
+  /**
+   * @return the public key for the Kyber prekey, or null if the bundle has none.
+   */
+  public KEMPublicKey getKyberPreKey() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      long handle = Native.PreKeyBundle_GetKyberPreKeyPublic(guard.nativeHandle());
+      if (handle != 0) {
+        return new KEMPublicKey(handle);
+      }
+      return null;
+    }
+  }
+
+  /**
+   * @return the signature over the Kyber prekey, or an empty array if the bundle has none.
+   */
+  public byte[] getKyberPreKeySignature() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return Native.PreKeyBundle_GetKyberPreKeySignature(guard.nativeHandle());
+    }
+  }
+
   /**
    * @return the registration ID associated with this PreKey.
    */
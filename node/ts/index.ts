@@ -290,7 +290,10 @@ export class PreKeyBundle {
     signed_prekey_id: number,
     signed_prekey: PublicKey,
     signed_prekey_signature: Buffer,
-    identity_key: PublicKey
+    identity_key: PublicKey,
+    kyber_prekey_id: number | null = null,
+    kyber_prekey: KEMPublicKey | null = null,
+    kyber_prekey_signature: Buffer = Buffer.alloc(0)
   ): PreKeyBundle {
     return new PreKeyBundle(
       Native.PreKeyBundle_New(
@@ -302,7 +305,10 @@ export class PreKeyBundle {
         signed_prekey_id,
         signed_prekey,
         signed_prekey_signature,
-        identity_key
+        identity_key,
+        kyber_prekey_id,
+        kyber_prekey,
+        kyber_prekey_signature
       )
     );
   }
@@ -341,6 +347,21 @@ export class PreKeyBundle {
   signedPreKeySignature(): Buffer {
     return Native.PreKeyBundle_GetSignedPreKeySignature(this);
   }
+  kyberPreKeyId(): number | null {
+    return Native.PreKeyBundle_GetKyberPreKeyId(this);
+  }
+  kyberPreKeyPublic(): KEMPublicKey | null {
+    const handle = Native.PreKeyBundle_GetKyberPreKeyPublic(this);
+
+    if (handle == null) {
+      return null;
+    } else {
+      return KEMPublicKey._fromNativeHandle(handle);
+    }
+  }
+  kyberPreKeySignature(): Buffer {
+    return Native.PreKeyBundle_GetKyberPreKeySignature(this);
+  }
 }
 
 export class PreKeyRecord {
@@ -381,6 +402,141 @@ export class PreKeyRecord {
   }
 }
 
+export class KEMPublicKey {
+  readonly _nativeHandle: Native.KyberPublicKey;
+
+  private constructor(handle: Native.KyberPublicKey) {
+    this._nativeHandle = handle;
+  }
+
+  static _fromNativeHandle(handle: Native.KyberPublicKey): KEMPublicKey {
+    return new KEMPublicKey(handle);
+  }
+
+  static deserialize(buf: Buffer): KEMPublicKey {
+    return new KEMPublicKey(Native.KyberPublicKey_Deserialize(buf));
+  }
+
+  serialize(): Buffer {
+    return Native.KyberPublicKey_Serialize(this);
+  }
+
+  equals(other: KEMPublicKey): boolean {
+    return Native.KyberPublicKey_Equals(this, other);
+  }
+}
+
+export class KEMSecretKey {
+  readonly _nativeHandle: Native.KyberSecretKey;
+
+  private constructor(handle: Native.KyberSecretKey) {
+    this._nativeHandle = handle;
+  }
+
+  static _fromNativeHandle(handle: Native.KyberSecretKey): KEMSecretKey {
+    return new KEMSecretKey(handle);
+  }
+
+  static deserialize(buf: Buffer): KEMSecretKey {
+    return new KEMSecretKey(Native.KyberSecretKey_Deserialize(buf));
+  }
+
+  serialize(): Buffer {
+    return Native.KyberSecretKey_Serialize(this);
+  }
+}
+
+export class KEMKeyPair {
+  readonly _nativeHandle: Native.KyberKeyPair;
+
+  private constructor(handle: Native.KyberKeyPair) {
+    this._nativeHandle = handle;
+  }
+
+  static _fromNativeHandle(handle: Native.KyberKeyPair): KEMKeyPair {
+    return new KEMKeyPair(handle);
+  }
+
+  static generate(): KEMKeyPair {
+    return new KEMKeyPair(Native.KyberKeyPair_Generate());
+  }
+
+  getPublicKey(): KEMPublicKey {
+    return KEMPublicKey._fromNativeHandle(
+      Native.KyberKeyPair_GetPublicKey(this)
+    );
+  }
+
+  getSecretKey(): KEMSecretKey {
+    return KEMSecretKey._fromNativeHandle(
+      Native.KyberKeyPair_GetSecretKey(this)
+    );
+  }
+}
+
+export class KyberPreKeyRecord {
+  readonly _nativeHandle: Native.KyberPreKeyRecord;
+
+  private constructor(handle: Native.KyberPreKeyRecord) {
+    this._nativeHandle = handle;
+  }
+
+  static _fromNativeHandle(
+    nativeHandle: Native.KyberPreKeyRecord
+  ): KyberPreKeyRecord {
+    return new KyberPreKeyRecord(nativeHandle);
+  }
+
+  static new(
+    id: number,
+    timestamp: number,
+    keyPair: KEMKeyPair,
+    signature: Buffer
+  ): KyberPreKeyRecord {
+    return new KyberPreKeyRecord(
+      Native.KyberPreKeyRecord_New(id, timestamp, keyPair, signature)
+    );
+  }
+
+  static deserialize(buffer: Buffer): KyberPreKeyRecord {
+    return new KyberPreKeyRecord(Native.KyberPreKeyRecord_Deserialize(buffer));
+  }
+
+  id(): number {
+    return Native.KyberPreKeyRecord_GetId(this);
+  }
+
+  keyPair(): KEMKeyPair {
+    return KEMKeyPair._fromNativeHandle(
+      Native.KyberPreKeyRecord_GetKeyPair(this)
+    );
+  }
+
+  publicKey(): KEMPublicKey {
+    return KEMPublicKey._fromNativeHandle(
+      Native.KyberPreKeyRecord_GetPublicKey(this)
+    );
+  }
+
+  secretKey(): KEMSecretKey {
+    return KEMSecretKey._fromNativeHandle(
+      Native.KyberPreKeyRecord_GetSecretKey(this)
+    );
+  }
+
+  serialize(): Buffer {
+    return Native.KyberPreKeyRecord_Serialize(this);
+  }
+
+  signature(): Buffer {
+    return Native.KyberPreKeyRecord_GetSignature(this);
+  }
+
+  timestamp(): number {
+    return Native.KyberPreKeyRecord_GetTimestamp(this);
+  }
+}
+
 export class SignedPreKeyRecord {
   readonly _nativeHandle: Native.SignedPreKeyRecord;
 
@@ -518,6 +674,8 @@ export class PreKeySignalMessage {
     registrationId: number,
     preKeyId: number | null,
     signedPreKeyId: number,
+    kyberPreKeyId: number | null,
+    kyberCiphertext: Buffer,
     baseKey: PublicKey,
     identityKey: PublicKey,
     signalMessage: SignalMessage
@@ -528,6 +686,8 @@ export class PreKeySignalMessage {
         registrationId,
         preKeyId,
         signedPreKeyId,
+        kyberPreKeyId,
+        kyberCiphertext,
         baseKey,
         identityKey,
         signalMessage
@@ -1073,6 +1233,29 @@ export abstract class SignedPreKeyStore implements Native.SignedPreKeyStore {
   abstract getSignedPreKey(id: number): Promise<SignedPreKeyRecord>;
 }
 
+export abstract class KyberPreKeyStore implements Native.KyberPreKeyStore {
+  async _saveKyberPreKey(
+    id: number,
+    record: Native.KyberPreKeyRecord
+  ): Promise<void> {
+    return this.saveKyberPreKey(id, KyberPreKeyRecord._fromNativeHandle(record));
+  }
+  async _getKyberPreKey(id: number): Promise<Native.KyberPreKeyRecord> {
+    const pk = await this.getKyberPreKey(id);
+    return pk._nativeHandle;
+  }
+  async _markKyberPreKeyUsed(id: number): Promise<void> {
+    return this.markKyberPreKeyUsed(id);
+  }
+
+  abstract saveKyberPreKey(
+    id: number,
+    record: KyberPreKeyRecord
+  ): Promise<void>;
+  abstract getKyberPreKey(id: number): Promise<KyberPreKeyRecord>;
+  abstract markKyberPreKeyUsed(id: number): Promise<void>;
+}
+
 export abstract class SenderKeyStore implements Native.SenderKeyStore {
   async _saveSenderKey(
     sender: Native.ProtocolAddress,
@@ -1344,7 +1527,8 @@ export function signalDecryptPreKey(
   sessionStore: SessionStore,
   identityStore: IdentityKeyStore,
   prekeyStore: PreKeyStore,
-  signedPrekeyStore: SignedPreKeyStore
+  signedPrekeyStore: SignedPreKeyStore,
+  kyberPrekeyStore: KyberPreKeyStore
 ): Promise<Buffer> {
   return Native.SessionCipher_DecryptPreKeySignalMessage(
     message,
@@ -1353,6 +1537,7 @@ export function signalDecryptPreKey(
     identityStore,
     prekeyStore,
     signedPrekeyStore,
+    kyberPrekeyStore,
     null
   );
 }
@@ -1420,7 +1605,8 @@ export async function sealedSenderDecryptMessage(
   sessionStore: SessionStore,
   identityStore: IdentityKeyStore,
   prekeyStore: PreKeyStore,
-  signedPrekeyStore: SignedPreKeyStore
+  signedPrekeyStore: SignedPreKeyStore,
+  kyberPrekeyStore: KyberPreKeyStore
 ): Promise<SealedSenderDecryptionResult> {
   const ssdr = await Native.SealedSender_DecryptMessage(
     message,
@@ -1432,7 +1618,8 @@ export async function sealedSenderDecryptMessage(
     sessionStore,
     identityStore,
     prekeyStore,
-    signedPrekeyStore
+    signedPrekeyStore,
+    kyberPrekeyStore
   );
   return SealedSenderDecryptionResult._fromNativeHandle(ssdr);
 }
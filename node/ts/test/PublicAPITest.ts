@@ -139,6 +139,27 @@ class InMemorySignedPreKeyStore extends SignalClient.SignedPreKeyStore {
   }
 }
 
+class InMemoryKyberPreKeyStore extends SignalClient.KyberPreKeyStore {
+  private state = new Map<number, Buffer>();
+  private used = new Set<number>();
+  async saveKyberPreKey(
+    id: number,
+    record: SignalClient.KyberPreKeyRecord
+  ): Promise<void> {
+    this.state.set(id, record.serialize());
+  }
+  async getKyberPreKey(id: number): Promise<SignalClient.KyberPreKeyRecord> {
+    const record = this.state.get(id);
+    if (!record) {
+      throw new Error(`kyber pre-key ${id} not found`);
+    }
+    return SignalClient.KyberPreKeyRecord.deserialize(record);
+  }
+  async markKyberPreKeyUsed(id: number): Promise<void> {
+    this.used.add(id);
+  }
+}
+
 class InMemorySenderKeyStore extends SignalClient.SenderKeyStore {
   private state = new Map<string, SignalClient.SenderKeyRecord>();
   async saveSenderKey(
@@ -545,6 +566,8 @@ describe('SignalClient', () => {
       registrationId,
       preKeyId,
       signedPreKeyId,
+      null,
+      Buffer.alloc(0),
       baseKey,
       identityKey,
       sm
@@ -570,6 +593,7 @@ describe('SignalClient', () => {
 
     const bPreK = new InMemoryPreKeyStore();
     const bSPreK = new InMemorySignedPreKeyStore();
+    const bKyberPreK = new InMemoryKyberPreKeyStore();
 
     const bPreKey = SignalClient.PrivateKey.generate();
     const bSPreKey = SignalClient.PrivateKey.generate();
@@ -643,7 +667,8 @@ describe('SignalClient', () => {
       bSess,
       bKeys,
       bPreK,
-      bSPreK
+      bSPreK,
+      bKyberPreK
     );
     assert.deepEqual(bDPlaintext, aMessage);
 
@@ -707,6 +732,7 @@ describe('SignalClient', () => {
 
     const bPreK = new InMemoryPreKeyStore();
     const bSPreK = new InMemorySignedPreKeyStore();
+    const bKyberPreK = new InMemoryKyberPreKeyStore();
 
     const bPreKey = SignalClient.PrivateKey.generate();
     const bSPreKey = SignalClient.PrivateKey.generate();
@@ -780,7 +806,8 @@ describe('SignalClient', () => {
       bSess,
       bKeys,
       bPreK,
-      bSPreK
+      bSPreK,
+      bKyberPreK
     );
     assert.deepEqual(bDPlaintext, aMessage);
 
@@ -791,7 +818,8 @@ describe('SignalClient', () => {
         bSess,
         bKeys,
         bPreK,
-        bSPreK
+        bSPreK,
+        bKyberPreK
       );
       assert.fail();
     } catch (e) {
@@ -857,6 +885,7 @@ describe('SignalClient', () => {
 
       const bPreK = new InMemoryPreKeyStore();
       const bSPreK = new InMemorySignedPreKeyStore();
+      const bKyberPreK = new InMemoryKyberPreKeyStore();
 
       const bPreKey = SignalClient.PrivateKey.generate();
       const bSPreKey = SignalClient.PrivateKey.generate();
@@ -956,7 +985,8 @@ describe('SignalClient', () => {
         bSess,
         bKeys,
         bPreK,
-        bSPreK
+        bSPreK,
+        bKyberPreK
       );
 
       assert(bPlaintext != null);
@@ -1005,6 +1035,7 @@ describe('SignalClient', () => {
 
       const bPreK = new InMemoryPreKeyStore();
       const bSPreK = new InMemorySignedPreKeyStore();
+      const bKyberPreK = new InMemoryKyberPreKeyStore();
 
       const bPreKey = SignalClient.PrivateKey.generate();
       const bSPreKey = SignalClient.PrivateKey.generate();
@@ -1104,7 +1135,8 @@ describe('SignalClient', () => {
           bSess,
           sharedKeys,
           bPreK,
-          bSPreK
+          bSPreK,
+          bKyberPreK
         );
         assert.fail();
       } catch (e) {
@@ -1394,6 +1426,7 @@ describe('SignalClient', () => {
 
     const bPreK = new InMemoryPreKeyStore();
     const bSPreK = new InMemorySignedPreKeyStore();
+    const bKyberPreK = new InMemoryKyberPreKeyStore();
 
     const bPreKey = SignalClient.PrivateKey.generate();
     const bSPreKey = SignalClient.PrivateKey.generate();
@@ -1494,7 +1527,8 @@ describe('SignalClient', () => {
       bSess,
       bKeys,
       bPreK,
-      bSPreK
+      bSPreK,
+      bKyberPreK
     );
 
     // Pretend to send a message from B back to A that "fails".
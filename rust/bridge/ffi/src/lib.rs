@@ -163,6 +163,7 @@ pub unsafe extern "C" fn signal_sealed_session_cipher_decrypt(
     identity_store: *const FfiIdentityKeyStoreStruct,
     prekey_store: *const FfiPreKeyStoreStruct,
     signed_prekey_store: *const FfiSignedPreKeyStoreStruct,
+    kyber_prekey_store: *const FfiKyberPreKeyStoreStruct,
     ctx: *mut c_void,
 ) -> *mut SignalFfiError {
     run_ffi_safe(|| {
@@ -174,6 +175,9 @@ pub unsafe extern "C" fn signal_sealed_session_cipher_decrypt(
         let mut signed_prekey_store = signed_prekey_store
             .as_ref()
             .ok_or(SignalFfiError::NullPointer)?;
+        let mut kyber_prekey_store = kyber_prekey_store
+            .as_ref()
+            .ok_or(SignalFfiError::NullPointer)?;
 
         let local_e164 = Option::convert_from(local_e164)?;
         let local_uuid = Option::convert_from(local_uuid)?.ok_or(SignalFfiError::NullPointer)?;
@@ -189,6 +193,8 @@ pub unsafe extern "C" fn signal_sealed_session_cipher_decrypt(
             &mut session_store,
             &mut prekey_store,
             &mut signed_prekey_store,
+            &mut kyber_prekey_store,
+            &SessionPolicy::default(),
             Some(ctx),
         )
         .now_or_never()
@@ -87,7 +87,9 @@ impl From<&SignalFfiError> for SignalErrorCode {
             }
 
             SignalFfiError::Signal(SignalProtocolError::InvalidPreKeyId)
-            | SignalFfiError::Signal(SignalProtocolError::InvalidSignedPreKeyId) => {
+            | SignalFfiError::Signal(SignalProtocolError::InvalidSignedPreKeyId)
+            | SignalFfiError::Signal(SignalProtocolError::InvalidKyberPreKeyId)
+            | SignalFfiError::Signal(SignalProtocolError::SignedPreKeyExpired(_)) => {
                 SignalErrorCode::InvalidKeyIdentifier
             }
 
@@ -102,6 +104,10 @@ impl From<&SignalFfiError> for SignalErrorCode {
             SignalFfiError::Signal(SignalProtocolError::NoKeyTypeIdentifier)
             | SignalFfiError::Signal(SignalProtocolError::BadKeyType(_))
             | SignalFfiError::Signal(SignalProtocolError::BadKeyLength(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::BadKEMKeyType(_))
+            | SignalFfiError::Signal(SignalProtocolError::WrongKEMKeyType(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::BadKEMKeyLength(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::BadKEMCiphertextLength(_, _))
             | SignalFfiError::Signal(SignalProtocolError::InvalidMacKeyLength(_))
             | SignalFfiError::DeviceTransfer(DeviceTransferError::KeyDecodingFailed)
             | SignalFfiError::HsmEnclave(HsmEnclaveError::InvalidPublicKeyError)
@@ -144,7 +150,9 @@ impl From<&SignalFfiError> for SignalErrorCode {
 
             SignalFfiError::Signal(SignalProtocolError::InvalidMessage(..))
             | SignalFfiError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
+            | SignalFfiError::Signal(SignalProtocolError::InvalidPadding)
             | SignalFfiError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
+            | SignalFfiError::Signal(SignalProtocolError::InvalidProvisioningMessage(_))
             | SignalFfiError::SignalCrypto(SignalCryptoError::InvalidTag)
             | SignalFfiError::Sgx(SgxError::DcapError(_))
             | SignalFfiError::Sgx(SgxError::NoiseError(_))
@@ -164,6 +172,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
             }
 
             SignalFfiError::Signal(SignalProtocolError::InvalidState(_, _))
+            | SignalFfiError::Signal(SignalProtocolError::InvalidEncryptedRecord(_))
+            | SignalFfiError::Signal(SignalProtocolError::SenderKeyRotationRequired { .. })
             | SignalFfiError::Sgx(SgxError::InvalidBridgeStateError)
             | SignalFfiError::HsmEnclave(HsmEnclaveError::InvalidBridgeStateError) => {
                 SignalErrorCode::InvalidState
@@ -185,7 +195,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
                 SignalErrorCode::CallbackError
             }
 
-            SignalFfiError::ZkGroupVerificationFailure(ZkGroupVerificationFailure) => {
+            SignalFfiError::ZkGroupVerificationFailure(ZkGroupVerificationFailure)
+            | SignalFfiError::Signal(SignalProtocolError::KeyTransparencyVerificationFailed(_)) => {
                 SignalErrorCode::VerificationFailure
             }
 
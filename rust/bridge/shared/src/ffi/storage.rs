@@ -50,6 +50,8 @@ pub struct FfiIdentityKeyStoreStruct {
     is_trusted_identity: IsTrustedIdentity,
 }
 
+impl ProtocolStoreTransaction for &FfiIdentityKeyStoreStruct {}
+
 #[async_trait(?Send)]
 impl IdentityKeyStore for &FfiIdentityKeyStoreStruct {
     async fn get_identity_key_pair(
@@ -95,18 +97,24 @@ impl IdentityKeyStore for &FfiIdentityKeyStoreStruct {
         Ok(id)
     }
 
+    // The app's callback does not report what it replaced, so the previous identity is looked up
+    // first. That lookup is a separate call, not atomic with the save: if the identity changes in
+    // between, the returned IdentityChange may be wrong.
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
         ctx: Context,
-    ) -> Result<bool, SignalProtocolError> {
+    ) -> Result<IdentityChange, SignalProtocolError> {
+        let previous = self.get_identity(address, ctx).await?;
         let ctx = ctx.unwrap_or(std::ptr::null_mut());
         let result = (self.save_identity)(self.ctx, address, identity.public_key(), ctx);
 
         match result {
-            0 => Ok(false),
-            1 => Ok(true),
+            0 if previous.is_none() => Ok(IdentityChange::New),
+            0 => Ok(IdentityChange::Unchanged),
+            // The app does not tell us whether the old identity was verified.
+            1 => Ok(IdentityChange::ReplacedUnverified),
             r => Err(SignalProtocolError::ApplicationCallbackError(
                 "save_identity",
                 Box::new(CallbackError::check(r).expect("verified non-zero")),
@@ -168,6 +176,29 @@ impl IdentityKeyStore for &FfiIdentityKeyStoreStruct {
 
         Ok(Some(IdentityKey::new(*pk)))
     }
+
+    // Verification state is kept by the app and not exposed through this store, so every identity
+    // reads as unverified here and attempts to change it fail.
+    async fn get_verified_status(
+        &self,
+        _address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<VerifiedStatus, SignalProtocolError> {
+        Ok(VerifiedStatus::Default)
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        _address: &ProtocolAddress,
+        _identity: &IdentityKey,
+        _status: VerifiedStatus,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "set_verified_status",
+            "verification state is kept by the app".to_string(),
+        ))
+    }
 }
 
 type LoadPreKey = extern "C" fn(
@@ -193,6 +224,8 @@ pub struct FfiPreKeyStoreStruct {
     remove_pre_key: RemovePreKey,
 }
 
+impl ProtocolStoreTransaction for &FfiPreKeyStoreStruct {}
+
 #[async_trait(?Send)]
 impl PreKeyStore for &FfiPreKeyStoreStruct {
     async fn get_pre_key(
@@ -325,6 +358,97 @@ impl SignedPreKeyStore for &FfiSignedPreKeyStoreStruct {
     }
 }
 
+type LoadKyberPreKey = extern "C" fn(
+    store_ctx: *mut c_void,
+    recordp: *mut *mut KyberPreKeyRecord,
+    id: u32,
+    ctx: *mut c_void,
+) -> c_int;
+type StoreKyberPreKey = extern "C" fn(
+    store_ctx: *mut c_void,
+    id: u32,
+    record: *const KyberPreKeyRecord,
+    ctx: *mut c_void,
+) -> c_int;
+type MarkKyberPreKeyUsed =
+    extern "C" fn(store_ctx: *mut c_void, id: u32, ctx: *mut c_void) -> c_int;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FfiKyberPreKeyStoreStruct {
+    ctx: *mut c_void,
+    load_kyber_pre_key: LoadKyberPreKey,
+    store_kyber_pre_key: StoreKyberPreKey,
+    mark_kyber_pre_key_used: MarkKyberPreKeyUsed,
+}
+
+impl ProtocolStoreTransaction for &FfiKyberPreKeyStoreStruct {}
+
+#[async_trait(?Send)]
+impl KyberPreKeyStore for &FfiKyberPreKeyStoreStruct {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<KyberPreKeyRecord, SignalProtocolError> {
+        let ctx = ctx.unwrap_or(std::ptr::null_mut());
+        let mut record = std::ptr::null_mut();
+        let result = (self.load_kyber_pre_key)(self.ctx, &mut record, kyber_prekey_id.into(), ctx);
+
+        if let Some(error) = CallbackError::check(result) {
+            return Err(SignalProtocolError::ApplicationCallbackError(
+                "load_kyber_pre_key",
+                Box::new(error),
+            ));
+        }
+
+        if record.is_null() {
+            return Err(SignalProtocolError::InvalidKyberPreKeyId);
+        }
+
+        let record = unsafe { Box::from_raw(record) };
+
+        Ok(*record)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        let ctx = ctx.unwrap_or(std::ptr::null_mut());
+        let result = (self.store_kyber_pre_key)(self.ctx, kyber_prekey_id.into(), record, ctx);
+
+        if let Some(error) = CallbackError::check(result) {
+            return Err(SignalProtocolError::ApplicationCallbackError(
+                "store_kyber_pre_key",
+                Box::new(error),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        let ctx = ctx.unwrap_or(std::ptr::null_mut());
+        let result = (self.mark_kyber_pre_key_used)(self.ctx, kyber_prekey_id.into(), ctx);
+
+        if let Some(error) = CallbackError::check(result) {
+            return Err(SignalProtocolError::ApplicationCallbackError(
+                "mark_kyber_pre_key_used",
+                Box::new(error),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 type LoadSession = extern "C" fn(
     store_ctx: *mut c_void,
     recordp: *mut *mut SessionRecord,
@@ -346,6 +470,8 @@ pub struct FfiSessionStoreStruct {
     store_session: StoreSession,
 }
 
+impl ProtocolStoreTransaction for &FfiSessionStoreStruct {}
+
 #[async_trait(?Send)]
 impl SessionStore for &FfiSessionStoreStruct {
     async fn load_session(
@@ -416,6 +542,8 @@ pub struct FfiSenderKeyStoreStruct {
     store_sender_key: StoreSenderKey,
 }
 
+impl ProtocolStoreTransaction for &FfiSenderKeyStoreStruct {}
+
 #[async_trait(?Send)]
 impl SenderKeyStore for &FfiSenderKeyStoreStruct {
     async fn store_sender_key(
@@ -345,6 +345,7 @@ impl<'a> SimpleArgTypeInfo<'a> for Context {
 }
 
 store!(IdentityKeyStore);
+store!(KyberPreKeyStore);
 store!(PreKeyStore);
 store!(SenderKeyStore);
 store!(SessionStore);
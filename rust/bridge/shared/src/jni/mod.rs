@@ -203,6 +203,8 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         SignalJniError::NullHandle => jni_class_name!(java.lang.NullPointerException),
 
         SignalJniError::Signal(SignalProtocolError::InvalidState(_, _))
+        | SignalJniError::Signal(SignalProtocolError::InvalidEncryptedRecord(_))
+        | SignalJniError::Signal(SignalProtocolError::SenderKeyRotationRequired { .. })
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidState) => {
             jni_class_name!(java.lang.IllegalStateException)
         }
@@ -229,14 +231,21 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         }
 
         SignalJniError::Signal(SignalProtocolError::InvalidPreKeyId)
-        | SignalJniError::Signal(SignalProtocolError::InvalidSignedPreKeyId) => {
+        | SignalJniError::Signal(SignalProtocolError::InvalidSignedPreKeyId)
+        | SignalJniError::Signal(SignalProtocolError::InvalidKyberPreKeyId)
+        | SignalJniError::Signal(SignalProtocolError::SignedPreKeyExpired(_)) => {
             jni_class_name!(org.signal.libsignal.protocol.InvalidKeyIdException)
         }
 
         SignalJniError::Signal(SignalProtocolError::NoKeyTypeIdentifier)
         | SignalJniError::Signal(SignalProtocolError::SignatureValidationFailed)
+        | SignalJniError::Signal(SignalProtocolError::KeyTransparencyVerificationFailed(_))
         | SignalJniError::Signal(SignalProtocolError::BadKeyType(_))
         | SignalJniError::Signal(SignalProtocolError::BadKeyLength(_, _))
+        | SignalJniError::Signal(SignalProtocolError::BadKEMKeyType(_))
+        | SignalJniError::Signal(SignalProtocolError::WrongKEMKeyType(_, _))
+        | SignalJniError::Signal(SignalProtocolError::BadKEMKeyLength(_, _))
+        | SignalJniError::Signal(SignalProtocolError::BadKEMCiphertextLength(_, _))
         | SignalJniError::Signal(SignalProtocolError::InvalidMacKeyLength(_))
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidKeySize) => {
             jni_class_name!(org.signal.libsignal.protocol.InvalidKeyException)
@@ -254,7 +263,9 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         SignalJniError::Signal(SignalProtocolError::InvalidMessage(..))
         | SignalJniError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
         | SignalJniError::Signal(SignalProtocolError::InvalidProtobufEncoding)
+        | SignalJniError::Signal(SignalProtocolError::InvalidPadding)
         | SignalJniError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
+        | SignalJniError::Signal(SignalProtocolError::InvalidProvisioningMessage(_))
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidTag) => {
             jni_class_name!(org.signal.libsignal.protocol.InvalidMessageException)
         }
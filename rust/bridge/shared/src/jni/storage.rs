@@ -9,6 +9,7 @@ use uuid::Uuid;
 
 pub type JavaIdentityKeyStore<'a> = JObject<'a>;
 pub type JavaPreKeyStore<'a> = JObject<'a>;
+pub type JavaKyberPreKeyStore<'a> = JObject<'a>;
 pub type JavaSignedPreKeyStore<'a> = JObject<'a>;
 pub type JavaSessionStore<'a> = JObject<'a>;
 pub type JavaSenderKeyStore<'a> = JObject<'a>;
@@ -150,6 +151,8 @@ impl<'a> JniIdentityKeyStore<'a> {
     }
 }
 
+impl<'a> ProtocolStoreTransaction for JniIdentityKeyStore<'a> {}
+
 #[async_trait(?Send)]
 impl<'a> IdentityKeyStore for JniIdentityKeyStore<'a> {
     async fn get_identity_key_pair(
@@ -163,13 +166,23 @@ impl<'a> IdentityKeyStore for JniIdentityKeyStore<'a> {
         Ok(self.do_get_local_registration_id()?)
     }
 
+    // The app's callback does not report what it replaced, so the previous identity is looked up
+    // first. That lookup is a separate call, not atomic with the save: if the identity changes in
+    // between, the returned IdentityChange may be wrong.
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
         _ctx: Context,
-    ) -> Result<bool, SignalProtocolError> {
-        Ok(self.do_save_identity(address, identity)?)
+    ) -> Result<IdentityChange, SignalProtocolError> {
+        let previous = self.do_get_identity(address)?;
+        let replaced = self.do_save_identity(address, identity)?;
+        Ok(match previous {
+            None => IdentityChange::New,
+            // The app does not tell us whether the old identity was verified.
+            Some(_) if replaced => IdentityChange::ReplacedUnverified,
+            Some(_) => IdentityChange::Unchanged,
+        })
     }
 
     async fn is_trusted_identity(
@@ -189,6 +202,29 @@ impl<'a> IdentityKeyStore for JniIdentityKeyStore<'a> {
     ) -> Result<Option<IdentityKey>, SignalProtocolError> {
         Ok(self.do_get_identity(address)?)
     }
+
+    // Verification state is kept by the app and not exposed through this store, so every identity
+    // reads as unverified here and attempts to change it fail.
+    async fn get_verified_status(
+        &self,
+        _address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<VerifiedStatus, SignalProtocolError> {
+        Ok(VerifiedStatus::Default)
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        _address: &ProtocolAddress,
+        _identity: &IdentityKey,
+        _status: VerifiedStatus,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "set_verified_status",
+            "verification state is kept by the app".to_string(),
+        ))
+    }
 }
 
 pub struct JniPreKeyStore<'a> {
@@ -249,6 +285,8 @@ impl<'a> JniPreKeyStore<'a> {
     }
 }
 
+impl<'a> ProtocolStoreTransaction for JniPreKeyStore<'a> {}
+
 #[async_trait(?Send)]
 impl<'a> PreKeyStore for JniPreKeyStore<'a> {
     async fn get_pre_key(
@@ -347,6 +385,96 @@ impl<'a> SignedPreKeyStore for JniSignedPreKeyStore<'a> {
     }
 }
 
+pub struct JniKyberPreKeyStore<'a> {
+    env: &'a JNIEnv<'a>,
+    store: JObject<'a>,
+}
+
+impl<'a> JniKyberPreKeyStore<'a> {
+    pub fn new(env: &'a JNIEnv, store: JObject<'a>) -> Result<Self, SignalJniError> {
+        check_jobject_type(
+            env,
+            store,
+            jni_class_name!(org.signal.libsignal.protocol.state.KyberPreKeyStore),
+        )?;
+        Ok(Self { env, store })
+    }
+}
+
+impl<'a> JniKyberPreKeyStore<'a> {
+    fn do_get_kyber_pre_key(&self, prekey_id: u32) -> Result<KyberPreKeyRecord, SignalJniError> {
+        let callback_args = jni_args!((
+            prekey_id.convert_into(self.env)? => int
+        ) -> org.signal.libsignal.protocol.state.KyberPreKeyRecord);
+        let kpk: Option<KyberPreKeyRecord> =
+            get_object_with_native_handle(self.env, self.store, callback_args, "loadKyberPreKey")?;
+        match kpk {
+            Some(kpk) => Ok(kpk),
+            None => Err(SignalJniError::Signal(
+                SignalProtocolError::InvalidKyberPreKeyId,
+            )),
+        }
+    }
+
+    fn do_save_kyber_pre_key(
+        &mut self,
+        prekey_id: u32,
+        record: &KyberPreKeyRecord,
+    ) -> Result<(), SignalJniError> {
+        let jobject_record = jobject_from_native_handle(
+            self.env,
+            jni_class_name!(org.signal.libsignal.protocol.state.KyberPreKeyRecord),
+            record.clone().convert_into(self.env)?,
+        )?;
+        let callback_args = jni_args!((
+            prekey_id.convert_into(self.env)? => int,
+            jobject_record => org.signal.libsignal.protocol.state.KyberPreKeyRecord
+        ) -> void);
+        call_method_checked(self.env, self.store, "storeKyberPreKey", callback_args)?;
+        Ok(())
+    }
+
+    fn do_mark_kyber_pre_key_used(&mut self, prekey_id: u32) -> Result<(), SignalJniError> {
+        call_method_checked(
+            self.env,
+            self.store,
+            "markKyberPreKeyUsed",
+            jni_args!((prekey_id.convert_into(self.env)? => int) -> void),
+        )?;
+        Ok(())
+    }
+}
+
+impl<'a> ProtocolStoreTransaction for JniKyberPreKeyStore<'a> {}
+
+#[async_trait(?Send)]
+impl<'a> KyberPreKeyStore for JniKyberPreKeyStore<'a> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<KyberPreKeyRecord, SignalProtocolError> {
+        Ok(self.do_get_kyber_pre_key(kyber_prekey_id.into())?)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        Ok(self.do_save_kyber_pre_key(kyber_prekey_id.into(), record)?)
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        Ok(self.do_mark_kyber_pre_key_used(kyber_prekey_id.into())?)
+    }
+}
+
 pub struct JniSessionStore<'a> {
     env: &'a JNIEnv<'a>,
     store: JObject<'a>,
@@ -397,6 +525,8 @@ impl<'a> JniSessionStore<'a> {
     }
 }
 
+impl<'a> ProtocolStoreTransaction for JniSessionStore<'a> {}
+
 #[async_trait(?Send)]
 impl<'a> SessionStore for JniSessionStore<'a> {
     async fn load_session(
@@ -473,6 +603,8 @@ impl<'a> JniSenderKeyStore<'a> {
     }
 }
 
+impl<'a> ProtocolStoreTransaction for JniSenderKeyStore<'a> {}
+
 #[async_trait(?Send)]
 impl<'a> SenderKeyStore for JniSenderKeyStore<'a> {
     async fn store_sender_key(
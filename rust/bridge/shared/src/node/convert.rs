@@ -534,6 +534,7 @@ macro_rules! store {
 }
 
 store!(IdentityKeyStore);
+store!(KyberPreKeyStore);
 store!(PreKeyStore);
 store!(SenderKeyStore);
 store!(SessionStore);
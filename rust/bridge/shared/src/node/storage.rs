@@ -101,6 +101,8 @@ impl Finalize for NodePreKeyStore {
     }
 }
 
+impl ProtocolStoreTransaction for NodePreKeyStore {}
+
 #[async_trait(?Send)]
 impl PreKeyStore for NodePreKeyStore {
     async fn get_pre_key(
@@ -230,6 +232,136 @@ impl SignedPreKeyStore for NodeSignedPreKeyStore {
     }
 }
 
+pub struct NodeKyberPreKeyStore {
+    js_channel: Channel,
+    store_object: Arc<Root<JsObject>>,
+}
+
+impl NodeKyberPreKeyStore {
+    pub(crate) fn new(cx: &mut FunctionContext, store: Handle<JsObject>) -> Self {
+        Self {
+            js_channel: cx.channel(),
+            store_object: Arc::new(store.root(cx)),
+        }
+    }
+
+    async fn do_get_kyber_pre_key(&self, id: u32) -> Result<KyberPreKeyRecord, String> {
+        let store_object_shared = self.store_object.clone();
+        JsFuture::get_promise(&self.js_channel, move |cx| {
+            let store_object = store_object_shared.to_inner(cx);
+            let id = id.convert_into(cx)?;
+            let result = call_method(cx, store_object, "_getKyberPreKey", [id.upcast()])?;
+            let result = result.downcast_or_throw(cx)?;
+            store_object_shared.finalize(cx);
+            Ok(result)
+        })
+        .then(|cx, result| match result {
+            Ok(value) => match value.downcast::<DefaultJsBox<KyberPreKeyRecord>, _>(cx) {
+                Ok(obj) => Ok((***obj).clone()),
+                Err(_) => Err("result must be an object".to_owned()),
+            },
+            Err(error) => Err(error
+                .to_string(cx)
+                .expect("can convert to string")
+                .value(cx)),
+        })
+        .await
+    }
+
+    async fn do_save_kyber_pre_key(
+        &self,
+        id: u32,
+        record: KyberPreKeyRecord,
+    ) -> Result<(), String> {
+        let store_object_shared = self.store_object.clone();
+        JsFuture::get_promise(&self.js_channel, move |cx| {
+            let store_object = store_object_shared.to_inner(cx);
+            let id: Handle<JsNumber> = id.convert_into(cx)?;
+            let record: Handle<JsValue> = record.convert_into(cx)?;
+            let result = call_method(cx, store_object, "_saveKyberPreKey", [id.upcast(), record])?
+                .downcast_or_throw(cx)?;
+            store_object_shared.finalize(cx);
+            Ok(result)
+        })
+        .then(|cx, result| match result {
+            Ok(value) => match value.downcast::<JsUndefined, _>(cx) {
+                Ok(_) => Ok(()),
+                Err(_) => Err("unexpected result from _saveKyberPreKey".into()),
+            },
+            Err(error) => Err(error
+                .to_string(cx)
+                .expect("can convert to string")
+                .value(cx)),
+        })
+        .await
+    }
+
+    async fn do_mark_kyber_pre_key_used(&self, id: u32) -> Result<(), String> {
+        let store_object_shared = self.store_object.clone();
+        JsFuture::get_promise(&self.js_channel, move |cx| {
+            let store_object = store_object_shared.to_inner(cx);
+            let id: Handle<JsNumber> = id.convert_into(cx)?;
+            let result = call_method(cx, store_object, "_markKyberPreKeyUsed", [id.upcast()])?
+                .downcast_or_throw(cx)?;
+            store_object_shared.finalize(cx);
+            Ok(result)
+        })
+        .then(|cx, result| match result {
+            Ok(value) => match value.downcast::<JsUndefined, _>(cx) {
+                Ok(_) => Ok(()),
+                Err(_) => Err("unexpected result from _markKyberPreKeyUsed".into()),
+            },
+            Err(error) => Err(error
+                .to_string(cx)
+                .expect("can convert to string")
+                .value(cx)),
+        })
+        .await
+    }
+}
+
+impl Finalize for NodeKyberPreKeyStore {
+    fn finalize<'b, C: neon::prelude::Context<'b>>(self, cx: &mut C) {
+        self.store_object.finalize(cx)
+    }
+}
+
+impl ProtocolStoreTransaction for NodeKyberPreKeyStore {}
+
+#[async_trait(?Send)]
+impl KyberPreKeyStore for NodeKyberPreKeyStore {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_pre_key_id: KyberPreKeyId,
+        _ctx: libsignal_protocol::Context,
+    ) -> Result<KyberPreKeyRecord, SignalProtocolError> {
+        self.do_get_kyber_pre_key(kyber_pre_key_id.into())
+            .await
+            .map_err(|s| js_error_to_rust("getKyberPreKey", s))
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_pre_key_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        _ctx: libsignal_protocol::Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.do_save_kyber_pre_key(kyber_pre_key_id.into(), record.clone())
+            .await
+            .map_err(|s| js_error_to_rust("saveKyberPreKey", s))
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_pre_key_id: KyberPreKeyId,
+        _ctx: libsignal_protocol::Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.do_mark_kyber_pre_key_used(kyber_pre_key_id.into())
+            .await
+            .map_err(|s| js_error_to_rust("markKyberPreKeyUsed", s))
+    }
+}
+
 pub struct NodeSessionStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -307,6 +439,8 @@ impl Finalize for NodeSessionStore {
     }
 }
 
+impl ProtocolStoreTransaction for NodeSessionStore {}
+
 #[async_trait(?Send)]
 impl SessionStore for NodeSessionStore {
     async fn load_session(
@@ -489,6 +623,8 @@ impl Finalize for NodeIdentityKeyStore {
     }
 }
 
+impl ProtocolStoreTransaction for NodeIdentityKeyStore {}
+
 #[async_trait(?Send)]
 impl IdentityKeyStore for NodeIdentityKeyStore {
     async fn get_identity_key_pair(
@@ -524,15 +660,29 @@ impl IdentityKeyStore for NodeIdentityKeyStore {
             .map(IdentityKey::new))
     }
 
+    // The app's callback does not report what it replaced, so the previous identity is looked up
+    // first. That lookup is a separate call, not atomic with the save: if the identity changes in
+    // between, the returned IdentityChange may be wrong.
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
         _ctx: libsignal_protocol::Context,
-    ) -> Result<bool, SignalProtocolError> {
-        self.do_save_identity(address.clone(), *identity.public_key())
+    ) -> Result<IdentityChange, SignalProtocolError> {
+        let previous = self
+            .do_get_identity(address.clone())
             .await
-            .map_err(|s| js_error_to_rust("saveIdentity", s))
+            .map_err(|s| js_error_to_rust("getIdentity", s))?;
+        let replaced = self
+            .do_save_identity(address.clone(), *identity.public_key())
+            .await
+            .map_err(|s| js_error_to_rust("saveIdentity", s))?;
+        Ok(match previous {
+            None => IdentityChange::New,
+            // The app does not tell us whether the old identity was verified.
+            Some(_) if replaced => IdentityChange::ReplacedUnverified,
+            Some(_) => IdentityChange::Unchanged,
+        })
     }
 
     async fn is_trusted_identity(
@@ -546,6 +696,29 @@ impl IdentityKeyStore for NodeIdentityKeyStore {
             .await
             .map_err(|s| js_error_to_rust("isTrustedIdentity", s))
     }
+
+    // Verification state is kept by the app and not exposed through this store, so every identity
+    // reads as unverified here and attempts to change it fail.
+    async fn get_verified_status(
+        &self,
+        _address: &ProtocolAddress,
+        _ctx: libsignal_protocol::Context,
+    ) -> Result<VerifiedStatus, SignalProtocolError> {
+        Ok(VerifiedStatus::Default)
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        _address: &ProtocolAddress,
+        _identity: &IdentityKey,
+        _status: VerifiedStatus,
+        _ctx: libsignal_protocol::Context,
+    ) -> Result<bool, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "set_verified_status",
+            "verification state is kept by the app".to_string(),
+        ))
+    }
 }
 
 pub struct NodeSenderKeyStore {
@@ -637,6 +810,8 @@ impl Finalize for NodeSenderKeyStore {
     }
 }
 
+impl ProtocolStoreTransaction for NodeSenderKeyStore {}
+
 #[async_trait(?Send)]
 impl SenderKeyStore for NodeSenderKeyStore {
     async fn load_sender_key(
@@ -20,6 +20,10 @@ use crate::*;
 bridge_handle!(CiphertextMessage, clone = false, jni = false);
 bridge_handle!(DecryptionErrorMessage);
 bridge_handle!(Fingerprint, jni = NumericFingerprintGenerator);
+bridge_handle!(KyberKeyPair);
+bridge_handle!(KyberPreKeyRecord);
+bridge_handle!(KyberPublicKey);
+bridge_handle!(KyberSecretKey);
 bridge_handle!(PlaintextContent);
 bridge_handle!(PreKeyBundle);
 bridge_handle!(PreKeyRecord);
@@ -38,6 +42,10 @@ bridge_handle!(SignedPreKeyRecord);
 bridge_handle!(UnidentifiedSenderMessageContent, clone = false);
 bridge_handle!(SealedSenderDecryptionResult, ffi = false, jni = false);
 
+pub type KyberKeyPair = kem::KeyPair;
+pub type KyberPublicKey = kem::PublicKey;
+pub type KyberSecretKey = kem::SecretKey;
+
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct Timestamp(u64);
 
@@ -303,21 +311,37 @@ fn SignalMessage_GetSenderRatchetKey(m: &SignalMessage) -> PublicKey {
     *m.sender_ratchet_key()
 }
 
+#[allow(clippy::too_many_arguments)]
 #[bridge_fn]
 fn PreKeySignalMessage_New(
     message_version: u8,
     registration_id: u32,
     pre_key_id: Option<u32>,
     signed_pre_key_id: u32,
+    kyber_pre_key_id: Option<u32>,
+    kyber_ciphertext: &[u8],
     base_key: &PublicKey,
     identity_key: &PublicKey,
     signal_message: &SignalMessage,
 ) -> Result<PreKeySignalMessage> {
+    let kyber_payload = match (kyber_pre_key_id, kyber_ciphertext) {
+        (None, []) => None,
+        (Some(id), ciphertext) if !ciphertext.is_empty() => {
+            Some(KyberPayload::new(id.into(), ciphertext.into()))
+        }
+        _ => {
+            return Err(SignalProtocolError::InvalidArgument(
+                "Must supply both or neither of kyber_pre_key_id and kyber_ciphertext".to_owned(),
+            ))
+        }
+    };
+
     PreKeySignalMessage::new(
         message_version,
         registration_id,
         pre_key_id.map(|id| id.into()),
         signed_pre_key_id.into(),
+        kyber_payload,
         *base_key,
         IdentityKey::new(*identity_key),
         signal_message.clone(),
@@ -483,6 +507,7 @@ fn PlaintextContent_DeserializeAndGetContent(bytes: &[u8]) -> Result<Vec<u8>> {
     Ok(PlaintextContent::try_from(bytes)?.body().to_vec())
 }
 
+#[allow(clippy::too_many_arguments)]
 #[bridge_fn]
 fn PreKeyBundle_New(
     registration_id: u32,
@@ -493,6 +518,9 @@ fn PreKeyBundle_New(
     signed_prekey: &PublicKey,
     signed_prekey_signature: &[u8],
     identity_key: &PublicKey,
+    kyber_prekey_id: Option<u32>,
+    kyber_prekey: Option<&KyberPublicKey>,
+    kyber_prekey_signature: &[u8],
 ) -> Result<PreKeyBundle> {
     let identity_key = IdentityKey::new(*identity_key);
 
@@ -506,7 +534,7 @@ fn PreKeyBundle_New(
         }
     };
 
-    PreKeyBundle::new(
+    let bundle = PreKeyBundle::new(
         registration_id,
         device_id.into(),
         prekey,
@@ -514,7 +542,17 @@ fn PreKeyBundle_New(
         *signed_prekey,
         signed_prekey_signature.to_vec(),
         identity_key,
-    )
+    )?;
+
+    match (kyber_prekey, kyber_prekey_id) {
+        (None, None) => Ok(bundle),
+        (Some(k), Some(id)) => {
+            Ok(bundle.with_kyber_pre_key(id.into(), k.clone(), kyber_prekey_signature.to_vec()))
+        }
+        _ => Err(SignalProtocolError::InvalidArgument(
+            "Must supply both or neither of kyber_prekey and kyber_prekey_id".to_owned(),
+        )),
+    }
 }
 
 #[bridge_fn]
@@ -529,6 +567,17 @@ bridge_get!(PreKeyBundle::signed_pre_key_id -> u32);
 bridge_get!(PreKeyBundle::pre_key_id -> Option<u32>);
 bridge_get!(PreKeyBundle::pre_key_public -> Option<PublicKey>);
 bridge_get!(PreKeyBundle::signed_pre_key_public -> PublicKey);
+bridge_get!(PreKeyBundle::kyber_pre_key_id -> Option<u32>);
+
+#[bridge_fn]
+fn PreKeyBundle_GetKyberPreKeyPublic(bundle: &PreKeyBundle) -> Result<Option<KyberPublicKey>> {
+    Ok(bundle.kyber_pre_key_public()?.cloned())
+}
+
+#[bridge_fn]
+fn PreKeyBundle_GetKyberPreKeySignature(bundle: &PreKeyBundle) -> Result<&[u8]> {
+    Ok(bundle.kyber_pre_key_signature()?.unwrap_or(&[]))
+}
 
 bridge_deserialize!(SignedPreKeyRecord::deserialize);
 bridge_get!(SignedPreKeyRecord::signature -> Vec<u8>);
@@ -553,6 +602,54 @@ fn SignedPreKeyRecord_New(
     SignedPreKeyRecord::new(id.into(), timestamp.as_millis(), &keypair, signature)
 }
 
+bridge_deserialize!(KyberPreKeyRecord::deserialize);
+bridge_get!(KyberPreKeyRecord::signature -> Vec<u8>);
+bridge_get!(
+    KyberPreKeyRecord::serialize as Serialize -> Vec<u8>,
+    jni = "KyberPreKeyRecord_1GetSerialized"
+);
+bridge_get!(KyberPreKeyRecord::id -> u32);
+bridge_get!(KyberPreKeyRecord::timestamp -> Timestamp);
+bridge_get!(KyberPreKeyRecord::public_key -> KyberPublicKey);
+bridge_get!(KyberPreKeyRecord::secret_key -> KyberSecretKey);
+bridge_get!(KyberPreKeyRecord::key_pair -> KyberKeyPair);
+
+#[bridge_fn]
+fn KyberPreKeyRecord_New(
+    id: u32,
+    timestamp: Timestamp,
+    key_pair: &KyberKeyPair,
+    signature: &[u8],
+) -> KyberPreKeyRecord {
+    KyberPreKeyRecord::new(id.into(), timestamp.as_millis(), key_pair, signature)
+}
+
+#[bridge_fn]
+fn KyberKeyPair_Generate() -> KyberKeyPair {
+    kem::KeyPair::generate(kem::KeyType::Kyber1024)
+}
+
+#[bridge_fn]
+fn KyberKeyPair_GetPublicKey(key_pair: &KyberKeyPair) -> KyberPublicKey {
+    key_pair.public_key.clone()
+}
+
+#[bridge_fn]
+fn KyberKeyPair_GetSecretKey(key_pair: &KyberKeyPair) -> KyberSecretKey {
+    key_pair.secret_key.clone()
+}
+
+bridge_deserialize!(KyberPublicKey::deserialize);
+bridge_get!(KyberPublicKey::serialize as Serialize -> Vec<u8>);
+
+#[bridge_fn]
+fn KyberPublicKey_Equals(lhs: &KyberPublicKey, rhs: &KyberPublicKey) -> bool {
+    lhs == rhs
+}
+
+bridge_deserialize!(KyberSecretKey::deserialize);
+bridge_get!(KyberSecretKey::serialize as Serialize -> Vec<u8>);
+
 bridge_deserialize!(PreKeyRecord::deserialize);
 bridge_get!(
     PreKeyRecord::serialize as Serialize -> Vec<u8>,
@@ -967,6 +1064,7 @@ async fn SessionCipher_EncryptMessage(
         protocol_address,
         session_store,
         identity_key_store,
+        &SessionPolicy::default(),
         ctx,
     )
     .await
@@ -986,6 +1084,7 @@ async fn SessionCipher_DecryptSignalMessage(
         protocol_address,
         session_store,
         identity_key_store,
+        &SessionPolicy::default(),
         &mut csprng,
         ctx,
     )
@@ -1000,6 +1099,7 @@ async fn SessionCipher_DecryptPreKeySignalMessage(
     identity_key_store: &mut dyn IdentityKeyStore,
     prekey_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
+    kyber_prekey_store: &mut dyn KyberPreKeyStore,
     ctx: Context,
 ) -> Result<Vec<u8>> {
     let mut csprng = rand::rngs::OsRng;
@@ -1010,6 +1110,8 @@ async fn SessionCipher_DecryptPreKeySignalMessage(
         identity_key_store,
         prekey_store,
         signed_prekey_store,
+        kyber_prekey_store,
+        &SessionPolicy::default(),
         &mut csprng,
         ctx,
     )
@@ -1101,6 +1203,7 @@ async fn SealedSender_DecryptMessage(
     identity_store: &mut dyn IdentityKeyStore,
     prekey_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
+    kyber_prekey_store: &mut dyn KyberPreKeyStore,
 ) -> Result<SealedSenderDecryptionResult> {
     sealed_sender_decrypt(
         message,
@@ -1113,6 +1216,8 @@ async fn SealedSender_DecryptMessage(
         session_store,
         prekey_store,
         signed_prekey_store,
+        kyber_prekey_store,
+        &SessionPolicy::default(),
         None,
     )
     .await
@@ -1152,7 +1257,16 @@ async fn GroupCipher_EncryptMessage(
     ctx: Context,
 ) -> Result<CiphertextMessage> {
     let mut rng = rand::rngs::OsRng;
-    let ctext = group_encrypt(store, sender, distribution_id, message, &mut rng, ctx).await?;
+    let ctext = group_encrypt(
+        store,
+        sender,
+        distribution_id,
+        message,
+        &SessionPolicy::default(),
+        &mut rng,
+        ctx,
+    )
+    .await?;
     Ok(CiphertextMessage::SenderKeyMessage(ctext))
 }
 
@@ -1163,5 +1277,5 @@ async fn GroupCipher_DecryptMessage(
     store: &mut dyn SenderKeyStore,
     ctx: Context,
 ) -> Result<Vec<u8>> {
-    group_decrypt(message, store, sender, ctx).await
+    group_decrypt(message, store, sender, &SessionPolicy::default(), ctx).await
 }
@@ -0,0 +1,593 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Encryption for attachments.
+//!
+//! An attachment is encrypted with a 64-byte key: an AES-256 key followed by an HMAC-SHA256 key.
+//! The plaintext is first padded with zeros to one of a set of size buckets (see
+//! [padded_attachment_size]), so that the size of the upload reveals less about the file. The
+//! encrypted attachment is laid out as
+//!
+//! ```text
+//! iv || AES-256-CBC(padded plaintext, PKCS#7) || HMAC-SHA256(iv || ciphertext)
+//! ```
+//!
+//! and its digest is the SHA-256 of all of that.
+//!
+//! The sender also computes an [IncrementalMac] over fixed-size chunks, which lets the receiver
+//! check each chunk while downloading instead of waiting for the end of the file.
+
+use crate::{Error, Result};
+
+use aes::{Aes256, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use generic_array::GenericArray;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use subtle::ConstantTimeEq;
+
+const BLOCK_SIZE: usize = aes::BLOCK_SIZE;
+const KEY_SIZE: usize = 64;
+const IV_SIZE: usize = BLOCK_SIZE;
+const MAC_SIZE: usize = 32;
+const DIGEST_SIZE: usize = 32;
+
+const MIN_PADDED_SIZE: u64 = 541;
+const PADDING_BUCKET_GROWTH: f64 = 1.05;
+
+const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+const MAX_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+/// Aim for about 8 KiB of incremental MAC digests.
+const TARGET_CHUNK_COUNT: u64 = 256;
+
+/// The size a plaintext of `plaintext_size` bytes is padded to before it is encrypted.
+///
+/// Sizes are rounded up to the next power of 1.05, with a minimum of 541 bytes.
+pub fn padded_attachment_size(plaintext_size: u64) -> u64 {
+    let exponent = ((plaintext_size as f64).ln() / PADDING_BUCKET_GROWTH.ln()).ceil();
+    let bucket = PADDING_BUCKET_GROWTH.powf(exponent).floor() as u64;
+    bucket.max(MIN_PADDED_SIZE).max(plaintext_size)
+}
+
+/// The size of the encrypted attachment for a plaintext of `plaintext_size` bytes, including the
+/// IV and MAC.
+pub fn encrypted_attachment_size(plaintext_size: u64) -> u64 {
+    let padded_size = padded_attachment_size(plaintext_size);
+    let block_size = BLOCK_SIZE as u64;
+    (IV_SIZE as u64) + (padded_size / block_size + 1) * block_size + (MAC_SIZE as u64)
+}
+
+fn split_key(key: &[u8]) -> Result<(Aes256, Hmac<Sha256>)> {
+    if key.len() != KEY_SIZE {
+        return Err(Error::InvalidKeySize);
+    }
+    let (aes_key, mac_key) = key.split_at(KEY_SIZE - MAC_SIZE);
+    Ok((
+        Aes256::new_from_slice(aes_key).map_err(|_| Error::InvalidKeySize)?,
+        Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC accepts any key length"),
+    ))
+}
+
+fn invalid_data(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+struct CbcEncryption {
+    aes256: Aes256,
+    prev: [u8; BLOCK_SIZE],
+    buf: [u8; BLOCK_SIZE],
+    buf_offset: usize,
+}
+
+impl CbcEncryption {
+    fn new(aes256: Aes256, iv: [u8; IV_SIZE]) -> Self {
+        Self {
+            aes256,
+            prev: iv,
+            buf: [0u8; BLOCK_SIZE],
+            buf_offset: 0,
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8], output: &mut Vec<u8>) {
+        while !input.is_empty() {
+            let taking = std::cmp::min(input.len(), BLOCK_SIZE - self.buf_offset);
+            self.buf[self.buf_offset..self.buf_offset + taking].copy_from_slice(&input[..taking]);
+            self.buf_offset += taking;
+            input = &input[taking..];
+
+            if self.buf_offset == BLOCK_SIZE {
+                self.encrypt_buffered_block(output);
+            }
+        }
+    }
+
+    fn encrypt_buffered_block(&mut self, output: &mut Vec<u8>) {
+        for (b, p) in self.buf.iter_mut().zip(self.prev.iter()) {
+            *b ^= p;
+        }
+        self.aes256
+            .encrypt_block(GenericArray::from_mut_slice(&mut self.buf));
+        self.prev = self.buf;
+        output.extend_from_slice(&self.buf);
+        self.buf_offset = 0;
+    }
+
+    fn finalize(mut self, output: &mut Vec<u8>) {
+        let padding = BLOCK_SIZE - self.buf_offset;
+        for b in &mut self.buf[self.buf_offset..] {
+            *b = padding as u8;
+        }
+        self.encrypt_buffered_block(output);
+    }
+}
+
+struct CbcDecryption {
+    aes256: Aes256,
+    prev: [u8; BLOCK_SIZE],
+    buf: [u8; BLOCK_SIZE],
+    buf_offset: usize,
+}
+
+impl CbcDecryption {
+    fn new(aes256: Aes256, iv: [u8; IV_SIZE]) -> Self {
+        Self {
+            aes256,
+            prev: iv,
+            buf: [0u8; BLOCK_SIZE],
+            buf_offset: 0,
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8], output: &mut Vec<u8>) {
+        while !input.is_empty() {
+            // The last block holds the padding, so only decrypt a block once we know it isn't
+            // the last one.
+            if self.buf_offset == BLOCK_SIZE {
+                let block = self.decrypt_buffered_block();
+                output.extend_from_slice(&block);
+            }
+
+            let taking = std::cmp::min(input.len(), BLOCK_SIZE - self.buf_offset);
+            self.buf[self.buf_offset..self.buf_offset + taking].copy_from_slice(&input[..taking]);
+            self.buf_offset += taking;
+            input = &input[taking..];
+        }
+    }
+
+    fn decrypt_buffered_block(&mut self) -> [u8; BLOCK_SIZE] {
+        let mut block = self.buf;
+        self.aes256
+            .decrypt_block(GenericArray::from_mut_slice(&mut block));
+        for (b, p) in block.iter_mut().zip(self.prev.iter()) {
+            *b ^= p;
+        }
+        self.prev = self.buf;
+        self.buf_offset = 0;
+        block
+    }
+
+    fn finalize(mut self, output: &mut Vec<u8>) -> Result<()> {
+        if self.buf_offset != BLOCK_SIZE {
+            return Err(Error::InvalidInputSize);
+        }
+        let block = self.decrypt_buffered_block();
+        let padding = block[BLOCK_SIZE - 1] as usize;
+        if padding == 0
+            || padding > BLOCK_SIZE
+            || block[BLOCK_SIZE - padding..]
+                .iter()
+                .any(|&b| b as usize != padding)
+        {
+            return Err(Error::InvalidInputSize);
+        }
+        output.extend_from_slice(&block[..BLOCK_SIZE - padding]);
+        Ok(())
+    }
+}
+
+/// An HMAC-SHA256 over a stream, with a digest at the end of every chunk.
+///
+/// Each digest covers everything from the start of the stream up to the end of its chunk, so a
+/// receiver that knows the digests can check each chunk as soon as it arrives.
+#[derive(Clone)]
+pub struct IncrementalMac {
+    mac: Hmac<Sha256>,
+    chunk_size: usize,
+    chunk_offset: usize,
+    digests: Vec<u8>,
+}
+
+impl IncrementalMac {
+    pub const DIGEST_SIZE: usize = 32;
+
+    /// The chunk size to use for a stream of `data_size` bytes.
+    pub fn chunk_size_for(data_size: u64) -> usize {
+        let target = (data_size + TARGET_CHUNK_COUNT - 1) / TARGET_CHUNK_COUNT;
+        target.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) as usize
+    }
+
+    /// The number of bytes of digests for a stream of `data_size` bytes.
+    fn digests_size(data_size: u64, chunk_size: usize) -> u64 {
+        let chunk_count = std::cmp::max(1, (data_size + chunk_size as u64 - 1) / chunk_size as u64);
+        chunk_count * (Self::DIGEST_SIZE as u64)
+    }
+
+    pub fn new(key: &[u8], chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidInputSize);
+        }
+        Ok(Self::with_mac(
+            Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length"),
+            chunk_size,
+        ))
+    }
+
+    fn with_mac(mac: Hmac<Sha256>, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        Self {
+            mac,
+            chunk_size,
+            chunk_offset: 0,
+            digests: vec![],
+        }
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) -> Result<()> {
+        while !input.is_empty() {
+            let taking = std::cmp::min(input.len(), self.chunk_size - self.chunk_offset);
+            self.mac.update(&input[..taking]);
+            self.chunk_offset += taking;
+            input = &input[taking..];
+
+            if self.chunk_offset == self.chunk_size {
+                self.push_digest();
+            }
+        }
+        Ok(())
+    }
+
+    fn push_digest(&mut self) {
+        let digest = self.mac.clone().finalize().into_bytes();
+        self.digests.extend_from_slice(&digest);
+        self.chunk_offset = 0;
+    }
+
+    /// The digests of the chunks completed so far.
+    pub fn digests(&self) -> &[u8] {
+        &self.digests
+    }
+
+    /// The digests of all chunks, including a final partial one.
+    pub fn finalize(mut self) -> Result<Vec<u8>> {
+        if self.chunk_offset > 0 || self.digests.is_empty() {
+            self.push_digest();
+        }
+        Ok(self.digests)
+    }
+}
+
+/// What the sender needs to tell the receiver about an encrypted attachment, besides its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptedAttachment {
+    /// The size of the encrypted attachment, including the IV and MAC.
+    pub size: u64,
+    /// The SHA-256 digest of the encrypted attachment.
+    pub digest: [u8; DIGEST_SIZE],
+    /// The [IncrementalMac] digests over the encrypted attachment, not including its MAC.
+    pub incremental_mac: Vec<u8>,
+    /// The chunk size used for [incremental_mac](Self::incremental_mac).
+    pub incremental_mac_chunk_size: usize,
+}
+
+/// Encrypts an attachment as it is written, and writes the result to another writer.
+///
+/// The plaintext size must be known up front to choose the padding, and exactly that many bytes
+/// must be written before calling [finish](Self::finish).
+pub struct AttachmentWriter<W: Write> {
+    inner: W,
+    cbc: CbcEncryption,
+    mac: Hmac<Sha256>,
+    digest: Sha256,
+    incremental_mac: IncrementalMac,
+    plaintext_remaining: u64,
+    padding_size: u64,
+    size: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> AttachmentWriter<W> {
+    pub const KEY_SIZE: usize = KEY_SIZE;
+    pub const IV_SIZE: usize = IV_SIZE;
+
+    pub fn new(inner: W, key: &[u8], iv: &[u8], plaintext_size: u64) -> Result<Self> {
+        let (aes256, mac) = split_key(key)?;
+        let iv: [u8; IV_SIZE] = iv.try_into().map_err(|_| Error::InvalidNonceSize)?;
+
+        let size = encrypted_attachment_size(plaintext_size);
+        let incremental_mac =
+            IncrementalMac::with_mac(mac.clone(), IncrementalMac::chunk_size_for(size));
+
+        Ok(Self {
+            inner,
+            cbc: CbcEncryption::new(aes256, iv),
+            mac,
+            digest: Sha256::new(),
+            incremental_mac,
+            plaintext_remaining: plaintext_size,
+            padding_size: padded_attachment_size(plaintext_size) - plaintext_size,
+            size,
+            buf: iv.to_vec(),
+        })
+    }
+
+    fn write_buffered(&mut self) -> io::Result<()> {
+        self.mac.update(&self.buf);
+        self.digest.update(&self.buf);
+        self.incremental_mac
+            .update(&self.buf)
+            .map_err(invalid_data)?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Pad and finish encrypting the attachment, returning the inner writer along with the
+    /// attachment's digests.
+    pub fn finish(mut self) -> io::Result<(W, EncryptedAttachment)> {
+        if self.plaintext_remaining != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "attachment is shorter than its declared size",
+            ));
+        }
+
+        let zeros = [0u8; 4096];
+        while self.padding_size > 0 {
+            let taking = std::cmp::min(self.padding_size, zeros.len() as u64) as usize;
+            self.cbc.update(&zeros[..taking], &mut self.buf);
+            self.padding_size -= taking as u64;
+            self.write_buffered()?;
+        }
+
+        let Self {
+            mut inner,
+            cbc,
+            mut mac,
+            mut digest,
+            mut incremental_mac,
+            size,
+            mut buf,
+            ..
+        } = self;
+
+        cbc.finalize(&mut buf);
+        mac.update(&buf);
+        digest.update(&buf);
+        incremental_mac.update(&buf).map_err(invalid_data)?;
+        inner.write_all(&buf)?;
+
+        let mac = mac.finalize().into_bytes();
+        digest.update(mac);
+        inner.write_all(&mac)?;
+        inner.flush()?;
+
+        let attachment = EncryptedAttachment {
+            size,
+            digest: digest.finalize().into(),
+            incremental_mac_chunk_size: incremental_mac.chunk_size,
+            incremental_mac: incremental_mac.finalize().map_err(invalid_data)?,
+        };
+        Ok((inner, attachment))
+    }
+}
+
+impl<W: Write> Write for AttachmentWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.plaintext_remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "attachment is longer than its declared size",
+            ));
+        }
+        self.cbc.update(buf, &mut self.buf);
+        self.plaintext_remaining -= buf.len() as u64;
+        self.write_buffered()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an attachment as it is read from another reader.
+///
+/// The encrypted attachment is read a chunk at a time. If the sender's incremental MAC is
+/// provided with [with_incremental_mac](Self::with_incremental_mac), each chunk is checked before
+/// any of it is returned. Otherwise the MAC and digest can only be checked at the end of the
+/// attachment, so earlier data is returned before it has been authenticated; if the final read
+/// fails, everything read before it must be discarded.
+///
+/// Errors from the attachment's contents are reported as [io::ErrorKind::InvalidData], wrapping
+/// an [Error].
+pub struct AttachmentReader<R: Read> {
+    inner: R,
+    aes256: Aes256,
+    cbc: Option<CbcDecryption>,
+    mac: Hmac<Sha256>,
+    digest: Sha256,
+    expected_digest: [u8; DIGEST_SIZE],
+    incremental_mac: Option<(IncrementalMac, Vec<u8>)>,
+    chunk_size: usize,
+    unread: u64,
+    plaintext_remaining: u64,
+    chunk: Vec<u8>,
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+    finished: bool,
+    failed: bool,
+}
+
+impl<R: Read> AttachmentReader<R> {
+    pub const KEY_SIZE: usize = KEY_SIZE;
+
+    /// Decrypt an attachment of `size` encrypted bytes, which should have the given `digest` and
+    /// decrypt to `plaintext_size` bytes (not counting padding).
+    pub fn new(
+        inner: R,
+        key: &[u8],
+        digest: &[u8],
+        size: u64,
+        plaintext_size: u64,
+    ) -> Result<Self> {
+        let (aes256, mac) = split_key(key)?;
+        let expected_digest = digest.try_into().map_err(|_| Error::InvalidInputSize)?;
+
+        let overhead = (IV_SIZE + MAC_SIZE) as u64;
+        if size < overhead + BLOCK_SIZE as u64
+            || (size - overhead) % BLOCK_SIZE as u64 != 0
+            || plaintext_size >= size - overhead
+        {
+            return Err(Error::InvalidInputSize);
+        }
+
+        Ok(Self {
+            inner,
+            aes256,
+            cbc: None,
+            mac,
+            digest: Sha256::new(),
+            expected_digest,
+            incremental_mac: None,
+            chunk_size: IncrementalMac::chunk_size_for(size),
+            unread: size - MAC_SIZE as u64,
+            plaintext_remaining: plaintext_size,
+            chunk: vec![],
+            plaintext: vec![],
+            plaintext_offset: 0,
+            finished: false,
+            failed: false,
+        })
+    }
+
+    /// Check the attachment against the sender's [IncrementalMac] digests, so that each chunk is
+    /// authenticated before it is returned.
+    pub fn with_incremental_mac(mut self, digests: &[u8], chunk_size: usize) -> Result<Self> {
+        if chunk_size < IV_SIZE
+            || digests.len() as u64 != IncrementalMac::digests_size(self.unread, chunk_size)
+        {
+            return Err(Error::InvalidInputSize);
+        }
+        let incremental_mac = IncrementalMac::with_mac(self.mac.clone(), chunk_size);
+        self.incremental_mac = Some((incremental_mac, digests.to_vec()));
+        self.chunk_size = chunk_size;
+        Ok(self)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let len = std::cmp::min(self.chunk_size as u64, self.unread) as usize;
+        self.chunk.resize(len, 0);
+        self.inner.read_exact(&mut self.chunk)?;
+        self.unread -= len as u64;
+
+        self.mac.update(&self.chunk);
+        self.digest.update(&self.chunk);
+        if let Some((incremental_mac, expected)) = &mut self.incremental_mac {
+            incremental_mac.update(&self.chunk).map_err(invalid_data)?;
+            let computed = incremental_mac.digests();
+            if !bool::from(computed.ct_eq(&expected[..computed.len()])) {
+                return Err(invalid_data(Error::InvalidTag));
+            }
+        }
+
+        let mut ciphertext = &self.chunk[..];
+        if self.cbc.is_none() {
+            // The first chunk always starts with the whole IV.
+            let (iv, rest) = ciphertext.split_at(IV_SIZE);
+            self.cbc = Some(CbcDecryption::new(
+                self.aes256.clone(),
+                iv.try_into().expect("correct length"),
+            ));
+            ciphertext = rest;
+        }
+        self.cbc
+            .as_mut()
+            .expect("initialized above")
+            .update(ciphertext, &mut self.plaintext);
+
+        if self.unread == 0 {
+            self.finish()?;
+        }
+
+        let released = std::cmp::min(self.plaintext.len() as u64, self.plaintext_remaining);
+        self.plaintext.truncate(released as usize);
+        self.plaintext_remaining -= released;
+        if self.finished && self.plaintext_remaining != 0 {
+            return Err(invalid_data(Error::InvalidInputSize));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut their_mac = [0u8; MAC_SIZE];
+        self.inner.read_exact(&mut their_mac)?;
+        self.digest.update(their_mac);
+
+        let our_mac = self.mac.clone().finalize().into_bytes();
+        if !bool::from(our_mac.ct_eq(&their_mac)) {
+            return Err(invalid_data(Error::InvalidTag));
+        }
+        let digest = self.digest.clone().finalize();
+        if !bool::from(digest.ct_eq(&self.expected_digest)) {
+            return Err(invalid_data(Error::InvalidTag));
+        }
+        if let Some((incremental_mac, expected)) = self.incremental_mac.take() {
+            let computed = incremental_mac.finalize().map_err(invalid_data)?;
+            if !bool::from(computed.ct_eq(&expected)) {
+                return Err(invalid_data(Error::InvalidTag));
+            }
+        }
+
+        self.cbc
+            .take()
+            .ok_or(Error::InvalidState)
+            .and_then(|cbc| cbc.finalize(&mut self.plaintext))
+            .map_err(invalid_data)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for AttachmentReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(invalid_data(Error::InvalidState));
+        }
+
+        while self.plaintext_offset == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.plaintext.clear();
+            self.plaintext_offset = 0;
+            if let Err(e) = self.read_chunk() {
+                self.failed = true;
+                return Err(e);
+            }
+        }
+
+        let available = &self.plaintext[self.plaintext_offset..];
+        let len = std::cmp::min(buf.len(), available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.plaintext_offset += len;
+        Ok(len)
+    }
+}
@@ -29,3 +29,5 @@ impl fmt::Display for Error {
         }
     }
 }
+
+impl std::error::Error for Error {}
@@ -10,8 +10,13 @@ mod hash;
 
 mod aes_ctr;
 mod aes_gcm;
+mod attachment;
 
 pub use aes_ctr::Aes256Ctr32;
 pub use aes_gcm::{Aes256GcmDecryption, Aes256GcmEncryption};
+pub use attachment::{
+    encrypted_attachment_size, padded_attachment_size, AttachmentReader, AttachmentWriter,
+    EncryptedAttachment, IncrementalMac,
+};
 pub use error::{Error, Result};
 pub use hash::{CryptographicHash, CryptographicMac};
@@ -0,0 +1,256 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use rand::Rng;
+use signal_crypto::{
+    encrypted_attachment_size, padded_attachment_size, AttachmentReader, AttachmentWriter,
+    CryptographicHash, CryptographicMac, EncryptedAttachment,
+};
+use std::io::{self, Read, Write};
+
+fn encrypt(key: &[u8], iv: &[u8], plaintext: &[u8]) -> (Vec<u8>, EncryptedAttachment) {
+    let mut rng = rand::rngs::OsRng;
+    let mut writer =
+        AttachmentWriter::new(vec![], key, iv, plaintext.len() as u64).expect("valid key and IV");
+
+    // Write in pieces of random sizes.
+    let mut written = 0;
+    while written < plaintext.len() {
+        let this_time = rng.gen_range(1, 70_000).min(plaintext.len() - written);
+        writer
+            .write_all(&plaintext[written..written + this_time])
+            .expect("can write");
+        written += this_time;
+    }
+
+    writer.finish().expect("can finish")
+}
+
+fn read_all<R: Read>(mut reader: R) -> (Vec<u8>, io::Result<()>) {
+    let mut rng = rand::rngs::OsRng;
+    let mut plaintext = vec![];
+    loop {
+        let mut buf = vec![0u8; rng.gen_range(1, 70_000)];
+        match reader.read(&mut buf) {
+            Ok(0) => return (plaintext, Ok(())),
+            Ok(len) => plaintext.extend_from_slice(&buf[..len]),
+            Err(e) => return (plaintext, Err(e)),
+        }
+    }
+}
+
+fn assert_invalid_tag(result: io::Result<()>) {
+    let error = result.expect_err("should fail");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<signal_crypto::Error>()),
+        Some(&signal_crypto::Error::InvalidTag)
+    );
+}
+
+#[test]
+fn attachment_padded_sizes() {
+    for (size, padded) in [
+        (0, 541),
+        (1, 541),
+        (541, 541),
+        (542, 568),
+        (1000, 1020),
+        (1_000_000, 1_041_743),
+        (1_000_000_000, 1_012_633_066),
+    ] {
+        assert_eq!(padded_attachment_size(size), padded);
+    }
+
+    // The IV, the padded plaintext plus at least one byte of PKCS#7 padding, and the MAC.
+    assert_eq!(encrypted_attachment_size(0), 16 + 544 + 32);
+    assert_eq!(encrypted_attachment_size(1000), 16 + 1024 + 32);
+}
+
+#[test]
+fn attachment_known_answer() -> Result<(), signal_crypto::Error> {
+    // The AES part is CBC-AES256 from NIST SP 800-38A, F.2.5.
+    let aes_key = hex::decode("603DEB1015CA71BE2B73AEF0857D77811F352C073B6108D72D9810A30914DFF4")
+        .expect("valid hex");
+    let mac_key = [0x42u8; 32];
+    let key = [&aes_key[..], &mac_key[..]].concat();
+    let iv = hex::decode("000102030405060708090A0B0C0D0E0F").expect("valid hex");
+    let plaintext = hex::decode("6BC1BEE22E409F96E93D7E117393172AAE2D8A571E03AC9C9EB76FAC45AF8E5130C81C46A35CE411E5FBC1191A0A52EFF69F2445DF4F9B17AD2B417BE66C3710").expect("valid hex");
+    let ciphertext = hex::decode("F58C4C04D6E5F1BA779EABFB5F7BFBD69CFC4E967EDB808D679F777BC6702C7D39F23369A9D9BACFA530E26304231461B2EB05E2C39BE9FCDA6C19078C6A9D1B").expect("valid hex");
+
+    let (encrypted, attachment) = encrypt(&key, &iv, &plaintext);
+    assert_eq!(encrypted.len() as u64, encrypted_attachment_size(64));
+    assert_eq!(attachment.size, encrypted.len() as u64);
+    assert_eq!(encrypted[..16], iv[..]);
+    assert_eq!(hex::encode(&encrypted[16..80]), hex::encode(ciphertext));
+
+    let (authenticated, mac) = encrypted.split_at(encrypted.len() - 32);
+    let mut expected_mac = CryptographicMac::new("HmacSha256", &mac_key)?;
+    expected_mac.update(authenticated)?;
+    assert_eq!(mac, &expected_mac.finalize()?[..]);
+
+    let mut expected_digest = CryptographicHash::new("SHA-256")?;
+    expected_digest.update(&encrypted)?;
+    assert_eq!(attachment.digest[..], expected_digest.finalize()?[..]);
+
+    // A small attachment fits in one chunk, so its only incremental digest is the MAC.
+    assert_eq!(attachment.incremental_mac_chunk_size, 64 * 1024);
+    assert_eq!(attachment.incremental_mac, mac);
+
+    Ok(())
+}
+
+#[test]
+fn attachment_round_trip() -> Result<(), signal_crypto::Error> {
+    let mut rng = rand::rngs::OsRng;
+
+    for size in [0, 1, 15, 16, 541, 542, 65_536, 300_000, 1_000_000] {
+        let key: Vec<u8> = (0..AttachmentWriter::<Vec<u8>>::KEY_SIZE)
+            .map(|_| rng.gen())
+            .collect();
+        let iv: [u8; 16] = rng.gen();
+        let plaintext: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+
+        let (encrypted, attachment) = encrypt(&key, &iv, &plaintext);
+        assert_eq!(
+            encrypted.len() as u64,
+            encrypted_attachment_size(size as u64)
+        );
+        assert_eq!(
+            attachment.incremental_mac.len(),
+            32 * ((encrypted.len() - 32 + attachment.incremental_mac_chunk_size - 1)
+                / attachment.incremental_mac_chunk_size)
+        );
+
+        let reader = AttachmentReader::new(
+            &encrypted[..],
+            &key,
+            &attachment.digest,
+            attachment.size,
+            size as u64,
+        )?;
+        let (decrypted, result) = read_all(reader);
+        result.expect("valid");
+        assert_eq!(decrypted, plaintext);
+
+        let reader = AttachmentReader::new(
+            &encrypted[..],
+            &key,
+            &attachment.digest,
+            attachment.size,
+            size as u64,
+        )?
+        .with_incremental_mac(
+            &attachment.incremental_mac,
+            attachment.incremental_mac_chunk_size,
+        )?;
+        let (decrypted, result) = read_all(reader);
+        result.expect("valid");
+        assert_eq!(decrypted, plaintext);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn attachment_rejects_tampering() -> Result<(), signal_crypto::Error> {
+    let mut rng = rand::rngs::OsRng;
+    let key: [u8; 32] = rng.gen();
+    let key = [key, rng.gen()].concat();
+    let iv: [u8; 16] = rng.gen();
+    let plaintext: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+    let (encrypted, attachment) = encrypt(&key, &iv, &plaintext);
+    let chunk_size = attachment.incremental_mac_chunk_size;
+    let size = plaintext.len() as u64;
+
+    let reader = |encrypted: &[u8]| {
+        AttachmentReader::new(
+            io::Cursor::new(encrypted.to_vec()),
+            &key,
+            &attachment.digest,
+            attachment.size,
+            size,
+        )
+    };
+
+    // Corrupt the third chunk.
+    let mut corrupted = encrypted.clone();
+    corrupted[2 * chunk_size + 100] ^= 1;
+
+    // Without the incremental MAC, the corruption is only caught at the end.
+    let (decrypted, result) = read_all(reader(&corrupted)?);
+    assert_invalid_tag(result);
+    assert!(decrypted.len() > 2 * chunk_size);
+
+    // With it, nothing from the corrupted chunk is returned.
+    let (decrypted, result) = read_all(
+        reader(&corrupted)?.with_incremental_mac(&attachment.incremental_mac, chunk_size)?,
+    );
+    assert_invalid_tag(result);
+    assert_eq!(decrypted, plaintext[..2 * chunk_size - 16 - 16]);
+
+    // Every part of the attachment is covered.
+    for index in [0, 20, encrypted.len() - 40, encrypted.len() - 1] {
+        let mut corrupted = encrypted.clone();
+        corrupted[index] ^= 1;
+        assert_invalid_tag(read_all(reader(&corrupted)?).1);
+    }
+
+    let mut wrong_digest = attachment.digest;
+    wrong_digest[0] ^= 1;
+    let reader_with_wrong_digest =
+        AttachmentReader::new(&encrypted[..], &key, &wrong_digest, attachment.size, size)?;
+    assert_invalid_tag(read_all(reader_with_wrong_digest).1);
+
+    let mut wrong_incremental_mac = attachment.incremental_mac.clone();
+    wrong_incremental_mac[40] ^= 1;
+    let (decrypted, result) =
+        read_all(reader(&encrypted)?.with_incremental_mac(&wrong_incremental_mac, chunk_size)?);
+    assert_invalid_tag(result);
+    assert!(decrypted.len() < chunk_size);
+
+    // Truncated downloads are caught too.
+    let truncated = &encrypted[..encrypted.len() - 1];
+    let (_, result) = read_all(AttachmentReader::new(
+        truncated,
+        &key,
+        &attachment.digest,
+        attachment.size,
+        size,
+    )?);
+    assert_eq!(
+        result.expect_err("should fail").kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+
+    Ok(())
+}
+
+#[test]
+fn attachment_invalid_arguments() {
+    let key = [0u8; 64];
+    let iv = [0u8; 16];
+
+    assert!(AttachmentWriter::new(vec![], &key[..32], &iv, 10).is_err());
+    assert!(AttachmentWriter::new(vec![], &key, &iv[..12], 10).is_err());
+
+    let mut writer = AttachmentWriter::new(vec![], &key, &iv, 10).expect("valid");
+    assert!(writer.write_all(&[0u8; 11]).is_err());
+    writer.write_all(&[0u8; 9]).expect("can write");
+    assert!(writer.finish().is_err());
+
+    let size = encrypted_attachment_size(10);
+    let digest = [0u8; 32];
+    assert!(AttachmentReader::new(&[][..], &key, &digest, size, 10).is_ok());
+    assert!(AttachmentReader::new(&[][..], &key[..32], &digest, size, 10).is_err());
+    assert!(AttachmentReader::new(&[][..], &key, &digest[..16], size, 10).is_err());
+    assert!(AttachmentReader::new(&[][..], &key, &digest, size - 1, 10).is_err());
+    assert!(AttachmentReader::new(&[][..], &key, &digest, size, size).is_err());
+    assert!(AttachmentReader::new(&[][..], &key, &digest, size, 10)
+        .and_then(|reader| reader.with_incremental_mac(&[0u8; 64], 64 * 1024))
+        .is_err());
+}
@@ -57,6 +57,7 @@ pub fn ratchet_forward_result(c: &mut Criterion) -> Result<(), SignalProtocolErr
                 &sender_address,
                 distribution_id,
                 format!("nefarious plotting {}", i).as_bytes(),
+                &SessionPolicy::default(),
                 &mut csprng,
                 None,
             )
@@ -69,6 +70,7 @@ pub fn ratchet_forward_result(c: &mut Criterion) -> Result<(), SignalProtocolErr
             &sender_address,
             distribution_id,
             "you got the plan?".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -82,6 +84,7 @@ pub fn ratchet_forward_result(c: &mut Criterion) -> Result<(), SignalProtocolErr
                     alice_ciphertext.serialized(),
                     &mut bob_store,
                     &sender_address,
+                    &SessionPolicy::default(),
                     None,
                 )
                 .now_or_never()
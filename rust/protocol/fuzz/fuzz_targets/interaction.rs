@@ -123,6 +123,7 @@ impl Participant {
             &them.address,
             &mut self.store.session_store,
             &mut self.store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await
@@ -156,6 +157,8 @@ impl Participant {
                 &mut self.store.identity_store,
                 &mut self.store.pre_key_store,
                 &mut self.store.signed_pre_key_store,
+                &mut self.store.kyber_pre_key_store,
+                &SessionPolicy::default(),
                 rng,
                 None,
             )
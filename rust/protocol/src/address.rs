@@ -12,6 +12,250 @@ use crate::SignalMessage;
 
 use std::fmt;
 
+use uuid::Uuid;
+
+/// The kind of a [ServiceId], which determines how it is encoded.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub enum ServiceIdKind {
+    /// An [Aci], the primary identifier for a Signal account.
+    Aci,
+    /// A [Pni], an identifier tied to the phone number of a Signal account.
+    Pni,
+}
+
+impl ServiceIdKind {
+    fn type_byte(self) -> u8 {
+        match self {
+            ServiceIdKind::Aci => 0x00,
+            ServiceIdKind::Pni => 0x01,
+        }
+    }
+
+    fn from_type_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(ServiceIdKind::Aci),
+            0x01 => Some(ServiceIdKind::Pni),
+            _ => None,
+        }
+    }
+
+    fn string_prefix(self) -> &'static str {
+        match self {
+            ServiceIdKind::Aci => "",
+            ServiceIdKind::Pni => "PNI:",
+        }
+    }
+}
+
+impl fmt::Display for ServiceIdKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Parses a UUID in its canonical hyphenated form, rejecting the other forms accepted by [Uuid].
+fn parse_hyphenated_uuid(input: &str) -> Option<Uuid> {
+    if input.len() != 36 {
+        return None;
+    }
+    Uuid::parse_str(input).ok()
+}
+
+macro_rules! specific_service_id {
+    ($(#[$attr:meta])* $name:ident, $kind:ident) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+        pub struct $name(Uuid);
+
+        impl $name {
+            /// Wraps the raw bytes of a UUID.
+            pub fn from_uuid_bytes(bytes: [u8; 16]) -> Self {
+                Self(Uuid::from_bytes(bytes))
+            }
+
+            /// The encoding of this identifier used in protobufs and other binary formats.
+            ///
+            /// See [ServiceId::service_id_binary].
+            pub fn service_id_binary(&self) -> Vec<u8> {
+                ServiceId::from(*self).service_id_binary()
+            }
+
+            /// The encoding of this identifier used when a string is expected.
+            ///
+            /// See [ServiceId::service_id_string].
+            pub fn service_id_string(&self) -> String {
+                ServiceId::from(*self).service_id_string()
+            }
+
+            /// Parses the output of [Self::service_id_binary], rejecting other kinds of
+            /// identifier.
+            pub fn parse_from_service_id_binary(bytes: &[u8]) -> Option<Self> {
+                match ServiceId::parse_from_service_id_binary(bytes)? {
+                    ServiceId::$kind(id) => Some(id),
+                    _ => None,
+                }
+            }
+
+            /// Parses the output of [Self::service_id_string], rejecting other kinds of
+            /// identifier.
+            pub fn parse_from_service_id_string(input: &str) -> Option<Self> {
+                match ServiceId::parse_from_service_id_string(input)? {
+                    ServiceId::$kind(id) => Some(id),
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<Uuid> for $name {
+            fn from(value: Uuid) -> Self {
+                Self(value)
+            }
+        }
+
+        impl From<$name> for Uuid {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl From<$name> for ServiceId {
+            fn from(value: $name) -> Self {
+                ServiceId::$kind(value)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.service_id_string())
+            }
+        }
+    };
+}
+
+specific_service_id!(
+    /// An "ACI", the account identifier that stays the same for the lifetime of a Signal account.
+    Aci,
+    Aci
+);
+
+specific_service_id!(
+    /// A "PNI", an identifier associated with the phone number of a Signal account.
+    ///
+    /// PNIs are distinguished from ACIs in their encodings by a prefix; see [ServiceId].
+    Pni,
+    Pni
+);
+
+/// Either an [Aci] or a [Pni].
+///
+/// The two kinds are encoded differently so that they can never be confused:
+///
+/// | Kind | Binary                     | String           |
+/// |------|----------------------------|------------------|
+/// | ACI  | `uuid` (16 bytes)          | `<uuid>`         |
+/// | PNI  | `0x01 \|\| uuid` (17 bytes) | `PNI:<uuid>`     |
+///
+/// UUIDs in strings are always in lowercase hyphenated form. There is also a
+/// [fixed-width binary](Self::service_id_fixed_width_binary) encoding that always includes the
+/// type byte (`0x00` for ACIs).
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub enum ServiceId {
+    /// An account identifier.
+    Aci(Aci),
+    /// A phone number identifier.
+    Pni(Pni),
+}
+
+impl ServiceId {
+    /// The length of [Self::service_id_fixed_width_binary].
+    pub const FIXED_WIDTH_BINARY_LEN: usize = 17;
+
+    /// Which kind of identifier this is.
+    pub fn kind(&self) -> ServiceIdKind {
+        match self {
+            ServiceId::Aci(_) => ServiceIdKind::Aci,
+            ServiceId::Pni(_) => ServiceIdKind::Pni,
+        }
+    }
+
+    /// The UUID of this identifier, without any indication of its kind.
+    pub fn raw_uuid(&self) -> Uuid {
+        match *self {
+            ServiceId::Aci(Aci(uuid)) | ServiceId::Pni(Pni(uuid)) => uuid,
+        }
+    }
+
+    /// The encoding of this identifier used in protobufs and other binary formats.
+    ///
+    /// ACIs are encoded as a bare UUID; PNIs are prefixed with a type byte.
+    pub fn service_id_binary(&self) -> Vec<u8> {
+        match self {
+            ServiceId::Aci(aci) => aci.0.as_bytes().to_vec(),
+            ServiceId::Pni(_) => self.service_id_fixed_width_binary().to_vec(),
+        }
+    }
+
+    /// An encoding of this identifier that always includes a type byte, so that all identifiers
+    /// have the same length.
+    pub fn service_id_fixed_width_binary(&self) -> [u8; Self::FIXED_WIDTH_BINARY_LEN] {
+        let mut result = [0; Self::FIXED_WIDTH_BINARY_LEN];
+        result[0] = self.kind().type_byte();
+        result[1..].copy_from_slice(self.raw_uuid().as_bytes());
+        result
+    }
+
+    /// The encoding of this identifier used when a string is expected.
+    ///
+    /// ACIs are encoded as a bare UUID; PNIs are prefixed with `PNI:`.
+    pub fn service_id_string(&self) -> String {
+        format!(
+            "{}{}",
+            self.kind().string_prefix(),
+            self.raw_uuid().hyphenated()
+        )
+    }
+
+    /// Parses the output of [Self::service_id_binary].
+    pub fn parse_from_service_id_binary(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            16 => Some(Aci::from(Uuid::from_slice(bytes).ok()?).into()),
+            Self::FIXED_WIDTH_BINARY_LEN => match ServiceIdKind::from_type_byte(bytes[0])? {
+                // ACIs are never encoded with a type byte in this format.
+                ServiceIdKind::Aci => None,
+                ServiceIdKind::Pni => Some(Pni::from(Uuid::from_slice(&bytes[1..]).ok()?).into()),
+            },
+            _ => None,
+        }
+    }
+
+    /// Parses the output of [Self::service_id_fixed_width_binary].
+    pub fn parse_from_service_id_fixed_width_binary(
+        bytes: &[u8; Self::FIXED_WIDTH_BINARY_LEN],
+    ) -> Option<Self> {
+        let uuid = Uuid::from_slice(&bytes[1..]).ok()?;
+        match ServiceIdKind::from_type_byte(bytes[0])? {
+            ServiceIdKind::Aci => Some(Aci::from(uuid).into()),
+            ServiceIdKind::Pni => Some(Pni::from(uuid).into()),
+        }
+    }
+
+    /// Parses the output of [Self::service_id_string].
+    pub fn parse_from_service_id_string(input: &str) -> Option<Self> {
+        let pni_prefix = ServiceIdKind::Pni.string_prefix();
+        if let Some(uuid) = input.strip_prefix(pni_prefix) {
+            Some(Pni::from(parse_hyphenated_uuid(uuid)?).into())
+        } else {
+            Some(Aci::from(parse_hyphenated_uuid(input)?).into())
+        }
+    }
+}
+
+impl fmt::Display for ServiceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.service_id_string())
+    }
+}
+
 /// The type used in memory to represent a *device*, i.e. a particular Signal client instance which
 /// represents some user.
 ///
@@ -68,6 +312,23 @@ impl ProtocolAddress {
         ProtocolAddress { name, device_id }
     }
 
+    /// Create an address for a device of the account identified by `service_id`.
+    ///
+    /// The name of the resulting address is the [service ID string][ServiceId::service_id_string].
+    ///
+    ///```
+    /// use libsignal_protocol::{Pni, ProtocolAddress, ServiceId};
+    ///
+    /// let pni = Pni::from_uuid_bytes([0x11; 16]);
+    /// let address = ProtocolAddress::from_service_id(pni.into(), 1.into());
+    ///
+    /// assert_eq!(address.name(), "PNI:11111111-1111-1111-1111-111111111111");
+    /// assert_eq!(address.service_id(), Some(ServiceId::Pni(pni)));
+    ///```
+    pub fn from_service_id(service_id: ServiceId, device_id: DeviceId) -> Self {
+        Self::new(service_id.service_id_string(), device_id)
+    }
+
     /// A unique identifier for the target user. This is usually a UUID.
     #[inline]
     pub fn name(&self) -> &str {
@@ -83,6 +344,12 @@ impl ProtocolAddress {
     pub fn device_id(&self) -> DeviceId {
         self.device_id
     }
+
+    /// The user ID of this address as a [ServiceId], or `None` if the name is not a valid service
+    /// ID string (for example, if it is an E164 phone number).
+    pub fn service_id(&self) -> Option<ServiceId> {
+        ServiceId::parse_from_service_id_string(&self.name)
+    }
 }
 
 impl fmt::Display for ProtocolAddress {
@@ -90,3 +357,113 @@ impl fmt::Display for ProtocolAddress {
         write!(f, "{}.{}", self.name, self.device_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_UUID: &str = "8c78cd2a-16ff-427d-83dc-1a5e36ce713d";
+
+    #[test]
+    fn test_aci_encodings() {
+        let aci = Aci::from(Uuid::parse_str(TEST_UUID).expect("valid"));
+        let service_id = ServiceId::from(aci);
+
+        assert_eq!(service_id.kind(), ServiceIdKind::Aci);
+        assert_eq!(aci.service_id_string(), TEST_UUID);
+        assert_eq!(aci.service_id_binary(), Uuid::from(aci).as_bytes());
+        assert_eq!(service_id.service_id_fixed_width_binary()[0], 0x00);
+
+        assert_eq!(Aci::parse_from_service_id_string(TEST_UUID), Some(aci));
+        assert_eq!(
+            Aci::parse_from_service_id_binary(&aci.service_id_binary()),
+            Some(aci)
+        );
+        assert_eq!(
+            ServiceId::parse_from_service_id_fixed_width_binary(
+                &service_id.service_id_fixed_width_binary()
+            ),
+            Some(service_id)
+        );
+        assert_eq!(Pni::parse_from_service_id_string(TEST_UUID), None);
+    }
+
+    #[test]
+    fn test_pni_encodings() {
+        let pni = Pni::from(Uuid::parse_str(TEST_UUID).expect("valid"));
+        let service_id = ServiceId::from(pni);
+
+        assert_eq!(service_id.kind(), ServiceIdKind::Pni);
+        assert_eq!(pni.service_id_string(), format!("PNI:{}", TEST_UUID));
+        assert_eq!(pni.service_id_binary().len(), 17);
+        assert_eq!(pni.service_id_binary()[0], 0x01);
+        assert_eq!(
+            pni.service_id_binary(),
+            service_id.service_id_fixed_width_binary()
+        );
+
+        assert_eq!(
+            Pni::parse_from_service_id_string(&pni.service_id_string()),
+            Some(pni)
+        );
+        assert_eq!(
+            Pni::parse_from_service_id_binary(&pni.service_id_binary()),
+            Some(pni)
+        );
+        assert_eq!(
+            Aci::parse_from_service_id_string(&pni.service_id_string()),
+            None
+        );
+        assert_eq!(
+            Aci::parse_from_service_id_binary(&pni.service_id_binary()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_encodings() {
+        for input in [
+            "",
+            "PNI:",
+            "pni:8c78cd2a-16ff-427d-83dc-1a5e36ce713d",
+            "ACI:8c78cd2a-16ff-427d-83dc-1a5e36ce713d",
+            "8c78cd2a16ff427d83dc1a5e36ce713d",
+            "{8c78cd2a-16ff-427d-83dc-1a5e36ce713d}",
+            "+14151231234",
+        ] {
+            assert_eq!(
+                ServiceId::parse_from_service_id_string(input),
+                None,
+                "{}",
+                input
+            );
+        }
+
+        let uuid = Uuid::parse_str(TEST_UUID).expect("valid");
+        let mut aci_with_type_byte = vec![0x00];
+        aci_with_type_byte.extend_from_slice(uuid.as_bytes());
+        assert_eq!(
+            ServiceId::parse_from_service_id_binary(&aci_with_type_byte),
+            None
+        );
+        assert_eq!(ServiceId::parse_from_service_id_binary(&[0; 15]), None);
+
+        let mut unknown_kind = [0; ServiceId::FIXED_WIDTH_BINARY_LEN];
+        unknown_kind[0] = 0x02;
+        assert_eq!(
+            ServiceId::parse_from_service_id_fixed_width_binary(&unknown_kind),
+            None
+        );
+    }
+
+    #[test]
+    fn test_protocol_address_service_id() {
+        let aci = Aci::from(Uuid::parse_str(TEST_UUID).expect("valid"));
+        let address = ProtocolAddress::from_service_id(aci.into(), 2.into());
+        assert_eq!(address.name(), TEST_UUID);
+        assert_eq!(address.service_id(), Some(ServiceId::Aci(aci)));
+
+        let address = ProtocolAddress::new("+14151231234".to_owned(), 1.into());
+        assert_eq!(address.service_id(), None);
+    }
+}
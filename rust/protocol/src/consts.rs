@@ -8,3 +8,6 @@ pub const MAX_MESSAGE_KEYS: usize = 2000;
 pub const MAX_RECEIVER_CHAINS: usize = 5;
 pub const ARCHIVED_STATES_MAX_LENGTH: usize = 40;
 pub const MAX_SENDER_KEY_STATES: usize = 5;
+
+/// Valid registration IDs fit in 14 bits.
+pub const VALID_REGISTRATION_ID_MASK: u32 = 0x3FFF;
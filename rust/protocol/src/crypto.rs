@@ -8,6 +8,8 @@ use std::result::Result;
 
 use aes::cipher::{NewCipher, StreamCipher};
 use aes::{Aes256, Aes256Ctr};
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
 use block_modes::block_padding::Pkcs7;
 use block_modes::{BlockMode, Cbc};
 use hmac::{Hmac, Mac, NewMac};
@@ -75,6 +77,51 @@ pub(crate) fn aes_256_cbc_decrypt(
         .map_err(|_| DecryptionError::BadCiphertext("failed to decrypt"))
 }
 
+/// The nonce length for [aes_256_gcm_siv_encrypt] and [aes_256_gcm_siv_decrypt].
+pub(crate) const AES_256_GCM_SIV_NONCE_LENGTH: usize = 12;
+
+pub(crate) fn aes_256_gcm_siv_encrypt(
+    ptext: &[u8],
+    key: &[u8],
+    nonce: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, EncryptionError> {
+    if nonce.len() != AES_256_GCM_SIV_NONCE_LENGTH {
+        return Err(EncryptionError::BadKeyOrIv);
+    }
+    let cipher = Aes256GcmSiv::new_from_slice(key).map_err(|_| EncryptionError::BadKeyOrIv)?;
+    Ok(cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ptext,
+                aad: associated_data,
+            },
+        )
+        .expect("AES-GCM-SIV encryption only fails for enormous inputs"))
+}
+
+pub(crate) fn aes_256_gcm_siv_decrypt(
+    ctext: &[u8],
+    key: &[u8],
+    nonce: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, DecryptionError> {
+    if nonce.len() != AES_256_GCM_SIV_NONCE_LENGTH {
+        return Err(DecryptionError::BadKeyOrIv);
+    }
+    let cipher = Aes256GcmSiv::new_from_slice(key).map_err(|_| DecryptionError::BadKeyOrIv)?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ctext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| DecryptionError::BadCiphertext("failed to decrypt"))
+}
+
 pub(crate) fn hmac_sha256(key: &[u8], input: &[u8]) -> [u8; 32] {
     let mut hmac =
         Hmac::<Sha256>::new_from_slice(key).expect("HMAC-SHA256 should accept any size key");
@@ -141,6 +188,28 @@ mod test {
         assert_eq!(hex::encode(recovered), "b0736294a124482a4159");
     }
 
+    #[test]
+    fn aes_gcm_siv_test() {
+        // From RFC 8452, appendix C.2.
+        let key = hex::decode("0100000000000000000000000000000000000000000000000000000000000000")
+            .expect("valid hex");
+        let nonce = hex::decode("030000000000000000000000").expect("valid hex");
+        let ptext = hex::decode("0100000000000000").expect("valid hex");
+
+        let ctext = aes_256_gcm_siv_encrypt(&ptext, &key, &nonce, &[]).expect("valid key");
+        assert_eq!(
+            hex::encode(&ctext),
+            "c2ef328e5c71c83b843122130f7364b761e0b97427e3df28"
+        );
+        let recovered = aes_256_gcm_siv_decrypt(&ctext, &key, &nonce, &[]).expect("valid");
+        assert_eq!(recovered, ptext);
+
+        // The associated data is authenticated too.
+        assert!(aes_256_gcm_siv_decrypt(&ctext, &key, &nonce, b"header").is_err());
+        assert!(aes_256_gcm_siv_decrypt(&ctext[1..], &key, &nonce, &[]).is_err());
+        assert!(aes_256_gcm_siv_decrypt(&ctext, &key, &nonce[1..], &[]).is_err());
+    }
+
     #[test]
     fn aes_ctr_test() {
         let key = hex::decode("603DEB1015CA71BE2B73AEF0857D77811F352C073B6108D72D9810A30914DFF4")
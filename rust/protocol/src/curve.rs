@@ -130,6 +130,27 @@ impl PublicKey {
         }
     }
 
+    /// Verifies a VXEdDSA signature calculated by [PrivateKey::calculate_vrf_signature], returning
+    /// the VRF output it proves, or `None` if it is not valid.
+    pub fn verify_vrf_signature(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<Option<[u8; curve25519::VRF_OUTPUT_LENGTH]>> {
+        match &self.key {
+            PublicKeyData::DjbPublicKey(pub_key) => {
+                if signature.len() != curve25519::VRF_SIGNATURE_LENGTH {
+                    return Ok(None);
+                }
+                Ok(curve25519::PrivateKey::verify_vrf_signature(
+                    pub_key,
+                    message,
+                    array_ref![signature, 0, curve25519::VRF_SIGNATURE_LENGTH],
+                ))
+            }
+        }
+    }
+
     fn key_data(&self) -> &[u8] {
         match &self.key {
             PublicKeyData::DjbPublicKey(ref k) => k.as_ref(),
@@ -273,6 +294,26 @@ impl PrivateKey {
         }
     }
 
+    /// Calculates a VXEdDSA signature of `message`, returning it along with the VRF output it
+    /// proves.
+    ///
+    /// The output depends only on this key and `message`, so it can be used as a verifiable
+    /// pseudorandom value; anyone with the public key can check it with
+    /// [PublicKey::verify_vrf_signature].
+    pub fn calculate_vrf_signature<R: CryptoRng + Rng>(
+        &self,
+        message: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Box<[u8]>, [u8; curve25519::VRF_OUTPUT_LENGTH])> {
+        match self.key {
+            PrivateKeyData::DjbPrivateKey(k) => {
+                let private_key = curve25519::PrivateKey::from(k);
+                let (signature, output) = private_key.calculate_vrf_signature(csprng, message);
+                Ok((Box::new(signature), output))
+            }
+        }
+    }
+
     pub fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
         match (self.key, their_key.key) {
             (PrivateKeyData::DjbPrivateKey(priv_key), PublicKeyData::DjbPublicKey(pub_key)) => {
@@ -394,6 +435,73 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_vrf_signatures() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate(&mut csprng);
+        let message = b"lookup key";
+        let (signature, output) = key_pair
+            .private_key
+            .calculate_vrf_signature(message, &mut csprng)?;
+
+        assert_eq!(
+            key_pair
+                .public_key
+                .verify_vrf_signature(message, &signature)?,
+            Some(output)
+        );
+        assert_eq!(
+            key_pair
+                .public_key
+                .verify_vrf_signature(message, &signature[..64])?,
+            None
+        );
+        // An XEdDSA signature is not a VXEdDSA signature.
+        let signature = key_pair
+            .private_key
+            .calculate_signature(message, &mut csprng)?;
+        assert_eq!(
+            key_pair
+                .public_key
+                .verify_vrf_signature(message, &signature)?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vrf_signature_known_answer() -> Result<()> {
+        // Computed outside this crate by an independent implementation of VXEdDSA written from
+        // the specification.
+        let public_key = PublicKey::deserialize(
+            &hex::decode("05653614993d2b15ee9e5fd3d86ce719ef4ec1daae1886a87b3f5fa9565a27a22f")
+                .expect("valid hex"),
+        )?;
+        let signature = hex::decode(
+            "250288702363f63772bdf85df116abf76a956d3d28e5af72e1254d0d1f5f8aba\
+             eddba9260969f0fedef81361e22c864b4ce43dc0109ec4bf310a8ff31d9b4f05\
+             7f29031e33062e381aa4664d05df551e251263659d8527fb80b30d45e92fe709",
+        )
+        .expect("valid hex");
+        let output =
+            hex::decode("a7937fe052f277c5798c223eb749c69b381569f44c1eadf021f7f7739d39b3c8")
+                .expect("valid hex");
+
+        assert_eq!(
+            public_key
+                .verify_vrf_signature(b"lookup key", &signature)?
+                .map(|output| output.to_vec()),
+            Some(output)
+        );
+        assert_eq!(
+            public_key.verify_vrf_signature(b"lookup keys", &signature)?,
+            None
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_decode_size() -> Result<()> {
         let mut csprng = OsRng;
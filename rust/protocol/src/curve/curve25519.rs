@@ -4,18 +4,20 @@
 //
 
 use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
-use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
 use rand::{CryptoRng, Rng};
 use sha2::{Digest, Sha512};
-use subtle::ConstantTimeEq;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 const AGREEMENT_LENGTH: usize = 32;
 pub const PRIVATE_KEY_LENGTH: usize = 32;
 pub const PUBLIC_KEY_LENGTH: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 64;
+pub const VRF_SIGNATURE_LENGTH: usize = 96;
+pub const VRF_OUTPUT_LENGTH: usize = 32;
 
 #[derive(Clone)]
 pub struct PrivateKey {
@@ -142,6 +144,101 @@ impl PrivateKey {
         bool::from(cap_r_check.as_bytes().ct_eq(&cap_r))
     }
 
+    /// Calculates a VXEdDSA signature using the X25519 private key directly, returning it along
+    /// with the VRF output it proves.
+    ///
+    /// Refer to https://signal.org/docs/specifications/xeddsa/#vxeddsa for more details. Unlike
+    /// [Self::calculate_signature], this follows the specification exactly: the Ed25519 key is
+    /// always the one with a sign bit of 0.
+    pub fn calculate_vrf_signature<R>(
+        &self,
+        csprng: &mut R,
+        message: &[u8],
+    ) -> ([u8; VRF_SIGNATURE_LENGTH], [u8; VRF_OUTPUT_LENGTH])
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut random_bytes = [0u8; 64];
+        csprng.fill_bytes(&mut random_bytes);
+        self.calculate_vrf_signature_with_random(&random_bytes, message)
+    }
+
+    /// [Self::calculate_vrf_signature] with the given random bytes, `Z` in the specification.
+    fn calculate_vrf_signature_with_random(
+        &self,
+        random_bytes: &[u8; 64],
+        message: &[u8],
+    ) -> ([u8; VRF_SIGNATURE_LENGTH], [u8; VRF_OUTPUT_LENGTH]) {
+        let k = Scalar::from_bytes_mod_order(self.secret.to_bytes());
+        let cap_e = (&k * &ED25519_BASEPOINT_TABLE).compress();
+        let a = Scalar::conditional_select(&k, &-k, Choice::from(cap_e.as_bytes()[31] >> 7));
+        let cap_a = (&a * &ED25519_BASEPOINT_TABLE).compress();
+
+        let cap_b_v = hash_to_point(&cap_a, message);
+        let point_v = a * cap_b_v;
+        let cap_v = point_v.compress();
+
+        let mut hash3 = Sha512::new();
+        hash3.update(&hash_prefix(3)[..]);
+        hash3.update(a.as_bytes());
+        hash3.update(cap_v.as_bytes());
+        hash3.update(&random_bytes[..]);
+        let r = Scalar::from_hash(hash3);
+
+        let cap_r = (&r * &ED25519_BASEPOINT_TABLE).compress();
+        let cap_r_v = (r * cap_b_v).compress();
+        let h = vrf_challenge(&cap_a, &cap_v, &cap_r, &cap_r_v, message);
+        let s = r + h * a;
+
+        let mut signature = [0u8; VRF_SIGNATURE_LENGTH];
+        signature[..32].copy_from_slice(cap_v.as_bytes());
+        signature[32..64].copy_from_slice(h.as_bytes());
+        signature[64..].copy_from_slice(s.as_bytes());
+        (signature, vrf_output(&point_v))
+    }
+
+    /// Verifies a VXEdDSA signature, returning the VRF output it proves if it is valid.
+    pub fn verify_vrf_signature(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; VRF_SIGNATURE_LENGTH],
+    ) -> Option<[u8; VRF_OUTPUT_LENGTH]> {
+        if !is_canonical_field_element(their_public_key) {
+            return None;
+        }
+        let mut h = [0u8; 32];
+        h.copy_from_slice(&signature[32..64]);
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature[64..]);
+        // Both must be less than 2^253.
+        if (h[31] & 0b1110_0000_u8) != 0 || (s[31] & 0b1110_0000_u8) != 0 {
+            return None;
+        }
+
+        let point_a = MontgomeryPoint(*their_public_key).to_edwards(0)?;
+        let cap_a = point_a.compress();
+        let mut cap_v = [0u8; 32];
+        cap_v.copy_from_slice(&signature[..32]);
+        let cap_v = CompressedEdwardsY(cap_v);
+        let point_v = cap_v.decompress()?;
+        let cap_b_v = hash_to_point(&cap_a, message);
+        if point_a.is_small_order() || point_v.is_small_order() || cap_b_v.is_small_order() {
+            return None;
+        }
+
+        let h = Scalar::from_bits(h);
+        let s = Scalar::from_bits(s);
+        let cap_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &-point_a, &s).compress();
+        let cap_r_v = (s * cap_b_v - h * point_v).compress();
+        let h_check = vrf_challenge(&cap_a, &cap_v, &cap_r, &cap_r_v, message);
+
+        if bool::from(h_check.as_bytes().ct_eq(h.as_bytes())) {
+            Some(vrf_output(&point_v))
+        } else {
+            None
+        }
+    }
+
     pub fn derive_public_key_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH] {
         *PublicKey::from(&self.secret).as_bytes()
     }
@@ -151,6 +248,59 @@ impl PrivateKey {
     }
 }
 
+/// The prefix that separates the hash function `hash_i` from the others, as defined by XEdDSA.
+fn hash_prefix(i: u8) -> [u8; 32] {
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] -= i;
+    prefix
+}
+
+/// Maps `cap_a || message` to a point of prime order, as `hash_to_point` in VXEdDSA.
+fn hash_to_point(cap_a: &CompressedEdwardsY, message: &[u8]) -> EdwardsPoint {
+    let mut input = Vec::with_capacity(64 + message.len());
+    input.extend_from_slice(&hash_prefix(2));
+    input.extend_from_slice(cap_a.as_bytes());
+    input.extend_from_slice(message);
+    // Hashes with SHA-512, then applies Elligator 2 and clears the cofactor, just like VXEdDSA.
+    EdwardsPoint::hash_from_bytes::<Sha512>(&input)
+}
+
+fn vrf_challenge(
+    cap_a: &CompressedEdwardsY,
+    cap_v: &CompressedEdwardsY,
+    cap_r: &CompressedEdwardsY,
+    cap_r_v: &CompressedEdwardsY,
+    message: &[u8],
+) -> Scalar {
+    let mut hash4 = Sha512::new();
+    hash4.update(&hash_prefix(4)[..]);
+    hash4.update(cap_a.as_bytes());
+    hash4.update(cap_v.as_bytes());
+    hash4.update(cap_r.as_bytes());
+    hash4.update(cap_r_v.as_bytes());
+    hash4.update(message);
+    Scalar::from_hash(hash4)
+}
+
+fn vrf_output(point_v: &EdwardsPoint) -> [u8; VRF_OUTPUT_LENGTH] {
+    let mut hash5 = Sha512::new();
+    hash5.update(&hash_prefix(5)[..]);
+    hash5.update(point_v.mul_by_cofactor().compress().as_bytes());
+    let mut output = [0u8; VRF_OUTPUT_LENGTH];
+    output.copy_from_slice(&hash5.finalize()[..VRF_OUTPUT_LENGTH]);
+    output
+}
+
+/// Whether `bytes` encode an integer less than 2^255 - 19.
+fn is_canonical_field_element(bytes: &[u8; 32]) -> bool {
+    const P: [u8; 32] = [
+        0xED, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0x7F,
+    ];
+    bytes.iter().rev().lt(P.iter().rev())
+}
+
 impl From<[u8; PRIVATE_KEY_LENGTH]> for PrivateKey {
     fn from(private_key: [u8; 32]) -> Self {
         let secret = StaticSecret::from(private_key);
@@ -162,6 +312,7 @@ impl From<[u8; PRIVATE_KEY_LENGTH]> for PrivateKey {
 mod tests {
     use rand::rngs::OsRng;
     use rand::RngCore;
+    use std::convert::TryInto;
 
     use super::*;
 
@@ -279,6 +430,169 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_vrf_signature() {
+        // Regression vectors calculated with the XEdDSA test key above.
+        let key = PrivateKey::from([
+            0xc0, 0x97, 0x24, 0x84, 0x12, 0xe5, 0x8b, 0xf0, 0x5d, 0xf4, 0x87, 0x96, 0x82, 0x05,
+            0x13, 0x27, 0x94, 0x17, 0x8e, 0x36, 0x76, 0x37, 0xf5, 0x81, 0x8f, 0x81, 0xe0, 0xe6,
+            0xce, 0x73, 0xe8, 0x65,
+        ]);
+        let public_key = key.derive_public_key_bytes();
+        let vectors: [(&[u8], &str, &str); 2] = [
+            (
+                b"",
+                "5c80f3950c82e567d1e4fe4c7332772ddaf20430d64300d9fae1019e5cdfd9bd\
+                 3b8e0aa9a2a007045a497c1b0f464bde6e4f2c9594d11ee83aaa77bced59f400\
+                 bcbd029dc8862552a0f442c13cfb2087c2b1a9e8e2191ba3dfaca40c4d5fcc08",
+                "325e7017ecda402587f77492dd08b90c34cee31e0875bd709237fa68823eb057",
+            ),
+            (
+                b"abc",
+                "9581acfe656884a4fc9c87b67b5175e923c25a893f263a7a260e99b3552e58c6\
+                 1e4f7d7fc91ff4b1a4ac978dc3b4a929646aa80077bea71bb6d0eaacd2262500\
+                 9f318184b08f291b21adcb75fcf1bb83026baeec36d408112d546a62d888e50f",
+                "8eac0ec884de8feefcb1a16aef0a1bc32a85d04d600d076818501ee4ed495ba3",
+            ),
+        ];
+
+        for (message, signature, output) in vectors {
+            let signature: [u8; VRF_SIGNATURE_LENGTH] = hex::decode(signature)
+                .expect("valid hex")
+                .try_into()
+                .expect("correct length");
+            let output = hex::decode(output).expect("valid hex");
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(&public_key, message, &signature)
+                    .expect("valid signature"),
+                &output[..]
+            );
+
+            // A fresh signature proves the same output.
+            let (new_signature, new_output) = key.calculate_vrf_signature(&mut OsRng, message);
+            assert_ne!(new_signature, signature);
+            assert_eq!(new_output, &output[..]);
+
+            for i in 0..signature.len() {
+                let mut signature_copy = signature;
+                signature_copy[i] ^= 0x01u8;
+                assert_eq!(
+                    PrivateKey::verify_vrf_signature(&public_key, message, &signature_copy),
+                    None,
+                    "signature check passed when it should not have"
+                );
+            }
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(&public_key, b"other", &signature),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_vrf_signature_known_answers() {
+        // Computed outside this crate by an independent implementation of VXEdDSA written from
+        // the specification (with its own Elligator 2 and Edwards arithmetic), with
+        // Z = 00 01 02 .. 3f. The first key has an Ed25519 public key with a sign bit of 1 and so
+        // signs with its negation; the second does not.
+        let keys = [
+            PrivateKey::from([
+                0xc0, 0x97, 0x24, 0x84, 0x12, 0xe5, 0x8b, 0xf0, 0x5d, 0xf4, 0x87, 0x96, 0x82, 0x05,
+                0x13, 0x27, 0x94, 0x17, 0x8e, 0x36, 0x76, 0x37, 0xf5, 0x81, 0x8f, 0x81, 0xe0, 0xe6,
+                0xce, 0x73, 0xe8, 0x65,
+            ]),
+            PrivateKey::from([
+                0xb0, 0x3b, 0x34, 0xc3, 0x3a, 0x1c, 0x44, 0xf2, 0x25, 0xb6, 0x62, 0xd2, 0xbf, 0x48,
+                0x59, 0xb8, 0x13, 0x54, 0x11, 0xfa, 0x7b, 0x03, 0x86, 0xd4, 0x5f, 0xb7, 0x5d, 0xc5,
+                0xb9, 0x1b, 0x44, 0x66,
+            ]),
+        ];
+        let vectors: [(usize, &[u8], &str, &str); 3] = [
+            (
+                0,
+                b"",
+                "5c80f3950c82e567d1e4fe4c7332772ddaf20430d64300d9fae1019e5cdfd9bd\
+                 028c2c5ae823b67a1a1abd9b8b778a470befa3a127dcbd0b9315c4f29553bf05\
+                 542a9bc5c232d963309e71af59af4d027eb62ce923f947869c23a592f3afda0d",
+                "325e7017ecda402587f77492dd08b90c34cee31e0875bd709237fa68823eb057",
+            ),
+            (
+                0,
+                b"abc",
+                "9581acfe656884a4fc9c87b67b5175e923c25a893f263a7a260e99b3552e58c6\
+                 9edaabd68dc01138aeabd9fa6d445afe657e36304c4305b948b55962f189ef0b\
+                 5d77518babd7e032414092979236a12e3ef9634b306f05b933546182b4caa40a",
+                "8eac0ec884de8feefcb1a16aef0a1bc32a85d04d600d076818501ee4ed495ba3",
+            ),
+            (
+                1,
+                b"lookup key",
+                "250288702363f63772bdf85df116abf76a956d3d28e5af72e1254d0d1f5f8aba\
+                 eddba9260969f0fedef81361e22c864b4ce43dc0109ec4bf310a8ff31d9b4f05\
+                 7f29031e33062e381aa4664d05df551e251263659d8527fb80b30d45e92fe709",
+                "a7937fe052f277c5798c223eb749c69b381569f44c1eadf021f7f7739d39b3c8",
+            ),
+        ];
+        let mut random_bytes = [0u8; 64];
+        for (i, byte) in random_bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        for (key_index, message, signature, output) in vectors {
+            let key = &keys[key_index];
+            let (calculated_signature, calculated_output) =
+                key.calculate_vrf_signature_with_random(&random_bytes, message);
+            assert_eq!(hex::encode(calculated_signature), signature);
+            assert_eq!(hex::encode(calculated_output), output);
+
+            let signature: [u8; VRF_SIGNATURE_LENGTH] = hex::decode(signature)
+                .expect("valid hex")
+                .try_into()
+                .expect("correct length");
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(
+                    &key.derive_public_key_bytes(),
+                    message,
+                    &signature
+                ),
+                Some(calculated_output)
+            );
+        }
+    }
+
+    #[test]
+    fn test_random_vrf_signatures() {
+        let mut csprng = OsRng;
+        for _ in 0..50 {
+            let mut message = [0u8; 64];
+            csprng.fill_bytes(&mut message);
+            let key = PrivateKey::new(&mut csprng);
+            let other_key = PrivateKey::new(&mut csprng);
+            let (signature, output) = key.calculate_vrf_signature(&mut csprng, &message);
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(
+                    &key.derive_public_key_bytes(),
+                    &message,
+                    &signature
+                ),
+                Some(output),
+                "signature check failed"
+            );
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(
+                    &other_key.derive_public_key_bytes(),
+                    &message,
+                    &signature
+                ),
+                None
+            );
+            assert_ne!(
+                other_key.calculate_vrf_signature(&mut csprng, &message).1,
+                output
+            );
+        }
+    }
+
     #[test]
     fn test_random_signatures() {
         let mut csprng = OsRng;
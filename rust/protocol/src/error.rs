@@ -4,6 +4,7 @@
 //
 
 use crate::curve::KeyType;
+use crate::kem;
 
 use displaydoc::Display;
 use thiserror::Error;
@@ -43,6 +44,14 @@ pub enum SignalProtocolError {
     BadKeyType(u8),
     /// bad key length <{1}> for key with type <{0}>
     BadKeyLength(KeyType, usize),
+    /// bad KEM key type <{0:#04x}>
+    BadKEMKeyType(u8),
+    /// unexpected KEM key type <{0:#04x}> (expected <{1:#04x}>)
+    WrongKEMKeyType(u8, u8),
+    /// bad KEM key length <{1}> for key with type <{0}>
+    BadKEMKeyLength(kem::KeyType, usize),
+    /// bad KEM ciphertext length <{1}> for key with type <{0}>
+    BadKEMCiphertextLength(kem::KeyType, usize),
 
     /// invalid signature detected
     SignatureValidationFailed,
@@ -54,6 +63,10 @@ pub enum SignalProtocolError {
     InvalidPreKeyId,
     /// invalid signed prekey identifier
     InvalidSignedPreKeyId,
+    /// invalid Kyber prekey identifier
+    InvalidKyberPreKeyId,
+    /// signed prekey {0} is older than the session policy allows
+    SignedPreKeyExpired(crate::SignedPreKeyId),
 
     /// invalid MAC key length <{0}>
     InvalidMacKeyLength(usize),
@@ -67,6 +80,11 @@ pub enum SignalProtocolError {
     InvalidSessionStructure(&'static str),
     /// invalid sender key session with distribution ID {distribution_id}
     InvalidSenderKeySession { distribution_id: Uuid },
+    /// sender key for distribution ID {distribution_id} must be rotated: {reason}
+    SenderKeyRotationRequired {
+        distribution_id: Uuid,
+        reason: crate::SenderKeyRotationReason,
+    },
     /// session for {0} has invalid registration ID {1:X}
     InvalidRegistrationId(crate::ProtocolAddress, u32),
 
@@ -74,6 +92,8 @@ pub enum SignalProtocolError {
     DuplicatedMessage(u32, u32),
     /// invalid {0:?} message: {1}
     InvalidMessage(crate::CiphertextMessageType, &'static str),
+    /// message padding is malformed
+    InvalidPadding,
 
     /// error while invoking an ffi callback: {0}
     FfiBindingError(String),
@@ -89,4 +109,13 @@ pub enum SignalProtocolError {
     UnknownSealedSenderVersion(u8),
     /// self send of a sealed sender message
     SealedSenderSelfSend,
+
+    /// invalid encrypted record: {0}
+    InvalidEncryptedRecord(String),
+
+    /// invalid provisioning message: {0}
+    InvalidProvisioningMessage(String),
+
+    /// key transparency verification failed: {0}
+    KeyTransparencyVerificationFailed(&'static str),
 }
@@ -3,7 +3,11 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::{proto, IdentityKey, Result, SignalProtocolError};
+mod qr;
+
+pub use qr::QrCode;
+
+use crate::{proto, Aci, IdentityKey, Result, SignalProtocolError};
 use prost::Message;
 use sha2::digest::Digest;
 use sha2::Sha512;
@@ -60,6 +64,20 @@ impl DisplayableFingerprint {
     }
 }
 
+/// Why a scanned fingerprint did not match ours.
+///
+/// The result of [ScannableFingerprint::mismatch_reason].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintMismatch {
+    /// The fingerprints were generated with different versions, and so cannot be compared.
+    VersionMismatch { theirs: u32, ours: u32 },
+    /// The scanned fingerprint has the same local and remote identities as ours rather than the
+    /// other way around, as happens when scanning a code displayed by the same account.
+    SwappedIdentities,
+    /// At least one of the identity keys (or identifiers) does not match.
+    DifferentKeys,
+}
+
 #[derive(Debug, Clone)]
 pub struct ScannableFingerprint {
     version: u32,
@@ -112,36 +130,67 @@ impl ScannableFingerprint {
     }
 
     pub fn compare(&self, combined: &[u8]) -> Result<bool> {
+        match self.mismatch_reason(combined)? {
+            None => Ok(true),
+            Some(FingerprintMismatch::VersionMismatch { theirs, ours }) => Err(
+                SignalProtocolError::FingerprintVersionMismatch(theirs, ours),
+            ),
+            Some(FingerprintMismatch::SwappedIdentities | FingerprintMismatch::DifferentKeys) => {
+                Ok(false)
+            }
+        }
+    }
+
+    /// Compare against a scanned fingerprint, explaining why it does not match.
+    ///
+    /// Returns `None` if the fingerprints match. Unlike [compare](Self::compare), a version
+    /// mismatch is reported as a [FingerprintMismatch] rather than an error.
+    pub fn mismatch_reason(&self, combined: &[u8]) -> Result<Option<FingerprintMismatch>> {
         let combined = proto::fingerprint::CombinedFingerprints::decode(combined)
             .map_err(|_| SignalProtocolError::FingerprintParsingError)?;
 
         let their_version = combined.version.unwrap_or(0);
 
         if their_version != self.version {
-            return Err(SignalProtocolError::FingerprintVersionMismatch(
-                their_version,
-                self.version,
-            ));
+            return Ok(Some(FingerprintMismatch::VersionMismatch {
+                theirs: their_version,
+                ours: self.version,
+            }));
         }
 
-        let same1 = combined
+        let their_local = combined
             .local_fingerprint
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
             .content
             .as_ref()
-            .ok_or(SignalProtocolError::FingerprintParsingError)?
-            .ct_eq(&self.remote_fingerprint);
-        let same2 = combined
+            .ok_or(SignalProtocolError::FingerprintParsingError)?;
+        let their_remote = combined
             .remote_fingerprint
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
             .content
             .as_ref()
-            .ok_or(SignalProtocolError::FingerprintParsingError)?
-            .ct_eq(&self.local_fingerprint);
+            .ok_or(SignalProtocolError::FingerprintParsingError)?;
+
+        let same1 = their_local.ct_eq(&self.remote_fingerprint);
+        let same2 = their_remote.ct_eq(&self.local_fingerprint);
+        if (same1 & same2).into() {
+            return Ok(None);
+        }
+
+        let swapped1 = their_local.ct_eq(&self.local_fingerprint);
+        let swapped2 = their_remote.ct_eq(&self.remote_fingerprint);
+        if (swapped1 & swapped2).into() {
+            return Ok(Some(FingerprintMismatch::SwappedIdentities));
+        }
+
+        Ok(Some(FingerprintMismatch::DifferentKeys))
+    }
 
-        Ok(same1.into() && same2.into())
+    /// Encode this fingerprint as a QR code, for the other party to scan.
+    pub fn to_qr_code(&self) -> Result<QrCode> {
+        QrCode::encode(&self.serialize()?)
     }
 }
 
@@ -152,6 +201,11 @@ pub struct Fingerprint {
 }
 
 impl Fingerprint {
+    /// The scannable fingerprint version of [Fingerprint::from_aci].
+    pub const ACI_VERSION: u32 = 2;
+    /// The number of hash iterations used by [Fingerprint::from_aci].
+    pub const ACI_ITERATIONS: u32 = 5200;
+
     fn get_fingerprint(
         iterations: u32,
         local_id: &[u8],
@@ -205,6 +259,24 @@ impl Fingerprint {
         })
     }
 
+    /// Generate a fingerprint that identifies each party by their ACI rather than an arbitrary
+    /// stable identifier such as a phone number.
+    pub fn from_aci(
+        local_aci: Aci,
+        local_key: &IdentityKey,
+        remote_aci: Aci,
+        remote_key: &IdentityKey,
+    ) -> Result<Fingerprint> {
+        Fingerprint::new(
+            Self::ACI_VERSION,
+            Self::ACI_ITERATIONS,
+            &local_aci.service_id_binary(),
+            local_key,
+            &remote_aci.service_id_binary(),
+            remote_key,
+        )
+    }
+
     pub fn display_string(&self) -> Result<String> {
         Ok(format!("{}", self.display))
     }
@@ -226,9 +298,21 @@ mod test {
     const ALICE_SCANNABLE_FINGERPRINT_V2 : &str = "080212220a201e301a0353dce3dbe7684cb8336e85136cdc0ee96219494ada305d62a7bd61df1a220a20d62cbf73a11592015b6b9f1682ac306fea3aaf3885b84d12bca631e9d4fb3a4d";
     const BOB_SCANNABLE_FINGERPRINT_V2   : & str = "080212220a20d62cbf73a11592015b6b9f1682ac306fea3aaf3885b84d12bca631e9d4fb3a4d1a220a201e301a0353dce3dbe7684cb8336e85136cdc0ee96219494ada305d62a7bd61df";
 
+    const DISPLAYABLE_FINGERPRINT_ACI: &str =
+        "446657246696264293037458627907774872764702919995219132117432";
+    const ALICE_SCANNABLE_FINGERPRINT_ACI : &str = "080212220a208a2c258a99951098bd3222aa59be88e53fad3f3712fbd9ed3aa9ba26092317791a220a200a6bb4b36fb0dc81a39f14f4acac47b1250386e1a5fccce93971d687a1b856b6";
+    const BOB_SCANNABLE_FINGERPRINT_ACI   : &str = "080212220a200a6bb4b36fb0dc81a39f14f4acac47b1250386e1a5fccce93971d687a1b856b61a220a208a2c258a99951098bd3222aa59be88e53fad3f3712fbd9ed3aa9ba2609231779";
+
     const ALICE_STABLE_ID: &str = "+14152222222";
     const BOB_STABLE_ID: &str = "+14153333333";
 
+    const ALICE_ACI: &str = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+    const BOB_ACI: &str = "796abedb-ca4e-4f18-8803-1fde5b921f9f";
+
+    fn aci(s: &str) -> Aci {
+        Aci::from(uuid::Uuid::parse_str(s).expect("valid UUID"))
+    }
+
     #[test]
     fn fingerprint_encodings() -> Result<()> {
         let l = vec![0x12; 32];
@@ -542,4 +626,150 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn fingerprint_aci() -> Result<()> {
+        // Computed outside this crate, by an implementation that also reproduces the version 1
+        // vectors above.
+        let a_key = IdentityKey::decode(&hex::decode(ALICE_IDENTITY).expect("valid hex"))?;
+        let b_key = IdentityKey::decode(&hex::decode(BOB_IDENTITY).expect("valid hex"))?;
+
+        let a_fprint = Fingerprint::from_aci(aci(ALICE_ACI), &a_key, aci(BOB_ACI), &b_key)?;
+        let b_fprint = Fingerprint::from_aci(aci(BOB_ACI), &b_key, aci(ALICE_ACI), &a_key)?;
+
+        assert_eq!(a_fprint.display_string()?, DISPLAYABLE_FINGERPRINT_ACI);
+        assert_eq!(b_fprint.display_string()?, DISPLAYABLE_FINGERPRINT_ACI);
+        assert_eq!(
+            hex::encode(a_fprint.scannable.serialize()?),
+            ALICE_SCANNABLE_FINGERPRINT_ACI
+        );
+        assert_eq!(
+            hex::encode(b_fprint.scannable.serialize()?),
+            BOB_SCANNABLE_FINGERPRINT_ACI
+        );
+
+        assert!(a_fprint
+            .scannable
+            .compare(&b_fprint.scannable.serialize()?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint_mismatch_reasons() -> Result<()> {
+        use crate::IdentityKeyPair;
+        use rand::rngs::OsRng;
+
+        let a_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let b_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let m_key = *IdentityKeyPair::generate(&mut OsRng).identity_key(); // mitm
+
+        let a_fprint = Fingerprint::from_aci(aci(ALICE_ACI), &a_key, aci(BOB_ACI), &b_key)?;
+        let b_fprint = Fingerprint::from_aci(aci(BOB_ACI), &b_key, aci(ALICE_ACI), &a_key)?;
+        let m_fprint = Fingerprint::from_aci(aci(BOB_ACI), &m_key, aci(ALICE_ACI), &a_key)?;
+        let v1_fprint = Fingerprint::new(
+            1,
+            Fingerprint::ACI_ITERATIONS,
+            BOB_STABLE_ID.as_bytes(),
+            &b_key,
+            ALICE_STABLE_ID.as_bytes(),
+            &a_key,
+        )?;
+
+        let a_scannable = &a_fprint.scannable;
+        assert_eq!(
+            a_scannable.mismatch_reason(&b_fprint.scannable.serialize()?)?,
+            None
+        );
+        assert_eq!(
+            a_scannable.mismatch_reason(&a_scannable.serialize()?)?,
+            Some(FingerprintMismatch::SwappedIdentities)
+        );
+        assert_eq!(
+            a_scannable.mismatch_reason(&m_fprint.scannable.serialize()?)?,
+            Some(FingerprintMismatch::DifferentKeys)
+        );
+        assert_eq!(
+            a_scannable.mismatch_reason(&v1_fprint.scannable.serialize()?)?,
+            Some(FingerprintMismatch::VersionMismatch { theirs: 1, ours: 2 })
+        );
+
+        assert!(matches!(
+            a_scannable.compare(&v1_fprint.scannable.serialize()?),
+            Err(SignalProtocolError::FingerprintVersionMismatch(1, 2))
+        ));
+        assert!(matches!(
+            a_scannable.mismatch_reason(b"not a fingerprint"),
+            Err(SignalProtocolError::FingerprintParsingError)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint_qr_code() -> Result<()> {
+        let l = vec![0x12; 32];
+        let r = vec![0xBA; 32];
+
+        let code = ScannableFingerprint::new(2, &l, &r).to_qr_code()?;
+        // 74 bytes of protobuf is too much for version 4-M.
+        assert_eq!(code.version(), 5);
+        assert_eq!(code.size(), 37);
+
+        // The same code drawn by an independent encoder, given the mask chosen here.
+        const ALICE_QR_CODE_ACI: [&str; 37] = [
+            "#######.##...##.##.##....##.#.#######",
+            "#.....#.#.##..####.#.##...#.#.#.....#",
+            "#.###.#.#..####.#.##.#..##.##.#.###.#",
+            "#.###.#..#.#...##.#.####.##...#.###.#",
+            "#.###.#.#.#...##..#####.#.#...#.###.#",
+            "#.....#...#.#...#.....##..#...#.....#",
+            "#######.#.#.#.#.#.#.#.#.#.#.#.#######",
+            ".................##.###..#..#........",
+            "#..######.###.##.....#.#.#...#..#.###",
+            ".##.#..#.#..#...###....##.#####..####",
+            "#.#...##.......######.##.##.#.##.#.##",
+            "#..##....##...#.##.##..###.#..#....#.",
+            "#....##..##..#.##.#..##.#.##.#.#...##",
+            "#..###..###....#..#.#.######..###..#.",
+            "...####.##....###.#.#..#....##.#.#...",
+            ".#.#.....########...####.#..##.#..##.",
+            ".####.##..#####..#####..#.#..##..###.",
+            "...##..#.####....#....#..#....#.#.###",
+            "####.###..###.#.#.###.#.#.####..#..##",
+            "..##.....##.#####....#...##.#..###..#",
+            ".#.##.#.##.##...#.##...#.##....###...",
+            "..#.##....#.#...##.##..##...###.#.#..",
+            "##.#..#....##.##...##.##.#....#..##.#",
+            "#.####..##..#####.##.#.#.#..###.#.##.",
+            ".######...###.##.#....###..##..#####.",
+            "#.####.##..###....#.##....##.#.#.##.#",
+            "##.#.####.##.###.#....####..#..#....#",
+            "#..#...##..##...#..#.#...#...###.....",
+            "#.#.###...###..#.....##.###.#####.##.",
+            "........###..#.###.#......###...#..##",
+            "#######.#.....######.##.#####.#.##.##",
+            "#.....#.#.....##...#.##.#...#...#...#",
+            "#.###.#.##..##..#.#.#.##...#######..#",
+            "#.###.#.###.###..#...#.#.####...#.#.#",
+            "#.###.#...###.#..##.##...#....#..#..#",
+            "#.....#...##.#.#.#...#.#.#....#..#.##",
+            "#######.#...#.#..##.#..#####.####.#.#",
+        ];
+        let scannable = ScannableFingerprint::deserialize(
+            &hex::decode(ALICE_SCANNABLE_FINGERPRINT_ACI).expect("valid hex"),
+        )?;
+        let rows: Vec<String> = scannable
+            .to_qr_code()?
+            .rows()
+            .map(|row| {
+                row.iter()
+                    .map(|&dark| if dark { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, ALICE_QR_CODE_ACI);
+
+        Ok(())
+    }
 }
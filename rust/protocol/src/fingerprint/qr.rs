@@ -0,0 +1,753 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A minimal QR code encoder, enough to display a [ScannableFingerprint].
+//!
+//! Data is always encoded in byte mode at error correction level M, in the smallest of versions 1
+//! to 10 that fits. See ISO/IEC 18004 for the details of each step.
+//!
+//! [ScannableFingerprint]: super::ScannableFingerprint
+
+use crate::{Result, SignalProtocolError};
+
+const MAX_VERSION: usize = 10;
+
+/// A group of error correction blocks, as (number of blocks, data codewords per block).
+type BlockGroup = (usize, usize);
+
+/// The error correction block structure of each version at level M, as error correction codewords
+/// per block, followed by the two block groups.
+const BLOCKS_M: [(usize, BlockGroup, BlockGroup); MAX_VERSION] = [
+    (10, (1, 16), (0, 0)),
+    (16, (1, 28), (0, 0)),
+    (26, (1, 44), (0, 0)),
+    (18, (2, 32), (0, 0)),
+    (24, (2, 43), (0, 0)),
+    (16, (4, 27), (0, 0)),
+    (18, (4, 31), (0, 0)),
+    (22, (2, 38), (2, 39)),
+    (22, (3, 36), (2, 37)),
+    (26, (4, 43), (1, 44)),
+];
+
+/// The format information bits for level M.
+const FORMAT_BITS_M: u32 = 0b00;
+
+/// A QR code, as a square matrix of modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `data` as a QR code.
+    ///
+    /// Fails if `data` is too long to fit in a version 10 code.
+    pub fn encode(data: &[u8]) -> Result<Self> {
+        let version = (1..=MAX_VERSION)
+            .find(|&version| data_capacity_bits(version) >= data_bits(version, data.len()))
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!(
+                    "{} bytes is too long to encode as a QR code",
+                    data.len()
+                ))
+            })?;
+        let codewords = add_error_correction(version, &data_codewords(version, data));
+
+        let mut builder = Builder::new(version);
+        builder.draw_function_patterns();
+        builder.draw_codewords(&codewords);
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                let mut candidate = builder.clone();
+                candidate.apply_mask(mask);
+                candidate.draw_format_bits(mask);
+                candidate.penalty_score()
+            })
+            .expect("there are masks to choose from");
+        builder.apply_mask(mask);
+        builder.draw_format_bits(mask);
+
+        Ok(Self {
+            version,
+            size: builder.size,
+            modules: builder.modules,
+        })
+    }
+
+    /// The QR code version, from 1 to 10.
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// The number of modules along each side, not counting the quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module in column `x` and row `y` is dark, counting from the top left.
+    ///
+    /// Modules outside the code are light, as is the quiet zone that should surround it.
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Each row of modules from top to bottom, with `true` for dark modules.
+    pub fn rows(&self) -> impl Iterator<Item = &[bool]> {
+        self.modules.chunks_exact(self.size)
+    }
+}
+
+fn size_for_version(version: usize) -> usize {
+    version * 4 + 17
+}
+
+fn data_capacity_bits(version: usize) -> usize {
+    let (_, (blocks1, len1), (blocks2, len2)) = BLOCKS_M[version - 1];
+    (blocks1 * len1 + blocks2 * len2) * 8
+}
+
+fn count_bits(version: usize) -> usize {
+    if version < 10 {
+        8
+    } else {
+        16
+    }
+}
+
+fn data_bits(version: usize, len: usize) -> usize {
+    4 + count_bits(version) + len * 8
+}
+
+/// Encodes `data` in byte mode, then pads it to the capacity of `version`.
+fn data_codewords(version: usize, data: &[u8]) -> Vec<u8> {
+    let capacity = data_capacity_bits(version);
+    let mut bits = BitBuffer::default();
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, count_bits(version));
+    for &byte in data {
+        bits.push(byte.into(), 8);
+    }
+    let terminator = (capacity - bits.len).min(4);
+    bits.push(0, terminator);
+    bits.push(0, (8 - bits.len % 8) % 8);
+    for &pad in [0xEC, 0x11].iter().cycle() {
+        if bits.len >= capacity {
+            break;
+        }
+        bits.push(pad, 8);
+    }
+    bits.bytes
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len % 8 == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().expect("just pushed") |= bit << (7 - self.len % 8);
+            self.len += 1;
+        }
+    }
+}
+
+/// Splits `data` into blocks, adds error correction to each, and interleaves the result.
+fn add_error_correction(version: usize, data: &[u8]) -> Vec<u8> {
+    let (ec_len, (blocks1, len1), (blocks2, len2)) = BLOCKS_M[version - 1];
+    let divisor = reed_solomon_divisor(ec_len);
+
+    let mut blocks = Vec::with_capacity(blocks1 + blocks2);
+    let mut remaining = data;
+    for i in 0..blocks1 + blocks2 {
+        let len = if i < blocks1 { len1 } else { len2 };
+        let (block, rest) = remaining.split_at(len);
+        blocks.push((block, reed_solomon_remainder(block, &divisor)));
+        remaining = rest;
+    }
+    debug_assert!(remaining.is_empty());
+
+    let mut result = Vec::with_capacity(data.len() + ec_len * blocks.len());
+    for i in 0..len1.max(len2) {
+        result.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ec_len {
+        result.extend(blocks.iter().map(|(_, ecc)| ecc[i]));
+    }
+    result
+}
+
+/// Multiplies two elements of GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z = 0u8;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1D);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+/// The generator polynomial of the given degree, without its leading coefficient.
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (coefficient, &d) in result.iter_mut().zip(divisor) {
+            *coefficient ^= gf_multiply(d, factor);
+        }
+    }
+    result
+}
+
+fn alignment_pattern_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return vec![];
+    }
+    let count = version / 7 + 2;
+    let step = (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2;
+    let mut result = vec![6];
+    let last = size_for_version(version) - 7;
+    result.extend((0..count - 1).rev().map(|i| last - i * step));
+    result
+}
+
+#[derive(Clone)]
+struct Builder {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl Builder {
+    fn new(version: usize) -> Self {
+        let size = size_for_version(version);
+        Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            is_function: vec![false; size * size],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function_module(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..self.size {
+            self.set_function_module(6, i, i % 2 == 0);
+            self.set_function_module(i, 6, i % 2 == 0);
+        }
+
+        self.draw_finder_pattern(3, 3);
+        self.draw_finder_pattern(self.size - 4, 3);
+        self.draw_finder_pattern(3, self.size - 4);
+
+        let positions = alignment_pattern_positions(self.version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the corners taken by finder patterns.
+                if (i, j) != (0, 0) && (i, j) != (0, last) && (i, j) != (last, 0) {
+                    self.draw_alignment_pattern(x, y);
+                }
+            }
+        }
+
+        // Reserve the format areas until a mask is chosen.
+        self.draw_format_bits(0);
+        self.draw_version();
+    }
+
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if (0..self.size as i32).contains(&xx) && (0..self.size as i32).contains(&yy) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function_module(
+                        xx as usize,
+                        yy as usize,
+                        distance != 2 && distance != 4,
+                    );
+                }
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                self.set_function_module(
+                    (x as i32 + dx) as usize,
+                    (y as i32 + dy) as usize,
+                    dx.abs().max(dy.abs()) != 1,
+                );
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let data = FORMAT_BITS_M << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        // Around the top left finder pattern.
+        for i in 0..=5 {
+            self.set_function_module(8, i, bit(i));
+        }
+        self.set_function_module(8, 7, bit(6));
+        self.set_function_module(8, 8, bit(7));
+        self.set_function_module(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function_module(14 - i, 8, bit(i));
+        }
+
+        // Split between the other two finder patterns.
+        let size = self.size;
+        for i in 0..8 {
+            self.set_function_module(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function_module(8, size - 15 + i, bit(i));
+        }
+        self.set_function_module(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let mut remainder = self.version as u32;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+        }
+        let bits = (self.version as u32) << 12 | remainder;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function_module(a, b, dark);
+            self.set_function_module(b, a, dark);
+        }
+    }
+
+    /// Places `codewords` in the zig-zag order of the standard, skipping function modules.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let mut i = 0;
+        let mut right = self.size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vert in 0..self.size {
+                let y = if upward { self.size - 1 - vert } else { vert };
+                for x in [right, right - 1] {
+                    if !self.is_function[y * self.size + x] && i < codewords.len() * 8 {
+                        self.modules[y * self.size + x] =
+                            (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if !self.is_function[y * self.size + x] && mask_applies(mask, x, y) {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// Scores how hard the code would be to scan, using the penalty rules of the standard.
+    fn penalty_score(&self) -> usize {
+        let size = self.size;
+        let mut result = 0;
+
+        for line in 0..size {
+            result += line_penalty((0..size).map(|i| self.get(i, line)), size);
+            result += line_penalty((0..size).map(|i| self.get(line, i)), size);
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.get(x, y);
+                if color == self.get(x + 1, y)
+                    && color == self.get(x, y + 1)
+                    && color == self.get(x + 1, y + 1)
+                {
+                    result += 3;
+                }
+            }
+        }
+
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        result += ((deviation + total - 1) / total - 1) * 10;
+        result
+    }
+}
+
+fn mask_applies(mask: u32, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y) % 2 == 0,
+        1 => y % 2 == 0,
+        2 => x % 3 == 0,
+        3 => (x + y) % 3 == 0,
+        4 => (x / 3 + y / 2) % 2 == 0,
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3) % 2 == 0,
+        7 => ((x + y) % 2 + x * y % 3) % 2 == 0,
+        _ => unreachable!("there are only 8 masks"),
+    }
+}
+
+/// Penalizes runs of five or more modules of the same color, and patterns that look like finder
+/// patterns, in one row or column.
+fn line_penalty(line: impl Iterator<Item = bool>, size: usize) -> usize {
+    let mut result = 0;
+    let mut run_color = false;
+    let mut run_length = 0;
+    let mut history = FinderHistory::new(size);
+    for color in line {
+        if color == run_color {
+            run_length += 1;
+            match run_length {
+                5 => result += 3,
+                6.. => result += 1,
+                _ => {}
+            }
+        } else {
+            history.add(run_length);
+            if !run_color {
+                result += history.count_patterns() * 40;
+            }
+            run_color = color;
+            run_length = 1;
+        }
+    }
+    if run_color {
+        history.add(run_length);
+        run_length = 0;
+    }
+    // The light quiet zone extends the final run.
+    history.add(run_length + size);
+    result + history.count_patterns() * 40
+}
+
+/// The lengths of the most recent runs in a line, newest first.
+struct FinderHistory {
+    runs: [usize; 7],
+    size: usize,
+}
+
+impl FinderHistory {
+    fn new(size: usize) -> Self {
+        Self { runs: [0; 7], size }
+    }
+
+    fn add(&mut self, mut run_length: usize) {
+        if self.runs[0] == 0 {
+            // The light quiet zone extends the first run.
+            run_length += self.size;
+        }
+        self.runs.copy_within(0..6, 1);
+        self.runs[0] = run_length;
+    }
+
+    /// Counts 1:1:3:1:1 patterns with four light modules on at least one side.
+    fn count_patterns(&self) -> usize {
+        let [before, a, b, c, d, e, after] = self.runs;
+        let core = a > 0 && b == a && c == a * 3 && d == a && e == a;
+        usize::from(core && before >= a * 4 && after >= a)
+            + usize::from(core && after >= a * 4 && before >= a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon() {
+        // "HELLO WORLD" at version 1-M, from the worked example at
+        // https://www.thonky.com/qr-code-tutorial/error-correction-coding
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        assert_eq!(
+            reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn test_alignment_pattern_positions() {
+        assert_eq!(alignment_pattern_positions(1), []);
+        assert_eq!(alignment_pattern_positions(2), [6, 18]);
+        assert_eq!(alignment_pattern_positions(5), [6, 30]);
+        assert_eq!(alignment_pattern_positions(7), [6, 22, 38]);
+        assert_eq!(alignment_pattern_positions(10), [6, 28, 50]);
+    }
+
+    /// Reads the data codewords back out of `code`, undoing each encoding step.
+    ///
+    /// This reuses the encoder's layout, so it only checks that the steps are consistent with
+    /// each other; `test_known_answers` checks the layout itself.
+    fn decode(code: &QrCode) -> Vec<u8> {
+        let version = code.version();
+        let mut builder = Builder::new(version);
+        builder.draw_function_patterns();
+
+        // The format bits next to the top left finder pattern, which hold the mask.
+        let format = (0..=5)
+            .map(|i| (8, i))
+            .chain([(8, 7), (8, 8), (7, 8)])
+            .chain((9..15).map(|i| (14 - i, 8)))
+            .enumerate()
+            .fold(0u32, |acc, (i, (x, y))| {
+                acc | (u32::from(code.is_dark(x, y)) << i)
+            });
+        let format = (format ^ 0x5412) >> 10;
+        assert_eq!(format >> 3, FORMAT_BITS_M);
+        let mask = format & 0b111;
+
+        builder.modules = code.modules.clone();
+        builder.apply_mask(mask);
+
+        // Read the bits in the same order they were placed.
+        let (ec_len, (blocks1, len1), (blocks2, len2)) = BLOCKS_M[version - 1];
+        let total = data_capacity_bits(version) / 8 + ec_len * (blocks1 + blocks2);
+        let mut marker = Builder::new(version);
+        marker.draw_function_patterns();
+        let positions: Vec<usize> = (0..total * 8)
+            .map(|i| {
+                let mut probe = vec![0u8; total];
+                probe[i / 8] = 0x80 >> (i % 8);
+                let mut placed = marker.clone();
+                placed.draw_codewords(&probe);
+                placed
+                    .modules
+                    .iter()
+                    .zip(&marker.modules)
+                    .position(|(a, b)| a != b)
+                    .expect("every bit is placed")
+            })
+            .collect();
+        let mut interleaved = vec![0u8; total];
+        for (i, &position) in positions.iter().enumerate() {
+            if builder.modules[position] {
+                interleaved[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+
+        // Undo the interleaving, checking each block's error correction codewords.
+        let mut blocks = vec![vec![]; blocks1 + blocks2];
+        let mut next = interleaved.iter();
+        for i in 0..len1.max(len2) {
+            for (b, block) in blocks.iter_mut().enumerate() {
+                let len = if b < blocks1 { len1 } else { len2 };
+                if i < len {
+                    block.push(*next.next().expect("enough codewords"));
+                }
+            }
+        }
+        let ecc_start = data_capacity_bits(version) / 8;
+        let divisor = reed_solomon_divisor(ec_len);
+        for (b, block) in blocks.iter().enumerate() {
+            let ecc: Vec<u8> = (0..ec_len)
+                .map(|i| interleaved[ecc_start + i * blocks.len() + b])
+                .collect();
+            assert_eq!(ecc, reed_solomon_remainder(block, &divisor));
+        }
+        blocks.concat()
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        for len in [0, 1, 14, 74, 100, 213] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let code = QrCode::encode(&data)?;
+            assert_eq!(code.size(), code.version() * 4 + 17);
+            assert_eq!(code.rows().count(), code.size());
+
+            let codewords = decode(&code);
+            assert_eq!(codewords, data_codewords(code.version(), &data));
+            // Byte mode.
+            assert_eq!(codewords[0] >> 4, 0b0100);
+        }
+        assert_eq!(QrCode::encode(&[0; 74])?.version(), 5);
+        assert!(QrCode::encode(&[0; 214]).is_err());
+        Ok(())
+    }
+
+    fn matrix(code: &QrCode) -> Vec<String> {
+        code.rows()
+            .map(|row| {
+                row.iter()
+                    .map(|&dark| if dark { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_known_answers() -> Result<()> {
+        // Drawn by an independent encoder (Kazuhiko Arase's QR Code generator, at level M) with
+        // the mask chosen here. It scores masks differently from the standard, so left to itself
+        // it would pick another one.
+        const VERSION_1: [&str; 21] = [
+            "#######.#.###.#######",
+            "#.....#...###.#.....#",
+            "#.###.#..#..#.#.###.#",
+            "#.###.#.#.##..#.###.#",
+            "#.###.#.##..#.#.###.#",
+            "#.....#.#...#.#.....#",
+            "#######.#.#.#.#######",
+            "........#.#..........",
+            "#...#.###.########..#",
+            "....#....##..#..#.##.",
+            "####..#.#.#..#..#.##.",
+            "##..##.#.###.#.##..##",
+            "..##.##..#.##...#..##",
+            "........#.#..##.#.#..",
+            "#######.#....##.#..#.",
+            "#.....#...#.#..##...#",
+            "#.###.#.#..#.#.#.....",
+            "#.###.#...#..#####.##",
+            "#.###.#..#......###..",
+            "#.....#.....##.###...",
+            "#######.##..##......#",
+        ];
+        assert_eq!(matrix(&QrCode::encode(b"scan this code")?), VERSION_1);
+
+        // Covers the version information and two groups of blocks.
+        const VERSION_8: [&str; 49] = [
+            "#######..##.##..##.#.#.###.#...##..#.#..#.#######",
+            "#.....#.#.....#..##.#########.##.#....###.#.....#",
+            "#.###.#..##...#...##..##.#.##.#....#...##.#.###.#",
+            "#.###.#...#..##..##...#.#...#.###......#..#.###.#",
+            "#.###.#.#####...##..#######.....###.......#.###.#",
+            "#.....#.....####..#...#...###..#..#..##...#.....#",
+            "#######.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#######",
+            ".........###...#..##..#...##..#..#..###.#........",
+            "#.#.#.#..#.##..##.###.######.##..#.####.....#..#.",
+            "..####..##.####.#..#..#.#.##.####..#.............",
+            "....#.###...##.####.#####.#.#..###.#.####.#.#.#.#",
+            "##.#......#.#####..#..###...#.....####..##..##.##",
+            "##.#.###.##...#.#...##.#.#...#...#####.###..###..",
+            ".##..#..#.##...###..#.###.######.####....#.#...#.",
+            "#...####.###...##......##.###..#..#.###.#...###.#",
+            "##...#..###########.##.###...##.#.##....#.####.#.",
+            ".#..###.#..#.##..####.##.###..#.......#.###.#...#",
+            ".#..#...#....##.#.##.#.#..##.#######.....#.##..##",
+            "..#...#...#...#...###.##.#####...##.#....#.###..#",
+            ".#..#...##.##.####.##.#..#.#.#####..#..#...###.##",
+            "##.##.#...#.######..#..#...#......###..#.#..#.##.",
+            "...#....#..#.....#.#.####.#.###.....#..#.#.#.#...",
+            ".#..#####..###....##.######.#....#.#..#.#######.#",
+            "##..#...##.#...#.#..###...##.....#####.##...##...",
+            ".##.#.#.##.###.#####.##.#.##..#....##.#.#.#.####.",
+            "....#...###...##....#.#...#..##.###.....#...####.",
+            ".###########..###..########....#..############..#",
+            "#####..##.#.##.#.##..###.##.#.##...######.#.##..#",
+            "##..#.###.#.#..#....#..#.##.......###...#...#...#",
+            "#.#.##....#..###...#....##..###..##.#...##....##.",
+            "##....##.###...#####.#..##.#.#.##.#.....###...#.#",
+            "##.#.#.#.###..##.###.###.#....###..#.#####.###.##",
+            "..#######.#....####.#....#.#.#...##..#.....#.#.##",
+            "##.#.#..#..#..###..#.##.###...#.###....#####..#..",
+            "###.#.#.#.##..#.......##.#.#..#.###..#..#.###...#",
+            "..####....##..##...#.#..#..#.#.....##.###.####..#",
+            "..#####..#..#.###.##.#.##..#.##..#.####..##..####",
+            "#.#....#..#...##.##.#.#.##.#.####..#.....#.#.###.",
+            ".#...####.#..##...#.##.###.#....##...###..##..#.#",
+            ".###....#.#..#...#####......####.#.##.....#.##.##",
+            "###...#..###..#....#..#####..#...#####..#########",
+            "........#.#####.##..###...######.####...#...##.#.",
+            "#######..#.....####..##.#.#.##....##.##.#.#.#...#",
+            "#.....#..#.##...#.#.#.#...###..#.##..#.##...##.#.",
+            "#.###.#.####...##..########..##..#.####.#####..#.",
+            "#.###.#..##.....####..#..###.#######......#..#...",
+            "#.###.#.#.###.#..#..#..#.##..#.#.####..##...##...",
+            "#.....#...##..#...#.#.#.#.##...##.#.##..#....#.#.",
+            "#######.#...#.##..#.#.#..#.#......###..#..####.##",
+        ];
+        let data: Vec<u8> = (0..150).map(|i| (i * 7) as u8).collect();
+        let code = QrCode::encode(&data)?;
+        assert_eq!(code.version(), 8);
+        assert_eq!(matrix(&code), VERSION_8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_patterns() -> Result<()> {
+        let code = QrCode::encode(b"function patterns")?;
+        let size = code.size();
+        for (x, y) in [(0, 0), (size - 7, 0), (0, size - 7)] {
+            for i in 0..7 {
+                // The dark outer ring of each finder pattern, and its light separator.
+                assert!(code.is_dark(x + i, y) && code.is_dark(x, y + i));
+                assert!(code.is_dark(x + i, y + 6) && code.is_dark(x + 6, y + i));
+            }
+            assert!(code.is_dark(x + 3, y + 3));
+            assert!(!code.is_dark(x + 1, y + 1));
+        }
+        for i in 8..size - 8 {
+            assert_eq!(code.is_dark(i, 6), i % 2 == 0);
+            assert_eq!(code.is_dark(6, i), i % 2 == 0);
+        }
+        assert!(code.is_dark(8, size - 8));
+        assert!(!code.is_dark(size, 0));
+        Ok(())
+    }
+}
@@ -3,27 +3,86 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::{consts, crypto};
+use crate::crypto;
 
 use crate::{
     CiphertextMessageType, Context, KeyPair, ProtocolAddress, Result, SenderKeyDistributionMessage,
-    SenderKeyMessage, SenderKeyRecord, SenderKeyStore, SignalProtocolError,
+    SenderKeyMessage, SenderKeyRecord, SenderKeyStore, SessionPolicy, SignalProtocolError,
 };
 
 use crate::protocol::SENDERKEY_MESSAGE_CURRENT_VERSION;
-use crate::sender_keys::{SenderKeyState, SenderMessageKey};
+use crate::sender_keys::{SenderKeyRotationReason, SenderKeyState, SenderMessageKey};
+use crate::storage::flavor::{Flavor, Local, ProtocolStoreTransactionFor, SenderKeyStoreFor};
+use crate::storage::in_transaction;
 
 use rand::{CryptoRng, Rng};
 use std::convert::TryFrom;
+use std::time::SystemTime;
 use uuid::Uuid;
 
+/// Encrypt `plaintext` for the members of `distribution_id` with our current sender key.
+///
+/// Fails with [SignalProtocolError::SenderKeyRotationRequired] if the sender key has to be
+/// replaced first, either because `policy` limits how long or how much it may be used, or because
+/// a member who had received it was [removed](remove_sender_key_recipients). After
+/// [rotate_sender_key], the new distribution message must be sent to the remaining members before
+/// they can decrypt messages.
+///
+/// `plaintext` is padded according to `policy` before it is encrypted.
 pub async fn group_encrypt<R: Rng + CryptoRng>(
     sender_key_store: &mut dyn SenderKeyStore,
     sender: &ProtocolAddress,
     distribution_id: Uuid,
     plaintext: &[u8],
+    policy: &SessionPolicy,
     csprng: &mut R,
     ctx: Context,
+) -> Result<SenderKeyMessage> {
+    group_encrypt_impl::<Local, _>(
+        sender_key_store,
+        sender,
+        distribution_id,
+        plaintext,
+        policy,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn group_encrypt_impl<F: Flavor, R: Rng + CryptoRng>(
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintext: &[u8],
+    policy: &SessionPolicy,
+    csprng: &mut R,
+    ctx: F::Context,
+) -> Result<SenderKeyMessage> {
+    in_transaction!(
+        ctx,
+        [sender_key_store],
+        group_encrypt_inner::<F, _>(
+            sender_key_store,
+            sender,
+            distribution_id,
+            plaintext,
+            policy,
+            csprng,
+            ctx
+        )
+        .await?
+    )
+}
+
+async fn group_encrypt_inner<F: Flavor, R: Rng + CryptoRng>(
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintext: &[u8],
+    policy: &SessionPolicy,
+    csprng: &mut R,
+    ctx: F::Context,
 ) -> Result<SenderKeyMessage> {
     let mut record = sender_key_store
         .load_sender_key(sender, distribution_id, ctx)
@@ -34,21 +93,37 @@ pub async fn group_encrypt<R: Rng + CryptoRng>(
         .sender_key_state_mut()
         .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
 
+    if let Some(reason) = sender_key_state.rotation_reason(policy, SystemTime::now()) {
+        log::info!(
+            "SenderKey distribution {} with chain ID {} must be rotated: {}",
+            distribution_id,
+            sender_key_state.chain_id(),
+            reason
+        );
+        return Err(SignalProtocolError::SenderKeyRotationRequired {
+            distribution_id,
+            reason,
+        });
+    }
+
     let sender_chain_key = sender_key_state
         .sender_chain_key()
         .ok_or(SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
 
     let message_keys = sender_chain_key.sender_message_key();
 
-    let ciphertext =
-        crypto::aes_256_cbc_encrypt(plaintext, message_keys.cipher_key(), message_keys.iv())
-            .map_err(|_| {
-                log::error!(
-                    "outgoing sender key state corrupt for distribution ID {}",
-                    distribution_id,
-                );
-                SignalProtocolError::InvalidSenderKeySession { distribution_id }
-            })?;
+    let ciphertext = crypto::aes_256_cbc_encrypt(
+        &policy.pad(plaintext),
+        message_keys.cipher_key(),
+        message_keys.iv(),
+    )
+    .map_err(|_| {
+        log::error!(
+            "outgoing sender key state corrupt for distribution ID {}",
+            distribution_id,
+        );
+        SignalProtocolError::InvalidSenderKeySession { distribution_id }
+    })?;
 
     let signing_key = sender_key_state
         .signing_key_private()
@@ -77,6 +152,8 @@ fn get_sender_key(
     state: &mut SenderKeyState,
     iteration: u32,
     distribution_id: Uuid,
+    policy: &SessionPolicy,
+    now: SystemTime,
 ) -> Result<SenderMessageKey> {
     let sender_chain_key = state
         .sender_chain_key()
@@ -100,11 +177,11 @@ fn get_sender_key(
     }
 
     let jump = (iteration - current_iteration) as usize;
-    if jump > consts::MAX_FORWARD_JUMPS {
+    if jump > policy.max_forward_jumps {
         log::error!(
             "SenderKey distribution {} Exceeded future message limit: {}, current iteration: {})",
             distribution_id,
-            consts::MAX_FORWARD_JUMPS,
+            policy.max_forward_jumps,
             current_iteration
         );
         return Err(SignalProtocolError::InvalidMessage(
@@ -116,7 +193,7 @@ fn get_sender_key(
     let mut sender_chain_key = sender_chain_key;
 
     while sender_chain_key.iteration() < iteration {
-        state.add_sender_message_key(&sender_chain_key.sender_message_key());
+        state.add_sender_message_key(&sender_chain_key.sender_message_key(), policy, now);
         sender_chain_key = sender_chain_key.next();
     }
 
@@ -128,7 +205,32 @@ pub async fn group_decrypt(
     skm_bytes: &[u8],
     sender_key_store: &mut dyn SenderKeyStore,
     sender: &ProtocolAddress,
+    policy: &SessionPolicy,
     ctx: Context,
+) -> Result<Vec<u8>> {
+    group_decrypt_impl::<Local>(skm_bytes, sender_key_store, sender, policy, ctx).await
+}
+
+pub(crate) async fn group_decrypt_impl<F: Flavor>(
+    skm_bytes: &[u8],
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    sender: &ProtocolAddress,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<Vec<u8>> {
+    in_transaction!(
+        ctx,
+        [sender_key_store],
+        group_decrypt_inner::<F>(skm_bytes, sender_key_store, sender, policy, ctx).await?
+    )
+}
+
+async fn group_decrypt_inner<F: Flavor>(
+    skm_bytes: &[u8],
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    sender: &ProtocolAddress,
+    policy: &SessionPolicy,
+    ctx: F::Context,
 ) -> Result<Vec<u8>> {
     let skm = SenderKeyMessage::try_from(skm_bytes)?;
 
@@ -167,7 +269,15 @@ pub async fn group_decrypt(
         return Err(SignalProtocolError::SignatureValidationFailed);
     }
 
-    let sender_key = get_sender_key(sender_key_state, skm.iteration(), distribution_id)?;
+    let now = SystemTime::now();
+    sender_key_state.expire_sender_message_keys(policy, now);
+    let sender_key = get_sender_key(
+        sender_key_state,
+        skm.iteration(),
+        distribution_id,
+        policy,
+        now,
+    )?;
 
     let plaintext = match crypto::aes_256_cbc_decrypt(
         skm.ciphertext(),
@@ -192,6 +302,8 @@ pub async fn group_decrypt(
             ));
         }
     };
+    // Unpad before anything is stored, so a bad message doesn't use up its key.
+    let plaintext = policy.unpad(plaintext)?;
 
     sender_key_store
         .store_sender_key(sender, distribution_id, &record, ctx)
@@ -205,6 +317,29 @@ pub async fn process_sender_key_distribution_message(
     skdm: &SenderKeyDistributionMessage,
     sender_key_store: &mut dyn SenderKeyStore,
     ctx: Context,
+) -> Result<()> {
+    process_sender_key_distribution_message_impl::<Local>(sender, skdm, sender_key_store, ctx).await
+}
+
+pub(crate) async fn process_sender_key_distribution_message_impl<F: Flavor>(
+    sender: &ProtocolAddress,
+    skdm: &SenderKeyDistributionMessage,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
+) -> Result<()> {
+    in_transaction!(
+        ctx,
+        [sender_key_store],
+        process_sender_key_distribution_message_inner::<F>(sender, skdm, sender_key_store, ctx)
+            .await?
+    )
+}
+
+async fn process_sender_key_distribution_message_inner<F: Flavor>(
+    sender: &ProtocolAddress,
+    skdm: &SenderKeyDistributionMessage,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
 ) -> Result<()> {
     let distribution_id = skdm.distribution_id()?;
     log::info!(
@@ -233,12 +368,56 @@ pub async fn process_sender_key_distribution_message(
     Ok(())
 }
 
+/// Return the distribution message for our current sender key for `distribution_id`, creating the
+/// key if there is none yet.
+///
+/// This never replaces an existing key, whether or not it needs rotating; that is done with
+/// [rotate_sender_key] once [group_encrypt] reports why with
+/// [SignalProtocolError::SenderKeyRotationRequired].
 pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
     sender: &ProtocolAddress,
     distribution_id: Uuid,
     sender_key_store: &mut dyn SenderKeyStore,
     csprng: &mut R,
     ctx: Context,
+) -> Result<SenderKeyDistributionMessage> {
+    create_sender_key_distribution_message_impl::<Local, _>(
+        sender,
+        distribution_id,
+        sender_key_store,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn create_sender_key_distribution_message_impl<F: Flavor, R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    csprng: &mut R,
+    ctx: F::Context,
+) -> Result<SenderKeyDistributionMessage> {
+    in_transaction!(
+        ctx,
+        [sender_key_store],
+        create_sender_key_distribution_message_inner::<F, _>(
+            sender,
+            distribution_id,
+            sender_key_store,
+            csprng,
+            ctx
+        )
+        .await?
+    )
+}
+
+async fn create_sender_key_distribution_message_inner<F: Flavor, R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    csprng: &mut R,
+    ctx: F::Context,
 ) -> Result<SenderKeyDistributionMessage> {
     let sender_key_record = sender_key_store
         .load_sender_key(sender, distribution_id, ctx)
@@ -247,26 +426,8 @@ pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
     let sender_key_record = match sender_key_record {
         Some(record) => record,
         None => {
-            // libsignal-protocol-java uses 31-bit integers for sender key chain IDs
-            let chain_id = (csprng.gen::<u32>()) >> 1;
-            log::info!(
-                "Creating SenderKey for distribution {} with chain ID {}",
-                distribution_id,
-                chain_id
-            );
-
-            let iteration = 0;
-            let sender_key: [u8; 32] = csprng.gen();
-            let signing_key = KeyPair::generate(csprng);
             let mut record = SenderKeyRecord::new_empty();
-            record.add_sender_key_state(
-                SENDERKEY_MESSAGE_CURRENT_VERSION,
-                chain_id,
-                iteration,
-                &sender_key,
-                signing_key.public_key,
-                Some(signing_key.private_key),
-            );
+            add_own_sender_key_state(&mut record, distribution_id, csprng);
             sender_key_store
                 .store_sender_key(sender, distribution_id, &record, ctx)
                 .await?;
@@ -274,6 +435,229 @@ pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
         }
     };
 
+    distribution_message(&sender_key_record, distribution_id)
+}
+
+/// Replace our sender key for `distribution_id` with a new one, returning the distribution message
+/// for it.
+///
+/// The new distribution message has to be sent to every member before they can decrypt messages
+/// sent with the new key. Members that are sent it should be recorded with
+/// [mark_sender_key_distributed].
+pub async fn rotate_sender_key<R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut dyn SenderKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SenderKeyDistributionMessage> {
+    rotate_sender_key_impl::<Local, _>(sender, distribution_id, sender_key_store, csprng, ctx).await
+}
+
+pub(crate) async fn rotate_sender_key_impl<F: Flavor, R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    csprng: &mut R,
+    ctx: F::Context,
+) -> Result<SenderKeyDistributionMessage> {
+    in_transaction!(ctx, [sender_key_store], {
+        let mut record = sender_key_store
+            .load_sender_key(sender, distribution_id, ctx)
+            .await?
+            .unwrap_or_else(SenderKeyRecord::new_empty);
+        add_own_sender_key_state(&mut record, distribution_id, csprng);
+        sender_key_store
+            .store_sender_key(sender, distribution_id, &record, ctx)
+            .await?;
+        distribution_message(&record, distribution_id)?
+    })
+}
+
+/// Record that `recipients` have been sent the distribution message for our current sender key
+/// for `distribution_id`.
+pub async fn mark_sender_key_distributed(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    recipients: &[ProtocolAddress],
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    mark_sender_key_distributed_impl::<Local>(
+        sender,
+        distribution_id,
+        recipients,
+        sender_key_store,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn mark_sender_key_distributed_impl<F: Flavor>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    recipients: &[ProtocolAddress],
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
+) -> Result<()> {
+    in_transaction!(ctx, [sender_key_store], {
+        let mut record =
+            load_own_sender_key::<F>(sender, distribution_id, sender_key_store, ctx).await?;
+        let state = record
+            .sender_key_state_mut()
+            .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
+        for recipient in recipients {
+            state.add_distributed_to(recipient);
+        }
+        sender_key_store
+            .store_sender_key(sender, distribution_id, &record, ctx)
+            .await?;
+    })
+}
+
+/// Return the addresses in `members` that have not been sent the distribution message for our
+/// current sender key for `distribution_id`, in the same order.
+///
+/// Members are only considered to have the key once they are recorded with
+/// [mark_sender_key_distributed].
+pub async fn members_missing_sender_key(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    members: &[ProtocolAddress],
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<Vec<ProtocolAddress>> {
+    members_missing_sender_key_impl::<Local>(
+        sender,
+        distribution_id,
+        members,
+        sender_key_store,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn members_missing_sender_key_impl<F: Flavor>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    members: &[ProtocolAddress],
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
+) -> Result<Vec<ProtocolAddress>> {
+    let record = match sender_key_store
+        .load_sender_key(sender, distribution_id, ctx)
+        .await?
+    {
+        Some(record) => record,
+        None => return Ok(members.to_vec()),
+    };
+    let state = record
+        .sender_key_state()
+        .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
+    Ok(members
+        .iter()
+        .filter(|member| !state.is_distributed_to(member))
+        .cloned()
+        .collect())
+}
+
+/// Record that `removed` have left the group using `distribution_id`.
+///
+/// If any of them had been sent our current sender key, it has to be rotated before it is used
+/// again, so [group_encrypt] will fail with [SignalProtocolError::SenderKeyRotationRequired] until
+/// [rotate_sender_key] is called. Returns whether that is the case.
+///
+/// Only recipients recorded with [mark_sender_key_distributed] are known to have the key. If none
+/// have been recorded, removing anyone requires rotation.
+pub async fn remove_sender_key_recipients(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    removed: &[ProtocolAddress],
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<bool> {
+    remove_sender_key_recipients_impl::<Local>(
+        sender,
+        distribution_id,
+        removed,
+        sender_key_store,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn remove_sender_key_recipients_impl<F: Flavor>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    removed: &[ProtocolAddress],
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
+) -> Result<bool> {
+    in_transaction!(ctx, [sender_key_store], {
+        let mut record =
+            load_own_sender_key::<F>(sender, distribution_id, sender_key_store, ctx).await?;
+        let state = record
+            .sender_key_state_mut()
+            .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
+        for recipient in removed {
+            state.remove_recipient(recipient);
+        }
+        let rotation_required = state.rotation_reason(&SessionPolicy::default(), SystemTime::now())
+            == Some(SenderKeyRotationReason::MemberRemoved);
+        sender_key_store
+            .store_sender_key(sender, distribution_id, &record, ctx)
+            .await?;
+        rotation_required
+    })
+}
+
+async fn load_own_sender_key<F: Flavor>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    ctx: F::Context,
+) -> Result<SenderKeyRecord> {
+    sender_key_store
+        .load_sender_key(sender, distribution_id, ctx)
+        .await?
+        .ok_or(SignalProtocolError::NoSenderKeyState { distribution_id })
+}
+
+/// Add a newly generated sender key state for ourselves to the front of `record`.
+fn add_own_sender_key_state<R: Rng + CryptoRng>(
+    record: &mut SenderKeyRecord,
+    distribution_id: Uuid,
+    csprng: &mut R,
+) {
+    // libsignal-protocol-java uses 31-bit integers for sender key chain IDs
+    let chain_id = (csprng.gen::<u32>()) >> 1;
+    log::info!(
+        "Creating SenderKey for distribution {} with chain ID {}",
+        distribution_id,
+        chain_id
+    );
+
+    let iteration = 0;
+    let sender_key: [u8; 32] = csprng.gen();
+    let signing_key = KeyPair::generate(csprng);
+    record.add_sender_key_state(
+        SENDERKEY_MESSAGE_CURRENT_VERSION,
+        chain_id,
+        iteration,
+        &sender_key,
+        signing_key.public_key,
+        Some(signing_key.private_key),
+    );
+    record
+        .sender_key_state_mut()
+        .expect("just added")
+        .set_created_at(SystemTime::now());
+}
+
+fn distribution_message(
+    sender_key_record: &SenderKeyRecord,
+    distribution_id: Uuid,
+) -> Result<SenderKeyDistributionMessage> {
     let state = sender_key_record
         .sender_key_state()
         .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
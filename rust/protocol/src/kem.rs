@@ -0,0 +1,355 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Keys and operations for key encapsulation mechanisms (KEMs).
+//!
+//! A KEM lets the holder of a [`PublicKey`] produce a shared secret together with a ciphertext
+//! that only the holder of the matching [`SecretKey`] can turn back into the same secret. This is
+//! used by PQXDH to mix a post-quantum secret into the initial session keys.
+//!
+//! Keys and ciphertexts are serialized with a leading [`KeyType`] byte, the same way as
+//! [`crate::PublicKey`].
+
+use crate::{Result, SignalProtocolError};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use pqcrypto_kyber::kyber1024;
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SecretKey as _, SharedSecret as _};
+use subtle::ConstantTimeEq;
+
+/// A KEM ciphertext, including its leading [`KeyType`] byte.
+pub type SerializedCiphertext = Box<[u8]>;
+
+/// The secret shared by both sides of a successful encapsulation.
+pub type SharedSecret = Box<[u8]>;
+
+/// The KEM algorithms supported by this library.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyType {
+    /// CRYSTALS-Kyber, parameter set 1024.
+    Kyber1024,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl KeyType {
+    fn value(&self) -> u8 {
+        match self {
+            KeyType::Kyber1024 => 0x08,
+        }
+    }
+
+    fn public_key_length(&self) -> usize {
+        match self {
+            KeyType::Kyber1024 => kyber1024::public_key_bytes(),
+        }
+    }
+
+    fn secret_key_length(&self) -> usize {
+        match self {
+            KeyType::Kyber1024 => kyber1024::secret_key_bytes(),
+        }
+    }
+
+    fn ciphertext_length(&self) -> usize {
+        match self {
+            KeyType::Kyber1024 => kyber1024::ciphertext_bytes(),
+        }
+    }
+}
+
+impl TryFrom<u8> for KeyType {
+    type Error = SignalProtocolError;
+
+    fn try_from(x: u8) -> Result<Self> {
+        match x {
+            0x08 => Ok(KeyType::Kyber1024),
+            t => Err(SignalProtocolError::BadKEMKeyType(t)),
+        }
+    }
+}
+
+/// Splits off and checks the leading type byte of a serialized key or ciphertext.
+fn split_key_type(value: &[u8]) -> Result<(KeyType, &[u8])> {
+    let (&type_byte, rest) = value
+        .split_first()
+        .ok_or(SignalProtocolError::NoKeyTypeIdentifier)?;
+    Ok((KeyType::try_from(type_byte)?, rest))
+}
+
+fn serialize_with_key_type(key_type: KeyType, data: &[u8]) -> Box<[u8]> {
+    let mut result = Vec::with_capacity(1 + data.len());
+    result.push(key_type.value());
+    result.extend_from_slice(data);
+    result.into_boxed_slice()
+}
+
+/// The public half of a KEM key pair.
+#[derive(Clone, Eq)]
+pub struct PublicKey {
+    key_type: KeyType,
+    key: Box<[u8]>,
+}
+
+impl PublicKey {
+    pub fn deserialize(value: &[u8]) -> Result<Self> {
+        let (key_type, key) = split_key_type(value)?;
+        if key.len() != key_type.public_key_length() {
+            return Err(SignalProtocolError::BadKEMKeyLength(key_type, value.len()));
+        }
+        Ok(Self {
+            key_type,
+            key: key.into(),
+        })
+    }
+
+    pub fn serialize(&self) -> Box<[u8]> {
+        serialize_with_key_type(self.key_type, &self.key)
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    /// Generates a fresh shared secret, along with the ciphertext that lets the owner of the
+    /// matching [`SecretKey`] recover it.
+    pub fn encapsulate(&self) -> (SharedSecret, SerializedCiphertext) {
+        match self.key_type {
+            KeyType::Kyber1024 => {
+                let public_key = kyber1024::PublicKey::from_bytes(&self.key)
+                    .expect("length checked on construction");
+                let (shared_secret, ciphertext) = kyber1024::encapsulate(&public_key);
+                (
+                    shared_secret.as_bytes().into(),
+                    serialize_with_key_type(self.key_type, ciphertext.as_bytes()),
+                )
+            }
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        Self::deserialize(value)
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &PublicKey) -> bool {
+        self.key_type == other.key_type && bool::from(self.key.ct_eq(&other.key))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "kem::PublicKey {{ key_type={}, key={} }}",
+            self.key_type,
+            hex::encode(&self.key[..16]),
+        )
+    }
+}
+
+/// The secret half of a KEM key pair.
+#[derive(Clone)]
+pub struct SecretKey {
+    key_type: KeyType,
+    key: Box<[u8]>,
+}
+
+impl SecretKey {
+    pub fn deserialize(value: &[u8]) -> Result<Self> {
+        let (key_type, key) = split_key_type(value)?;
+        if key.len() != key_type.secret_key_length() {
+            return Err(SignalProtocolError::BadKEMKeyLength(key_type, value.len()));
+        }
+        Ok(Self {
+            key_type,
+            key: key.into(),
+        })
+    }
+
+    pub fn serialize(&self) -> Box<[u8]> {
+        serialize_with_key_type(self.key_type, &self.key)
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    /// Recovers the shared secret produced by [`PublicKey::encapsulate`].
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Result<SharedSecret> {
+        let (ciphertext_type, ciphertext) = split_key_type(ciphertext)?;
+        if ciphertext_type != self.key_type {
+            return Err(SignalProtocolError::WrongKEMKeyType(
+                ciphertext_type.value(),
+                self.key_type.value(),
+            ));
+        }
+        if ciphertext.len() != self.key_type.ciphertext_length() {
+            return Err(SignalProtocolError::BadKEMCiphertextLength(
+                self.key_type,
+                ciphertext.len() + 1,
+            ));
+        }
+        match self.key_type {
+            KeyType::Kyber1024 => {
+                let secret_key = kyber1024::SecretKey::from_bytes(&self.key)
+                    .expect("length checked on construction");
+                let ciphertext =
+                    kyber1024::Ciphertext::from_bytes(ciphertext).expect("length checked above");
+                Ok(kyber1024::decapsulate(&ciphertext, &secret_key)
+                    .as_bytes()
+                    .into())
+            }
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for SecretKey {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        Self::deserialize(value)
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kem::SecretKey {{ key_type={} }}", self.key_type)
+    }
+}
+
+/// A matching KEM [`PublicKey`] and [`SecretKey`].
+#[derive(Clone)]
+pub struct KeyPair {
+    pub public_key: PublicKey,
+    pub secret_key: SecretKey,
+}
+
+impl KeyPair {
+    /// Generates a new key pair of the given type.
+    ///
+    /// The underlying implementation draws its randomness from the operating system.
+    pub fn generate(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::Kyber1024 => {
+                let (public_key, secret_key) = kyber1024::keypair();
+                Self {
+                    public_key: PublicKey {
+                        key_type,
+                        key: public_key.as_bytes().into(),
+                    },
+                    secret_key: SecretKey {
+                        key_type,
+                        key: secret_key.as_bytes().into(),
+                    },
+                }
+            }
+        }
+    }
+
+    pub fn new(public_key: PublicKey, secret_key: SecretKey) -> Self {
+        Self {
+            public_key,
+            secret_key,
+        }
+    }
+
+    pub fn from_public_and_private(public_key: &[u8], secret_key: &[u8]) -> Result<Self> {
+        let public_key = PublicKey::deserialize(public_key)?;
+        let secret_key = SecretKey::deserialize(secret_key)?;
+        if public_key.key_type != secret_key.key_type {
+            return Err(SignalProtocolError::WrongKEMKeyType(
+                secret_key.key_type.value(),
+                public_key.key_type.value(),
+            ));
+        }
+        Ok(Self::new(public_key, secret_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let key_pair = KeyPair::generate(KeyType::Kyber1024);
+        let (shared_secret, ciphertext) = key_pair.public_key.encapsulate();
+        assert_eq!(ciphertext[0], 0x08);
+        assert_eq!(shared_secret, key_pair.secret_key.decapsulate(&ciphertext)?);
+
+        let other_key_pair = KeyPair::generate(KeyType::Kyber1024);
+        assert_ne!(
+            shared_secret,
+            other_key_pair.secret_key.decapsulate(&ciphertext)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialization() -> Result<()> {
+        let key_pair = KeyPair::generate(KeyType::Kyber1024);
+        let public_key = key_pair.public_key.serialize();
+        let secret_key = key_pair.secret_key.serialize();
+        assert_eq!(public_key.len(), 1 + kyber1024::public_key_bytes());
+        assert_eq!(secret_key.len(), 1 + kyber1024::secret_key_bytes());
+
+        let restored = KeyPair::from_public_and_private(&public_key, &secret_key)?;
+        assert_eq!(restored.public_key, key_pair.public_key);
+        assert_eq!(restored.secret_key.serialize(), secret_key);
+
+        assert!(matches!(
+            PublicKey::deserialize(&[]),
+            Err(SignalProtocolError::NoKeyTypeIdentifier)
+        ));
+        assert!(matches!(
+            PublicKey::deserialize(&public_key[..public_key.len() - 1]),
+            Err(SignalProtocolError::BadKEMKeyLength(KeyType::Kyber1024, _))
+        ));
+
+        let mut bad_key_type = public_key.to_vec();
+        bad_key_type[0] = 0x05;
+        assert!(matches!(
+            PublicKey::deserialize(&bad_key_type),
+            Err(SignalProtocolError::BadKEMKeyType(0x05))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_ciphertext() -> Result<()> {
+        let key_pair = KeyPair::generate(KeyType::Kyber1024);
+        let (_, ciphertext) = key_pair.public_key.encapsulate();
+
+        assert!(matches!(
+            key_pair
+                .secret_key
+                .decapsulate(&ciphertext[..ciphertext.len() - 1]),
+            Err(SignalProtocolError::BadKEMCiphertextLength(
+                KeyType::Kyber1024,
+                _
+            ))
+        ));
+
+        let mut bad_key_type = ciphertext.to_vec();
+        bad_key_type[0] = 0x05;
+        assert!(matches!(
+            key_pair.secret_key.decapsulate(&bad_key_type),
+            Err(SignalProtocolError::BadKEMKeyType(0x05))
+        ));
+        Ok(())
+    }
+}
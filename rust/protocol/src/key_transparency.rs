@@ -0,0 +1,291 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Client-side verification of a key transparency log.
+//!
+//! A key transparency log is an append-only [Merkle tree] of the identity key published for each
+//! [ServiceId], which anyone can audit. Each leaf is indexed by a [VRF] output for its service ID
+//! rather than the service ID itself, so that the log does not reveal who is in it; the log proves
+//! the index is correct with a VXEdDSA signature, checked with [PublicKey::verify_vrf_signature].
+//!
+//! A client checks each [SearchResponse] with [verify_search], which verifies that:
+//! - the log signed the [TreeHead] it is reporting, recently enough,
+//! - that tree extends the one the client saw last, as shown by a consistency proof, and was
+//!   signed no earlier, so the log has not rewritten its history,
+//! - the leaf is indexed by the VRF output for the service ID that was searched for, and
+//! - the leaf is included in the tree.
+//!
+//! [KeyTransparencyClient] keeps track of the tree heads and keys verified this way.
+//!
+//! This does not prove that the leaf found is the most recent one for the service ID, or that a
+//! service ID has no leaf at all. Those require auditing the whole log. A log could therefore keep
+//! serving a key that has since been replaced, so the keys found are not used to decide whether an
+//! identity is trusted; [KeyTransparencyClient::check_identity] leaves that to the caller.
+//!
+//! [Merkle tree]: https://www.rfc-editor.org/rfc/rfc9162#section-2.1
+//! [VRF]: https://signal.org/docs/specifications/xeddsa/#vxeddsa
+
+mod inmem;
+mod merkle;
+
+pub use inmem::InMemKeyTransparencyLog;
+pub use merkle::Hash;
+
+use crate::{
+    policy, IdentityKey, ProtocolAddress, PublicKey, Result, ServiceId, SignalProtocolError,
+};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+const TREE_HEAD_LABEL: &[u8] = b"Signal_KeyTransparency_TreeHead_v1";
+
+/// A signed commitment by the log to the contents of its tree at some point.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeHead {
+    /// The number of leaves in the tree.
+    pub tree_size: u64,
+    /// When the log signed the tree head, in milliseconds since the epoch.
+    pub timestamp: u64,
+    /// The root hash of the tree.
+    pub root: Hash,
+    /// The log's signature over the other fields.
+    pub signature: Box<[u8]>,
+}
+
+impl TreeHead {
+    fn signed_message(tree_size: u64, timestamp: u64, root: &Hash) -> Vec<u8> {
+        [
+            TREE_HEAD_LABEL,
+            &tree_size.to_be_bytes(),
+            &timestamp.to_be_bytes(),
+            root,
+        ]
+        .concat()
+    }
+
+    /// Whether `signature_key` signed this tree head.
+    pub fn verify_signature(&self, signature_key: &PublicKey) -> Result<bool> {
+        signature_key.verify_signature(
+            &Self::signed_message(self.tree_size, self.timestamp, &self.root),
+            &self.signature,
+        )
+    }
+}
+
+/// The public keys that identify a key transparency log, and how much of it to trust.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyTransparencyConfig {
+    /// The key the log signs its [TreeHead]s with.
+    pub signature_key: PublicKey,
+    /// The key the log computes the index of each service ID with.
+    pub vrf_key: PublicKey,
+    /// How long ago a [TreeHead] may have been signed and still be accepted, or `None` for no
+    /// limit.
+    ///
+    /// Without a limit, a log could keep showing a client an old tree that lacks newer entries.
+    pub max_tree_head_age: Option<Duration>,
+}
+
+impl KeyTransparencyConfig {
+    /// The usual value of [max_tree_head_age](Self::max_tree_head_age): one day.
+    pub const DEFAULT_MAX_TREE_HEAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+}
+
+/// The log's answer to a search for the identity key of a service ID.
+#[derive(Clone, Debug)]
+pub struct SearchResponse {
+    /// The current tree head.
+    pub tree_head: TreeHead,
+    /// Proves that `tree_head` extends the tree the client saw last.
+    ///
+    /// Empty if the client has not seen a tree yet, or if the tree has not grown since.
+    pub consistency_proof: Vec<Hash>,
+    /// The VXEdDSA signature over the service ID, whose output is the index of its leaf.
+    pub vrf_proof: Box<[u8]>,
+    /// The position of the leaf in the tree.
+    pub position: u64,
+    /// The identity key in the leaf.
+    pub identity_key: IdentityKey,
+    /// Proves that the leaf is at `position` in the tree.
+    pub inclusion_proof: Vec<Hash>,
+}
+
+/// The hash of the leaf recording `identity_key` for the service ID with VRF output `index`.
+fn entry_leaf_hash(index: &[u8; 32], identity_key: &IdentityKey) -> Hash {
+    merkle::leaf_hash(&[&index[..], &identity_key.serialize()].concat())
+}
+
+fn verification_failed(reason: &'static str) -> SignalProtocolError {
+    SignalProtocolError::KeyTransparencyVerificationFailed(reason)
+}
+
+/// Check `response` to a search for `service_id`, given the last tree head the client verified.
+///
+/// The response's tree head must be consistent with `last_tree_head`, and no older than the
+/// configured maximum age at `now`. Only a client that has not verified any tree head yet may pass
+/// `None`, in which case the tree head is trusted on first use.
+///
+/// On success, returns the new tree head, which the client should keep for its next search.
+pub fn verify_search(
+    config: &KeyTransparencyConfig,
+    service_id: ServiceId,
+    last_tree_head: Option<&TreeHead>,
+    response: &SearchResponse,
+    now: SystemTime,
+) -> Result<TreeHead> {
+    let tree_head = &response.tree_head;
+    if !tree_head.verify_signature(&config.signature_key)? {
+        return Err(verification_failed("invalid tree head signature"));
+    }
+    if let Some(max_age) = config.max_tree_head_age {
+        if policy::is_older_than(tree_head.timestamp, max_age, now) {
+            return Err(verification_failed("tree head is too old"));
+        }
+    }
+
+    let (old_size, old_root) = match last_tree_head {
+        Some(last) => {
+            if tree_head.timestamp < last.timestamp {
+                return Err(verification_failed(
+                    "tree head is older than one seen before",
+                ));
+            }
+            (last.tree_size, last.root)
+        }
+        None => (0, merkle::root(&[])),
+    };
+    if tree_head.tree_size < old_size {
+        return Err(verification_failed("tree is smaller than one seen before"));
+    }
+    if !merkle::verify_consistency(
+        old_size,
+        tree_head.tree_size,
+        &old_root,
+        &tree_head.root,
+        &response.consistency_proof,
+    ) {
+        return Err(verification_failed("invalid consistency proof"));
+    }
+
+    let index = config
+        .vrf_key
+        .verify_vrf_signature(&service_id.service_id_binary(), &response.vrf_proof)?
+        .ok_or_else(|| verification_failed("invalid VRF proof"))?;
+
+    if !merkle::verify_inclusion(
+        &entry_leaf_hash(&index, &response.identity_key),
+        response.position,
+        tree_head.tree_size,
+        &response.inclusion_proof,
+        &tree_head.root,
+    ) {
+        return Err(verification_failed("invalid inclusion proof"));
+    }
+
+    Ok(tree_head.clone())
+}
+
+/// A connection to a key transparency log.
+#[async_trait]
+pub trait KeyTransparencyLog: Send + Sync {
+    /// Search the log for the identity key of `service_id`.
+    ///
+    /// `last_tree_size` is the size of the last tree the client verified, which the response's
+    /// consistency proof must start from. Returns `None` if the log has no entry for
+    /// `service_id`.
+    async fn search(
+        &self,
+        service_id: ServiceId,
+        last_tree_size: Option<u64>,
+    ) -> Result<Option<SearchResponse>>;
+}
+
+/// Searches a key transparency log on behalf of a client, remembering what it has verified.
+///
+/// The last verified tree head is kept in memory only; clients should persist
+/// [tree_head](Self::tree_head) and restore it with [with_tree_head](Self::with_tree_head), so that
+/// the log cannot rewrite history across restarts.
+#[derive(Clone, Debug)]
+pub struct KeyTransparencyClient {
+    config: KeyTransparencyConfig,
+    tree_head: Option<TreeHead>,
+    verified_keys: HashMap<ServiceId, IdentityKey>,
+}
+
+impl KeyTransparencyClient {
+    /// Create a client for the log identified by `config`.
+    pub fn new(config: KeyTransparencyConfig) -> Self {
+        Self {
+            config,
+            tree_head: None,
+            verified_keys: HashMap::new(),
+        }
+    }
+
+    /// Start from a tree head verified earlier.
+    pub fn with_tree_head(mut self, tree_head: TreeHead) -> Self {
+        self.tree_head = Some(tree_head);
+        self
+    }
+
+    /// The last tree head verified.
+    pub fn tree_head(&self) -> Option<&TreeHead> {
+        self.tree_head.as_ref()
+    }
+
+    /// The identity key found in the log for `service_id`, if it has been searched for.
+    pub fn verified_key(&self, service_id: ServiceId) -> Option<&IdentityKey> {
+        self.verified_keys.get(&service_id)
+    }
+
+    /// Whether `identity` matches the key found in the log for the service ID of `address`, or
+    /// `None` if there is no such key.
+    ///
+    /// The log does not prove that the key it found is still current, so a mismatch may only mean
+    /// that the log is behind. It is up to the caller how to treat one; this is not taken into
+    /// account by [IdentityKeyStore::is_trusted_identity](crate::IdentityKeyStore::is_trusted_identity).
+    pub fn check_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Option<bool> {
+        let verified = self.verified_keys.get(&address.service_id()?)?;
+        if verified != identity {
+            log::warn!(
+                "identity for {} does not match the key transparency log",
+                address
+            );
+        }
+        Some(verified == identity)
+    }
+
+    /// Search `log` for the identity key of `service_id`, and verify the response with
+    /// [verify_search] as of now.
+    ///
+    /// Returns `None`, and leaves the client unchanged, if the log has no entry for `service_id`.
+    pub async fn search(
+        &mut self,
+        service_id: ServiceId,
+        log: &dyn KeyTransparencyLog,
+    ) -> Result<Option<IdentityKey>> {
+        let last_tree_size = self.tree_head.as_ref().map(|head| head.tree_size);
+        let response = match log.search(service_id, last_tree_size).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let tree_head = verify_search(
+            &self.config,
+            service_id,
+            self.tree_head.as_ref(),
+            &response,
+            SystemTime::now(),
+        )?;
+        self.tree_head = Some(tree_head);
+        self.verified_keys.insert(service_id, response.identity_key);
+        Ok(Some(response.identity_key))
+    }
+}
@@ -0,0 +1,150 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use super::{entry_leaf_hash, merkle, Hash, KeyTransparencyConfig, SearchResponse, TreeHead};
+use crate::{IdentityKey, KeyPair, Result, ServiceId, SignalProtocolError};
+
+use async_trait::async_trait;
+use rand::{CryptoRng, Rng};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// The latest leaf for a service ID.
+struct LatestEntry {
+    position: u64,
+    identity_key: IdentityKey,
+    vrf_proof: Box<[u8]>,
+}
+
+/// Reference implementation of [super::KeyTransparencyLog], which keeps the whole log in memory.
+///
+/// This is the server side of the log, for testing clients.
+pub struct InMemKeyTransparencyLog {
+    signature_key: KeyPair,
+    vrf_key: KeyPair,
+    leaves: Vec<Hash>,
+    latest: HashMap<ServiceId, LatestEntry>,
+    tree_head: TreeHead,
+}
+
+impl InMemKeyTransparencyLog {
+    /// Create an empty log with newly generated keys.
+    pub fn new<R: Rng + CryptoRng>(csprng: &mut R) -> Result<Self> {
+        Self::with_keys(KeyPair::generate(csprng), KeyPair::generate(csprng), csprng)
+    }
+
+    /// Create an empty log that signs tree heads with `signature_key` and computes indexes with
+    /// `vrf_key`.
+    pub fn with_keys<R: Rng + CryptoRng>(
+        signature_key: KeyPair,
+        vrf_key: KeyPair,
+        csprng: &mut R,
+    ) -> Result<Self> {
+        let tree_head = Self::sign_tree_head(&signature_key, &[], 0, csprng)?;
+        Ok(Self {
+            signature_key,
+            vrf_key,
+            leaves: Vec::new(),
+            latest: HashMap::new(),
+            tree_head,
+        })
+    }
+
+    /// The public keys clients need to verify this log, with the default maximum tree head age.
+    pub fn config(&self) -> KeyTransparencyConfig {
+        KeyTransparencyConfig {
+            signature_key: self.signature_key.public_key,
+            vrf_key: self.vrf_key.public_key,
+            max_tree_head_age: Some(KeyTransparencyConfig::DEFAULT_MAX_TREE_HEAD_AGE),
+        }
+    }
+
+    /// The current tree head.
+    pub fn tree_head(&self) -> &TreeHead {
+        &self.tree_head
+    }
+
+    fn sign_tree_head<R: Rng + CryptoRng>(
+        signature_key: &KeyPair,
+        leaves: &[Hash],
+        timestamp: u64,
+        csprng: &mut R,
+    ) -> Result<TreeHead> {
+        let tree_size = leaves.len() as u64;
+        let root = merkle::root(leaves);
+        let signature = signature_key.calculate_signature(
+            &TreeHead::signed_message(tree_size, timestamp, &root),
+            csprng,
+        )?;
+        Ok(TreeHead {
+            tree_size,
+            timestamp,
+            root,
+            signature,
+        })
+    }
+
+    /// Append a leaf publishing `identity_key` for `service_id`, and sign the new tree head with
+    /// `timestamp`.
+    pub fn update<R: Rng + CryptoRng>(
+        &mut self,
+        service_id: ServiceId,
+        identity_key: IdentityKey,
+        timestamp: u64,
+        csprng: &mut R,
+    ) -> Result<()> {
+        let (vrf_proof, index) = self
+            .vrf_key
+            .private_key
+            .calculate_vrf_signature(&service_id.service_id_binary(), csprng)?;
+        let leaves = [&self.leaves[..], &[entry_leaf_hash(&index, &identity_key)]].concat();
+        let tree_head = Self::sign_tree_head(&self.signature_key, &leaves, timestamp, csprng)?;
+
+        self.leaves = leaves;
+        self.latest.insert(
+            service_id,
+            LatestEntry {
+                position: tree_head.tree_size - 1,
+                identity_key,
+                vrf_proof,
+            },
+        );
+        self.tree_head = tree_head;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl super::KeyTransparencyLog for InMemKeyTransparencyLog {
+    async fn search(
+        &self,
+        service_id: ServiceId,
+        last_tree_size: Option<u64>,
+    ) -> Result<Option<SearchResponse>> {
+        let entry = match self.latest.get(&service_id) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let last_tree_size = usize::try_from(last_tree_size.unwrap_or(0))
+            .ok()
+            .filter(|&size| size <= self.leaves.len())
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!(
+                    "last tree size is larger than the log ({})",
+                    self.leaves.len()
+                ))
+            })?;
+        let position = usize::try_from(entry.position).expect("leaves are in memory");
+
+        Ok(Some(SearchResponse {
+            tree_head: self.tree_head.clone(),
+            consistency_proof: merkle::consistency_proof(&self.leaves, last_tree_size),
+            vrf_proof: entry.vrf_proof.clone(),
+            position: entry.position,
+            identity_key: entry.identity_key,
+            inclusion_proof: merkle::inclusion_proof(&self.leaves, position),
+        }))
+    }
+}
@@ -0,0 +1,256 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Merkle tree hashing and proofs, as specified for Certificate Transparency in [RFC 9162].
+//!
+//! [RFC 9162]: https://www.rfc-editor.org/rfc/rfc9162#section-2.1
+
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 hash of a leaf or interior node.
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// The hash of a leaf holding `data`.
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut sha256 = Sha256::new();
+    sha256.update([LEAF_PREFIX]);
+    sha256.update(data);
+    sha256.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut sha256 = Sha256::new();
+    sha256.update([NODE_PREFIX]);
+    sha256.update(left);
+    sha256.update(right);
+    sha256.finalize().into()
+}
+
+/// The largest power of two smaller than `n`, which must be at least 2.
+fn split_point(n: usize) -> usize {
+    debug_assert!(n >= 2);
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// The root hash of the tree with the given leaf hashes.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves {
+        [] => Sha256::digest(&[]).into(),
+        [leaf] => *leaf,
+        _ => {
+            let k = split_point(leaves.len());
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// The proof that leaf `index` is included in the tree with the given leaf hashes.
+pub fn inclusion_proof(leaves: &[Hash], index: usize) -> Vec<Hash> {
+    if leaves.len() <= 1 {
+        return vec![];
+    }
+    let k = split_point(leaves.len());
+    let (mut proof, sibling) = if index < k {
+        (inclusion_proof(&leaves[..k], index), root(&leaves[k..]))
+    } else {
+        (inclusion_proof(&leaves[k..], index - k), root(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+/// The proof that the tree made of the first `old_size` of the given leaf hashes is a prefix of
+/// the tree made of all of them.
+pub fn consistency_proof(leaves: &[Hash], old_size: usize) -> Vec<Hash> {
+    if old_size == 0 || old_size >= leaves.len() {
+        return vec![];
+    }
+    subproof(leaves, old_size, true)
+}
+
+fn subproof(leaves: &[Hash], m: usize, complete: bool) -> Vec<Hash> {
+    if m == leaves.len() {
+        return if complete { vec![] } else { vec![root(leaves)] };
+    }
+    let k = split_point(leaves.len());
+    let (mut proof, sibling) = if m <= k {
+        (subproof(&leaves[..k], m, complete), root(&leaves[k..]))
+    } else {
+        (subproof(&leaves[k..], m - k, false), root(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+/// Checks that `leaf` is at `index` in the tree of `tree_size` leaves with the given `root`.
+pub fn verify_inclusion(
+    leaf: &Hash,
+    index: u64,
+    tree_size: u64,
+    proof: &[Hash],
+    root: &Hash,
+) -> bool {
+    if index >= tree_size {
+        return false;
+    }
+    let (mut f_n, mut s_n) = (index, tree_size - 1);
+    let mut r = *leaf;
+    for p in proof {
+        if s_n == 0 {
+            return false;
+        }
+        if f_n & 1 == 1 || f_n == s_n {
+            r = node_hash(p, &r);
+            while f_n & 1 == 0 && f_n != 0 {
+                f_n >>= 1;
+                s_n >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    s_n == 0 && r == *root
+}
+
+/// Checks that the tree of `old_size` leaves with root `old_root` is a prefix of the tree of
+/// `new_size` leaves with root `new_root`.
+pub fn verify_consistency(
+    old_size: u64,
+    new_size: u64,
+    old_root: &Hash,
+    new_root: &Hash,
+    proof: &[Hash],
+) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+    if old_size == 0 {
+        // The empty tree is a prefix of every tree.
+        return proof.is_empty();
+    }
+
+    let mut proof = proof.iter();
+    let first = if old_size.is_power_of_two() {
+        old_root
+    } else {
+        match proof.next() {
+            Some(first) => first,
+            None => return false,
+        }
+    };
+
+    let (mut f_n, mut s_n) = (old_size - 1, new_size - 1);
+    while f_n & 1 == 1 {
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    let (mut f_r, mut s_r) = (*first, *first);
+    for c in proof {
+        if s_n == 0 {
+            return false;
+        }
+        if f_n & 1 == 1 || f_n == s_n {
+            f_r = node_hash(c, &f_r);
+            s_r = node_hash(c, &s_r);
+            while f_n & 1 == 0 && f_n != 0 {
+                f_n >>= 1;
+                s_n >>= 1;
+            }
+        } else {
+            s_r = node_hash(&s_r, c);
+        }
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    s_n == 0 && f_r == *old_root && s_r == *new_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n).map(|i| leaf_hash(&i.to_be_bytes())).collect()
+    }
+
+    #[test]
+    fn test_empty_root() {
+        assert_eq!(
+            hex::encode(root(&[])),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_inclusion() {
+        for n in 1..=17 {
+            let leaves = leaves(n);
+            let root = root(&leaves);
+            for index in 0..n {
+                let proof = inclusion_proof(&leaves, index);
+                let (i, size) = (index as u64, n as u64);
+                assert!(verify_inclusion(&leaves[index], i, size, &proof, &root));
+
+                let other = (index + 1) % n;
+                if other != index {
+                    assert!(!verify_inclusion(&leaves[other], i, size, &proof, &root));
+                }
+                assert!(!verify_inclusion(&leaves[index], size, size, &proof, &root));
+                if let Some((_, shorter)) = proof.split_last() {
+                    assert!(!verify_inclusion(&leaves[index], i, size, shorter, &root));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistency() {
+        let all = leaves(17);
+        for new_size in 1..=all.len() {
+            let new_root = root(&all[..new_size]);
+            for old_size in 0..=new_size {
+                let old_root = root(&all[..old_size]);
+                let proof = consistency_proof(&all[..new_size], old_size);
+                let (old, new) = (old_size as u64, new_size as u64);
+                assert!(verify_consistency(old, new, &old_root, &new_root, &proof));
+
+                if old_size > 0 && old_size < new_size {
+                    let wrong_root = leaf_hash(b"wrong");
+                    assert!(!verify_consistency(
+                        old,
+                        new,
+                        &wrong_root,
+                        &new_root,
+                        &proof
+                    ));
+                    assert!(!verify_consistency(
+                        old,
+                        new,
+                        &old_root,
+                        &wrong_root,
+                        &proof
+                    ));
+                    let (_, shorter) = proof.split_last().expect("non-empty proof");
+                    assert!(!verify_consistency(old, new, &old_root, &new_root, shorter));
+                }
+            }
+        }
+        assert!(!verify_consistency(
+            2,
+            1,
+            &root(&all[..2]),
+            &root(&all[..1]),
+            &[]
+        ));
+    }
+}
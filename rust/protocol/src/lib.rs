@@ -8,10 +8,12 @@
 //!
 //! In particular, this library implements operations conforming to the following specifications:
 //! - the **[X3DH]** key agreement protocol,
+//! - the **[PQXDH]** post-quantum extension of X3DH,
 //! - the **[Double Ratchet]** *(Axolotl)* messaging protocol,
 //!
 //! [Signal Protocol]: https://signal.org/
 //! [X3DH]: https://signal.org/docs/specifications/x3dh/
+//! [PQXDH]: https://signal.org/docs/specifications/pqxdh/
 //! [Double Ratchet]: https://signal.org/docs/specifications/doubleratchet/
 
 #![warn(clippy::unwrap_used)]
@@ -30,10 +32,19 @@ pub mod error;
 mod fingerprint;
 mod group_cipher;
 mod identity_key;
+pub mod kem;
+pub mod key_transparency;
+pub mod padding;
+mod pni_change;
+mod policy;
+mod prekey_manager;
 mod proto;
 mod protocol;
+mod provisioning;
 mod ratchet;
+mod retry;
 mod sealed_sender;
+pub mod send;
 mod sender_keys;
 mod session;
 mod session_cipher;
@@ -43,40 +54,69 @@ mod utils;
 
 use error::Result;
 
-pub use address::{DeviceId, ProtocolAddress};
+pub use address::{Aci, DeviceId, Pni, ProtocolAddress, ServiceId, ServiceIdKind};
 pub use curve::{KeyPair, PrivateKey, PublicKey};
 pub use error::SignalProtocolError;
-pub use fingerprint::{DisplayableFingerprint, Fingerprint, ScannableFingerprint};
+pub use fingerprint::{
+    DisplayableFingerprint, Fingerprint, FingerprintMismatch, QrCode, ScannableFingerprint,
+};
 pub use group_cipher::{
     create_sender_key_distribution_message, group_decrypt, group_encrypt,
-    process_sender_key_distribution_message,
+    mark_sender_key_distributed, members_missing_sender_key,
+    process_sender_key_distribution_message, remove_sender_key_recipients, rotate_sender_key,
 };
 pub use identity_key::{IdentityKey, IdentityKeyPair};
+pub use pni_change::{
+    PniChangeBundle, PniChangeBundleBuilder, PniChangeDevice, PniChangeDeviceBundle,
+};
+pub use policy::SessionPolicy;
+pub use prekey_manager::{
+    KyberPreKeyUpload, PreKeyManager, PreKeyManagerConfig, PreKeyManagerState, PreKeyUpload,
+    SignedPreKeyUpload, MAX_PRE_KEY_ID,
+};
 pub use protocol::{
     extract_decryption_error_message_from_serialized_content, CiphertextMessage,
-    CiphertextMessageType, DecryptionErrorMessage, PlaintextContent, PreKeySignalMessage,
-    SenderKeyDistributionMessage, SenderKeyMessage, SignalMessage,
+    CiphertextMessageType, DecryptionErrorMessage, EndSessionMessage, KyberPayload,
+    PlaintextContent, PreKeySignalMessage, SenderKeyDistributionMessage, SenderKeyMessage,
+    SignalMessage,
 };
+pub use provisioning::{ProvisionMessage, ProvisioningCipher};
 pub use ratchet::{
     initialize_alice_session_record, initialize_bob_session_record, AliceSignalProtocolParameters,
     BobSignalProtocolParameters,
 };
+pub use retry::{process_decryption_error_message, ResendLogEntry, RetryPlan};
 pub use sealed_sender::{
     sealed_sender_decrypt, sealed_sender_decrypt_to_usmc, sealed_sender_encrypt,
     sealed_sender_encrypt_from_usmc, sealed_sender_multi_recipient_encrypt,
     sealed_sender_multi_recipient_fan_out, ContentHint, SealedSenderDecryptionResult,
-    SenderCertificate, ServerCertificate, UnidentifiedSenderMessageContent,
+    SealedSenderV2SentMessage, SealedSenderV2SentRecipient, SenderCertificate, ServerCertificate,
+    UnidentifiedSenderMessageContent,
+};
+pub use sender_keys::{SenderKeyRecord, SenderKeyRotationReason};
+pub use session::{
+    end_session, process_end_session_message, process_prekey, process_prekey_bundle,
+    process_prekey_bundle_with_policy, PreKeysUsed,
 };
-pub use sender_keys::SenderKeyRecord;
-pub use session::{process_prekey, process_prekey_bundle};
 pub use session_cipher::{
-    message_decrypt, message_decrypt_prekey, message_decrypt_signal, message_encrypt,
+    encrypt_for_all_devices, message_decrypt, message_decrypt_prekey, message_decrypt_signal,
+    message_encrypt, AllDevicesMessages, DeviceMessage,
 };
 pub use state::{
-    PreKeyBundle, PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord,
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyBundle, PreKeyId, PreKeyRecord, ReceiverChainSnapshot,
+    SenderChainSnapshot, SessionRecord, SessionSnapshot, SessionStateSnapshot, SignedPreKeyId,
+    SignedPreKeyRecord,
+};
+pub use storage::{
+    Context, Direction, EncryptedRecordStore, IdentityChange, IdentityKeyStore,
+    InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemPreKeyStore, InMemRecordStore,
+    InMemResendLogStore, InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore,
+    InMemSignedPreKeyStore, KyberPreKeyStore, PreKeyStore, ProtocolStore, ProtocolStoreTransaction,
+    RecordCipher, RecordKind, RecordStore, ResendLogStore, SenderKeyStore, SessionStore,
+    SignedPreKeyStore, VerifiedStatus,
 };
+#[cfg(feature = "sqlite")]
 pub use storage::{
-    Context, Direction, IdentityKeyStore, InMemIdentityKeyStore, InMemPreKeyStore,
-    InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
-    PreKeyStore, ProtocolStore, SenderKeyStore, SessionStore, SignedPreKeyStore,
+    SqliteIdentityKeyStore, SqliteKyberPreKeyStore, SqlitePreKeyStore, SqliteResendLogStore,
+    SqliteSenderKeyStore, SqliteSessionStore, SqliteSignalProtocolStore, SqliteSignedPreKeyStore,
 };
@@ -0,0 +1,154 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Padding for message plaintexts, to hide their exact length.
+//!
+//! Padded messages are followed by a single [boundary byte](BOUNDARY_BYTE) and then zero bytes up
+//! to the padded length (ISO/IEC 7816-4 padding). The schemes only differ in the padded length, so
+//! [unpad] works for all of them.
+
+use crate::{Result, SignalProtocolError};
+
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// The byte that separates a message from its padding.
+pub const BOUNDARY_BYTE: u8 = 0x80;
+
+/// How far to pad messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingScheme {
+    /// Pad to a multiple of `block_size` bytes. A block size of 0 is treated as 1.
+    Blocks { block_size: usize },
+    /// Pad with [PADMÉ], which leaks at most `O(log log n)` bits of a length `n` and adds at most
+    /// 12% overhead.
+    ///
+    /// [PADMÉ]: https://petsymposium.org/2019/files/papers/issue4/popets-2019-0056.pdf
+    Padme,
+}
+
+impl PaddingScheme {
+    /// Pad to 160-byte blocks, like the Signal apps.
+    pub const SIGNAL_BLOCKS: Self = Self::Blocks { block_size: 160 };
+
+    /// The length of a message of `message_len` bytes after padding, including the boundary
+    /// byte.
+    pub fn padded_len(&self, message_len: usize) -> usize {
+        let min_len = message_len.saturating_add(1);
+        match *self {
+            Self::Blocks { block_size } => {
+                let block_size = block_size.max(1);
+                let remainder = min_len % block_size;
+                if remainder == 0 {
+                    min_len
+                } else {
+                    min_len.saturating_add(block_size - remainder)
+                }
+            }
+            Self::Padme => {
+                if min_len < 2 {
+                    return min_len;
+                }
+                let exponent = usize::BITS - 1 - min_len.leading_zeros();
+                let exponent_bits = u32::BITS - exponent.leading_zeros();
+                let mask = (1usize << (exponent - exponent_bits)) - 1;
+                min_len.saturating_add(mask) & !mask
+            }
+        }
+    }
+
+    /// Pad `message` with this scheme.
+    pub fn pad(&self, message: &[u8]) -> Vec<u8> {
+        let padded_len = self.padded_len(message.len());
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(message);
+        padded.push(BOUNDARY_BYTE);
+        padded.resize(padded_len, 0);
+        padded
+    }
+}
+
+/// Strip the padding from a message padded with any [PaddingScheme].
+///
+/// Takes the same time for any input of the same length, so it does not reveal where the padding
+/// starts or why it was rejected.
+pub fn unpad(padded: &[u8]) -> Result<&[u8]> {
+    let mut message_len = 0u64;
+    let mut found_boundary = Choice::from(0);
+    let mut invalid = Choice::from(0);
+    for (i, byte) in padded.iter().enumerate().rev() {
+        let is_boundary = !found_boundary & byte.ct_eq(&BOUNDARY_BYTE);
+        message_len.conditional_assign(&(i as u64), is_boundary);
+        invalid |= !found_boundary & !is_boundary & !byte.ct_eq(&0);
+        found_boundary |= is_boundary;
+    }
+    if bool::from(found_boundary & !invalid) {
+        Ok(&padded[..message_len as usize])
+    } else {
+        Err(SignalProtocolError::InvalidPadding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_padding() {
+        let scheme = PaddingScheme::SIGNAL_BLOCKS;
+        assert_eq!(scheme.padded_len(0), 160);
+        assert_eq!(scheme.padded_len(159), 160);
+        assert_eq!(scheme.padded_len(160), 320);
+        assert_eq!(PaddingScheme::Blocks { block_size: 0 }.padded_len(5), 6);
+
+        let padded = scheme.pad(b"hello");
+        assert_eq!(padded.len(), 160);
+        assert_eq!(&padded[..6], b"hello\x80");
+        assert!(padded[6..].iter().all(|&b| b == 0));
+        assert_eq!(unpad(&padded).expect("valid"), b"hello");
+    }
+
+    #[test]
+    fn test_padme_padding() {
+        let scheme = PaddingScheme::Padme;
+        for (message_len, expected) in [
+            (0, 1),
+            (1, 2),
+            (8, 10),
+            (9, 10),
+            (99, 104),
+            (1000, 1024),
+            (1023, 1024),
+            (1024, 1088),
+        ] {
+            assert_eq!(scheme.padded_len(message_len), expected, "{}", message_len);
+        }
+        for message_len in 0..5000 {
+            let padded_len = scheme.padded_len(message_len);
+            assert!(padded_len > message_len);
+            assert!(padded_len - message_len - 1 <= message_len / 8 + 1);
+        }
+
+        let message = [0x80; 99];
+        assert_eq!(unpad(&scheme.pad(&message)).expect("valid"), message);
+    }
+
+    #[test]
+    fn test_malformed_padding() {
+        for malformed in [
+            &[][..],
+            &[0, 0, 0][..],
+            &b"no boundary"[..],
+            &b"nonzero\x80\x00\x01"[..],
+        ] {
+            assert!(matches!(
+                unpad(malformed),
+                Err(SignalProtocolError::InvalidPadding)
+            ));
+        }
+        // Only the last boundary byte counts.
+        assert_eq!(unpad(b"\x80\x80\x00").expect("valid"), b"\x80");
+        assert_eq!(unpad(b"\x80").expect("valid"), b"");
+    }
+}
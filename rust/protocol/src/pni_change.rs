@@ -0,0 +1,366 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Replacing this account's PNI identity, for example when its phone number changes.
+//!
+//! The primary device generates the new PNI identity, along with a signed pre-key, a last-resort
+//! Kyber pre-key and a registration id for each of the account's devices, using
+//! [PniChangeBundleBuilder]. The account's ACI identity signs the new PNI identity key, so that
+//! linked devices can tell the change came from the account. The primary device uploads the
+//! public parts to the server, and sends each linked device its [PniChangeDeviceBundle], which the
+//! linked device checks with [PniChangeDeviceBundle::verify] before using it.
+
+use crate::consts::VALID_REGISTRATION_ID_MASK;
+use crate::policy::timestamp_millis;
+use crate::prekey_manager::MAX_PRE_KEY_ID;
+use crate::{
+    kem, proto, DeviceId, IdentityKey, IdentityKeyPair, KeyPair, KyberPreKeyId, KyberPreKeyRecord,
+    KyberPreKeyUpload, Result, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+    SignedPreKeyUpload,
+};
+
+use prost::Message;
+use rand::{CryptoRng, Rng};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+/// The keys generated for one device of the account.
+#[derive(Clone, Debug)]
+pub struct PniChangeDevice {
+    device_id: DeviceId,
+    registration_id: u32,
+    signed_pre_key: SignedPreKeyRecord,
+    last_resort_kyber_pre_key: KyberPreKeyRecord,
+}
+
+impl PniChangeDevice {
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// The device's new PNI registration id.
+    pub fn registration_id(&self) -> u32 {
+        self.registration_id
+    }
+
+    /// The device's new PNI signed pre-key, including its private key.
+    pub fn signed_pre_key(&self) -> &SignedPreKeyRecord {
+        &self.signed_pre_key
+    }
+
+    /// The public part of [signed_pre_key](Self::signed_pre_key), to be uploaded to the server.
+    pub fn signed_pre_key_upload(&self) -> Result<SignedPreKeyUpload> {
+        Ok(SignedPreKeyUpload {
+            id: self.signed_pre_key.id()?,
+            public_key: self.signed_pre_key.public_key()?,
+            signature: self.signed_pre_key.signature()?,
+        })
+    }
+
+    /// The device's new PNI last-resort Kyber pre-key, including its secret key.
+    pub fn last_resort_kyber_pre_key(&self) -> &KyberPreKeyRecord {
+        &self.last_resort_kyber_pre_key
+    }
+
+    /// The public part of [last_resort_kyber_pre_key](Self::last_resort_kyber_pre_key), to be
+    /// uploaded to the server.
+    pub fn last_resort_kyber_pre_key_upload(&self) -> Result<KyberPreKeyUpload> {
+        Ok(KyberPreKeyUpload {
+            id: self.last_resort_kyber_pre_key.id()?,
+            public_key: self.last_resort_kyber_pre_key.public_key()?,
+            signature: self.last_resort_kyber_pre_key.signature()?,
+        })
+    }
+}
+
+/// Generates a [PniChangeBundle].
+///
+///```
+/// use libsignal_protocol::{IdentityKeyPair, PniChangeBundleBuilder};
+/// use rand::rngs::OsRng;
+/// use std::time::SystemTime;
+///
+/// # fn main() -> Result<(), libsignal_protocol::SignalProtocolError> {
+/// let aci_identity = IdentityKeyPair::generate(&mut OsRng);
+/// let bundle = PniChangeBundleBuilder::new(aci_identity)
+///     .with_device(1.into())
+///     .with_device(2.into())
+///     .build(SystemTime::now(), &mut OsRng)?;
+/// assert_eq!(bundle.devices().len(), 2);
+/// # Ok(())
+/// # }
+///```
+#[derive(Clone)]
+pub struct PniChangeBundleBuilder {
+    aci_identity_key_pair: IdentityKeyPair,
+    identity_key_pair: Option<IdentityKeyPair>,
+    device_ids: Vec<DeviceId>,
+}
+
+impl PniChangeBundleBuilder {
+    /// Start a PNI change for the account whose ACI identity is `aci_identity_key_pair`, which
+    /// signs the new PNI identity.
+    pub fn new(aci_identity_key_pair: IdentityKeyPair) -> Self {
+        Self {
+            aci_identity_key_pair,
+            identity_key_pair: None,
+            device_ids: vec![],
+        }
+    }
+
+    /// Use `identity_key_pair` as the new PNI identity, instead of generating one.
+    pub fn with_identity_key_pair(mut self, identity_key_pair: IdentityKeyPair) -> Self {
+        self.identity_key_pair = Some(identity_key_pair);
+        self
+    }
+
+    /// Generate keys for the device `device_id`, which should include the primary device itself.
+    pub fn with_device(mut self, device_id: DeviceId) -> Self {
+        if !self.device_ids.contains(&device_id) {
+            self.device_ids.push(device_id);
+        }
+        self
+    }
+
+    /// Generate the new identity and the keys for each device, with pre-keys stamped with `now`.
+    pub fn build<R: Rng + CryptoRng>(
+        self,
+        now: SystemTime,
+        csprng: &mut R,
+    ) -> Result<PniChangeBundle> {
+        if self.device_ids.is_empty() {
+            return Err(SignalProtocolError::InvalidArgument(
+                "a PNI change needs at least one device".to_string(),
+            ));
+        }
+
+        let identity_key_pair = match self.identity_key_pair {
+            Some(identity_key_pair) => identity_key_pair,
+            None => IdentityKeyPair::generate(csprng),
+        };
+        let pni_signature = identity_key_pair
+            .sign_alternate_identity(self.aci_identity_key_pair.identity_key(), csprng)?;
+        let aci_signature = self
+            .aci_identity_key_pair
+            .sign_alternate_identity(identity_key_pair.identity_key(), csprng)?;
+
+        let timestamp = timestamp_millis(now);
+        let mut devices = Vec::with_capacity(self.device_ids.len());
+        for device_id in self.device_ids {
+            let key_pair = KeyPair::generate(csprng);
+            let signature = identity_key_pair
+                .private_key()
+                .calculate_signature(&key_pair.public_key.serialize(), csprng)?;
+            let id = SignedPreKeyId::from(csprng.gen_range(1, MAX_PRE_KEY_ID + 1));
+
+            let kyber_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+            let kyber_signature = identity_key_pair
+                .private_key()
+                .calculate_signature(&kyber_key_pair.public_key.serialize(), csprng)?;
+            let kyber_id = KyberPreKeyId::from(csprng.gen_range(1, MAX_PRE_KEY_ID + 1));
+
+            devices.push(PniChangeDevice {
+                device_id,
+                registration_id: csprng.gen_range(1, VALID_REGISTRATION_ID_MASK + 1),
+                signed_pre_key: SignedPreKeyRecord::new(id, timestamp, &key_pair, &signature),
+                last_resort_kyber_pre_key: KyberPreKeyRecord::new(
+                    kyber_id,
+                    timestamp,
+                    &kyber_key_pair,
+                    &kyber_signature,
+                ),
+            });
+        }
+
+        Ok(PniChangeBundle {
+            identity_key_pair,
+            pni_signature,
+            aci_signature,
+            devices,
+        })
+    }
+}
+
+/// Everything generated for a PNI change, on the primary device.
+#[derive(Clone)]
+pub struct PniChangeBundle {
+    identity_key_pair: IdentityKeyPair,
+    pni_signature: Box<[u8]>,
+    aci_signature: Box<[u8]>,
+    devices: Vec<PniChangeDevice>,
+}
+
+impl PniChangeBundle {
+    /// The new PNI identity.
+    pub fn identity_key_pair(&self) -> &IdentityKeyPair {
+        &self.identity_key_pair
+    }
+
+    /// The new PNI identity's signature over the ACI identity key, from
+    /// [IdentityKeyPair::sign_alternate_identity].
+    pub fn pni_signature(&self) -> &[u8] {
+        &self.pni_signature
+    }
+
+    /// The ACI identity's signature over the new PNI identity key, from
+    /// [IdentityKeyPair::sign_alternate_identity].
+    pub fn aci_signature(&self) -> &[u8] {
+        &self.aci_signature
+    }
+
+    /// The keys for each device, in the order they were added to the builder.
+    pub fn devices(&self) -> &[PniChangeDevice] {
+        &self.devices
+    }
+
+    /// The part of the change to send to `device_id`, or `None` if it is not one of the devices.
+    pub fn device_bundle(&self, device_id: DeviceId) -> Option<PniChangeDeviceBundle> {
+        self.devices
+            .iter()
+            .find(|device| device.device_id == device_id)
+            .map(|device| PniChangeDeviceBundle {
+                identity_key_pair: self.identity_key_pair,
+                pni_signature: self.pni_signature.clone(),
+                aci_signature: self.aci_signature.clone(),
+                device: device.clone(),
+            })
+    }
+}
+
+/// The part of a [PniChangeBundle] for a single device.
+#[derive(Clone)]
+pub struct PniChangeDeviceBundle {
+    identity_key_pair: IdentityKeyPair,
+    pni_signature: Box<[u8]>,
+    aci_signature: Box<[u8]>,
+    device: PniChangeDevice,
+}
+
+impl PniChangeDeviceBundle {
+    /// The new PNI identity.
+    pub fn identity_key_pair(&self) -> &IdentityKeyPair {
+        &self.identity_key_pair
+    }
+
+    /// The new PNI identity's signature over the ACI identity key.
+    pub fn pni_signature(&self) -> &[u8] {
+        &self.pni_signature
+    }
+
+    /// The ACI identity's signature over the new PNI identity key.
+    pub fn aci_signature(&self) -> &[u8] {
+        &self.aci_signature
+    }
+
+    /// The keys for this device.
+    pub fn device(&self) -> &PniChangeDevice {
+        &self.device
+    }
+
+    /// Check that this bundle is for `local_device_id`, and was created by the account whose ACI
+    /// identity is `aci_identity_key`.
+    ///
+    /// This checks that the ACI identity signed the new identity key, that the new identity signed
+    /// the ACI identity key and both pre-keys, and that the new identity's private key matches its
+    /// public key.
+    pub fn verify(&self, aci_identity_key: &IdentityKey, local_device_id: DeviceId) -> Result<()> {
+        if self.device.device_id != local_device_id {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "PNI change is for device {}, not {}",
+                self.device.device_id, local_device_id
+            )));
+        }
+
+        let registration_id = self.device.registration_id;
+        if registration_id == 0 || registration_id & VALID_REGISTRATION_ID_MASK != registration_id {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "invalid PNI registration id {}",
+                registration_id
+            )));
+        }
+
+        let identity_key = self.identity_key_pair.identity_key();
+        if self.identity_key_pair.private_key().public_key()? != *identity_key.public_key() {
+            return Err(SignalProtocolError::InvalidArgument(
+                "PNI identity private key does not match its public key".to_string(),
+            ));
+        }
+
+        if !aci_identity_key.verify_alternate_identity(identity_key, &self.aci_signature)?
+            || !identity_key.verify_alternate_identity(aci_identity_key, &self.pni_signature)?
+        {
+            return Err(SignalProtocolError::SignatureValidationFailed);
+        }
+
+        let signed_pre_key = &self.device.signed_pre_key;
+        if !identity_key.public_key().verify_signature(
+            &signed_pre_key.public_key()?.serialize(),
+            &signed_pre_key.signature()?,
+        )? {
+            return Err(SignalProtocolError::SignatureValidationFailed);
+        }
+
+        let kyber_pre_key = &self.device.last_resort_kyber_pre_key;
+        if !identity_key.public_key().verify_signature(
+            &kyber_pre_key.public_key()?.serialize(),
+            &kyber_pre_key.signature()?,
+        )? {
+            return Err(SignalProtocolError::SignatureValidationFailed);
+        }
+
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let message = proto::service::PniChange {
+            identity_key_pair: Some(self.identity_key_pair.serialize().into_vec()),
+            signed_pre_key: Some(self.device.signed_pre_key.serialize()?),
+            registration_id: Some(self.device.registration_id),
+            pni_signature: Some(self.pni_signature.to_vec()),
+            device_id: Some(self.device.device_id.into()),
+            aci_signature: Some(self.aci_signature.to_vec()),
+            last_resort_kyber_pre_key: Some(self.device.last_resort_kyber_pre_key.serialize()?),
+        };
+        Ok(message.encode_to_vec())
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let message = proto::service::PniChange::decode(data)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let identity_key_pair = message
+            .identity_key_pair
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let signed_pre_key = message
+            .signed_pre_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let last_resort_kyber_pre_key = message
+            .last_resort_kyber_pre_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        Ok(Self {
+            identity_key_pair: IdentityKeyPair::try_from(&identity_key_pair[..])?,
+            pni_signature: message
+                .pni_signature
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+                .into_boxed_slice(),
+            aci_signature: message
+                .aci_signature
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+                .into_boxed_slice(),
+            device: PniChangeDevice {
+                device_id: message
+                    .device_id
+                    .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+                    .into(),
+                registration_id: message
+                    .registration_id
+                    .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+                signed_pre_key: SignedPreKeyRecord::deserialize(&signed_pre_key)?,
+                last_resort_kyber_pre_key: KyberPreKeyRecord::deserialize(
+                    &last_resort_kyber_pre_key,
+                )?,
+            },
+        })
+    }
+}
@@ -0,0 +1,213 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::padding::{self, PaddingScheme};
+use crate::{consts, Result};
+
+#[cfg(doc)]
+use crate::{
+    end_session, group_decrypt, group_encrypt, message_decrypt, message_encrypt,
+    process_decryption_error_message, process_end_session_message, sealed_sender_encrypt,
+    PreKeySignalMessage, SenderKeyRecord, SessionRecord, SignedPreKeyRecord,
+};
+
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Limits on how much state is kept for 1:1 sessions and sender key (group) sessions.
+///
+/// The [default](SessionPolicy::default) limits are the ones this library has always used.
+/// Clients with little memory may want smaller limits, while long-lived accounts that receive a
+/// lot of traffic may want larger ones.
+///
+/// A policy is passed to [message_encrypt], [message_decrypt], [sealed_sender_encrypt],
+/// [group_encrypt], [group_decrypt], [end_session], [process_end_session_message], and
+/// [process_decryption_error_message], which apply it as they update records. Stores can
+/// enforce a policy on records at rest with [SessionRecord::apply_policy] and
+/// [SenderKeyRecord::apply_policy]. Operations that do not take a policy use the default limits.
+///
+///```
+/// use libsignal_protocol::SessionPolicy;
+/// use std::time::Duration;
+///
+/// let policy = SessionPolicy {
+///     max_message_keys: 200,
+///     max_skipped_key_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+///     ..SessionPolicy::default()
+/// };
+///```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionPolicy {
+    /// How far ahead of the current chain index an incoming message may be.
+    pub max_forward_jumps: usize,
+    /// How many keys for skipped messages are kept for each receiver chain or sender key state.
+    pub max_message_keys: usize,
+    /// How many receiver chains are kept for each session state.
+    pub max_receiver_chains: usize,
+    /// How many archived session states are kept for each session record.
+    pub archived_states_max_length: usize,
+    /// How many sender key states are kept for each sender key record.
+    pub max_sender_key_states: usize,
+    /// How long keys for skipped messages are kept, or `None` to keep them until they are evicted
+    /// by [`max_message_keys`](Self::max_message_keys).
+    ///
+    /// Keys saved before their creation time was recorded are only evicted by count.
+    pub max_skipped_key_age: Option<Duration>,
+    /// How old a signed pre-key may be for an incoming [PreKeySignalMessage] to start a session
+    /// with it, or `None` to accept signed pre-keys of any age.
+    ///
+    /// Age is measured from [SignedPreKeyRecord::timestamp]. As with the other age limits, a
+    /// timestamp of 0 means the creation time is unknown, and such signed pre-keys never expire by
+    /// age.
+    pub max_signed_pre_key_age: Option<Duration>,
+    /// How many messages [group_encrypt] may send with one of our sender keys before it has to be
+    /// rotated, or `None` for no limit.
+    pub max_sender_key_messages: Option<u32>,
+    /// How long [group_encrypt] may keep using one of our sender keys before it has to be
+    /// rotated, or `None` for no limit.
+    ///
+    /// Sender keys created before their creation time was recorded never expire by age.
+    pub max_sender_key_age: Option<Duration>,
+    /// How plaintexts are padded before they are encrypted, or `None` to encrypt them as given.
+    ///
+    /// Both sides of a conversation have to agree on whether messages are padded: padded
+    /// plaintexts are unpadded after decryption, and a plaintext with [malformed
+    /// padding](crate::SignalProtocolError::InvalidPadding) is rejected. Any scheme can unpad
+    /// messages padded with any other.
+    pub padding: Option<PaddingScheme>,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self {
+            max_forward_jumps: consts::MAX_FORWARD_JUMPS,
+            max_message_keys: consts::MAX_MESSAGE_KEYS,
+            max_receiver_chains: consts::MAX_RECEIVER_CHAINS,
+            archived_states_max_length: consts::ARCHIVED_STATES_MAX_LENGTH,
+            max_sender_key_states: consts::MAX_SENDER_KEY_STATES,
+            max_skipped_key_age: None,
+            max_signed_pre_key_age: None,
+            max_sender_key_messages: None,
+            max_sender_key_age: None,
+            padding: None,
+        }
+    }
+}
+
+impl SessionPolicy {
+    /// Whether a skipped message key created at `created_at` (see [timestamp_millis]) should be
+    /// discarded at time `now`.
+    pub(crate) fn is_skipped_key_expired(&self, created_at: u64, now: SystemTime) -> bool {
+        match self.max_skipped_key_age {
+            None => false,
+            // Saved before creation times were recorded.
+            Some(_) if created_at == 0 => false,
+            Some(max_age) => is_older_than(created_at, max_age, now),
+        }
+    }
+
+    /// Whether one of our sender keys created at `created_at` (see [timestamp_millis]) is too old
+    /// to keep using at time `now`.
+    pub(crate) fn is_sender_key_expired(&self, created_at: u64, now: SystemTime) -> bool {
+        match self.max_sender_key_age {
+            None => false,
+            // Created before creation times were recorded.
+            Some(_) if created_at == 0 => false,
+            Some(max_age) => is_older_than(created_at, max_age, now),
+        }
+    }
+
+    /// Whether a signed pre-key created at `created_at` (see [timestamp_millis]) is too old to
+    /// accept at time `now`.
+    pub(crate) fn is_signed_pre_key_expired(&self, created_at: u64, now: SystemTime) -> bool {
+        match self.max_signed_pre_key_age {
+            None => false,
+            // No creation time was recorded.
+            Some(_) if created_at == 0 => false,
+            Some(max_age) => is_older_than(created_at, max_age, now),
+        }
+    }
+
+    /// Pads `ptext` with [`padding`](Self::padding), if set.
+    pub(crate) fn pad(&self, ptext: &[u8]) -> Vec<u8> {
+        match &self.padding {
+            Some(scheme) => scheme.pad(ptext),
+            None => ptext.to_vec(),
+        }
+    }
+
+    /// Strips the padding from a decrypted `ptext`, if [`padding`](Self::padding) is set.
+    pub(crate) fn unpad(&self, mut ptext: Vec<u8>) -> Result<Vec<u8>> {
+        if self.padding.is_some() {
+            let message_len = padding::unpad(&ptext)?.len();
+            ptext.truncate(message_len);
+        }
+        Ok(ptext)
+    }
+}
+
+pub(crate) fn is_older_than(created_at: u64, max_age: Duration, now: SystemTime) -> bool {
+    let created_at = UNIX_EPOCH + Duration::from_millis(created_at);
+    now.duration_since(created_at).unwrap_or_default() >= max_age
+}
+
+/// Whether a pre-key created at `created_at` (see [timestamp_millis]) should be returned by a
+/// query for keys created before `cutoff`.
+///
+/// Like the age limits above, keys with no recorded creation time are never considered old.
+pub(crate) fn is_created_before(created_at: u64, cutoff: u64) -> bool {
+    created_at != 0 && created_at < cutoff
+}
+
+/// Converts `time` to the representation stored alongside skipped message keys.
+pub(crate) fn timestamp_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skipped_key_expiry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let created_at = timestamp_millis(now - Duration::from_secs(60));
+
+        let policy = SessionPolicy::default();
+        assert!(!policy.is_skipped_key_expired(created_at, now));
+
+        let policy = SessionPolicy {
+            max_skipped_key_age: Some(Duration::from_secs(61)),
+            ..SessionPolicy::default()
+        };
+        assert!(!policy.is_skipped_key_expired(created_at, now));
+        assert!(policy.is_skipped_key_expired(created_at, now + Duration::from_secs(1)));
+        // Keys without a creation time are never expired by age.
+        assert!(!policy.is_skipped_key_expired(0, now));
+    }
+
+    #[test]
+    fn test_signed_pre_key_expiry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let created_at = timestamp_millis(now - Duration::from_secs(60));
+
+        let policy = SessionPolicy::default();
+        assert!(!policy.is_signed_pre_key_expired(created_at, now));
+        assert!(!policy.is_signed_pre_key_expired(0, now));
+
+        let policy = SessionPolicy {
+            max_signed_pre_key_age: Some(Duration::from_secs(61)),
+            ..SessionPolicy::default()
+        };
+        assert!(!policy.is_signed_pre_key_expired(created_at, now));
+        assert!(policy.is_signed_pre_key_expired(created_at, now + Duration::from_secs(1)));
+        // Signed pre-keys without a creation time are never expired by age.
+        assert!(!policy.is_signed_pre_key_expired(0, now));
+    }
+}
@@ -0,0 +1,473 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Generation, rotation, and replenishment of this client's pre-keys.
+
+use crate::policy::timestamp_millis;
+use crate::proto::storage::{pre_key_manager_state_structure, PreKeyManagerStateStructure};
+use crate::storage::flavor::{
+    Flavor, Local, PreKeyStoreFor, ProtocolStoreTransactionFor, SignedPreKeyStoreFor,
+};
+use crate::storage::in_transaction;
+use crate::{
+    kem, Context, IdentityKeyPair, KeyPair, KyberPreKeyId, PreKeyId, PreKeyRecord, PreKeyStore,
+    PublicKey, Result, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord, SignedPreKeyStore,
+};
+
+use prost::Message;
+use rand::{CryptoRng, Rng};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The largest pre-key id the manager allocates; ids wrap around to 1 after it.
+///
+/// Pre-key ids are 24 bits on the wire in some clients, so larger ids are avoided.
+pub const MAX_PRE_KEY_ID: u32 = 0xFF_FFFF;
+
+/// The id allocated after `id`.
+fn next_id(id: u32) -> u32 {
+    id % MAX_PRE_KEY_ID + 1
+}
+
+/// Settings for a [PreKeyManager].
+///
+/// Like [SessionPolicy](crate::SessionPolicy), the defaults can be overridden field by field:
+///
+///```
+/// use libsignal_protocol::PreKeyManagerConfig;
+///
+/// let config = PreKeyManagerConfig {
+///     batch_size: 50,
+///     ..PreKeyManagerConfig::default()
+/// };
+///```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreKeyManagerConfig {
+    /// How many one-time pre-keys are generated at a time.
+    pub batch_size: u32,
+    /// A new batch of one-time pre-keys is generated once fewer than this many remain.
+    pub minimum_pre_keys: u32,
+    /// How long a signed pre-key is used before it is replaced.
+    pub signed_pre_key_rotation_interval: Duration,
+    /// How long a replaced signed pre-key is kept, so that sessions started with it just before
+    /// it was replaced can still be set up.
+    pub signed_pre_key_grace_period: Duration,
+}
+
+impl Default for PreKeyManagerConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            minimum_pre_keys: 10,
+            signed_pre_key_rotation_interval: Duration::from_secs(2 * 24 * 60 * 60),
+            signed_pre_key_grace_period: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// The persistent state of a [PreKeyManager]: which ids to allocate next, and which keys are
+/// still outstanding.
+///
+/// The caller is responsible for saving this (see [serialize](Self::serialize)) whenever the
+/// manager changes it.
+#[derive(Clone, Debug)]
+pub struct PreKeyManagerState {
+    state: PreKeyManagerStateStructure,
+}
+
+impl PreKeyManagerState {
+    /// Create the state for a client with no pre-keys yet.
+    ///
+    /// Ids start at a random point, so that they are unlikely to collide with ids used by a
+    /// previous installation.
+    pub fn new<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
+        Self {
+            state: PreKeyManagerStateStructure {
+                next_pre_key_id: csprng.gen_range(1, MAX_PRE_KEY_ID + 1),
+                next_signed_pre_key_id: csprng.gen_range(1, MAX_PRE_KEY_ID + 1),
+                pre_key_ids: vec![],
+                current_signed_pre_key: None,
+                old_signed_pre_keys: vec![],
+            },
+        }
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            state: PreKeyManagerStateStructure::decode(data)
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.state.encode_to_vec())
+    }
+
+    /// The one-time pre-keys that were generated and have not been seen to be used.
+    pub fn pre_key_ids(&self) -> Vec<PreKeyId> {
+        self.state.pre_key_ids.iter().map(|&id| id.into()).collect()
+    }
+
+    /// The signed pre-key currently in use, if one has been generated.
+    pub fn signed_pre_key_id(&self) -> Option<SignedPreKeyId> {
+        self.state
+            .current_signed_pre_key
+            .as_ref()
+            .map(|key| key.id.into())
+    }
+
+    /// Replaced signed pre-keys that are still within their grace period.
+    pub fn old_signed_pre_key_ids(&self) -> Vec<SignedPreKeyId> {
+        self.state
+            .old_signed_pre_keys
+            .iter()
+            .map(|key| key.id.into())
+            .collect()
+    }
+
+    fn is_signed_pre_key_id_in_use(&self, id: u32) -> bool {
+        self.state
+            .current_signed_pre_key
+            .iter()
+            .chain(&self.state.old_signed_pre_keys)
+            .any(|key| key.id == id)
+    }
+}
+
+/// The public part of a signed pre-key, to be uploaded to the server.
+#[derive(Clone, Debug)]
+pub struct SignedPreKeyUpload {
+    pub id: SignedPreKeyId,
+    pub public_key: PublicKey,
+    /// The signature of `public_key` by the identity key.
+    pub signature: Vec<u8>,
+}
+
+/// The public part of a Kyber pre-key, to be uploaded to the server.
+#[derive(Clone, Debug)]
+pub struct KyberPreKeyUpload {
+    pub id: KyberPreKeyId,
+    pub public_key: kem::PublicKey,
+    /// The signature of `public_key` by the identity key.
+    pub signature: Vec<u8>,
+}
+
+/// Everything [PreKeyManager::refresh] changed that the server or the stores need to know about.
+#[derive(Clone, Debug, Default)]
+pub struct PreKeyUpload {
+    /// New one-time pre-keys to upload.
+    pub pre_keys: Vec<(PreKeyId, PublicKey)>,
+    /// A new signed pre-key to upload, which replaces the previous one.
+    pub signed_pre_key: Option<SignedPreKeyUpload>,
+    /// Signed pre-keys whose grace period is over. They are no longer tracked by the manager and
+    /// should be removed with [SignedPreKeyStore::remove_signed_pre_key].
+    pub expired_signed_pre_key_ids: Vec<SignedPreKeyId>,
+}
+
+impl PreKeyUpload {
+    /// Whether there is nothing new to upload.
+    pub fn is_empty(&self) -> bool {
+        self.pre_keys.is_empty() && self.signed_pre_key.is_none()
+    }
+}
+
+/// Keeps this client's one-time and signed pre-keys topped up.
+///
+/// Call [refresh](Self::refresh) periodically, for example on startup and after processing
+/// incoming messages, then upload the result and save the [state](Self::state).
+#[derive(Clone, Debug)]
+pub struct PreKeyManager {
+    config: PreKeyManagerConfig,
+    state: PreKeyManagerState,
+}
+
+impl PreKeyManager {
+    /// Create a manager that picks up from `state`.
+    pub fn new(config: PreKeyManagerConfig, state: PreKeyManagerState) -> Self {
+        Self { config, state }
+    }
+
+    pub fn config(&self) -> &PreKeyManagerConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> &PreKeyManagerState {
+        &self.state
+    }
+
+    /// Count the one-time pre-keys that have not been used yet.
+    ///
+    /// Keys that are no longer in `pre_key_store`, because they were used to set up a session,
+    /// are forgotten.
+    pub async fn remaining_pre_keys(
+        &mut self,
+        pre_key_store: &dyn PreKeyStore,
+        ctx: Context,
+    ) -> Result<usize> {
+        self.remaining_pre_keys_impl::<Local>(pre_key_store, ctx)
+            .await
+    }
+
+    pub(crate) async fn remaining_pre_keys_impl<F: Flavor>(
+        &mut self,
+        pre_key_store: &F::PreKeyStore<'_>,
+        ctx: F::Context,
+    ) -> Result<usize> {
+        let mut remaining = Vec::with_capacity(self.state.state.pre_key_ids.len());
+        for &id in &self.state.state.pre_key_ids {
+            match pre_key_store.get_pre_key(id.into(), ctx).await {
+                Ok(_) => remaining.push(id),
+                Err(SignalProtocolError::InvalidPreKeyId) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.state.state.pre_key_ids = remaining;
+        Ok(self.state.state.pre_key_ids.len())
+    }
+
+    /// Generate `count` one-time pre-keys with newly allocated ids and save them to
+    /// `pre_key_store`, returning their public parts.
+    ///
+    /// The records are stamped with `now` as their creation time.
+    pub async fn generate_pre_keys<R: Rng + CryptoRng>(
+        &mut self,
+        count: u32,
+        pre_key_store: &mut dyn PreKeyStore,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: Context,
+    ) -> Result<Vec<(PreKeyId, PublicKey)>> {
+        self.generate_pre_keys_impl::<Local, _>(count, pre_key_store, now, csprng, ctx)
+            .await
+    }
+
+    pub(crate) async fn generate_pre_keys_impl<F: Flavor, R: Rng + CryptoRng>(
+        &mut self,
+        count: u32,
+        pre_key_store: &mut F::PreKeyStore<'_>,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: F::Context,
+    ) -> Result<Vec<(PreKeyId, PublicKey)>> {
+        let timestamp = timestamp_millis(now);
+        let mut next_pre_key_id = self.state.state.next_pre_key_id;
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            // Skip ids that are still outstanding after wrapping around.
+            while self.state.state.pre_key_ids.contains(&next_pre_key_id) {
+                next_pre_key_id = next_id(next_pre_key_id);
+            }
+            let id = PreKeyId::from(next_pre_key_id);
+            records
+                .push(PreKeyRecord::new(id, &KeyPair::generate(csprng)).with_timestamp(timestamp));
+            next_pre_key_id = next_id(next_pre_key_id);
+        }
+
+        in_transaction!(ctx, [pre_key_store], {
+            for record in &records {
+                pre_key_store
+                    .save_pre_key(record.id()?, record, ctx)
+                    .await?;
+            }
+        })?;
+
+        let mut result = Vec::with_capacity(records.len());
+        for record in &records {
+            let id = record.id()?;
+            self.state.state.pre_key_ids.push(id.into());
+            result.push((id, record.public_key()?));
+        }
+        self.state.state.next_pre_key_id = next_pre_key_id;
+        Ok(result)
+    }
+
+    /// Generate a new signed pre-key, save it to `signed_pre_key_store`, and make it the current
+    /// one, returning its public part.
+    ///
+    /// The previous signed pre-key is kept for the configured grace period.
+    pub async fn rotate_signed_pre_key<R: Rng + CryptoRng>(
+        &mut self,
+        identity_key_pair: &IdentityKeyPair,
+        signed_pre_key_store: &mut dyn SignedPreKeyStore,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: Context,
+    ) -> Result<SignedPreKeyUpload> {
+        self.rotate_signed_pre_key_impl::<Local, _>(
+            identity_key_pair,
+            signed_pre_key_store,
+            now,
+            csprng,
+            ctx,
+        )
+        .await
+    }
+
+    pub(crate) async fn rotate_signed_pre_key_impl<F: Flavor, R: Rng + CryptoRng>(
+        &mut self,
+        identity_key_pair: &IdentityKeyPair,
+        signed_pre_key_store: &mut F::SignedPreKeyStore<'_>,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: F::Context,
+    ) -> Result<SignedPreKeyUpload> {
+        let mut next_signed_pre_key_id = self.state.state.next_signed_pre_key_id;
+        while self
+            .state
+            .is_signed_pre_key_id_in_use(next_signed_pre_key_id)
+        {
+            next_signed_pre_key_id = next_id(next_signed_pre_key_id);
+        }
+        let id = SignedPreKeyId::from(next_signed_pre_key_id);
+
+        let key_pair = KeyPair::generate(csprng);
+        let signature = identity_key_pair
+            .private_key()
+            .calculate_signature(&key_pair.public_key.serialize(), csprng)?;
+        let timestamp = timestamp_millis(now);
+        let record = SignedPreKeyRecord::new(id, timestamp, &key_pair, &signature);
+        signed_pre_key_store
+            .save_signed_pre_key(id, &record, ctx)
+            .await?;
+
+        let state = &mut self.state.state;
+        if let Some(mut previous) = state.current_signed_pre_key.take() {
+            previous.timestamp = timestamp;
+            state.old_signed_pre_keys.push(previous);
+        }
+        state.current_signed_pre_key = Some(pre_key_manager_state_structure::SignedPreKey {
+            id: id.into(),
+            timestamp,
+        });
+        state.next_signed_pre_key_id = next_id(next_signed_pre_key_id);
+
+        Ok(SignedPreKeyUpload {
+            id,
+            public_key: key_pair.public_key,
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// Bring the pre-keys up to date as of `now`.
+    ///
+    /// - A new batch of one-time pre-keys is generated if too few remain.
+    /// - The signed pre-key is replaced if there is none yet, or if it is due for rotation.
+    /// - Replaced signed pre-keys are dropped once their grace period is over.
+    ///
+    /// If any step fails, the manager is left as it was. Keys already saved to the stores by then
+    /// are not tracked, and will not be uploaded.
+    pub async fn refresh<R: Rng + CryptoRng>(
+        &mut self,
+        identity_key_pair: &IdentityKeyPair,
+        pre_key_store: &mut dyn PreKeyStore,
+        signed_pre_key_store: &mut dyn SignedPreKeyStore,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: Context,
+    ) -> Result<PreKeyUpload> {
+        self.refresh_impl::<Local, _>(
+            identity_key_pair,
+            pre_key_store,
+            signed_pre_key_store,
+            now,
+            csprng,
+            ctx,
+        )
+        .await
+    }
+
+    pub(crate) async fn refresh_impl<F: Flavor, R: Rng + CryptoRng>(
+        &mut self,
+        identity_key_pair: &IdentityKeyPair,
+        pre_key_store: &mut F::PreKeyStore<'_>,
+        signed_pre_key_store: &mut F::SignedPreKeyStore<'_>,
+        now: SystemTime,
+        csprng: &mut R,
+        ctx: F::Context,
+    ) -> Result<PreKeyUpload> {
+        // Work on a copy, so that nothing changes unless every step succeeds.
+        let mut manager = self.clone();
+        let mut upload = PreKeyUpload::default();
+
+        let remaining = manager
+            .remaining_pre_keys_impl::<F>(pre_key_store, ctx)
+            .await?;
+        if remaining < manager.config.minimum_pre_keys as usize {
+            upload.pre_keys = manager
+                .generate_pre_keys_impl::<F, _>(
+                    manager.config.batch_size,
+                    pre_key_store,
+                    now,
+                    csprng,
+                    ctx,
+                )
+                .await?;
+        }
+
+        let rotation_due = match &manager.state.state.current_signed_pre_key {
+            None => true,
+            Some(current) => has_elapsed(
+                current.timestamp,
+                manager.config.signed_pre_key_rotation_interval,
+                now,
+            ),
+        };
+        if rotation_due {
+            upload.signed_pre_key = Some(
+                manager
+                    .rotate_signed_pre_key_impl::<F, _>(
+                        identity_key_pair,
+                        signed_pre_key_store,
+                        now,
+                        csprng,
+                        ctx,
+                    )
+                    .await?,
+            );
+        }
+
+        let grace_period = manager.config.signed_pre_key_grace_period;
+        let (expired, kept) = std::mem::take(&mut manager.state.state.old_signed_pre_keys)
+            .into_iter()
+            .partition(|key| has_elapsed(key.timestamp, grace_period, now));
+        manager.state.state.old_signed_pre_keys = kept;
+        upload.expired_signed_pre_key_ids = expired
+            .into_iter()
+            .map(|key: pre_key_manager_state_structure::SignedPreKey| key.id.into())
+            .collect();
+
+        *self = manager;
+        Ok(upload)
+    }
+}
+
+/// Whether `duration` has passed between `timestamp` (see [timestamp_millis]) and `now`.
+fn has_elapsed(timestamp: u64, duration: Duration, now: SystemTime) -> bool {
+    let start = UNIX_EPOCH + Duration::from_millis(timestamp);
+    // If the clock went backwards, wait for it to catch up.
+    now.duration_since(start).unwrap_or_default() >= duration
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_id_wraps_around() {
+        assert_eq!(next_id(1), 2);
+        assert_eq!(next_id(MAX_PRE_KEY_ID - 1), MAX_PRE_KEY_ID);
+        assert_eq!(next_id(MAX_PRE_KEY_ID), 1);
+    }
+
+    #[test]
+    fn test_has_elapsed() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let timestamp = timestamp_millis(now - Duration::from_secs(60));
+        assert!(has_elapsed(timestamp, Duration::from_secs(60), now));
+        assert!(!has_elapsed(timestamp, Duration::from_secs(61), now));
+        // A timestamp in the future never counts as elapsed.
+        let future = timestamp_millis(now + Duration::from_secs(60));
+        assert!(!has_elapsed(future, Duration::from_millis(1), now));
+    }
+}
@@ -3,8 +3,8 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::state::{PreKeyId, SignedPreKeyId};
-use crate::{proto, IdentityKey, PrivateKey, PublicKey, Result, SignalProtocolError};
+use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
+use crate::{kem, padding, proto, IdentityKey, PrivateKey, PublicKey, Result, SignalProtocolError};
 
 use std::convert::TryFrom;
 
@@ -15,7 +15,12 @@ use sha2::Sha256;
 use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
-pub const CIPHERTEXT_MESSAGE_CURRENT_VERSION: u8 = 3;
+pub const CIPHERTEXT_MESSAGE_CURRENT_VERSION: u8 = 5;
+// PQXDH sessions that encrypt messages with AES-256-CBC and a truncated HMAC rather than
+// AES-256-GCM-SIV. Sessions started by peers that only support this version keep using it.
+pub const CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION: u8 = 4;
+// Sessions established without a Kyber pre-key (plain X3DH) keep using this version.
+pub const CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION: u8 = 3;
 pub const SENDERKEY_MESSAGE_CURRENT_VERSION: u8 = 3;
 
 pub enum CiphertextMessage {
@@ -59,7 +64,6 @@ pub struct SignalMessage {
     message_version: u8,
     sender_ratchet_key: PublicKey,
     counter: u32,
-    #[allow(dead_code)]
     previous_counter: u32,
     ciphertext: Box<[u8]>,
     serialized: Box<[u8]>,
@@ -68,6 +72,10 @@ pub struct SignalMessage {
 impl SignalMessage {
     const MAC_LENGTH: usize = 8;
 
+    /// Creates a message from an already-encrypted `ciphertext`.
+    ///
+    /// Messages of versions after 4 are encrypted with AES-256-GCM-SIV and carry no separate MAC:
+    /// `ciphertext` must already include its authentication tag, and `mac_key` is not used.
     pub fn new(
         message_version: u8,
         mac_key: &[u8],
@@ -85,18 +93,20 @@ impl SignalMessage {
             ciphertext: Some(Vec::<u8>::from(ciphertext)),
         };
         let mut serialized = Vec::new();
-        serialized.reserve(1 + message.encoded_len() + Self::MAC_LENGTH);
+        serialized.reserve(1 + message.encoded_len() + Self::mac_length(message_version));
         serialized.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
         message
             .encode(&mut serialized)
             .expect("can always append to a buffer");
-        let mac = Self::compute_mac(
-            sender_identity_key,
-            receiver_identity_key,
-            mac_key,
-            &serialized,
-        )?;
-        serialized.extend_from_slice(&mac);
+        if !Self::uses_aead(message_version) {
+            let mac = Self::compute_mac(
+                sender_identity_key,
+                receiver_identity_key,
+                mac_key,
+                &serialized,
+            )?;
+            serialized.extend_from_slice(&mac);
+        }
         let serialized = serialized.into_boxed_slice();
         Ok(Self {
             message_version,
@@ -133,12 +143,73 @@ impl SignalMessage {
         &self.ciphertext
     }
 
+    /// Whether messages of `message_version` are encrypted with AES-256-GCM-SIV, authenticating
+    /// the header as associated data, rather than with AES-256-CBC and a truncated HMAC.
+    pub(crate) fn uses_aead(message_version: u8) -> bool {
+        message_version > CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION
+    }
+
+    fn mac_length(message_version: u8) -> usize {
+        if Self::uses_aead(message_version) {
+            0
+        } else {
+            Self::MAC_LENGTH
+        }
+    }
+
+    /// The associated data for the ciphertext of an [AEAD](Self::uses_aead) message: both
+    /// parties' identity keys followed by the message with its ciphertext left out.
+    pub(crate) fn associated_data(
+        message_version: u8,
+        sender_ratchet_key: &PublicKey,
+        counter: u32,
+        previous_counter: u32,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+    ) -> Vec<u8> {
+        let header = proto::wire::SignalMessage {
+            ratchet_key: Some(sender_ratchet_key.serialize().into_vec()),
+            counter: Some(counter),
+            previous_counter: Some(previous_counter),
+            ciphertext: None,
+        };
+        let mut associated_data = Vec::new();
+        associated_data.extend_from_slice(&sender_identity_key.public_key().serialize());
+        associated_data.extend_from_slice(&receiver_identity_key.public_key().serialize());
+        associated_data.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
+        header
+            .encode(&mut associated_data)
+            .expect("can always append to a buffer");
+        associated_data
+    }
+
+    pub(crate) fn aead_associated_data(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+    ) -> Vec<u8> {
+        Self::associated_data(
+            self.message_version,
+            &self.sender_ratchet_key,
+            self.counter,
+            self.previous_counter,
+            sender_identity_key,
+            receiver_identity_key,
+        )
+    }
+
     pub fn verify_mac(
         &self,
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
         mac_key: &[u8],
     ) -> Result<bool> {
+        if Self::uses_aead(self.message_version) {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "version {} messages have no MAC; they are authenticated when decrypted",
+                self.message_version
+            )));
+        }
         let our_mac = &Self::compute_mac(
             sender_identity_key,
             receiver_identity_key,
@@ -189,11 +260,11 @@ impl TryFrom<&[u8]> for SignalMessage {
     type Error = SignalProtocolError;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        if value.len() < SignalMessage::MAC_LENGTH + 1 {
+        if value.is_empty() {
             return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
         }
         let message_version = value[0] >> 4;
-        if message_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version < CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION {
             return Err(SignalProtocolError::LegacyCiphertextVersion(
                 message_version,
             ));
@@ -203,9 +274,13 @@ impl TryFrom<&[u8]> for SignalMessage {
                 message_version,
             ));
         }
+        let mac_length = SignalMessage::mac_length(message_version);
+        if value.len() < mac_length + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
 
         let proto_structure =
-            proto::wire::SignalMessage::decode(&value[1..value.len() - SignalMessage::MAC_LENGTH])
+            proto::wire::SignalMessage::decode(&value[1..value.len() - mac_length])
                 .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
 
         let sender_ratchet_key = proto_structure
@@ -232,12 +307,39 @@ impl TryFrom<&[u8]> for SignalMessage {
     }
 }
 
+/// The Kyber pre-key information carried by a PQXDH [`PreKeySignalMessage`].
+#[derive(Debug, Clone)]
+pub struct KyberPayload {
+    pre_key_id: KyberPreKeyId,
+    ciphertext: kem::SerializedCiphertext,
+}
+
+impl KyberPayload {
+    pub fn new(pre_key_id: KyberPreKeyId, ciphertext: kem::SerializedCiphertext) -> Self {
+        Self {
+            pre_key_id,
+            ciphertext,
+        }
+    }
+
+    #[inline]
+    pub fn pre_key_id(&self) -> KyberPreKeyId {
+        self.pre_key_id
+    }
+
+    #[inline]
+    pub fn ciphertext(&self) -> &kem::SerializedCiphertext {
+        &self.ciphertext
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PreKeySignalMessage {
     message_version: u8,
     registration_id: u32,
     pre_key_id: Option<PreKeyId>,
     signed_pre_key_id: SignedPreKeyId,
+    kyber_payload: Option<KyberPayload>,
     base_key: PublicKey,
     identity_key: IdentityKey,
     message: SignalMessage,
@@ -250,6 +352,7 @@ impl PreKeySignalMessage {
         registration_id: u32,
         pre_key_id: Option<PreKeyId>,
         signed_pre_key_id: SignedPreKeyId,
+        kyber_payload: Option<KyberPayload>,
         base_key: PublicKey,
         identity_key: IdentityKey,
         message: SignalMessage,
@@ -258,6 +361,10 @@ impl PreKeySignalMessage {
             registration_id: Some(registration_id),
             pre_key_id: pre_key_id.map(|id| id.into()),
             signed_pre_key_id: Some(signed_pre_key_id.into()),
+            kyber_pre_key_id: kyber_payload.as_ref().map(|kyber| kyber.pre_key_id.into()),
+            kyber_ciphertext: kyber_payload
+                .as_ref()
+                .map(|kyber| kyber.ciphertext.to_vec()),
             base_key: Some(base_key.serialize().into_vec()),
             identity_key: Some(identity_key.serialize().into_vec()),
             message: Some(Vec::from(message.as_ref())),
@@ -273,6 +380,7 @@ impl PreKeySignalMessage {
             registration_id,
             pre_key_id,
             signed_pre_key_id,
+            kyber_payload,
             base_key,
             identity_key,
             message,
@@ -300,6 +408,21 @@ impl PreKeySignalMessage {
         self.signed_pre_key_id
     }
 
+    #[inline]
+    pub fn kyber_pre_key_id(&self) -> Option<KyberPreKeyId> {
+        self.kyber_payload.as_ref().map(|kyber| kyber.pre_key_id)
+    }
+
+    #[inline]
+    pub fn kyber_ciphertext(&self) -> Option<&kem::SerializedCiphertext> {
+        self.kyber_payload.as_ref().map(|kyber| &kyber.ciphertext)
+    }
+
+    #[inline]
+    pub fn kyber_payload(&self) -> Option<&KyberPayload> {
+        self.kyber_payload.as_ref()
+    }
+
     #[inline]
     pub fn base_key(&self) -> &PublicKey {
         &self.base_key
@@ -336,7 +459,7 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
         }
 
         let message_version = value[0] >> 4;
-        if message_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version < CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION {
             return Err(SignalProtocolError::LegacyCiphertextVersion(
                 message_version,
             ));
@@ -365,11 +488,42 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
 
         let base_key = PublicKey::deserialize(base_key.as_ref())?;
 
+        let kyber_payload = match (
+            proto_structure.kyber_pre_key_id,
+            proto_structure.kyber_ciphertext,
+        ) {
+            (Some(id), Some(ciphertext))
+                if message_version > CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION =>
+            {
+                Some(KyberPayload::new(id.into(), ciphertext.into_boxed_slice()))
+            }
+            (None, None) if message_version <= CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION => None,
+            (None, None) => {
+                return Err(SignalProtocolError::InvalidMessage(
+                    CiphertextMessageType::PreKey,
+                    "Kyber pre-key must be present for this session version",
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(SignalProtocolError::InvalidMessage(
+                    CiphertextMessageType::PreKey,
+                    "Kyber pre-key is not supported for this session version",
+                ))
+            }
+            _ => {
+                return Err(SignalProtocolError::InvalidMessage(
+                    CiphertextMessageType::PreKey,
+                    "both or neither of the Kyber pre-key ID and ciphertext must be present",
+                ))
+            }
+        };
+
         Ok(PreKeySignalMessage {
             message_version,
             registration_id: proto_structure.registration_id.unwrap_or(0),
             pre_key_id: proto_structure.pre_key_id.map(|id| id.into()),
             signed_pre_key_id: signed_pre_key_id.into(),
+            kyber_payload,
             base_key,
             identity_key: IdentityKey::try_from(identity_key.as_ref())?,
             message: SignalMessage::try_from(message.as_ref())?,
@@ -678,12 +832,6 @@ impl PlaintextContent {
     /// PlaintextContent; only messages that are okay to send as plaintext should be allowed.
     const PLAINTEXT_CONTEXT_IDENTIFIER_BYTE: u8 = 0xC0;
 
-    /// Marks the end of a message and the start of any padding.
-    ///
-    /// Usually messages are padded to avoid exposing patterns,
-    /// but PlaintextContent messages are all fixed-length anyway, so there won't be any padding.
-    const PADDING_BOUNDARY_BYTE: u8 = 0x80;
-
     #[inline]
     pub fn body(&self) -> &[u8] {
         &self.serialized[1..]
@@ -693,25 +841,48 @@ impl PlaintextContent {
     pub fn serialized(&self) -> &[u8] {
         &self.serialized
     }
-}
 
-impl From<DecryptionErrorMessage> for PlaintextContent {
-    fn from(message: DecryptionErrorMessage) -> Self {
-        let proto_structure = proto::service::Content {
-            decryption_error_message: Some(message.serialized().to_vec()),
-            ..Default::default()
-        };
+    /// The [EndSessionMessage] in this content, if it is one.
+    pub fn end_session_message(&self) -> Result<Option<EndSessionMessage>> {
+        decode_content(self.body())?
+            .end_session_message
+            .as_deref()
+            .map(EndSessionMessage::try_from)
+            .transpose()
+    }
+
+    fn from_content(content: proto::service::Content) -> Self {
         let mut serialized = vec![Self::PLAINTEXT_CONTEXT_IDENTIFIER_BYTE];
-        proto_structure
+        content
             .encode(&mut serialized)
             .expect("can always encode to a Vec");
-        serialized.push(Self::PADDING_BOUNDARY_BYTE);
+        // Usually messages are padded to avoid exposing patterns, but PlaintextContent messages
+        // are all fixed-length anyway, so only the boundary byte is added.
+        serialized.push(padding::BOUNDARY_BYTE);
         Self {
             serialized: Box::from(serialized),
         }
     }
 }
 
+impl From<DecryptionErrorMessage> for PlaintextContent {
+    fn from(message: DecryptionErrorMessage) -> Self {
+        Self::from_content(proto::service::Content {
+            decryption_error_message: Some(message.serialized().to_vec()),
+            ..Default::default()
+        })
+    }
+}
+
+impl From<EndSessionMessage> for PlaintextContent {
+    fn from(message: EndSessionMessage) -> Self {
+        Self::from_content(proto::service::Content {
+            end_session_message: Some(message.serialized().to_vec()),
+            ..Default::default()
+        })
+    }
+}
+
 impl TryFrom<&[u8]> for PlaintextContent {
     type Error = SignalProtocolError;
 
@@ -821,15 +992,92 @@ impl TryFrom<&[u8]> for DecryptionErrorMessage {
     }
 }
 
+/// Tells the recipient that the sender has ended their session.
+///
+/// Created by [end_session](crate::end_session) and handled with
+/// [process_end_session_message](crate::process_end_session_message). Like a
+/// [DecryptionErrorMessage], it is sent as [PlaintextContent], since the session it refers to may
+/// not be usable. It is authenticated instead with a key only the two ends of the session share,
+/// so it cannot be forged or used to end any other session.
+#[derive(Debug, Clone)]
+pub struct EndSessionMessage {
+    timestamp: u64,
+    mac: Vec<u8>,
+    serialized: Box<[u8]>,
+}
+
+impl EndSessionMessage {
+    pub(crate) fn new(timestamp: u64, end_session_key: &[u8]) -> Self {
+        let mac = Self::compute_mac(timestamp, end_session_key).to_vec();
+        let proto_message = proto::service::EndSessionMessage {
+            timestamp: Some(timestamp),
+            mac: Some(mac.clone()),
+        };
+        Self {
+            timestamp,
+            mac,
+            serialized: proto_message.encode_to_vec().into_boxed_slice(),
+        }
+    }
+
+    fn compute_mac(timestamp: u64, end_session_key: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(end_session_key)
+            .expect("HMAC-SHA256 should accept any size key");
+        mac.update(&timestamp.to_be_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Whether the message was created for the session with `end_session_key`.
+    pub(crate) fn verify_mac(&self, end_session_key: &[u8]) -> bool {
+        Self::compute_mac(self.timestamp, end_session_key)
+            .ct_eq(&self.mac)
+            .into()
+    }
+
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &[u8] {
+        &self.serialized
+    }
+}
+
+impl TryFrom<&[u8]> for EndSessionMessage {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let proto_structure = proto::service::EndSessionMessage::decode(value)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let timestamp = proto_structure
+            .timestamp
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let mac = proto_structure
+            .mac
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        Ok(Self {
+            timestamp,
+            mac,
+            serialized: Box::from(value),
+        })
+    }
+}
+
+/// Decodes the padded Content message at the end of a [PlaintextContent].
+fn decode_content(bytes: &[u8]) -> Result<proto::service::Content> {
+    padding::unpad(bytes)
+        .ok()
+        .and_then(|content| proto::service::Content::decode(content).ok())
+        .ok_or(SignalProtocolError::InvalidProtobufEncoding)
+}
+
 /// For testing
 pub fn extract_decryption_error_message_from_serialized_content(
     bytes: &[u8],
 ) -> Result<DecryptionErrorMessage> {
-    if bytes.last() != Some(&PlaintextContent::PADDING_BOUNDARY_BYTE) {
-        return Err(SignalProtocolError::InvalidProtobufEncoding);
-    }
-    let content = proto::service::Content::decode(bytes.split_last().expect("checked above").1)
-        .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+    let content = decode_content(bytes)?;
     content
         .decryption_error_message
         .as_deref()
@@ -907,6 +1155,7 @@ mod tests {
             365,
             None,
             97.into(),
+            None,
             base_key_pair.public_key,
             identity_key_pair.public_key.into(),
             message,
@@ -949,6 +1198,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_pre_key_signal_message_with_kyber_payload() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity_key_pair = KeyPair::generate(&mut csprng);
+        let base_key_pair = KeyPair::generate(&mut csprng);
+        let kyber_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+        let (_, kyber_ciphertext) = kyber_key_pair.public_key.encapsulate();
+        let message = create_signal_message(&mut csprng)?;
+
+        let pre_key_signal_message = PreKeySignalMessage::new(
+            4,
+            365,
+            Some(31.into()),
+            97.into(),
+            Some(KyberPayload::new(53.into(), kyber_ciphertext.clone())),
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message.clone(),
+        )?;
+        let deser_pre_key_signal_message =
+            PreKeySignalMessage::try_from(pre_key_signal_message.as_ref())
+                .expect("should deserialize without error");
+        assert_eq!(deser_pre_key_signal_message.message_version(), 4);
+        assert_eq!(
+            deser_pre_key_signal_message.kyber_pre_key_id(),
+            Some(53.into())
+        );
+        assert_eq!(
+            deser_pre_key_signal_message.kyber_ciphertext(),
+            Some(&kyber_ciphertext)
+        );
+
+        // A PQXDH message must carry a Kyber payload...
+        let missing_kyber = PreKeySignalMessage::new(
+            4,
+            365,
+            None,
+            97.into(),
+            None,
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message.clone(),
+        )?;
+        assert!(matches!(
+            PreKeySignalMessage::try_from(missing_kyber.as_ref()),
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::PreKey,
+                _
+            ))
+        ));
+
+        // ...and an X3DH message must not.
+        let unexpected_kyber = PreKeySignalMessage::new(
+            3,
+            365,
+            None,
+            97.into(),
+            Some(KyberPayload::new(53.into(), kyber_ciphertext)),
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message,
+        )?;
+        assert!(matches!(
+            PreKeySignalMessage::try_from(unexpected_kyber.as_ref()),
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::PreKey,
+                _
+            ))
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_sender_key_message_serialize_deserialize() -> Result<()> {
         let mut csprng = OsRng;
@@ -1017,6 +1338,7 @@ mod tests {
             365,
             None,
             97.into(),
+            None,
             base_key_pair.public_key,
             identity_key_pair.public_key.into(),
             message,
@@ -1071,4 +1393,33 @@ mod tests {
             Err(SignalProtocolError::InvalidArgument(_))
         ));
     }
+
+    #[test]
+    fn test_end_session_message() -> Result<()> {
+        let key = [1; 32];
+        let message = EndSessionMessage::new(1_700_000_000_000, &key);
+        let content = PlaintextContent::try_from(PlaintextContent::from(message).serialized())?;
+        let message = content
+            .end_session_message()?
+            .expect("contains an EndSessionMessage");
+        assert_eq!(message.timestamp(), 1_700_000_000_000);
+        assert!(message.verify_mac(&key));
+        assert!(!message.verify_mac(&[2; 32]));
+
+        let error_message = DecryptionErrorMessage::for_original(
+            create_signal_message(&mut OsRng)?.serialized(),
+            CiphertextMessageType::Whisper,
+            5,
+            7,
+        )?;
+        let content = PlaintextContent::from(error_message);
+        assert!(content.end_session_message()?.is_none());
+        assert!(matches!(
+            extract_decryption_error_message_from_serialized_content(
+                PlaintextContent::from(message).body()
+            ),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,252 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Sending an account's keys to a newly linked device.
+//!
+//! The new device generates a [ProvisioningCipher] and shows its public key to the existing
+//! device, typically in a QR code. The existing device fills in a [ProvisionMessage] and encrypts
+//! it for that key with [ProvisioningCipher::encrypt], and the new device decrypts it with
+//! [ProvisioningCipher::decrypt].
+//!
+//! The envelope is compatible with the one the Signal apps use: an ephemeral X25519 agreement,
+//! HKDF-SHA256 to derive an AES-256-CBC key and an HMAC-SHA256 key, and a body laid out as
+//! `version || iv || ciphertext || mac`.
+
+use crate::crypto::{aes_256_cbc_decrypt, aes_256_cbc_encrypt, hmac_sha256};
+use crate::{
+    proto, Aci, IdentityKey, IdentityKeyPair, KeyPair, Pni, PrivateKey, PublicKey, Result,
+    SignalProtocolError,
+};
+
+use arrayref::array_ref;
+use prost::Message;
+use rand::{CryptoRng, Rng};
+use std::convert::{TryFrom, TryInto};
+use subtle::ConstantTimeEq;
+
+const PROVISIONING_VERSION: u8 = 1;
+const PROVISIONING_KDF_INFO: &[u8] = b"TextSecure Provisioning Message";
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+/// The account details an existing device sends to a newly linked one.
+#[derive(Clone)]
+pub struct ProvisionMessage {
+    pub aci_identity_key_pair: IdentityKeyPair,
+    pub pni_identity_key_pair: Option<IdentityKeyPair>,
+    pub aci: Aci,
+    pub pni: Option<Pni>,
+    /// The account's phone number, in E164 format.
+    pub number: Option<String>,
+    /// The code the new device registers itself with.
+    pub provisioning_code: String,
+    pub user_agent: Option<String>,
+    pub profile_key: [u8; 32],
+    /// Whether the user has read receipts turned on.
+    pub read_receipts: bool,
+    pub provisioning_version: Option<u32>,
+}
+
+fn identity_key_pair_from_parts(public: &[u8], private: &[u8]) -> Result<IdentityKeyPair> {
+    Ok(IdentityKeyPair::new(
+        IdentityKey::decode(public)?,
+        PrivateKey::deserialize(private)?,
+    ))
+}
+
+impl ProvisionMessage {
+    pub fn serialize(&self) -> Vec<u8> {
+        let message = proto::provisioning::ProvisionMessage {
+            aci_identity_key_public: Some(
+                self.aci_identity_key_pair.identity_key().serialize().into(),
+            ),
+            aci_identity_key_private: Some(self.aci_identity_key_pair.private_key().serialize()),
+            pni_identity_key_public: self
+                .pni_identity_key_pair
+                .map(|pair| pair.identity_key().serialize().into()),
+            pni_identity_key_private: self
+                .pni_identity_key_pair
+                .map(|pair| pair.private_key().serialize()),
+            aci: Some(self.aci.service_id_string()),
+            pni: self.pni.map(|pni| pni.service_id_string()),
+            number: self.number.clone(),
+            provisioning_code: Some(self.provisioning_code.clone()),
+            user_agent: self.user_agent.clone(),
+            profile_key: Some(self.profile_key.to_vec()),
+            read_receipts: Some(self.read_receipts),
+            provisioning_version: self.provisioning_version,
+        };
+        message.encode_to_vec()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let message = proto::provisioning::ProvisionMessage::decode(data)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+
+        let aci_identity_key_pair = match (
+            &message.aci_identity_key_public,
+            &message.aci_identity_key_private,
+        ) {
+            (Some(public), Some(private)) => identity_key_pair_from_parts(public, private)?,
+            _ => return Err(SignalProtocolError::InvalidProtobufEncoding),
+        };
+        let pni_identity_key_pair = match (
+            &message.pni_identity_key_public,
+            &message.pni_identity_key_private,
+        ) {
+            (Some(public), Some(private)) => Some(identity_key_pair_from_parts(public, private)?),
+            (None, None) => None,
+            _ => return Err(SignalProtocolError::InvalidProtobufEncoding),
+        };
+
+        let aci = message
+            .aci
+            .as_deref()
+            .and_then(Aci::parse_from_service_id_string)
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let pni = match message.pni.as_deref() {
+            Some(pni) => Some(
+                Pni::parse_from_service_id_string(pni)
+                    .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            ),
+            None => None,
+        };
+
+        let profile_key = message
+            .profile_key
+            .as_deref()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        Ok(Self {
+            aci_identity_key_pair,
+            pni_identity_key_pair,
+            aci,
+            pni,
+            number: message.number,
+            provisioning_code: message
+                .provisioning_code
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            user_agent: message.user_agent,
+            profile_key,
+            read_receipts: message.read_receipts.unwrap_or(false),
+            provisioning_version: message.provisioning_version,
+        })
+    }
+}
+
+/// The keys for one provisioning envelope, derived from the agreement between the ephemeral key
+/// and the new device's key.
+struct ProvisioningKeys {
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl ProvisioningKeys {
+    fn derive(our_private_key: &PrivateKey, their_public_key: &PublicKey) -> Result<Self> {
+        let agreement = our_private_key.calculate_agreement(their_public_key)?;
+        let mut derived_values = [0; 64];
+        hkdf::Hkdf::<sha2::Sha256>::new(None, &agreement)
+            .expand(PROVISIONING_KDF_INFO, &mut derived_values)
+            .expect("valid output length");
+
+        Ok(Self {
+            cipher_key: *array_ref![&derived_values, 0, 32],
+            mac_key: *array_ref![&derived_values, 32, 32],
+        })
+    }
+}
+
+/// Encrypts and decrypts provisioning envelopes.
+///
+/// A cipher holds the new device's key pair, which should only be used for a single linking
+/// attempt.
+#[derive(Clone, Copy)]
+pub struct ProvisioningCipher {
+    key_pair: KeyPair,
+}
+
+impl ProvisioningCipher {
+    /// Create a cipher that decrypts envelopes encrypted for `key_pair`.
+    pub fn new(key_pair: KeyPair) -> Self {
+        Self { key_pair }
+    }
+
+    /// Create a cipher with a newly generated key pair.
+    pub fn generate<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
+        Self::new(KeyPair::generate(csprng))
+    }
+
+    /// The public key to give to the existing device.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key_pair.public_key
+    }
+
+    /// Encrypt `plaintext`, usually a serialized [ProvisionMessage], for the new device with key
+    /// `recipient`, returning a serialized envelope.
+    pub fn encrypt<R: Rng + CryptoRng>(
+        recipient: &PublicKey,
+        plaintext: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>> {
+        let ephemeral = KeyPair::generate(csprng);
+        let keys = ProvisioningKeys::derive(&ephemeral.private_key, recipient)?;
+
+        let mut iv = [0u8; IV_LEN];
+        csprng.fill_bytes(&mut iv);
+        let ciphertext = aes_256_cbc_encrypt(plaintext, &keys.cipher_key, &iv)
+            .expect("just generated key and IV of the correct length");
+
+        let mut body = Vec::with_capacity(1 + IV_LEN + ciphertext.len() + MAC_LEN);
+        body.push(PROVISIONING_VERSION);
+        body.extend_from_slice(&iv);
+        body.extend_from_slice(&ciphertext);
+        let mac = hmac_sha256(&keys.mac_key, &body);
+        body.extend_from_slice(&mac);
+
+        Ok(proto::provisioning::ProvisionEnvelope {
+            public_key: Some(ephemeral.public_key.serialize().into()),
+            body: Some(body),
+        }
+        .encode_to_vec())
+    }
+
+    /// Decrypt a serialized envelope produced by [encrypt](Self::encrypt) for this cipher's
+    /// public key.
+    pub fn decrypt(&self, envelope: &[u8]) -> Result<Vec<u8>> {
+        let envelope = proto::provisioning::ProvisionEnvelope::decode(envelope)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let their_public_key = PublicKey::try_from(
+            &envelope
+                .public_key
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?[..],
+        )?;
+        let body = envelope
+            .body
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        let invalid = |reason: &str| SignalProtocolError::InvalidProvisioningMessage(reason.into());
+        if body.len() < 1 + IV_LEN + MAC_LEN {
+            return Err(invalid("too short"));
+        }
+        if body[0] != PROVISIONING_VERSION {
+            return Err(SignalProtocolError::InvalidProvisioningMessage(format!(
+                "unknown version {}",
+                body[0]
+            )));
+        }
+
+        let keys = ProvisioningKeys::derive(&self.key_pair.private_key, &their_public_key)?;
+        let (authenticated, their_mac) = body.split_at(body.len() - MAC_LEN);
+        let our_mac = hmac_sha256(&keys.mac_key, authenticated);
+        if !bool::from(our_mac.ct_eq(their_mac)) {
+            return Err(invalid("MAC verification failed"));
+        }
+
+        let (iv, ciphertext) = authenticated[1..].split_at(IV_LEN);
+        aes_256_cbc_decrypt(ciphertext, &keys.cipher_key, iv)
+            .map_err(|_| invalid("failed to decrypt"))
+    }
+}
@@ -11,15 +11,23 @@ use std::convert::TryInto;
 pub(crate) use self::keys::{ChainKey, MessageKeys, RootKey};
 pub use self::params::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
 use crate::proto::storage::SessionStructure;
-use crate::protocol::CIPHERTEXT_MESSAGE_CURRENT_VERSION;
+use crate::protocol::{
+    CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION,
+    CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION,
+};
 use crate::state::SessionState;
-use crate::{KeyPair, Result, SessionRecord};
+use crate::{KeyPair, Result, SessionPolicy, SessionRecord, SignalProtocolError};
 use rand::{CryptoRng, Rng};
 
-fn derive_keys(secret_input: &[u8]) -> (RootKey, ChainKey) {
+fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
+    let label: &[u8] = if has_kyber {
+        b"WhisperText_X25519_SHA-256_CRYSTALS-KYBER-1024"
+    } else {
+        b"WhisperText"
+    };
     let mut secrets = [0; 64];
     hkdf::Hkdf::<sha2::Sha256>::new(None, secret_input)
-        .expand(b"WhisperText", &mut secrets)
+        .expand(label, &mut secrets)
         .expect("valid length");
     let (root_key_bytes, chain_key_bytes) = secrets.split_at(32);
 
@@ -29,6 +37,33 @@ fn derive_keys(secret_input: &[u8]) -> (RootKey, ChainKey) {
     (root_key, chain_key)
 }
 
+/// Derives the key that authenticates [EndSessionMessages](crate::EndSessionMessage) for a new
+/// session from its initial root key.
+fn derive_end_session_key(root_key: &RootKey) -> Vec<u8> {
+    let mut key = vec![0; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, root_key.key())
+        .expand(b"WhisperEndSession", &mut key)
+        .expect("valid length");
+    key
+}
+
+fn session_version(has_kyber: bool, requested: Option<u8>) -> Result<u32> {
+    let supported = if has_kyber {
+        CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION..=CIPHERTEXT_MESSAGE_CURRENT_VERSION
+    } else {
+        CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION..=CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION
+    };
+    match requested {
+        None => Ok((*supported.end()).into()),
+        Some(version) if supported.contains(&version) => Ok(version.into()),
+        Some(version) => Err(SignalProtocolError::InvalidArgument(format!(
+            "session version {} cannot be used {} a Kyber pre-key",
+            version,
+            if has_kyber { "with" } else { "without" }
+        ))),
+    }
+}
+
 pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     parameters: &AliceSignalProtocolParameters,
     mut csprng: &mut R,
@@ -37,7 +72,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
 
     let sending_ratchet_key = KeyPair::generate(&mut csprng);
 
-    let mut secrets = Vec::with_capacity(32 * 5);
+    let mut secrets = Vec::with_capacity(32 * 6);
 
     secrets.extend_from_slice(&[0xFFu8; 32]); // "discontinuity bytes"
 
@@ -63,7 +98,14 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
             .extend_from_slice(&our_base_private_key.calculate_agreement(their_one_time_prekey)?);
     }
 
-    let (root_key, chain_key) = derive_keys(&secrets);
+    let kyber_ciphertext = parameters.their_kyber_pre_key().map(|kyber_public| {
+        let (shared_secret, ciphertext) = kyber_public.encapsulate();
+        secrets.extend_from_slice(&shared_secret);
+        ciphertext
+    });
+    let has_kyber = kyber_ciphertext.is_some();
+
+    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
 
     let (sending_chain_root_key, sending_chain_chain_key) = root_key.create_chain(
         parameters.their_ratchet_key(),
@@ -71,7 +113,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     )?;
 
     let session = SessionStructure {
-        session_version: CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32,
+        session_version: session_version(has_kyber, parameters.session_version())?,
         local_identity_public: local_identity.public_key().serialize().to_vec(),
         remote_identity_public: parameters.their_identity_key().serialize().to_vec(),
         root_key: sending_chain_root_key.key().to_vec(),
@@ -82,13 +124,23 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
         remote_registration_id: 0,
         local_registration_id: 0,
         alice_base_key: vec![],
+        pending_kyber_pre_key: None,
+        end_session_key: derive_end_session_key(&root_key),
     };
 
     let mut session = SessionState::new(session);
 
-    session.add_receiver_chain(parameters.their_ratchet_key(), &chain_key);
+    session.add_receiver_chain(
+        parameters.their_ratchet_key(),
+        &chain_key,
+        &SessionPolicy::default(),
+    );
     session.set_sender_chain(&sending_ratchet_key, &sending_chain_chain_key);
 
+    if let Some(kyber_ciphertext) = kyber_ciphertext {
+        session.set_kyber_ciphertext(kyber_ciphertext);
+    }
+
     Ok(session)
 }
 
@@ -97,7 +149,7 @@ pub(crate) fn initialize_bob_session(
 ) -> Result<SessionState> {
     let local_identity = parameters.our_identity_key_pair().identity_key();
 
-    let mut secrets = Vec::with_capacity(32 * 5);
+    let mut secrets = Vec::with_capacity(32 * 6);
 
     secrets.extend_from_slice(&[0xFFu8; 32]); // "discontinuity bytes"
 
@@ -130,10 +182,25 @@ pub(crate) fn initialize_bob_session(
         );
     }
 
-    let (root_key, chain_key) = derive_keys(&secrets);
+    let has_kyber = match (
+        parameters.our_kyber_pre_key_pair(),
+        parameters.their_kyber_ciphertext(),
+    ) {
+        (Some(our_kyber_pre_key_pair), Some(their_kyber_ciphertext)) => {
+            secrets.extend_from_slice(
+                &our_kyber_pre_key_pair
+                    .secret_key
+                    .decapsulate(their_kyber_ciphertext)?,
+            );
+            true
+        }
+        _ => false,
+    };
+
+    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
 
     let session = SessionStructure {
-        session_version: CIPHERTEXT_MESSAGE_CURRENT_VERSION as u32,
+        session_version: session_version(has_kyber, parameters.session_version())?,
         local_identity_public: local_identity.public_key().serialize().to_vec(),
         remote_identity_public: parameters.their_identity_key().serialize().to_vec(),
         root_key: root_key.key().to_vec(),
@@ -144,6 +211,8 @@ pub(crate) fn initialize_bob_session(
         remote_registration_id: 0,
         local_registration_id: 0,
         alice_base_key: vec![],
+        pending_kyber_pre_key: None,
+        end_session_key: derive_end_session_key(&root_key),
     };
 
     let mut session = SessionState::new(session);
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::{IdentityKey, IdentityKeyPair, KeyPair, PublicKey};
+use crate::{kem, IdentityKey, IdentityKeyPair, KeyPair, PublicKey};
 
 pub struct AliceSignalProtocolParameters {
     our_identity_key_pair: IdentityKeyPair,
@@ -13,6 +13,9 @@ pub struct AliceSignalProtocolParameters {
     their_signed_pre_key: PublicKey,
     their_one_time_pre_key: Option<PublicKey>,
     their_ratchet_key: PublicKey,
+    their_kyber_pre_key: Option<kem::PublicKey>,
+
+    session_version: Option<u8>,
 }
 
 impl AliceSignalProtocolParameters {
@@ -31,9 +34,27 @@ impl AliceSignalProtocolParameters {
             their_signed_pre_key,
             their_one_time_pre_key,
             their_ratchet_key,
+            their_kyber_pre_key: None,
+            session_version: None,
         }
     }
 
+    /// Includes the other party's Kyber pre-key, upgrading the handshake to PQXDH.
+    pub fn with_their_kyber_pre_key(mut self, their_kyber_pre_key: kem::PublicKey) -> Self {
+        self.their_kyber_pre_key = Some(their_kyber_pre_key);
+        self
+    }
+
+    /// Uses `session_version` for the new session instead of the newest version the handshake
+    /// supports.
+    ///
+    /// PQXDH sessions can use version 4 to encrypt messages with AES-256-CBC for peers that do
+    /// not support version 5 yet.
+    pub fn with_session_version(mut self, session_version: u8) -> Self {
+        self.session_version = Some(session_version);
+        self
+    }
+
     #[inline]
     pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
         &self.our_identity_key_pair
@@ -63,6 +84,16 @@ impl AliceSignalProtocolParameters {
     pub fn their_ratchet_key(&self) -> &PublicKey {
         &self.their_ratchet_key
     }
+
+    #[inline]
+    pub fn their_kyber_pre_key(&self) -> Option<&kem::PublicKey> {
+        self.their_kyber_pre_key.as_ref()
+    }
+
+    #[inline]
+    pub fn session_version(&self) -> Option<u8> {
+        self.session_version
+    }
 }
 
 pub struct BobSignalProtocolParameters {
@@ -70,9 +101,13 @@ pub struct BobSignalProtocolParameters {
     our_signed_pre_key_pair: KeyPair,
     our_one_time_pre_key_pair: Option<KeyPair>,
     our_ratchet_key_pair: KeyPair,
+    our_kyber_pre_key_pair: Option<kem::KeyPair>,
 
     their_identity_key: IdentityKey,
     their_base_key: PublicKey,
+    their_kyber_ciphertext: Option<kem::SerializedCiphertext>,
+
+    session_version: Option<u8>,
 }
 
 impl BobSignalProtocolParameters {
@@ -89,11 +124,33 @@ impl BobSignalProtocolParameters {
             our_signed_pre_key_pair,
             our_one_time_pre_key_pair,
             our_ratchet_key_pair,
+            our_kyber_pre_key_pair: None,
             their_identity_key,
             their_base_key,
+            their_kyber_ciphertext: None,
+            session_version: None,
         }
     }
 
+    /// Includes our Kyber pre-key and the ciphertext the other party encapsulated to it,
+    /// upgrading the handshake to PQXDH.
+    pub fn with_kyber_pre_key(
+        mut self,
+        our_kyber_pre_key_pair: kem::KeyPair,
+        their_kyber_ciphertext: kem::SerializedCiphertext,
+    ) -> Self {
+        self.our_kyber_pre_key_pair = Some(our_kyber_pre_key_pair);
+        self.their_kyber_ciphertext = Some(their_kyber_ciphertext);
+        self
+    }
+
+    /// Uses `session_version` for the new session, normally the version of the message that
+    /// started it, instead of the newest version the handshake supports.
+    pub fn with_session_version(mut self, session_version: u8) -> Self {
+        self.session_version = Some(session_version);
+        self
+    }
+
     #[inline]
     pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
         &self.our_identity_key_pair
@@ -123,4 +180,19 @@ impl BobSignalProtocolParameters {
     pub fn their_base_key(&self) -> &PublicKey {
         &self.their_base_key
     }
+
+    #[inline]
+    pub fn our_kyber_pre_key_pair(&self) -> Option<&kem::KeyPair> {
+        self.our_kyber_pre_key_pair.as_ref()
+    }
+
+    #[inline]
+    pub fn their_kyber_ciphertext(&self) -> Option<&kem::SerializedCiphertext> {
+        self.their_kyber_ciphertext.as_ref()
+    }
+
+    #[inline]
+    pub fn session_version(&self) -> Option<u8> {
+        self.session_version
+    }
 }
@@ -0,0 +1,159 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::storage::flavor::{
+    Flavor, Local, ProtocolStoreTransactionFor, ResendLogStoreFor, SenderKeyStoreFor,
+    SessionStoreFor,
+};
+use crate::storage::in_transaction;
+use crate::{
+    Context, DecryptionErrorMessage, ProtocolAddress, ResendLogStore, Result, SenderKeyStore,
+    SessionPolicy, SessionStore,
+};
+
+#[cfg(doc)]
+use crate::{group_encrypt, members_missing_sender_key, process_prekey_bundle};
+
+use uuid::Uuid;
+
+/// A message we sent, kept in a [ResendLogStore] so that it can be sent again if a recipient fails
+/// to decrypt it.
+///
+/// Entries are identified by each recipient and the timestamp the message was sent with, which is
+/// what a [DecryptionErrorMessage] refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResendLogEntry {
+    /// The timestamp the message was sent with.
+    pub timestamp: u64,
+    /// The plaintext that was encrypted, before any padding.
+    pub content: Vec<u8>,
+    /// The distribution the message was encrypted for with [group_encrypt], or `None` if it was
+    /// encrypted for each recipient separately.
+    pub distribution_id: Option<Uuid>,
+    /// Every device the message was sent to.
+    pub recipients: Vec<ProtocolAddress>,
+}
+
+/// What has to be sent again in response to a [DecryptionErrorMessage].
+///
+/// The result of [process_decryption_error_message].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetryPlan {
+    /// Whether our current session with the requester was archived.
+    ///
+    /// A new session has to be set up with [process_prekey_bundle] before anything else is sent
+    /// to the requester.
+    pub session_archived: bool,
+    /// The distribution whose sender key has to be sent to the requester again before the message
+    /// is resent.
+    ///
+    /// The requester is no longer recorded as having our sender key for it, so it is reported by
+    /// [members_missing_sender_key] again.
+    pub sender_key_distribution_id: Option<Uuid>,
+    /// The failed message, if it is still in the resend log, to be encrypted for the requester
+    /// again.
+    pub resend: Option<ResendLogEntry>,
+}
+
+/// Work out how to recover from `requester` failing to decrypt a message that we, at
+/// `local_address`, sent to it.
+///
+/// If the failed message was encrypted with the requester's current session, that session is
+/// archived, since the requester evidently cannot use it. If it was encrypted with one of our
+/// sender keys, the distribution it was sent to is looked up in `resend_log`, and the requester is
+/// forgotten as a recipient of our sender key for that distribution.
+///
+/// Errors about messages sent by another of our devices are ignored, returning an empty plan.
+pub async fn process_decryption_error_message(
+    error_message: &DecryptionErrorMessage,
+    requester: &ProtocolAddress,
+    local_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    sender_key_store: &mut dyn SenderKeyStore,
+    resend_log: &dyn ResendLogStore,
+    policy: &SessionPolicy,
+    ctx: Context,
+) -> Result<RetryPlan> {
+    process_decryption_error_message_impl::<Local>(
+        error_message,
+        requester,
+        local_address,
+        session_store,
+        sender_key_store,
+        resend_log,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_decryption_error_message_impl<F: Flavor>(
+    error_message: &DecryptionErrorMessage,
+    requester: &ProtocolAddress,
+    local_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    sender_key_store: &mut F::SenderKeyStore<'_>,
+    resend_log: &F::ResendLogStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<RetryPlan> {
+    if error_message.device_id() != u32::from(local_address.device_id()) {
+        log::info!(
+            "ignoring decryption error from {} for a message sent by device {}",
+            requester,
+            error_message.device_id()
+        );
+        return Ok(RetryPlan::default());
+    }
+
+    let resend = resend_log
+        .load_resend_entry(requester, error_message.timestamp(), ctx)
+        .await?;
+    let sender_key_distribution_id = match error_message.ratchet_key() {
+        Some(_) => None,
+        None => resend.as_ref().and_then(|entry| entry.distribution_id),
+    };
+
+    let session_archived = in_transaction!(ctx, [session_store, sender_key_store], {
+        let mut session_archived = false;
+        if let Some(ratchet_key) = error_message.ratchet_key() {
+            if let Some(mut record) = session_store.load_session(requester, ctx).await? {
+                if record.current_ratchet_key_matches(ratchet_key)? {
+                    log::info!(
+                        "archiving session with {} after a decryption error",
+                        requester
+                    );
+                    record.archive_current_state_with_policy(policy)?;
+                    session_store.store_session(requester, &record, ctx).await?;
+                    session_archived = true;
+                }
+            }
+        }
+
+        if let Some(distribution_id) = sender_key_distribution_id {
+            if let Some(mut record) = sender_key_store
+                .load_sender_key(local_address, distribution_id, ctx)
+                .await?
+            {
+                let forgotten = record
+                    .sender_key_state_mut()
+                    .map_or(false, |state| state.remove_distributed_to(requester));
+                if forgotten {
+                    sender_key_store
+                        .store_sender_key(local_address, distribution_id, &record, ctx)
+                        .await?;
+                }
+            }
+        }
+        session_archived
+    })?;
+
+    Ok(RetryPlan {
+        session_archived,
+        sender_key_distribution_id,
+        resend,
+    })
+}
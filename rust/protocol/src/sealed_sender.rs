@@ -3,14 +3,17 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+#[cfg(doc)]
+use crate::message_encrypt;
 use crate::{
-    message_encrypt, CiphertextMessageType, Context, DeviceId, Direction, IdentityKey,
-    IdentityKeyPair, IdentityKeyStore, KeyPair, PreKeySignalMessage, PreKeyStore, PrivateKey,
-    ProtocolAddress, PublicKey, Result, SessionRecord, SessionStore, SignalMessage,
-    SignalProtocolError, SignedPreKeyStore,
+    Aci, CiphertextMessageType, Context, DeviceId, Direction, IdentityKey, IdentityKeyPair,
+    IdentityKeyStore, KeyPair, KyberPreKeyStore, PreKeySignalMessage, PreKeyStore, PrivateKey,
+    ProtocolAddress, PublicKey, Result, ServiceId, SessionPolicy, SessionRecord, SessionStore,
+    SignalMessage, SignalProtocolError, SignedPreKeyStore,
 };
 
-use crate::{crypto, curve, proto, session_cipher};
+use crate::storage::flavor::{Flavor, IdentityKeyStoreFor, Local};
+use crate::{consts, crypto, curve, proto, session_cipher};
 
 use aes_gcm_siv::aead::{AeadInPlace, NewAead};
 use aes_gcm_siv::Aes256GcmSiv;
@@ -19,7 +22,6 @@ use curve25519_dalek::scalar::Scalar;
 use prost::Message;
 use rand::{CryptoRng, Rng};
 use subtle::ConstantTimeEq;
-use uuid::Uuid;
 
 use proto::sealed_sender::unidentified_sender_message::message::Type as ProtoMessageType;
 
@@ -149,6 +151,15 @@ impl ServerCertificate {
     }
 }
 
+fn parse_sender_aci(sender_uuid: &str) -> Result<Aci> {
+    Aci::parse_from_service_id_string(sender_uuid).ok_or_else(|| {
+        SignalProtocolError::InvalidSealedSenderMessage(format!(
+            "sender UUID {} is not a valid ACI",
+            sender_uuid
+        ))
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct SenderCertificate {
     signer: ServerCertificate,
@@ -298,6 +309,11 @@ impl SenderCertificate {
         Ok(&self.sender_uuid)
     }
 
+    /// The sender's UUID, parsed as an [`Aci`].
+    pub fn sender_aci(&self) -> Result<Aci> {
+        parse_sender_aci(&self.sender_uuid)
+    }
+
     pub fn sender_e164(&self) -> Result<Option<&str>> {
         Ok(self.sender_e164.as_deref())
     }
@@ -520,6 +536,11 @@ enum UnidentifiedSenderMessage {
 
 const SEALED_SENDER_V1_VERSION: u8 = 1;
 const SEALED_SENDER_V2_VERSION: u8 = 2;
+/// The version byte of a sent v2 message whose recipients are identified by bare UUIDs.
+const SEALED_SENDER_V2_UUID_FULL_VERSION: u8 = 0x22;
+/// The version byte of a sent v2 message whose recipients are identified by fixed-width
+/// [`ServiceId`]s.
+const SEALED_SENDER_V2_SERVICE_ID_FULL_VERSION: u8 = 0x23;
 
 impl UnidentifiedSenderMessage {
     fn deserialize(data: &[u8]) -> Result<Self> {
@@ -769,19 +790,54 @@ mod sealed_sender_v1 {
 /// Encrypt the plaintext message `ptext`, generate an [`UnidentifiedSenderMessageContent`], then
 /// pass the result to [`sealed_sender_encrypt_from_usmc`].
 ///
-/// This is a simple way to encrypt a message in a 1:1 using [Sealed Sender v1].
+/// This is a simple way to encrypt a message in a 1:1 using [Sealed Sender v1]. `ptext` is padded
+/// according to `policy`, as with [`message_encrypt`].
 ///
 /// [Sealed Sender v1]: sealed_sender_encrypt_from_usmc
+#[allow(clippy::too_many_arguments)]
 pub async fn sealed_sender_encrypt<R: Rng + CryptoRng>(
     destination: &ProtocolAddress,
     sender_cert: &SenderCertificate,
     ptext: &[u8],
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
+    policy: &SessionPolicy,
     ctx: Context,
     rng: &mut R,
 ) -> Result<Vec<u8>> {
-    let message = message_encrypt(ptext, destination, session_store, identity_store, ctx).await?;
+    sealed_sender_encrypt_impl::<Local, _>(
+        destination,
+        sender_cert,
+        ptext,
+        session_store,
+        identity_store,
+        policy,
+        ctx,
+        rng,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn sealed_sender_encrypt_impl<F: Flavor, R: Rng + CryptoRng>(
+    destination: &ProtocolAddress,
+    sender_cert: &SenderCertificate,
+    ptext: &[u8],
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+    rng: &mut R,
+) -> Result<Vec<u8>> {
+    let message = session_cipher::message_encrypt_impl::<F>(
+        ptext,
+        destination,
+        session_store,
+        identity_store,
+        policy,
+        ctx,
+    )
+    .await?;
     let usmc = UnidentifiedSenderMessageContent::new(
         message.message_type(),
         sender_cert.clone(),
@@ -789,7 +845,7 @@ pub async fn sealed_sender_encrypt<R: Rng + CryptoRng>(
         ContentHint::Default,
         None,
     )?;
-    sealed_sender_encrypt_from_usmc(destination, &usmc, identity_store, ctx, rng).await
+    sealed_sender_encrypt_from_usmc_impl::<F, _>(destination, &usmc, identity_store, ctx, rng).await
 }
 
 /// This method implements the single-key single-recipient [KEM] described in [this Signal blog
@@ -848,6 +904,17 @@ pub async fn sealed_sender_encrypt_from_usmc<R: Rng + CryptoRng>(
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
     rng: &mut R,
+) -> Result<Vec<u8>> {
+    sealed_sender_encrypt_from_usmc_impl::<Local, _>(destination, usmc, identity_store, ctx, rng)
+        .await
+}
+
+pub(crate) async fn sealed_sender_encrypt_from_usmc_impl<F: Flavor, R: Rng + CryptoRng>(
+    destination: &ProtocolAddress,
+    usmc: &UnidentifiedSenderMessageContent,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    ctx: F::Context,
+    rng: &mut R,
 ) -> Result<Vec<u8>> {
     let our_identity = identity_store.get_identity_key_pair(ctx).await?;
     let their_identity = identity_store
@@ -1141,7 +1208,8 @@ mod sealed_sender_v2 {
 /// For testing purposes, [`sealed_sender_multi_recipient_fan_out`] can be used to convert such
 /// a bulk message produced by Sealed Sender v2 into a sequence of [received messages][receiving];
 /// however, in doing so it will drop all of the metadata necessary to identify the message's
-/// intended recipients.
+/// intended recipients. [`SealedSenderV2SentMessage`] parses the same message while keeping that
+/// metadata, and can produce the received message for each recipient.
 ///
 /// # Wire Format
 /// Multi-recipient sealed-sender does not use protobufs for its payload format. Instead, it uses
@@ -1167,6 +1235,12 @@ mod sealed_sender_v2 {
 /// of `0x22`. A hypothetical version byte `0x34` would indicate a message encoded
 /// as Sealed Sender v4, but decodable by any client that supports Sealed Sender v3.
 ///
+/// [Sent messages](#sent-messages) that identify recipients by [`ServiceId`] rather than by
+/// UUID use a version byte of `0x23`; the messages received by each recipient are unchanged.
+/// [`sealed_sender_multi_recipient_encrypt`] only produces `0x23` messages when at least one
+/// recipient is a PNI, so that servers that only understand `0x22` keep accepting messages sent
+/// to ACIs.
+///
 /// ## Received messages
 ///
 /// ```text
@@ -1186,7 +1260,7 @@ mod sealed_sender_v2 {
 ///
 /// ```text
 /// PerRecipientData {
-///     uuid: [u8; 16],
+///     service_id: [u8; 17], // or uuid: [u8; 16] for version 0x22 (ACIs only)
 ///     device_id: varint,
 ///     registration_id: u16,
 ///     c: [u8; 32],
@@ -1202,9 +1276,14 @@ mod sealed_sender_v2 {
 /// }
 /// ```
 ///
-/// The varint encoding used is the same as [protobuf's][varint]. Values are unsigned. UUIDs are
-/// encoded per [RFC 4122], with the first eight bytes considered "most significant". [^1]
-/// Fixed-width integers are unaligned and in network byte order (big-endian).
+/// The varint encoding used is the same as [protobuf's][varint]. Values are unsigned. Service IDs
+/// use their [fixed-width binary encoding][ServiceId::service_id_fixed_width_binary], a type byte
+/// followed by a UUID. UUIDs are encoded per [RFC 4122], with the first eight bytes considered
+/// "most significant". [^1] Fixed-width integers are unaligned and in network byte order
+/// (big-endian).
+///
+/// Each destination's [name][ProtocolAddress::name] must be a [service ID
+/// string][ServiceId::service_id_string], so that both ACIs and PNIs can be addressed.
 ///
 /// [varint]: https://developers.google.com/protocol-buffers/docs/encoding#varints
 /// [RFC 4122]: https://tools.ietf.org/html/rfc4122#section-4.1.2
@@ -1221,6 +1300,25 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
     rng: &mut R,
+) -> Result<Vec<u8>> {
+    sealed_sender_multi_recipient_encrypt_impl::<Local, _>(
+        destinations,
+        destination_sessions,
+        usmc,
+        identity_store,
+        ctx,
+        rng,
+    )
+    .await
+}
+
+pub(crate) async fn sealed_sender_multi_recipient_encrypt_impl<F: Flavor, R: Rng + CryptoRng>(
+    destinations: &[&ProtocolAddress],
+    destination_sessions: &[&SessionRecord],
+    usmc: &UnidentifiedSenderMessageContent,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    ctx: F::Context,
+    rng: &mut R,
 ) -> Result<Vec<u8>> {
     if destinations.len() != destination_sessions.len() {
         return Err(SignalProtocolError::InvalidArgument(
@@ -1251,23 +1349,40 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
         ciphertext
     };
 
-    // Uses a flat representation: count || UUID_i || deviceId_i || registrationId_i || C_i || AT_i || ... || E.pub || ciphertext
-    let version = SEALED_SENDER_V2_VERSION;
-    let mut serialized: Vec<u8> = vec![(version | (version << 4))];
+    let destination_service_ids = destinations
+        .iter()
+        .map(|destination| {
+            destination.service_id().ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!(
+                    "multi-recipient sealed sender requires ServiceId recipients (not {})",
+                    destination.name()
+                ))
+            })
+        })
+        .collect::<Result<Vec<ServiceId>>>()?;
+    // Only use the newer format when it's needed to address a PNI.
+    let version = if destination_service_ids
+        .iter()
+        .all(|service_id| matches!(service_id, ServiceId::Aci(_)))
+    {
+        SEALED_SENDER_V2_UUID_FULL_VERSION
+    } else {
+        SEALED_SENDER_V2_SERVICE_ID_FULL_VERSION
+    };
+
+    // Uses a flat representation: count || ServiceId_i || deviceId_i || registrationId_i || C_i || AT_i || ... || E.pub || ciphertext
+    let mut serialized: Vec<u8> = vec![version];
 
     prost::encode_length_delimiter(destinations.len(), &mut serialized)
         .expect("cannot fail encoding to Vec");
 
     let our_identity = identity_store.get_identity_key_pair(ctx).await?;
     let mut previous_their_identity = None;
-    for (&destination, session) in destinations.iter().zip(destination_sessions) {
-        let their_uuid = Uuid::parse_str(destination.name()).map_err(|_| {
-            SignalProtocolError::InvalidArgument(format!(
-                "multi-recipient sealed sender requires UUID recipients (not {})",
-                destination.name()
-            ))
-        })?;
-
+    for ((&destination, session), their_service_id) in destinations
+        .iter()
+        .zip(destination_sessions)
+        .zip(destination_service_ids)
+    {
         let their_identity = identity_store
             .get_identity(destination, ctx)
             .await?
@@ -1291,9 +1406,8 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
                 ),
             )
         })?;
-        // Valid registration IDs fit in 14 bits.
         // TODO: move this into a RegistrationId strong type.
-        if their_registration_id & 0x3FFF != their_registration_id {
+        if their_registration_id & consts::VALID_REGISTRATION_ID_MASK != their_registration_id {
             return Err(SignalProtocolError::InvalidRegistrationId(
                 destination.clone(),
                 their_registration_id,
@@ -1304,7 +1418,11 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
 
         let end_of_previous_recipient_data = serialized.len();
 
-        serialized.extend_from_slice(their_uuid.as_bytes());
+        if version == SEALED_SENDER_V2_UUID_FULL_VERSION {
+            serialized.extend_from_slice(their_service_id.raw_uuid().as_bytes());
+        } else {
+            serialized.extend_from_slice(&their_service_id.service_id_fixed_width_binary());
+        }
         let device_id: u32 = destination.device_id().into();
         prost::encode_length_delimiter(device_id as usize, &mut serialized)
             .expect("cannot fail encoding to Vec");
@@ -1348,6 +1466,179 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
     Ok(serialized)
 }
 
+/// One recipient of a [`SealedSenderV2SentMessage`].
+#[derive(Debug, Clone)]
+pub struct SealedSenderV2SentRecipient<'a> {
+    /// The recipient's account. For version `0x22` messages this is always an ACI.
+    pub service_id: ServiceId,
+    pub device_id: DeviceId,
+    pub registration_id: u16,
+    /// The full per-recipient entry, as it appears in the sent message.
+    entry: &'a [u8],
+    /// The recipient's encrypted message key and authentication tag (`C_i || AT_i`).
+    c_and_at: &'a [u8],
+}
+
+impl<'a> SealedSenderV2SentRecipient<'a> {
+    /// The recipient-specific key material, `C_i || AT_i`.
+    ///
+    /// This is borrowed from the original message.
+    pub fn key_material(&self) -> &'a [u8] {
+        self.c_and_at
+    }
+}
+
+/// A parsed view of a message produced by [`sealed_sender_multi_recipient_encrypt`], for use by
+/// the server or a relay.
+///
+/// Parsing does not copy any of the message; the recipient table and the shared ciphertext borrow
+/// from the original bytes. See [`sealed_sender_multi_recipient_encrypt`] for the format.
+#[derive(Debug, Clone)]
+pub struct SealedSenderV2SentMessage<'a> {
+    full_message: &'a [u8],
+    /// The version byte of the message, either `0x22` or `0x23`.
+    pub version: u8,
+    /// The recipients in the order they appear in the message.
+    pub recipients: Vec<SealedSenderV2SentRecipient<'a>>,
+    offset_of_shared_bytes: usize,
+}
+
+impl<'a> SealedSenderV2SentMessage<'a> {
+    /// Parse a sent multi-recipient message without decrypting it.
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let version = *data
+            .first()
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let recipient_id_len = match version {
+            SEALED_SENDER_V2_UUID_FULL_VERSION => 16,
+            SEALED_SENDER_V2_SERVICE_ID_FULL_VERSION => ServiceId::FIXED_WIDTH_BINARY_LEN,
+            _ => {
+                return Err(SignalProtocolError::UnknownSealedSenderVersion(
+                    version >> 4,
+                ))
+            }
+        };
+
+        fn advance<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+            if n > buf.len() {
+                return Err(SignalProtocolError::InvalidProtobufEncoding);
+            }
+            let (prefix, remaining) = buf.split_at(n);
+            *buf = remaining;
+            Ok(prefix)
+        }
+        fn decode_varint(buf: &mut &[u8]) -> Result<u32> {
+            let result: usize = prost::decode_length_delimiter(*buf)
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+            let _ = advance(buf, prost::length_delimiter_len(result))
+                .expect("just decoded that many bytes");
+            result
+                .try_into()
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)
+        }
+
+        let mut remaining = &data[1..];
+        let recipient_count = decode_varint(&mut remaining)?;
+
+        let mut recipients = Vec::new();
+        for _ in 0..recipient_count {
+            let entry_start = remaining;
+
+            let recipient_id = advance(&mut remaining, recipient_id_len)?;
+            let service_id = if version == SEALED_SENDER_V2_UUID_FULL_VERSION {
+                Aci::from_uuid_bytes(recipient_id.try_into().expect("correct length")).into()
+            } else {
+                ServiceId::parse_from_service_id_fixed_width_binary(
+                    recipient_id.try_into().expect("correct length"),
+                )
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+            };
+            let device_id = decode_varint(&mut remaining)?.into();
+            let registration_id = u16::from_be_bytes(
+                advance(&mut remaining, 2)?
+                    .try_into()
+                    .expect("correct length"),
+            );
+            let c_and_at = advance(
+                &mut remaining,
+                sealed_sender_v2::MESSAGE_KEY_LEN + sealed_sender_v2::AUTH_TAG_LEN,
+            )?;
+
+            recipients.push(SealedSenderV2SentRecipient {
+                service_id,
+                device_id,
+                registration_id,
+                entry: &entry_start[..entry_start.len() - remaining.len()],
+                c_and_at,
+            });
+        }
+
+        if remaining.len() < curve::curve25519::PUBLIC_KEY_LENGTH {
+            return Err(SignalProtocolError::InvalidProtobufEncoding);
+        }
+
+        Ok(Self {
+            full_message: data,
+            version,
+            recipients,
+            offset_of_shared_bytes: data.len() - remaining.len(),
+        })
+    }
+
+    /// The range of the original message holding the data shared by all recipients, `E.pub`
+    /// followed by the encrypted message.
+    pub fn range_for_shared_bytes(&self) -> std::ops::Range<usize> {
+        self.offset_of_shared_bytes..self.full_message.len()
+    }
+
+    /// The data shared by all recipients, `E.pub` followed by the encrypted message.
+    pub fn shared_bytes(&self) -> &'a [u8] {
+        &self.full_message[self.offset_of_shared_bytes..]
+    }
+
+    /// The pieces of the message received by `recipient`, to be concatenated without copying.
+    ///
+    /// `recipient` should be one of this message's [`recipients`](Self::recipients).
+    pub fn received_message_parts_for_recipient(
+        &self,
+        recipient: &SealedSenderV2SentRecipient<'a>,
+    ) -> [&'a [u8]; 3] {
+        // Received messages have the same format regardless of how recipients were identified.
+        const VERSION: &[u8] = &[SEALED_SENDER_V2_UUID_FULL_VERSION];
+        [VERSION, recipient.c_and_at, self.shared_bytes()]
+    }
+
+    /// Serialize the message received by `recipient`, as accepted by
+    /// [`sealed_sender_decrypt_to_usmc`].
+    pub fn received_message_for_recipient(
+        &self,
+        recipient: &SealedSenderV2SentRecipient<'a>,
+    ) -> Vec<u8> {
+        self.received_message_parts_for_recipient(recipient)
+            .concat()
+    }
+
+    /// Re-serialize this message without any of the devices belonging to `excluded`.
+    ///
+    /// The result keeps the original version byte, and can itself be parsed.
+    pub fn serialize_excluding(&self, excluded: &[ServiceId]) -> Vec<u8> {
+        let remaining: Vec<&SealedSenderV2SentRecipient<'a>> = self
+            .recipients
+            .iter()
+            .filter(|recipient| !excluded.contains(&recipient.service_id))
+            .collect();
+
+        let mut serialized = vec![self.version];
+        prost::encode_length_delimiter(remaining.len(), &mut serialized)
+            .expect("cannot fail encoding to Vec");
+        for recipient in remaining {
+            serialized.extend_from_slice(recipient.entry);
+        }
+        serialized.extend_from_slice(self.shared_bytes());
+        serialized
+    }
+}
+
 /// Split out the encoded message from [`sealed_sender_multi_recipient_encrypt`] into a sequence of
 /// individual encrypted [`UnidentifiedSenderMessageContent`]s. **Note: this method is only used in
 /// testing.**
@@ -1360,57 +1651,12 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
 ///
 /// [Routing messages to recipients]: sealed_sender_multi_recipient_encrypt#routing-messages-to-recipients
 pub fn sealed_sender_multi_recipient_fan_out(data: &[u8]) -> Result<Vec<Vec<u8>>> {
-    let version = data[0] >> 4;
-    if version != SEALED_SENDER_V2_VERSION {
-        return Err(SignalProtocolError::UnknownSealedSenderVersion(version));
-    }
-
-    fn advance<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
-        if n > buf.len() {
-            return Err(SignalProtocolError::InvalidProtobufEncoding);
-        }
-        let (prefix, remaining) = buf.split_at(n);
-        *buf = remaining;
-        Ok(prefix)
-    }
-    fn decode_varint(buf: &mut &[u8]) -> Result<u32> {
-        let result: usize = prost::decode_length_delimiter(*buf)
-            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
-        let _ = advance(buf, prost::length_delimiter_len(result))
-            .expect("just decoded that many bytes");
-        result
-            .try_into()
-            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)
-    }
-
-    let mut remaining = &data[1..];
-    let recipient_count = decode_varint(&mut remaining)?;
-
-    let mut messages: Vec<Vec<u8>> = Vec::new();
-    for _ in 0..recipient_count {
-        // Skip UUID.
-        let _ = advance(&mut remaining, 16)?;
-        // Skip device ID.
-        let _ = decode_varint(&mut remaining)?;
-        // Skip registration ID.
-        let _ = advance(&mut remaining, 2)?;
-        // Read C_i and AT_i.
-        let c_and_at = advance(
-            &mut remaining,
-            sealed_sender_v2::MESSAGE_KEY_LEN + sealed_sender_v2::AUTH_TAG_LEN,
-        )?;
-
-        let mut next_message = vec![data[0]];
-        next_message.extend_from_slice(c_and_at);
-        messages.push(next_message);
-    }
-
-    // Remaining data is shared among all messages.
-    for message in messages.iter_mut() {
-        message.extend_from_slice(remaining)
-    }
-
-    Ok(messages)
+    let message = SealedSenderV2SentMessage::parse(data)?;
+    Ok(message
+        .recipients
+        .iter()
+        .map(|recipient| message.received_message_for_recipient(recipient))
+        .collect())
 }
 
 /// Decrypt the payload of a sealed-sender message in either the v1 or v2 format.
@@ -1421,6 +1667,14 @@ pub async fn sealed_sender_decrypt_to_usmc(
     ciphertext: &[u8],
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
+) -> Result<UnidentifiedSenderMessageContent> {
+    sealed_sender_decrypt_to_usmc_impl::<Local>(ciphertext, identity_store, ctx).await
+}
+
+pub(crate) async fn sealed_sender_decrypt_to_usmc_impl<F: Flavor>(
+    ciphertext: &[u8],
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    ctx: F::Context,
 ) -> Result<UnidentifiedSenderMessageContent> {
     let our_identity = identity_store.get_identity_key_pair(ctx).await?;
 
@@ -1571,6 +1825,11 @@ impl SealedSenderDecryptionResult {
         Ok(self.sender_uuid.as_ref())
     }
 
+    /// The sender's UUID, parsed as an [`Aci`].
+    pub fn sender_aci(&self) -> Result<Aci> {
+        parse_sender_aci(&self.sender_uuid)
+    }
+
     pub fn sender_e164(&self) -> Result<Option<&str>> {
         Ok(self.sender_e164.as_deref())
     }
@@ -1603,9 +1862,45 @@ pub async fn sealed_sender_decrypt(
     session_store: &mut dyn SessionStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    policy: &SessionPolicy,
     ctx: Context,
 ) -> Result<SealedSenderDecryptionResult> {
-    let usmc = sealed_sender_decrypt_to_usmc(ciphertext, identity_store, ctx).await?;
+    sealed_sender_decrypt_impl::<Local>(
+        ciphertext,
+        trust_root,
+        timestamp,
+        local_e164,
+        local_uuid,
+        local_device_id,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn sealed_sender_decrypt_impl<F: Flavor>(
+    ciphertext: &[u8],
+    trust_root: &PublicKey,
+    timestamp: u64,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: DeviceId,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    session_store: &mut F::SessionStore<'_>,
+    pre_key_store: &mut F::PreKeyStore<'_>,
+    signed_pre_key_store: &mut F::SignedPreKeyStore<'_>,
+    kyber_pre_key_store: &mut F::KyberPreKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<SealedSenderDecryptionResult> {
+    let usmc = sealed_sender_decrypt_to_usmc_impl::<F>(ciphertext, identity_store, ctx).await?;
 
     if !usmc.sender()?.validate(trust_root, timestamp)? {
         return Err(SignalProtocolError::InvalidSealedSenderMessage(
@@ -1634,11 +1929,12 @@ pub async fn sealed_sender_decrypt(
     let message = match usmc.msg_type()? {
         CiphertextMessageType::Whisper => {
             let ctext = SignalMessage::try_from(usmc.contents()?)?;
-            session_cipher::message_decrypt_signal(
+            session_cipher::message_decrypt_signal_impl::<F, _>(
                 &ctext,
                 &remote_address,
                 session_store,
                 identity_store,
+                policy,
                 &mut rng,
                 ctx,
             )
@@ -1646,13 +1942,15 @@ pub async fn sealed_sender_decrypt(
         }
         CiphertextMessageType::PreKey => {
             let ctext = PreKeySignalMessage::try_from(usmc.contents()?)?;
-            session_cipher::message_decrypt_prekey(
+            session_cipher::message_decrypt_prekey_impl::<F, _>(
                 &ctext,
                 &remote_address,
                 session_store,
                 identity_store,
                 pre_key_store,
                 signed_pre_key_store,
+                kyber_pre_key_store,
+                policy,
                 &mut rng,
                 ctx,
             )
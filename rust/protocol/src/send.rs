@@ -0,0 +1,567 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The library's async functions for stores that can be used from any thread.
+//!
+//! Each function here behaves like the one of the same name at the crate root, but takes stores
+//! implementing the `Send` store traits re-exported here, which have no [Context](crate::Context)
+//! argument, and returns a future that is [Send]. That lets the whole pipeline run on a
+//! multi-threaded executor.
+//!
+//! Every `Send` store also implements the corresponding store trait at the crate root, so the same
+//! stores can still be passed to the functions there. The two sets of traits have methods of the
+//! same names, so it is best to import only one of them into any given scope.
+
+use std::time::SystemTime;
+
+use rand::{CryptoRng, Rng};
+use uuid::Uuid;
+
+use crate::storage::flavor::Threaded;
+use crate::{
+    group_cipher, retry, sealed_sender, session, session_cipher, AllDevicesMessages,
+    CiphertextMessage, DecryptionErrorMessage, DeviceId, EndSessionMessage, IdentityKeyPair,
+    PlaintextContent, PreKeyBundle, PreKeyId, PreKeyManager, PreKeySignalMessage, PreKeyUpload,
+    PreKeysUsed, ProtocolAddress, PublicKey, Result, RetryPlan, SealedSenderDecryptionResult,
+    SenderCertificate, SenderKeyDistributionMessage, SenderKeyMessage, SessionPolicy,
+    SessionRecord, SignalMessage, SignedPreKeyUpload, UnidentifiedSenderMessageContent,
+};
+
+pub use crate::storage::{
+    SendIdentityKeyStore, SendKyberPreKeyStore, SendPreKeyStore, SendProtocolStore,
+    SendProtocolStoreTransaction, SendRecordStore, SendResendLogStore, SendSenderKeyStore,
+    SendSessionStore, SendSignedPreKeyStore,
+};
+
+/// See [crate::message_encrypt].
+pub async fn message_encrypt(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    policy: &SessionPolicy,
+) -> Result<CiphertextMessage> {
+    session_cipher::message_encrypt_impl::<Threaded>(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::encrypt_for_all_devices].
+pub async fn encrypt_for_all_devices(
+    ptext: &[u8],
+    name: &str,
+    devices: &[(DeviceId, u32)],
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    policy: &SessionPolicy,
+) -> AllDevicesMessages {
+    session_cipher::encrypt_for_all_devices_impl::<Threaded>(
+        ptext,
+        name,
+        devices,
+        session_store,
+        identity_store,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::message_decrypt].
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt<R: Rng + CryptoRng + Send>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    signed_pre_key_store: &mut dyn SendSignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn SendKyberPreKeyStore,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+) -> Result<Vec<u8>> {
+    session_cipher::message_decrypt_impl::<Threaded, _>(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::message_decrypt_prekey].
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_prekey<R: Rng + CryptoRng + Send>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    signed_pre_key_store: &mut dyn SendSignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn SendKyberPreKeyStore,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+) -> Result<Vec<u8>> {
+    session_cipher::message_decrypt_prekey_impl::<Threaded, _>(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::message_decrypt_signal].
+pub async fn message_decrypt_signal<R: Rng + CryptoRng + Send>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+) -> Result<Vec<u8>> {
+    session_cipher::message_decrypt_signal_impl::<Threaded, _>(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        policy,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::process_prekey].
+#[allow(clippy::too_many_arguments)]
+pub async fn process_prekey(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    signed_prekey_store: &mut dyn SendSignedPreKeyStore,
+    kyber_prekey_store: &mut dyn SendKyberPreKeyStore,
+    policy: &SessionPolicy,
+) -> Result<PreKeysUsed> {
+    session::process_prekey_impl::<Threaded>(
+        message,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_prekey_store,
+        kyber_prekey_store,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::process_prekey_bundle].
+pub async fn process_prekey_bundle<R: Rng + CryptoRng + Send>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+) -> Result<()> {
+    process_prekey_bundle_with_policy(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        &SessionPolicy::default(),
+    )
+    .await
+}
+
+/// See [crate::process_prekey_bundle_with_policy].
+pub async fn process_prekey_bundle_with_policy<R: Rng + CryptoRng + Send>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    policy: &SessionPolicy,
+) -> Result<()> {
+    session::process_prekey_bundle_with_policy_impl::<Threaded, _>(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::end_session].
+pub async fn end_session(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    timestamp: u64,
+    policy: &SessionPolicy,
+) -> Result<PlaintextContent> {
+    session::end_session_impl::<Threaded>(remote_address, session_store, timestamp, policy, ())
+        .await
+}
+
+/// See [crate::process_end_session_message].
+pub async fn process_end_session_message(
+    message: &EndSessionMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    policy: &SessionPolicy,
+) -> Result<bool> {
+    session::process_end_session_message_impl::<Threaded>(
+        message,
+        remote_address,
+        session_store,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::group_encrypt].
+pub async fn group_encrypt<R: Rng + CryptoRng + Send>(
+    sender_key_store: &mut dyn SendSenderKeyStore,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintext: &[u8],
+    policy: &SessionPolicy,
+    csprng: &mut R,
+) -> Result<SenderKeyMessage> {
+    group_cipher::group_encrypt_impl::<Threaded, _>(
+        sender_key_store,
+        sender,
+        distribution_id,
+        plaintext,
+        policy,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::group_decrypt].
+pub async fn group_decrypt(
+    skm_bytes: &[u8],
+    sender_key_store: &mut dyn SendSenderKeyStore,
+    sender: &ProtocolAddress,
+    policy: &SessionPolicy,
+) -> Result<Vec<u8>> {
+    group_cipher::group_decrypt_impl::<Threaded>(skm_bytes, sender_key_store, sender, policy, ())
+        .await
+}
+
+/// See [crate::process_sender_key_distribution_message].
+pub async fn process_sender_key_distribution_message(
+    sender: &ProtocolAddress,
+    skdm: &SenderKeyDistributionMessage,
+    sender_key_store: &mut dyn SendSenderKeyStore,
+) -> Result<()> {
+    group_cipher::process_sender_key_distribution_message_impl::<Threaded>(
+        sender,
+        skdm,
+        sender_key_store,
+        (),
+    )
+    .await
+}
+
+/// See [crate::create_sender_key_distribution_message].
+pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng + Send>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut dyn SendSenderKeyStore,
+    csprng: &mut R,
+) -> Result<SenderKeyDistributionMessage> {
+    group_cipher::create_sender_key_distribution_message_impl::<Threaded, _>(
+        sender,
+        distribution_id,
+        sender_key_store,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::rotate_sender_key].
+pub async fn rotate_sender_key<R: Rng + CryptoRng + Send>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut dyn SendSenderKeyStore,
+    csprng: &mut R,
+) -> Result<SenderKeyDistributionMessage> {
+    group_cipher::rotate_sender_key_impl::<Threaded, _>(
+        sender,
+        distribution_id,
+        sender_key_store,
+        csprng,
+        (),
+    )
+    .await
+}
+
+/// See [crate::mark_sender_key_distributed].
+pub async fn mark_sender_key_distributed(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    recipients: &[ProtocolAddress],
+    sender_key_store: &mut dyn SendSenderKeyStore,
+) -> Result<()> {
+    group_cipher::mark_sender_key_distributed_impl::<Threaded>(
+        sender,
+        distribution_id,
+        recipients,
+        sender_key_store,
+        (),
+    )
+    .await
+}
+
+/// See [crate::members_missing_sender_key].
+pub async fn members_missing_sender_key(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    members: &[ProtocolAddress],
+    sender_key_store: &mut dyn SendSenderKeyStore,
+) -> Result<Vec<ProtocolAddress>> {
+    group_cipher::members_missing_sender_key_impl::<Threaded>(
+        sender,
+        distribution_id,
+        members,
+        sender_key_store,
+        (),
+    )
+    .await
+}
+
+/// See [crate::remove_sender_key_recipients].
+pub async fn remove_sender_key_recipients(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    removed: &[ProtocolAddress],
+    sender_key_store: &mut dyn SendSenderKeyStore,
+) -> Result<bool> {
+    group_cipher::remove_sender_key_recipients_impl::<Threaded>(
+        sender,
+        distribution_id,
+        removed,
+        sender_key_store,
+        (),
+    )
+    .await
+}
+
+/// See [crate::process_decryption_error_message].
+pub async fn process_decryption_error_message(
+    error_message: &DecryptionErrorMessage,
+    requester: &ProtocolAddress,
+    local_address: &ProtocolAddress,
+    session_store: &mut dyn SendSessionStore,
+    sender_key_store: &mut dyn SendSenderKeyStore,
+    resend_log: &dyn SendResendLogStore,
+    policy: &SessionPolicy,
+) -> Result<RetryPlan> {
+    retry::process_decryption_error_message_impl::<Threaded>(
+        error_message,
+        requester,
+        local_address,
+        session_store,
+        sender_key_store,
+        resend_log,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [crate::sealed_sender_encrypt].
+pub async fn sealed_sender_encrypt<R: Rng + CryptoRng + Send>(
+    destination: &ProtocolAddress,
+    sender_cert: &SenderCertificate,
+    ptext: &[u8],
+    session_store: &mut dyn SendSessionStore,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    policy: &SessionPolicy,
+    rng: &mut R,
+) -> Result<Vec<u8>> {
+    sealed_sender::sealed_sender_encrypt_impl::<Threaded, _>(
+        destination,
+        sender_cert,
+        ptext,
+        session_store,
+        identity_store,
+        policy,
+        (),
+        rng,
+    )
+    .await
+}
+
+/// See [crate::sealed_sender_encrypt_from_usmc].
+pub async fn sealed_sender_encrypt_from_usmc<R: Rng + CryptoRng + Send>(
+    destination: &ProtocolAddress,
+    usmc: &UnidentifiedSenderMessageContent,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    rng: &mut R,
+) -> Result<Vec<u8>> {
+    sealed_sender::sealed_sender_encrypt_from_usmc_impl::<Threaded, _>(
+        destination,
+        usmc,
+        identity_store,
+        (),
+        rng,
+    )
+    .await
+}
+
+/// See [crate::sealed_sender_multi_recipient_encrypt].
+pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng + Send>(
+    destinations: &[&ProtocolAddress],
+    destination_sessions: &[&SessionRecord],
+    usmc: &UnidentifiedSenderMessageContent,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    rng: &mut R,
+) -> Result<Vec<u8>> {
+    sealed_sender::sealed_sender_multi_recipient_encrypt_impl::<Threaded, _>(
+        destinations,
+        destination_sessions,
+        usmc,
+        identity_store,
+        (),
+        rng,
+    )
+    .await
+}
+
+/// See [crate::sealed_sender_decrypt_to_usmc].
+pub async fn sealed_sender_decrypt_to_usmc(
+    ciphertext: &[u8],
+    identity_store: &mut dyn SendIdentityKeyStore,
+) -> Result<UnidentifiedSenderMessageContent> {
+    sealed_sender::sealed_sender_decrypt_to_usmc_impl::<Threaded>(ciphertext, identity_store, ())
+        .await
+}
+
+/// See [crate::sealed_sender_decrypt].
+#[allow(clippy::too_many_arguments)]
+pub async fn sealed_sender_decrypt(
+    ciphertext: &[u8],
+    trust_root: &PublicKey,
+    timestamp: u64,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: DeviceId,
+    identity_store: &mut dyn SendIdentityKeyStore,
+    session_store: &mut dyn SendSessionStore,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    signed_pre_key_store: &mut dyn SendSignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn SendKyberPreKeyStore,
+    policy: &SessionPolicy,
+) -> Result<SealedSenderDecryptionResult> {
+    sealed_sender::sealed_sender_decrypt_impl::<Threaded>(
+        ciphertext,
+        trust_root,
+        timestamp,
+        local_e164,
+        local_uuid,
+        local_device_id,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        (),
+    )
+    .await
+}
+
+/// See [PreKeyManager::remaining_pre_keys].
+pub async fn remaining_pre_keys(
+    manager: &mut PreKeyManager,
+    pre_key_store: &dyn SendPreKeyStore,
+) -> Result<usize> {
+    manager
+        .remaining_pre_keys_impl::<Threaded>(pre_key_store, ())
+        .await
+}
+
+/// See [PreKeyManager::generate_pre_keys].
+pub async fn generate_pre_keys<R: Rng + CryptoRng + Send>(
+    manager: &mut PreKeyManager,
+    count: u32,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    now: SystemTime,
+    csprng: &mut R,
+) -> Result<Vec<(PreKeyId, PublicKey)>> {
+    manager
+        .generate_pre_keys_impl::<Threaded, _>(count, pre_key_store, now, csprng, ())
+        .await
+}
+
+/// See [PreKeyManager::rotate_signed_pre_key].
+pub async fn rotate_signed_pre_key<R: Rng + CryptoRng + Send>(
+    manager: &mut PreKeyManager,
+    identity_key_pair: &IdentityKeyPair,
+    signed_pre_key_store: &mut dyn SendSignedPreKeyStore,
+    now: SystemTime,
+    csprng: &mut R,
+) -> Result<SignedPreKeyUpload> {
+    manager
+        .rotate_signed_pre_key_impl::<Threaded, _>(
+            identity_key_pair,
+            signed_pre_key_store,
+            now,
+            csprng,
+            (),
+        )
+        .await
+}
+
+/// See [PreKeyManager::refresh].
+pub async fn refresh_pre_keys<R: Rng + CryptoRng + Send>(
+    manager: &mut PreKeyManager,
+    identity_key_pair: &IdentityKeyPair,
+    pre_key_store: &mut dyn SendPreKeyStore,
+    signed_pre_key_store: &mut dyn SendSignedPreKeyStore,
+    now: SystemTime,
+    csprng: &mut R,
+) -> Result<PreKeyUpload> {
+    manager
+        .refresh_impl::<Threaded, _>(
+            identity_key_pair,
+            pre_key_store,
+            signed_pre_key_store,
+            now,
+            csprng,
+            (),
+        )
+        .await
+}
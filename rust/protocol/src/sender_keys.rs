@@ -5,13 +5,28 @@
 
 use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::time::SystemTime;
 
 use itertools::Itertools;
 use prost::Message;
 
 use crate::crypto::hmac_sha256;
+use crate::policy::{self, SessionPolicy};
 use crate::proto::storage as storage_proto;
-use crate::{consts, PrivateKey, PublicKey, SignalProtocolError};
+use crate::{consts, PrivateKey, ProtocolAddress, PublicKey, SignalProtocolError};
+
+/// Why our sender key for a distribution has to be replaced before it is used again.
+///
+/// See [group_encrypt](crate::group_encrypt) and [rotate_sender_key](crate::rotate_sender_key).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, displaydoc::Display)]
+pub enum SenderKeyRotationReason {
+    /// a member who had received the sender key was removed
+    MemberRemoved,
+    /// the sender key has been used for the maximum number of messages
+    MessageLimit,
+    /// the sender key is older than the maximum age
+    Expired,
+}
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
 #[derive(Debug)]
@@ -69,6 +84,7 @@ impl SenderMessageKey {
         storage_proto::sender_key_state_structure::SenderMessageKey {
             iteration: self.iteration,
             seed: self.seed.clone(),
+            created_at: 0,
         }
     }
 }
@@ -152,6 +168,9 @@ impl SenderKeyState {
                 },
             ),
             sender_message_keys: vec![],
+            created_at: 0,
+            distributed_to: vec![],
+            member_removed: false,
         };
 
         Self { state }
@@ -206,12 +225,105 @@ impl SenderKeyState {
         self.state.clone()
     }
 
-    pub(crate) fn add_sender_message_key(&mut self, sender_message_key: &SenderMessageKey) {
+    pub(crate) fn add_sender_message_key(
+        &mut self,
+        sender_message_key: &SenderMessageKey,
+        policy: &SessionPolicy,
+        now: SystemTime,
+    ) {
+        self.state.sender_message_keys.push(
+            storage_proto::sender_key_state_structure::SenderMessageKey {
+                created_at: policy::timestamp_millis(now),
+                ..sender_message_key.as_protobuf()
+            },
+        );
+        self.trim_sender_message_keys(policy);
+    }
+
+    fn trim_sender_message_keys(&mut self, policy: &SessionPolicy) {
+        // Older keys are at the front.
+        let excess = self
+            .state
+            .sender_message_keys
+            .len()
+            .saturating_sub(policy.max_message_keys);
+        self.state.sender_message_keys.drain(..excess);
+    }
+
+    /// Discards skipped message keys that are too old or too numerous under `policy`.
+    pub(crate) fn expire_sender_message_keys(&mut self, policy: &SessionPolicy, now: SystemTime) {
         self.state
             .sender_message_keys
-            .push(sender_message_key.as_protobuf());
-        while self.state.sender_message_keys.len() > consts::MAX_MESSAGE_KEYS {
-            self.state.sender_message_keys.remove(0);
+            .retain(|key| !policy.is_skipped_key_expired(key.created_at, now));
+        self.trim_sender_message_keys(policy);
+    }
+
+    pub(crate) fn set_created_at(&mut self, now: SystemTime) {
+        self.state.created_at = policy::timestamp_millis(now);
+    }
+
+    /// Whether this state, which must be one of our own, has to be replaced under `policy`.
+    pub(crate) fn rotation_reason(
+        &self,
+        policy: &SessionPolicy,
+        now: SystemTime,
+    ) -> Option<SenderKeyRotationReason> {
+        if self.state.member_removed {
+            return Some(SenderKeyRotationReason::MemberRemoved);
+        }
+        let messages_sent = self.sender_chain_key().map_or(0, |key| key.iteration());
+        if policy
+            .max_sender_key_messages
+            .map_or(false, |max| messages_sent >= max)
+        {
+            return Some(SenderKeyRotationReason::MessageLimit);
+        }
+        if policy.is_sender_key_expired(self.state.created_at, now) {
+            return Some(SenderKeyRotationReason::Expired);
+        }
+        None
+    }
+
+    pub(crate) fn is_distributed_to(&self, address: &ProtocolAddress) -> bool {
+        self.state.distributed_to.iter().any(|recipient| {
+            recipient.name == address.name()
+                && recipient.device_id == u32::from(address.device_id())
+        })
+    }
+
+    pub(crate) fn add_distributed_to(&mut self, address: &ProtocolAddress) {
+        if !self.is_distributed_to(address) {
+            self.state
+                .distributed_to
+                .push(storage_proto::sender_key_state_structure::Recipient {
+                    name: address.name().to_owned(),
+                    device_id: address.device_id().into(),
+                });
+        }
+    }
+
+    /// Forgets that `address` was sent this state, so that it is sent again. Returns whether it
+    /// had been.
+    pub(crate) fn remove_distributed_to(&mut self, address: &ProtocolAddress) -> bool {
+        if !self.is_distributed_to(address) {
+            return false;
+        }
+        self.state.distributed_to.retain(|recipient| {
+            recipient.name != address.name()
+                || recipient.device_id != u32::from(address.device_id())
+        });
+        true
+    }
+
+    /// Forgets `address` as a recipient, requiring rotation unless it is known not to have been
+    /// sent this state.
+    ///
+    /// With no recipients recorded, the state may have been sent without being tracked, so
+    /// `address` could have it.
+    pub(crate) fn remove_recipient(&mut self, address: &ProtocolAddress) {
+        let untracked = self.state.distributed_to.is_empty();
+        if self.remove_distributed_to(address) || untracked {
+            self.state.member_removed = true;
         }
     }
 
@@ -342,6 +454,18 @@ impl SenderKeyRecord {
         initial_length - self.states.len()
     }
 
+    /// Discards state that exceeds the limits of `policy`.
+    ///
+    /// This trims the oldest sender key states, as well as skipped message keys, including keys
+    /// that have [expired](SessionPolicy::max_skipped_key_age).
+    pub fn apply_policy(&mut self, policy: &SessionPolicy) {
+        let now = SystemTime::now();
+        self.states.truncate(policy.max_sender_key_states);
+        for state in &mut self.states {
+            state.expire_sender_message_keys(policy, now);
+        }
+    }
+
     pub(crate) fn as_protobuf(&self) -> storage_proto::SenderKeyRecordStructure {
         let mut states = Vec::with_capacity(self.states.len());
         for state in &self.states {
@@ -4,14 +4,23 @@
 //
 
 use crate::{
-    Context, Direction, IdentityKeyStore, KeyPair, PreKeyBundle, PreKeyId, PreKeySignalMessage,
-    PreKeyStore, ProtocolAddress, Result, SessionRecord, SessionStore, SignalProtocolError,
-    SignedPreKeyStore,
+    CiphertextMessageType, Context, Direction, EndSessionMessage, IdentityKeyStore, KeyPair,
+    KyberPreKeyId, KyberPreKeyStore, PlaintextContent, PreKeyBundle, PreKeyId, PreKeySignalMessage,
+    PreKeyStore, ProtocolAddress, Result, SessionPolicy, SessionRecord, SessionStore,
+    SignalProtocolError, SignedPreKeyStore,
 };
 
+use crate::protocol::CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION;
 use crate::ratchet;
 use crate::ratchet::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
+use crate::state::SessionState;
+use crate::storage::flavor::{
+    Flavor, IdentityKeyStoreFor, KyberPreKeyStoreFor, Local, PreKeyStoreFor,
+    ProtocolStoreTransactionFor, SessionStoreFor, SignedPreKeyStoreFor,
+};
+use crate::storage::in_transaction;
 use rand::{CryptoRng, Rng};
+use std::time::SystemTime;
 
 /*
 These functions are on SessionBuilder in Java
@@ -22,6 +31,17 @@ its reference to the various data stores, instead the functions are
 free standing.
  */
 
+/// The pre-keys consumed by [process_prekey].
+///
+/// These should be cleaned up (see [PreKeyStore::remove_pre_key] and
+/// [KyberPreKeyStore::mark_kyber_pre_key_used]) once the new session has been saved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreKeysUsed {
+    pub pre_key_id: Option<PreKeyId>,
+    pub kyber_pre_key_id: Option<KyberPreKeyId>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn process_prekey(
     message: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
@@ -29,8 +49,36 @@ pub async fn process_prekey(
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
+    kyber_prekey_store: &mut dyn KyberPreKeyStore,
+    policy: &SessionPolicy,
     ctx: Context,
-) -> Result<Option<PreKeyId>> {
+) -> Result<PreKeysUsed> {
+    process_prekey_impl::<Local>(
+        message,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_prekey_store,
+        kyber_prekey_store,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_prekey_impl<F: Flavor>(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    pre_key_store: &mut F::PreKeyStore<'_>,
+    signed_prekey_store: &mut F::SignedPreKeyStore<'_>,
+    kyber_prekey_store: &mut F::KyberPreKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<PreKeysUsed> {
     let their_identity_key = message.identity_key();
 
     if !identity_store
@@ -47,13 +95,15 @@ pub async fn process_prekey(
         ));
     }
 
-    let unsigned_pre_key_id = process_prekey_v3(
+    let pre_keys_used = process_prekey_inner::<F>(
         message,
         remote_address,
         session_record,
         signed_prekey_store,
         pre_key_store,
+        kyber_prekey_store,
         identity_store,
+        policy,
         ctx,
     )
     .await?;
@@ -62,30 +112,43 @@ pub async fn process_prekey(
         .save_identity(remote_address, their_identity_key, ctx)
         .await?;
 
-    Ok(unsigned_pre_key_id)
+    Ok(pre_keys_used)
 }
 
-async fn process_prekey_v3(
+#[allow(clippy::too_many_arguments)]
+async fn process_prekey_inner<F: Flavor>(
     message: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
     session_record: &mut SessionRecord,
-    signed_prekey_store: &mut dyn SignedPreKeyStore,
-    pre_key_store: &mut dyn PreKeyStore,
-    identity_store: &mut dyn IdentityKeyStore,
-    ctx: Context,
-) -> Result<Option<PreKeyId>> {
+    signed_prekey_store: &mut F::SignedPreKeyStore<'_>,
+    pre_key_store: &mut F::PreKeyStore<'_>,
+    kyber_prekey_store: &mut F::KyberPreKeyStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<PreKeysUsed> {
     if session_record.has_session_state(
         message.message_version() as u32,
         &message.base_key().serialize(),
     )? {
-        // We've already setup a session for this V3 message, letting bundled message fall through
-        return Ok(None);
+        // We've already setup a session for this message, letting bundled message fall through
+        return Ok(PreKeysUsed::default());
     }
 
-    let our_signed_pre_key_pair = signed_prekey_store
+    let our_signed_pre_key = signed_prekey_store
         .get_signed_pre_key(message.signed_pre_key_id(), ctx)
-        .await?
-        .key_pair()?;
+        .await?;
+    if policy.is_signed_pre_key_expired(our_signed_pre_key.timestamp()?, SystemTime::now()) {
+        log::warn!(
+            "rejecting PreKey message from {} for expired signed prekey {}",
+            remote_address,
+            message.signed_pre_key_id()
+        );
+        return Err(SignalProtocolError::SignedPreKeyExpired(
+            message.signed_pre_key_id(),
+        ));
+    }
+    let our_signed_pre_key_pair = our_signed_pre_key.key_pair()?;
 
     let our_one_time_pre_key_pair = if let Some(pre_key_id) = message.pre_key_id() {
         log::info!("processing PreKey message from {}", remote_address);
@@ -103,7 +166,7 @@ async fn process_prekey_v3(
         None
     };
 
-    let parameters = BobSignalProtocolParameters::new(
+    let mut parameters = BobSignalProtocolParameters::new(
         identity_store.get_identity_key_pair(ctx).await?,
         our_signed_pre_key_pair, // signed pre key
         our_one_time_pre_key_pair,
@@ -112,7 +175,17 @@ async fn process_prekey_v3(
         *message.base_key(),
     );
 
-    session_record.archive_current_state()?;
+    if let Some(kyber_payload) = message.kyber_payload() {
+        let our_kyber_pre_key_pair = kyber_prekey_store
+            .get_kyber_pre_key(kyber_payload.pre_key_id(), ctx)
+            .await?
+            .key_pair()?;
+        parameters = parameters
+            .with_kyber_pre_key(our_kyber_pre_key_pair, kyber_payload.ciphertext().clone());
+    }
+
+    // Answer in the version the other party chose, in case they do not support the newest one.
+    parameters = parameters.with_session_version(message.message_version());
 
     let mut new_session = ratchet::initialize_bob_session(&parameters)?;
 
@@ -120,9 +193,12 @@ async fn process_prekey_v3(
     new_session.set_remote_registration_id(message.registration_id());
     new_session.set_alice_base_key(&message.base_key().serialize());
 
-    session_record.promote_state(new_session);
+    session_record.promote_state(new_session, policy);
 
-    Ok(message.pre_key_id())
+    Ok(PreKeysUsed {
+        pre_key_id: message.pre_key_id(),
+        kyber_pre_key_id: message.kyber_pre_key_id(),
+    })
 }
 
 pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
@@ -130,8 +206,52 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     bundle: &PreKeyBundle,
-    mut csprng: &mut R,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    process_prekey_bundle_with_policy(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        &SessionPolicy::default(),
+        ctx,
+    )
+    .await
+}
+
+/// Like [process_prekey_bundle], but keeps archived session states within the limits of `policy`
+/// rather than the default ones.
+pub async fn process_prekey_bundle_with_policy<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    policy: &SessionPolicy,
     ctx: Context,
+) -> Result<()> {
+    process_prekey_bundle_with_policy_impl::<Local, _>(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn process_prekey_bundle_with_policy_impl<F: Flavor, R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    bundle: &PreKeyBundle,
+    mut csprng: &mut R,
+    policy: &SessionPolicy,
+    ctx: F::Context,
 ) -> Result<()> {
     let their_identity_key = bundle.identity_key()?;
 
@@ -151,6 +271,18 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         return Err(SignalProtocolError::SignatureValidationFailed);
     }
 
+    if let (Some(kyber_pre_key_public), Some(kyber_pre_key_signature)) = (
+        bundle.kyber_pre_key_public()?,
+        bundle.kyber_pre_key_signature()?,
+    ) {
+        if !their_identity_key
+            .public_key()
+            .verify_signature(&kyber_pre_key_public.serialize(), kyber_pre_key_signature)?
+        {
+            return Err(SignalProtocolError::SignatureValidationFailed);
+        }
+    }
+
     let mut session_record = session_store
         .load_session(remote_address, ctx)
         .await?
@@ -164,7 +296,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
 
     let our_identity_key_pair = identity_store.get_identity_key_pair(ctx).await?;
 
-    let parameters = AliceSignalProtocolParameters::new(
+    let mut parameters = AliceSignalProtocolParameters::new(
         our_identity_key_pair,
         our_base_key_pair,
         *their_identity_key,
@@ -173,6 +305,14 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         their_signed_prekey,
     );
 
+    if let Some(their_kyber_prekey) = bundle.kyber_pre_key_public()? {
+        parameters = parameters.with_their_kyber_pre_key(their_kyber_prekey.clone());
+        // Only use AEAD message encryption with devices known to support it.
+        if !bundle.supports_aead_messages() {
+            parameters = parameters.with_session_version(CIPHERTEXT_MESSAGE_PRE_AEAD_VERSION);
+        }
+    }
+
     let mut session = ratchet::initialize_alice_session(&parameters, csprng)?;
 
     log::info!(
@@ -184,22 +324,145 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
     session.set_unacknowledged_pre_key_message(
         their_one_time_prekey_id,
         bundle.signed_pre_key_id()?,
+        bundle.kyber_pre_key_id()?,
         &our_base_key_pair.public_key,
-    );
+    )?;
 
     session.set_local_registration_id(identity_store.get_local_registration_id(ctx).await?);
     session.set_remote_registration_id(bundle.registration_id()?);
     session.set_alice_base_key(&our_base_key_pair.public_key.serialize());
 
-    identity_store
-        .save_identity(remote_address, their_identity_key, ctx)
-        .await?;
+    session_record.promote_state(session, policy);
 
-    session_record.promote_state(session);
+    in_transaction!(ctx, [identity_store, session_store], {
+        identity_store
+            .save_identity(remote_address, their_identity_key, ctx)
+            .await?;
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+        session_store
+            .store_session(remote_address, &session_record, ctx)
+            .await?;
+    })
+}
 
+/// End our current session with `remote_address`, returning the message that tells it to do the
+/// same.
+///
+/// The current session state is archived, so a new session has to be set up with
+/// [process_prekey_bundle] before the next message to `remote_address`. The returned content,
+/// stamped with `timestamp`, should be sent to `remote_address`, which passes it to
+/// [process_end_session_message]. It is only accepted for the session it ends.
+///
+/// Fails with [SignalProtocolError::SessionNotFound] if there is no current session.
+pub async fn end_session(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    timestamp: u64,
+    policy: &SessionPolicy,
+    ctx: Context,
+) -> Result<PlaintextContent> {
+    end_session_impl::<Local>(remote_address, session_store, timestamp, policy, ctx).await
+}
+
+pub(crate) async fn end_session_impl<F: Flavor>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    timestamp: u64,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<PlaintextContent> {
+    in_transaction!(ctx, [session_store], {
+        let mut record = session_store
+            .load_session(remote_address, ctx)
+            .await?
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+        let state = record
+            .session_state()
+            .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+        let message = EndSessionMessage::new(
+            timestamp,
+            state.end_session_key().ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "end_session",
+                    "session was created before end-session messages were supported".to_owned(),
+                )
+            })?,
+        );
+        record.archive_current_state_with_policy(policy)?;
+        session_store
+            .store_session(remote_address, &record, ctx)
+            .await?;
+        message.into()
+    })
+}
+
+/// Drop our current session with `remote_address`, which ended it with [end_session] and sent us
+/// `message`.
+///
+/// The session state is archived rather than deleted, so messages `remote_address` sent before
+/// ending the session can still be decrypted. Returns whether there was a current session to
+/// drop.
+///
+/// Fails with [SignalProtocolError::InvalidMessage], leaving the session as it was, if `message`
+/// was not created for the current session.
+pub async fn process_end_session_message(
+    message: &EndSessionMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    policy: &SessionPolicy,
+    ctx: Context,
+) -> Result<bool> {
+    process_end_session_message_impl::<Local>(message, remote_address, session_store, policy, ctx)
+        .await
+}
+
+pub(crate) async fn process_end_session_message_impl<F: Flavor>(
+    message: &EndSessionMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> Result<bool> {
+    in_transaction!(ctx, [session_store], {
+        match session_store.load_session(remote_address, ctx).await? {
+            Some(mut record) if record.has_current_session_state() => {
+                let state = record
+                    .session_state()
+                    .expect("checked for a current session");
+                check_end_session_message(message, remote_address, state)?;
+                log::info!(
+                    "{} ended our session at {}",
+                    remote_address,
+                    message.timestamp()
+                );
+                record.archive_current_state_with_policy(policy)?;
+                session_store
+                    .store_session(remote_address, &record, ctx)
+                    .await?;
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+fn check_end_session_message(
+    message: &EndSessionMessage,
+    remote_address: &ProtocolAddress,
+    state: &SessionState,
+) -> Result<()> {
+    if !state
+        .end_session_key()
+        .map_or(false, |key| message.verify_mac(key))
+    {
+        log::warn!(
+            "ignoring end-session message from {} for another session",
+            remote_address
+        );
+        return Err(SignalProtocolError::InvalidMessage(
+            CiphertextMessageType::Plaintext,
+            "end-session message is not for the current session",
+        ));
+    }
     Ok(())
 }
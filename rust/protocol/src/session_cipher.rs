@@ -4,24 +4,51 @@
 //
 
 use crate::{
-    CiphertextMessage, CiphertextMessageType, Context, Direction, IdentityKeyStore, KeyPair,
-    PreKeySignalMessage, PreKeyStore, ProtocolAddress, PublicKey, Result, SessionRecord,
-    SessionStore, SignalMessage, SignalProtocolError, SignedPreKeyStore,
+    CiphertextMessage, CiphertextMessageType, Context, DeviceId, Direction, IdentityKey,
+    IdentityKeyStore, KeyPair, KyberPreKeyStore, PreKeySignalMessage, PreKeyStore, ProtocolAddress,
+    PublicKey, Result, SessionPolicy, SessionRecord, SessionStore, SignalMessage,
+    SignalProtocolError, SignedPreKeyStore,
 };
 
-use crate::consts::MAX_FORWARD_JUMPS;
 use crate::ratchet::{ChainKey, MessageKeys};
 use crate::state::{InvalidSessionError, SessionState};
-use crate::{crypto, session};
+use crate::storage::flavor::{
+    Flavor, IdentityKeyStoreFor, KyberPreKeyStoreFor, Local, PreKeyStoreFor,
+    ProtocolStoreTransactionFor, SessionStoreFor,
+};
+use crate::storage::in_transaction;
+use crate::{consts, crypto, session};
 
 use rand::{CryptoRng, Rng};
+use std::collections::HashSet;
+use std::time::SystemTime;
 
 pub async fn message_encrypt(
     ptext: &[u8],
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
+    policy: &SessionPolicy,
     ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_impl::<Local>(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn message_encrypt_impl<F: Flavor>(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
 ) -> Result<CiphertextMessage> {
     let mut session_record = session_store
         .load_session(remote_address, ctx)
@@ -47,11 +74,19 @@ pub async fn message_encrypt(
         )
     })?;
 
-    let ctext = crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())
-        .map_err(|_| {
-            log::error!("session state corrupt for {}", remote_address);
-            SignalProtocolError::InvalidSessionStructure("invalid sender chain message keys")
-        })?;
+    let message = encrypt_signal_message(
+        session_version,
+        &message_keys,
+        sender_ephemeral,
+        previous_counter,
+        &policy.pad(ptext),
+        &local_identity_key,
+        &their_identity_key,
+    )
+    .map_err(|e| {
+        log::error!("session state corrupt for {}", remote_address);
+        e
+    })?;
 
     let message = if let Some(items) = session_state.unacknowledged_pre_key_message_items()? {
         let local_registration_id = session_state.local_registration_id();
@@ -64,37 +99,18 @@ pub async fn message_encrypt(
                 .map_or_else(|| "<none>".to_string(), |id| id.to_string())
         );
 
-        let message = SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?;
-
         CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::new(
             session_version,
             local_registration_id,
             items.pre_key_id(),
             items.signed_pre_key_id(),
+            items.kyber_payload().cloned(),
             *items.base_key(),
             local_identity_key,
             message,
         )?)
     } else {
-        CiphertextMessage::SignalMessage(SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?)
+        CiphertextMessage::SignalMessage(message)
     };
 
     session_state.set_sender_chain_key(&chain_key.next_chain_key());
@@ -117,17 +133,145 @@ pub async fn message_encrypt(
         ));
     }
 
-    // XXX this could be combined with the above call to the identity store (in a new API)
-    identity_store
-        .save_identity(remote_address, &their_identity_key, ctx)
-        .await?;
+    in_transaction!(ctx, [identity_store, session_store], {
+        // XXX this could be combined with the above call to the identity store (in a new API)
+        identity_store
+            .save_identity(remote_address, &their_identity_key, ctx)
+            .await?;
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+        session_store
+            .store_session(remote_address, &session_record, ctx)
+            .await?;
+    })?;
     Ok(message)
 }
 
+/// A message encrypted by [encrypt_for_all_devices] for one of the recipient's devices.
+pub struct DeviceMessage {
+    pub device_id: DeviceId,
+    /// The device's registration ID, as recorded in its session.
+    ///
+    /// Servers typically expect this alongside the message, to detect devices that have since
+    /// re-registered.
+    pub registration_id: u32,
+    pub message: CiphertextMessage,
+}
+
+/// The result of [encrypt_for_all_devices].
+///
+/// Every requested device appears in exactly one of the fields.
+#[derive(Default)]
+pub struct AllDevicesMessages {
+    /// One message for each device that could be encrypted to, in the order they were requested.
+    pub messages: Vec<DeviceMessage>,
+    /// Devices with no current session.
+    ///
+    /// A session has to be set up with [process_prekey_bundle](crate::process_prekey_bundle)
+    /// before these devices can be sent to.
+    pub missing_sessions: Vec<DeviceId>,
+    /// Devices whose session was set up with a different registration ID than the one requested,
+    /// or does not record a valid one, typically because the device has re-registered since.
+    ///
+    /// These sessions should be replaced by fetching a new pre-key bundle for the device.
+    pub stale_registration_ids: Vec<DeviceId>,
+    /// Devices that could not be encrypted to for any other reason.
+    ///
+    /// Messages for the other devices are still valid: each device's session is updated
+    /// independently.
+    pub errors: Vec<(DeviceId, SignalProtocolError)>,
+}
+
+/// Encrypt `ptext` for each of `devices` belonging to the recipient `name`.
+///
+/// Each device is given with the registration ID the recipient's account currently lists for
+/// it. Each device is encrypted to as with [message_encrypt], and so commits its own session
+/// update; a device that fails does not affect the others. The outcome for each device is
+/// reported in the result. Devices listed more than once are only encrypted to once.
+pub async fn encrypt_for_all_devices(
+    ptext: &[u8],
+    name: &str,
+    devices: &[(DeviceId, u32)],
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    policy: &SessionPolicy,
+    ctx: Context,
+) -> AllDevicesMessages {
+    encrypt_for_all_devices_impl::<Local>(
+        ptext,
+        name,
+        devices,
+        session_store,
+        identity_store,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn encrypt_for_all_devices_impl<F: Flavor>(
+    ptext: &[u8],
+    name: &str,
+    devices: &[(DeviceId, u32)],
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    policy: &SessionPolicy,
+    ctx: F::Context,
+) -> AllDevicesMessages {
+    let mut result = AllDevicesMessages::default();
+    let mut seen = HashSet::new();
+    for &(device_id, expected_registration_id) in devices {
+        if !seen.insert(device_id) {
+            continue;
+        }
+        let address = ProtocolAddress::new(name.to_owned(), device_id);
+        let registration_id = match session_store.load_session(&address, ctx).await {
+            Ok(Some(record)) if record.has_current_session_state() => {
+                match record.remote_registration_id() {
+                    Ok(registration_id) => registration_id,
+                    Err(e) => {
+                        result.errors.push((device_id, e));
+                        continue;
+                    }
+                }
+            }
+            Ok(_) => {
+                result.missing_sessions.push(device_id);
+                continue;
+            }
+            Err(e) => {
+                result.errors.push((device_id, e));
+                continue;
+            }
+        };
+        if registration_id != expected_registration_id
+            || registration_id == 0
+            || registration_id & consts::VALID_REGISTRATION_ID_MASK != registration_id
+        {
+            log::warn!(
+                "session with {} has registration ID {:X}, expected {:X}",
+                address,
+                registration_id,
+                expected_registration_id,
+            );
+            result.stale_registration_ids.push(device_id);
+            continue;
+        }
+
+        match message_encrypt_impl::<F>(ptext, &address, session_store, identity_store, policy, ctx)
+            .await
+        {
+            Ok(message) => result.messages.push(DeviceMessage {
+                device_id,
+                registration_id,
+                message,
+            }),
+            Err(e) => result.errors.push((device_id, e)),
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn message_decrypt<R: Rng + CryptoRng>(
     ciphertext: &CiphertextMessage,
     remote_address: &ProtocolAddress,
@@ -135,29 +279,62 @@ pub async fn message_decrypt<R: Rng + CryptoRng>(
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    policy: &SessionPolicy,
     csprng: &mut R,
     ctx: Context,
+) -> Result<Vec<u8>> {
+    message_decrypt_impl::<Local, _>(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn message_decrypt_impl<F: Flavor, R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    pre_key_store: &mut F::PreKeyStore<'_>,
+    signed_pre_key_store: &mut F::SignedPreKeyStore<'_>,
+    kyber_pre_key_store: &mut F::KyberPreKeyStore<'_>,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+    ctx: F::Context,
 ) -> Result<Vec<u8>> {
     match ciphertext {
         CiphertextMessage::SignalMessage(m) => {
-            message_decrypt_signal(
+            message_decrypt_signal_impl::<F, _>(
                 m,
                 remote_address,
                 session_store,
                 identity_store,
+                policy,
                 csprng,
                 ctx,
             )
             .await
         }
         CiphertextMessage::PreKeySignalMessage(m) => {
-            message_decrypt_prekey(
+            message_decrypt_prekey_impl::<F, _>(
                 m,
                 remote_address,
                 session_store,
                 identity_store,
                 pre_key_store,
                 signed_pre_key_store,
+                kyber_pre_key_store,
+                policy,
                 csprng,
                 ctx,
             )
@@ -170,6 +347,7 @@ pub async fn message_decrypt<R: Rng + CryptoRng>(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
     ciphertext: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
@@ -177,61 +355,113 @@ pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    policy: &SessionPolicy,
     csprng: &mut R,
     ctx: Context,
 ) -> Result<Vec<u8>> {
-    let mut session_record = session_store
-        .load_session(remote_address, ctx)
-        .await?
-        .unwrap_or_else(SessionRecord::new_fresh);
-
-    // Make sure we log the session state if we fail to process the pre-key.
-    let pre_key_id_or_err = session::process_prekey(
+    message_decrypt_prekey_impl::<Local, _>(
         ciphertext,
         remote_address,
-        &mut session_record,
+        session_store,
         identity_store,
         pre_key_store,
         signed_pre_key_store,
+        kyber_pre_key_store,
+        policy,
+        csprng,
         ctx,
     )
-    .await;
+    .await
+}
 
-    let pre_key_id = match pre_key_id_or_err {
-        Ok(id) => id,
-        Err(e) => {
-            let errs = [e];
-            log::error!(
-                "{}",
-                create_decryption_failure_log(
-                    remote_address,
-                    &errs,
-                    &session_record,
-                    ciphertext.message()
-                )?
-            );
-            let [e] = errs;
-            return Err(e);
-        }
-    };
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn message_decrypt_prekey_impl<F: Flavor, R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    pre_key_store: &mut F::PreKeyStore<'_>,
+    signed_pre_key_store: &mut F::SignedPreKeyStore<'_>,
+    kyber_pre_key_store: &mut F::KyberPreKeyStore<'_>,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+    ctx: F::Context,
+) -> Result<Vec<u8>> {
+    in_transaction!(
+        ctx,
+        [
+            session_store,
+            identity_store,
+            pre_key_store,
+            kyber_pre_key_store,
+        ],
+        {
+            let mut session_record = session_store
+                .load_session(remote_address, ctx)
+                .await?
+                .unwrap_or_else(SessionRecord::new_fresh);
+
+            // Make sure we log the session state if we fail to process the pre-key.
+            let pre_keys_used_or_err = session::process_prekey_impl::<F>(
+                ciphertext,
+                remote_address,
+                &mut session_record,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                kyber_pre_key_store,
+                policy,
+                ctx,
+            )
+            .await;
+
+            let pre_keys_used = match pre_keys_used_or_err {
+                Ok(id) => id,
+                Err(e) => {
+                    let errs = [e];
+                    log::error!(
+                        "{}",
+                        create_decryption_failure_log(
+                            remote_address,
+                            &errs,
+                            &session_record,
+                            ciphertext.message()
+                        )?
+                    );
+                    let [e] = errs;
+                    return Err(e);
+                }
+            };
+
+            let ptext = decrypt_message_with_record(
+                remote_address,
+                &mut session_record,
+                ciphertext.message(),
+                CiphertextMessageType::PreKey,
+                policy,
+                csprng,
+            )?;
+            // Unpad before anything is stored, so a bad message doesn't use up its keys.
+            let ptext = policy.unpad(ptext)?;
 
-    let ptext = decrypt_message_with_record(
-        remote_address,
-        &mut session_record,
-        ciphertext.message(),
-        CiphertextMessageType::PreKey,
-        csprng,
-    )?;
+            session_store
+                .store_session(remote_address, &session_record, ctx)
+                .await?;
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+            if let Some(pre_key_id) = pre_keys_used.pre_key_id {
+                pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
+            }
 
-    if let Some(pre_key_id) = pre_key_id {
-        pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
-    }
+            if let Some(kyber_pre_key_id) = pre_keys_used.kyber_pre_key_id {
+                kyber_pre_key_store
+                    .mark_kyber_pre_key_used(kyber_pre_key_id, ctx)
+                    .await?;
+            }
 
-    Ok(ptext)
+            ptext
+        }
+    )
 }
 
 pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
@@ -239,8 +469,30 @@ pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
+    policy: &SessionPolicy,
     csprng: &mut R,
     ctx: Context,
+) -> Result<Vec<u8>> {
+    message_decrypt_signal_impl::<Local, _>(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        policy,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+pub(crate) async fn message_decrypt_signal_impl<F: Flavor, R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut F::SessionStore<'_>,
+    identity_store: &mut F::IdentityKeyStore<'_>,
+    policy: &SessionPolicy,
+    csprng: &mut R,
+    ctx: F::Context,
 ) -> Result<Vec<u8>> {
     let mut session_record = session_store
         .load_session(remote_address, ctx)
@@ -252,6 +504,7 @@ pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
         &mut session_record,
         ciphertext,
         CiphertextMessageType::Whisper,
+        policy,
         csprng,
     )?;
 
@@ -285,15 +538,20 @@ pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
         ));
     }
 
-    identity_store
-        .save_identity(remote_address, &their_identity_key, ctx)
-        .await?;
+    // Unpad before anything is stored, so a bad message doesn't use up its keys.
+    let ptext = policy.unpad(ptext)?;
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+    in_transaction!(ctx, [identity_store, session_store], {
+        identity_store
+            .save_identity(remote_address, &their_identity_key, ctx)
+            .await?;
 
-    Ok(ptext)
+        session_store
+            .store_session(remote_address, &session_record, ctx)
+            .await?;
+
+        ptext
+    })
 }
 
 fn create_decryption_failure_log(
@@ -394,6 +652,7 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
     record: &mut SessionRecord,
     ciphertext: &SignalMessage,
     original_message_type: CiphertextMessageType,
+    policy: &SessionPolicy,
     csprng: &mut R,
 ) -> Result<Vec<u8>> {
     debug_assert!(matches!(
@@ -430,6 +689,7 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
             ciphertext,
             original_message_type,
             remote_address,
+            policy,
             csprng,
         );
 
@@ -468,6 +728,7 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
             ciphertext,
             original_message_type,
             remote_address,
+            policy,
             csprng,
         );
 
@@ -495,7 +756,7 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
     }
 
     if let Some((ptext, idx, updated_session)) = updated_session {
-        record.promote_old_session(idx, updated_session);
+        record.promote_old_session(idx, updated_session, policy);
         Ok(ptext)
     } else {
         let previous_state_count = || record.previous_session_states().len();
@@ -547,6 +808,7 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
     ciphertext: &SignalMessage,
     original_message_type: CiphertextMessageType,
     remote_address: &ProtocolAddress,
+    policy: &SessionPolicy,
     csprng: &mut R,
 ) -> Result<Vec<u8>> {
     if !state.has_sender_chain()? {
@@ -563,9 +825,13 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
         ));
     }
 
+    let now = SystemTime::now();
+    state.expire_message_keys(policy, now);
+
     let their_ephemeral = ciphertext.sender_ratchet_key();
     let counter = ciphertext.counter();
-    let chain_key = get_or_create_chain_key(state, their_ephemeral, remote_address, csprng)?;
+    let chain_key =
+        get_or_create_chain_key(state, their_ephemeral, remote_address, policy, csprng)?;
     let message_keys = get_or_create_message_key(
         state,
         their_ephemeral,
@@ -573,6 +839,8 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
         original_message_type,
         &chain_key,
         counter,
+        policy,
+        now,
     )?;
 
     let their_identity_key =
@@ -582,24 +850,37 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
                 "cannot decrypt without remote identity key",
             ))?;
 
-    let mac_valid = ciphertext.verify_mac(
-        &their_identity_key,
-        &state.local_identity_key()?,
-        message_keys.mac_key(),
-    )?;
+    let local_identity_key = state.local_identity_key()?;
 
-    if !mac_valid {
-        return Err(SignalProtocolError::InvalidMessage(
-            original_message_type,
-            "MAC verification failed",
-        ));
-    }
+    let ptext = if SignalMessage::uses_aead(ciphertext.message_version()) {
+        crypto::aes_256_gcm_siv_decrypt(
+            ciphertext.body(),
+            message_keys.cipher_key(),
+            aead_nonce(&message_keys),
+            &ciphertext.aead_associated_data(&their_identity_key, &local_identity_key),
+        )
+    } else {
+        let mac_valid = ciphertext.verify_mac(
+            &their_identity_key,
+            &local_identity_key,
+            message_keys.mac_key(),
+        )?;
 
-    let ptext = match crypto::aes_256_cbc_decrypt(
-        ciphertext.body(),
-        message_keys.cipher_key(),
-        message_keys.iv(),
-    ) {
+        if !mac_valid {
+            return Err(SignalProtocolError::InvalidMessage(
+                original_message_type,
+                "MAC verification failed",
+            ));
+        }
+
+        crypto::aes_256_cbc_decrypt(
+            ciphertext.body(),
+            message_keys.cipher_key(),
+            message_keys.iv(),
+        )
+    };
+
+    let ptext = match ptext {
         Ok(ptext) => ptext,
         Err(crypto::DecryptionError::BadKeyOrIv) => {
             log::warn!(
@@ -625,10 +906,65 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
     Ok(ptext)
 }
 
+/// Encrypts `ptext` with `message_keys` into a message of `session_version`.
+///
+/// Version 5 and later use AES-256-GCM-SIV with the header as associated data; earlier versions
+/// use AES-256-CBC followed by a truncated HMAC.
+fn encrypt_signal_message(
+    session_version: u8,
+    message_keys: &MessageKeys,
+    sender_ephemeral: PublicKey,
+    previous_counter: u32,
+    ptext: &[u8],
+    local_identity_key: &IdentityKey,
+    their_identity_key: &IdentityKey,
+) -> Result<SignalMessage> {
+    let ctext = if SignalMessage::uses_aead(session_version) {
+        let associated_data = SignalMessage::associated_data(
+            session_version,
+            &sender_ephemeral,
+            message_keys.counter(),
+            previous_counter,
+            local_identity_key,
+            their_identity_key,
+        );
+        crypto::aes_256_gcm_siv_encrypt(
+            ptext,
+            message_keys.cipher_key(),
+            aead_nonce(message_keys),
+            &associated_data,
+        )
+    } else {
+        crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())
+    }
+    .map_err(|_| {
+        SignalProtocolError::InvalidSessionStructure("invalid sender chain message keys")
+    })?;
+
+    SignalMessage::new(
+        session_version,
+        message_keys.mac_key(),
+        sender_ephemeral,
+        message_keys.counter(),
+        previous_counter,
+        &ctext,
+        local_identity_key,
+        their_identity_key,
+    )
+}
+
+/// The nonce for AEAD message encryption.
+///
+/// Each set of message keys is only used once, so a prefix of the derived IV is sufficient.
+fn aead_nonce(message_keys: &MessageKeys) -> &[u8] {
+    &message_keys.iv()[..crypto::AES_256_GCM_SIV_NONCE_LENGTH]
+}
+
 fn get_or_create_chain_key<R: Rng + CryptoRng>(
     state: &mut SessionState,
     their_ephemeral: &PublicKey,
     remote_address: &ProtocolAddress,
+    policy: &SessionPolicy,
     csprng: &mut R,
 ) -> Result<ChainKey> {
     if let Some(chain) = state.get_receiver_chain_key(their_ephemeral)? {
@@ -647,7 +983,7 @@ fn get_or_create_chain_key<R: Rng + CryptoRng>(
         .create_chain(their_ephemeral, &our_new_ephemeral.private_key)?;
 
     state.set_root_key(&sender_chain.0);
-    state.add_receiver_chain(their_ephemeral, &receiver_chain.1);
+    state.add_receiver_chain(their_ephemeral, &receiver_chain.1, policy);
 
     let current_index = state.get_sender_chain_key()?.index();
     let previous_index = if current_index > 0 {
@@ -668,6 +1004,8 @@ fn get_or_create_message_key(
     original_message_type: CiphertextMessageType,
     chain_key: &ChainKey,
     counter: u32,
+    policy: &SessionPolicy,
+    now: SystemTime,
 ) -> Result<MessageKeys> {
     let chain_index = chain_key.index();
 
@@ -689,7 +1027,7 @@ fn get_or_create_message_key(
 
     let jump = (counter - chain_index) as usize;
 
-    if jump > MAX_FORWARD_JUMPS {
+    if jump > policy.max_forward_jumps {
         if state.session_with_self()? {
             log::info!(
                 "{} Jumping ahead {} messages (index: {}, counter: {})",
@@ -702,7 +1040,7 @@ fn get_or_create_message_key(
             log::error!(
                 "{} Exceeded future message limit: {}, index: {}, counter: {})",
                 remote_address,
-                MAX_FORWARD_JUMPS,
+                policy.max_forward_jumps,
                 chain_index,
                 counter
             );
@@ -717,10 +1055,149 @@ fn get_or_create_message_key(
 
     while chain_key.index() < counter {
         let message_keys = chain_key.message_keys();
-        state.set_message_keys(their_ephemeral, &message_keys)?;
+        state.set_message_keys(their_ephemeral, &message_keys, policy, now)?;
         chain_key = chain_key.next_chain_key();
     }
 
     state.set_receiver_chain_key(their_ephemeral, &chain_key.next_chain_key())?;
     Ok(chain_key.message_keys())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrivateKey;
+    use std::convert::TryFrom;
+
+    fn public_key(private_key_byte: u8) -> PublicKey {
+        PrivateKey::deserialize(&[private_key_byte; 32])
+            .expect("valid private key")
+            .public_key()
+            .expect("can derive public key")
+    }
+
+    fn encrypt_test_vector(session_version: u8) -> Result<SignalMessage> {
+        encrypt_signal_message(
+            session_version,
+            &MessageKeys::new([4; 32], [5; 32], [6; 16], 7),
+            public_key(3),
+            6,
+            b"test vector",
+            &public_key(1).into(),
+            &public_key(2).into(),
+        )
+    }
+
+    #[test]
+    fn test_signal_message_vectors() -> Result<()> {
+        let sender_identity_key: IdentityKey = public_key(1).into();
+        let receiver_identity_key: IdentityKey = public_key(2).into();
+        let message_keys = MessageKeys::new([4; 32], [5; 32], [6; 16], 7);
+
+        // Computed outside this crate with Python's `cryptography` package, following the layouts
+        // documented on [encrypt_signal_message] and [SignalMessage::associated_data]: the
+        // protobuf header, then AES-256-CBC with an HMAC-SHA256 truncated to 8 bytes over both
+        // identity keys and the message, or AES-256-GCM-SIV with the IV's first 12 bytes as nonce.
+        let vectors = [
+            (
+                3,
+                "350a21055dfedd3b6bd47f6fa28ee15d969d5bb0ea53774d488bdaf9df1c6e0124b3ef22100718062210",
+                "04ec4caa3195150379802eb17286fa7471d8dd0cf025a11a",
+            ),
+            (
+                4,
+                "450a21055dfedd3b6bd47f6fa28ee15d969d5bb0ea53774d488bdaf9df1c6e0124b3ef22100718062210",
+                "04ec4caa3195150379802eb17286fa746f6b5396b8c80223",
+            ),
+            // Versions 3 and 4 share a ciphertext but not a MAC, which covers the version byte.
+            // Version 5 has no MAC, but a longer authentication tag.
+            (
+                5,
+                "550a21055dfedd3b6bd47f6fa28ee15d969d5bb0ea53774d488bdaf9df1c6e0124b3ef2210071806221b",
+                "4eaf1e436d17c30a2dc6d7626d5705e732937625daef5aee765c25",
+            ),
+        ];
+
+        for (session_version, expected_header, expected_body) in vectors {
+            let message = encrypt_test_vector(session_version)?;
+            assert_eq!(
+                hex::encode(message.serialized()),
+                format!("{}{}", expected_header, expected_body),
+                "v{}",
+                session_version
+            );
+
+            let message = SignalMessage::try_from(message.serialized())?;
+            assert_eq!(message.message_version(), session_version);
+            assert_eq!(message.counter(), 7);
+            let ptext = if SignalMessage::uses_aead(session_version) {
+                crypto::aes_256_gcm_siv_decrypt(
+                    message.body(),
+                    message_keys.cipher_key(),
+                    aead_nonce(&message_keys),
+                    &message.aead_associated_data(&sender_identity_key, &receiver_identity_key),
+                )
+            } else {
+                assert!(message.verify_mac(
+                    &sender_identity_key,
+                    &receiver_identity_key,
+                    message_keys.mac_key()
+                )?);
+                crypto::aes_256_cbc_decrypt(
+                    message.body(),
+                    message_keys.cipher_key(),
+                    message_keys.iv(),
+                )
+            }
+            .expect("valid");
+            assert_eq!(ptext, b"test vector");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_aead_message_authenticates_header() -> Result<()> {
+        let sender_identity_key: IdentityKey = public_key(1).into();
+        let receiver_identity_key: IdentityKey = public_key(2).into();
+        let message_keys = MessageKeys::new([4; 32], [5; 32], [6; 16], 7);
+        let message = encrypt_test_vector(5)?;
+
+        assert!(matches!(
+            message.verify_mac(
+                &sender_identity_key,
+                &receiver_identity_key,
+                message_keys.mac_key()
+            ),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        // Claiming a different counter for the same ciphertext is detected.
+        let forged = SignalMessage::new(
+            5,
+            message_keys.mac_key(),
+            public_key(3),
+            8,
+            6,
+            message.body(),
+            &sender_identity_key,
+            &receiver_identity_key,
+        )?;
+        assert!(crypto::aes_256_gcm_siv_decrypt(
+            forged.body(),
+            message_keys.cipher_key(),
+            aead_nonce(&message_keys),
+            &forged.aead_associated_data(&sender_identity_key, &receiver_identity_key),
+        )
+        .is_err());
+
+        // So is a message between different parties.
+        assert!(crypto::aes_256_gcm_siv_decrypt(
+            message.body(),
+            message_keys.cipher_key(),
+            aead_nonce(&message_keys),
+            &message.aead_associated_data(&receiver_identity_key, &sender_identity_key),
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -4,12 +4,17 @@
 //
 
 mod bundle;
+mod kyber_prekey;
 mod prekey;
 mod session;
 mod signed_prekey;
 
 pub use bundle::PreKeyBundle;
+pub use kyber_prekey::{KyberPreKeyId, KyberPreKeyRecord};
 pub use prekey::{PreKeyId, PreKeyRecord};
-pub use session::SessionRecord;
 pub(crate) use session::{InvalidSessionError, SessionState};
+pub use session::{
+    ReceiverChainSnapshot, SenderChainSnapshot, SessionRecord, SessionSnapshot,
+    SessionStateSnapshot,
+};
 pub use signed_prekey::{SignedPreKeyId, SignedPreKeyRecord};
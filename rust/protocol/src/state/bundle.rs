@@ -3,8 +3,15 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::state::{PreKeyId, SignedPreKeyId};
-use crate::{DeviceId, IdentityKey, PublicKey, Result};
+use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
+use crate::{kem, DeviceId, IdentityKey, PublicKey, Result};
+
+#[derive(Debug, Clone)]
+struct SignedKyberPreKey {
+    id: KyberPreKeyId,
+    public_key: kem::PublicKey,
+    signature: Vec<u8>,
+}
 
 #[derive(Debug, Clone)]
 pub struct PreKeyBundle {
@@ -16,6 +23,8 @@ pub struct PreKeyBundle {
     signed_pre_key_public: PublicKey,
     signed_pre_key_signature: Vec<u8>,
     identity_key: IdentityKey,
+    kyber_pre_key: Option<SignedKyberPreKey>,
+    supports_aead_messages: bool,
 }
 
 impl PreKeyBundle {
@@ -42,9 +51,36 @@ impl PreKeyBundle {
             signed_pre_key_public,
             signed_pre_key_signature,
             identity_key,
+            kyber_pre_key: None,
+            supports_aead_messages: false,
         })
     }
 
+    /// Adds a signed Kyber pre-key, so that sessions started from this bundle use PQXDH.
+    pub fn with_kyber_pre_key(
+        mut self,
+        pre_key_id: KyberPreKeyId,
+        public_key: kem::PublicKey,
+        signature: Vec<u8>,
+    ) -> Self {
+        self.kyber_pre_key = Some(SignedKyberPreKey {
+            id: pre_key_id,
+            public_key,
+            signature,
+        });
+        self
+    }
+
+    /// Records that the bundle's device can decrypt version 5 messages, encrypted with
+    /// AES-256-GCM-SIV, as advertised alongside the bundle.
+    ///
+    /// PQXDH sessions started from bundles without this use version 4 instead, which every device
+    /// with a Kyber pre-key supports. It has no effect on bundles without a Kyber pre-key.
+    pub fn with_aead_message_support(mut self) -> Self {
+        self.supports_aead_messages = true;
+        self
+    }
+
     pub fn registration_id(&self) -> Result<u32> {
         Ok(self.registration_id)
     }
@@ -76,4 +112,30 @@ impl PreKeyBundle {
     pub fn identity_key(&self) -> Result<&IdentityKey> {
         Ok(&self.identity_key)
     }
+
+    pub fn has_kyber_pre_key(&self) -> bool {
+        self.kyber_pre_key.is_some()
+    }
+
+    pub fn kyber_pre_key_id(&self) -> Result<Option<KyberPreKeyId>> {
+        Ok(self.kyber_pre_key.as_ref().map(|pre_key| pre_key.id))
+    }
+
+    pub fn kyber_pre_key_public(&self) -> Result<Option<&kem::PublicKey>> {
+        Ok(self
+            .kyber_pre_key
+            .as_ref()
+            .map(|pre_key| &pre_key.public_key))
+    }
+
+    pub fn kyber_pre_key_signature(&self) -> Result<Option<&[u8]>> {
+        Ok(self
+            .kyber_pre_key
+            .as_ref()
+            .map(|pre_key| pre_key.signature.as_ref()))
+    }
+
+    pub fn supports_aead_messages(&self) -> bool {
+        self.supports_aead_messages
+    }
 }
@@ -0,0 +1,97 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use crate::proto::storage::SignedPreKeyRecordStructure;
+use crate::{kem, Result, SignalProtocolError};
+
+use prost::Message;
+
+use std::fmt;
+
+/// A unique identifier selecting among this client's known Kyber pre-keys.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct KyberPreKeyId(u32);
+
+impl From<u32> for KyberPreKeyId {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
+impl From<KyberPreKeyId> for u32 {
+    fn from(value: KyberPreKeyId) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for KyberPreKeyId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A KEM key pair published for PQXDH, signed by the owner's identity key.
+///
+/// Kyber pre-keys are stored using the same structure as signed pre-keys. Whether a particular
+/// key is "one-time" or "last-resort" is up to the [crate::KyberPreKeyStore] holding it.
+#[derive(Debug, Clone)]
+pub struct KyberPreKeyRecord {
+    kyber_pre_key: SignedPreKeyRecordStructure,
+}
+
+impl KyberPreKeyRecord {
+    pub fn new(id: KyberPreKeyId, timestamp: u64, key: &kem::KeyPair, signature: &[u8]) -> Self {
+        let public_key = key.public_key.serialize().to_vec();
+        let private_key = key.secret_key.serialize().to_vec();
+        let signature = signature.to_vec();
+        Self {
+            kyber_pre_key: SignedPreKeyRecordStructure {
+                id: id.into(),
+                timestamp,
+                public_key,
+                private_key,
+                signature,
+            },
+        }
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            kyber_pre_key: SignedPreKeyRecordStructure::decode(data)
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?,
+        })
+    }
+
+    pub fn id(&self) -> Result<KyberPreKeyId> {
+        Ok(self.kyber_pre_key.id.into())
+    }
+
+    pub fn timestamp(&self) -> Result<u64> {
+        Ok(self.kyber_pre_key.timestamp)
+    }
+
+    pub fn signature(&self) -> Result<Vec<u8>> {
+        Ok(self.kyber_pre_key.signature.clone())
+    }
+
+    pub fn public_key(&self) -> Result<kem::PublicKey> {
+        kem::PublicKey::deserialize(&self.kyber_pre_key.public_key)
+    }
+
+    pub fn secret_key(&self) -> Result<kem::SecretKey> {
+        kem::SecretKey::deserialize(&self.kyber_pre_key.private_key)
+    }
+
+    pub fn key_pair(&self) -> Result<kem::KeyPair> {
+        kem::KeyPair::from_public_and_private(
+            &self.kyber_pre_key.public_key,
+            &self.kyber_pre_key.private_key,
+        )
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.kyber_pre_key.encode_to_vec())
+    }
+}
@@ -46,10 +46,22 @@ impl PreKeyRecord {
                 id: id.into(),
                 public_key,
                 private_key,
+                timestamp: 0,
             },
         }
     }
 
+    /// Records when this pre-key was created, in milliseconds since the epoch.
+    ///
+    /// Records created without a timestamp report 0, meaning the creation time is unknown. Like
+    /// the age limits in [SessionPolicy](crate::SessionPolicy), pruning queries such as
+    /// [PreKeyStore::pre_key_ids_created_before](crate::PreKeyStore::pre_key_ids_created_before)
+    /// never treat such records as old.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.pre_key.timestamp = timestamp;
+        self
+    }
+
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         Ok(Self {
             pre_key: PreKeyRecordStructure::decode(data)
@@ -61,6 +73,10 @@ impl PreKeyRecord {
         Ok(self.pre_key.id.into())
     }
 
+    pub fn timestamp(&self) -> Result<u64> {
+        Ok(self.pre_key.timestamp)
+    }
+
     pub fn key_pair(&self) -> Result<KeyPair> {
         KeyPair::from_public_and_private(&self.pre_key.public_key, &self.pre_key.private_key)
     }
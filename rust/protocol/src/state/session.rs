@@ -4,17 +4,20 @@
 //
 
 use std::convert::TryInto;
+use std::fmt;
 use std::result::Result;
+use std::time::SystemTime;
 
 use prost::Message;
+use sha2::{Digest, Sha256};
 use subtle::ConstantTimeEq;
 
 use crate::ratchet::{ChainKey, MessageKeys, RootKey};
-use crate::{IdentityKey, KeyPair, PrivateKey, PublicKey, SignalProtocolError};
+use crate::{kem, IdentityKey, KeyPair, KyberPayload, PrivateKey, PublicKey, SignalProtocolError};
 
-use crate::consts;
+use crate::policy::{self, SessionPolicy};
 use crate::proto::storage::{session_structure, RecordStructure, SessionStructure};
-use crate::state::{PreKeyId, SignedPreKeyId};
+use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
 #[derive(Debug)]
@@ -37,6 +40,7 @@ pub(crate) struct UnacknowledgedPreKeyMessageItems {
     pre_key_id: Option<PreKeyId>,
     signed_pre_key_id: SignedPreKeyId,
     base_key: PublicKey,
+    kyber_payload: Option<KyberPayload>,
 }
 
 impl UnacknowledgedPreKeyMessageItems {
@@ -44,11 +48,13 @@ impl UnacknowledgedPreKeyMessageItems {
         pre_key_id: Option<PreKeyId>,
         signed_pre_key_id: SignedPreKeyId,
         base_key: PublicKey,
+        kyber_payload: Option<KyberPayload>,
     ) -> Self {
         Self {
             pre_key_id,
             signed_pre_key_id,
             base_key,
+            kyber_payload,
         }
     }
 
@@ -63,6 +69,10 @@ impl UnacknowledgedPreKeyMessageItems {
     pub(crate) fn base_key(&self) -> &PublicKey {
         &self.base_key
     }
+
+    pub(crate) fn kyber_payload(&self) -> Option<&KyberPayload> {
+        self.kyber_payload.as_ref()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -85,6 +95,15 @@ impl SessionState {
         self.session.alice_base_key = key.to_vec();
     }
 
+    /// The key authenticating [EndSessionMessages](crate::EndSessionMessage), or `None` for
+    /// sessions created before they existed.
+    pub(crate) fn end_session_key(&self) -> Option<&[u8]> {
+        match self.session.end_session_key.len() {
+            0 => None,
+            _ => Some(&self.session.end_session_key),
+        }
+    }
+
     pub(crate) fn session_version(&self) -> Result<u32, InvalidSessionError> {
         match self.session.session_version {
             0 => Ok(2),
@@ -220,7 +239,12 @@ impl SessionState {
         }
     }
 
-    pub(crate) fn add_receiver_chain(&mut self, sender: &PublicKey, chain_key: &ChainKey) {
+    pub(crate) fn add_receiver_chain(
+        &mut self,
+        sender: &PublicKey,
+        chain_key: &ChainKey,
+        policy: &SessionPolicy,
+    ) {
         let chain_key = session_structure::chain::ChainKey {
             index: chain_key.index(),
             key: chain_key.key().to_vec(),
@@ -234,18 +258,42 @@ impl SessionState {
         };
 
         self.session.receiver_chains.push(chain);
+        self.trim_receiver_chains(policy);
+    }
 
-        if self.session.receiver_chains.len() > consts::MAX_RECEIVER_CHAINS {
+    fn trim_receiver_chains(&mut self, policy: &SessionPolicy) {
+        let excess = self
+            .session
+            .receiver_chains
+            .len()
+            .saturating_sub(policy.max_receiver_chains);
+        if excess > 0 {
             log::info!(
                 "Trimming excessive receiver_chain for session with base key {}, chain count: {}",
                 self.sender_ratchet_key_for_logging()
                     .unwrap_or_else(|e| format!("<error: {}>", e.0)),
                 self.session.receiver_chains.len()
             );
-            self.session.receiver_chains.remove(0);
+            self.session.receiver_chains.drain(..excess);
         }
     }
 
+    /// Discards skipped message keys that are too old or too numerous under `policy`.
+    pub(crate) fn expire_message_keys(&mut self, policy: &SessionPolicy, now: SystemTime) {
+        for chain in &mut self.session.receiver_chains {
+            chain
+                .message_keys
+                .retain(|key| !policy.is_skipped_key_expired(key.created_at, now));
+            // Newer keys are at the front.
+            chain.message_keys.truncate(policy.max_message_keys);
+        }
+    }
+
+    fn apply_policy(&mut self, policy: &SessionPolicy, now: SystemTime) {
+        self.trim_receiver_chains(policy);
+        self.expire_message_keys(policy, now);
+    }
+
     pub(crate) fn set_sender_chain(&mut self, sender: &KeyPair, next_chain_key: &ChainKey) {
         let chain_key = session_structure::chain::ChainKey {
             index: next_chain_key.index(),
@@ -352,12 +400,15 @@ impl SessionState {
         &mut self,
         sender: &PublicKey,
         message_keys: &MessageKeys,
+        policy: &SessionPolicy,
+        now: SystemTime,
     ) -> Result<(), InvalidSessionError> {
         let new_keys = session_structure::chain::MessageKey {
             cipher_key: message_keys.cipher_key().to_vec(),
             mac_key: message_keys.mac_key().to_vec(),
             iv: message_keys.iv().to_vec(),
             index: message_keys.counter(),
+            created_at: policy::timestamp_millis(now),
         };
 
         let chain_and_index = self
@@ -366,9 +417,7 @@ impl SessionState {
         let mut updated_chain = chain_and_index.0;
         updated_chain.message_keys.insert(0, new_keys);
 
-        if updated_chain.message_keys.len() > consts::MAX_MESSAGE_KEYS {
-            updated_chain.message_keys.pop();
-        }
+        updated_chain.message_keys.truncate(policy.max_message_keys);
 
         self.session.receiver_chains[chain_and_index.1] = updated_chain;
 
@@ -394,12 +443,35 @@ impl SessionState {
         Ok(())
     }
 
+    /// Records the ciphertext Alice produced for Bob's Kyber pre-key during PQXDH.
+    ///
+    /// The ID of that pre-key is filled in by [Self::set_unacknowledged_pre_key_message].
+    pub(crate) fn set_kyber_ciphertext(&mut self, ciphertext: kem::SerializedCiphertext) {
+        self.session.pending_kyber_pre_key = Some(session_structure::PendingKyberPreKey {
+            pre_key_id: 0,
+            ciphertext: ciphertext.into_vec(),
+        });
+    }
+
     pub(crate) fn set_unacknowledged_pre_key_message(
         &mut self,
         pre_key_id: Option<PreKeyId>,
         signed_pre_key_id: SignedPreKeyId,
+        kyber_pre_key_id: Option<KyberPreKeyId>,
         base_key: &PublicKey,
-    ) {
+    ) -> Result<(), InvalidSessionError> {
+        match (
+            kyber_pre_key_id,
+            self.session.pending_kyber_pre_key.as_mut(),
+        ) {
+            (Some(kyber_pre_key_id), Some(pending_kyber_pre_key)) => {
+                pending_kyber_pre_key.pre_key_id = kyber_pre_key_id.into();
+            }
+            (None, None) => {}
+            (Some(_), None) => return Err(InvalidSessionError("missing Kyber ciphertext")),
+            (None, Some(_)) => return Err(InvalidSessionError("missing Kyber pre-key ID")),
+        }
+
         let signed_pre_key_id: u32 = signed_pre_key_id.into();
         let pending = session_structure::PendingPreKey {
             pre_key_id: pre_key_id.map(PreKeyId::into).unwrap_or(0),
@@ -407,12 +479,19 @@ impl SessionState {
             base_key: base_key.serialize().to_vec(),
         };
         self.session.pending_pre_key = Some(pending);
+        Ok(())
     }
 
     pub(crate) fn unacknowledged_pre_key_message_items(
         &self,
     ) -> Result<Option<UnacknowledgedPreKeyMessageItems>, InvalidSessionError> {
         if let Some(ref pending_pre_key) = self.session.pending_pre_key {
+            let kyber_payload = self.session.pending_kyber_pre_key.as_ref().map(|pending| {
+                KyberPayload::new(
+                    pending.pre_key_id.into(),
+                    pending.ciphertext.clone().into_boxed_slice(),
+                )
+            });
             Ok(Some(UnacknowledgedPreKeyMessageItems::new(
                 match pending_pre_key.pre_key_id {
                     0 => None,
@@ -421,6 +500,7 @@ impl SessionState {
                 (pending_pre_key.signed_pre_key_id as u32).into(),
                 PublicKey::deserialize(&pending_pre_key.base_key)
                     .map_err(|_| InvalidSessionError("invalid pending PreKey message base key"))?,
+                kyber_payload,
             )))
         } else {
             Ok(None)
@@ -429,6 +509,38 @@ impl SessionState {
 
     pub(crate) fn clear_unacknowledged_pre_key_message(&mut self) {
         self.session.pending_pre_key = None;
+        self.session.pending_kyber_pre_key = None;
+    }
+
+    fn snapshot(&self) -> Result<SessionStateSnapshot, InvalidSessionError> {
+        let sender_chain = self
+            .session
+            .sender_chain
+            .as_ref()
+            .map(|chain| SenderChainSnapshot {
+                ratchet_key_fingerprint: key_fingerprint(&chain.sender_ratchet_key),
+                chain_index: chain.chain_key.as_ref().map(|chain_key| chain_key.index),
+            });
+        let receiver_chains = self
+            .session
+            .receiver_chains
+            .iter()
+            .map(|chain| ReceiverChainSnapshot {
+                ratchet_key_fingerprint: key_fingerprint(&chain.sender_ratchet_key),
+                chain_index: chain.chain_key.as_ref().map(|chain_key| chain_key.index),
+                skipped_message_keys: chain.message_keys.len(),
+            })
+            .collect();
+        Ok(SessionStateSnapshot {
+            version: self.session_version()?,
+            base_key_fingerprint: key_fingerprint(self.alice_base_key()),
+            local_registration_id: self.local_registration_id(),
+            remote_registration_id: self.remote_registration_id(),
+            previous_counter: self.previous_counter(),
+            sender_chain,
+            receiver_chains,
+            has_unacknowledged_pre_key_message: self.session.pending_pre_key.is_some(),
+        })
     }
 
     pub(crate) fn set_remote_registration_id(&mut self, registration_id: u32) {
@@ -466,6 +578,108 @@ impl From<&SessionState> for SessionStructure {
     }
 }
 
+/// A short, stable identifier for a public key, safe to include in logs and bug reports.
+///
+/// Both parties to a session compute the same fingerprint for the same key.
+fn key_fingerprint(key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(key)[..8])
+}
+
+/// A read-only summary of a [SessionRecord], for diagnosing decryption failures.
+///
+/// Contains no key material, only [fingerprints](SessionStateSnapshot::base_key_fingerprint) of
+/// public keys and counters, so it can be logged freely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    /// The state used for sending, if any.
+    pub current: Option<SessionStateSnapshot>,
+    /// Archived states that may still be used for decryption, most recent first.
+    pub archived: Vec<SessionStateSnapshot>,
+}
+
+/// A summary of one state within a [SessionRecord]; see [SessionSnapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStateSnapshot {
+    /// The session version, e.g. 3 for X3DH or 4 or 5 for PQXDH.
+    pub version: u32,
+    /// Identifies the base key that created this state; matches the peer's view of the same state.
+    pub base_key_fingerprint: String,
+    pub local_registration_id: u32,
+    pub remote_registration_id: u32,
+    /// The length of the previous sending chain, as sent in outgoing messages.
+    pub previous_counter: u32,
+    pub sender_chain: Option<SenderChainSnapshot>,
+    /// Receiver chains, oldest first.
+    pub receiver_chains: Vec<ReceiverChainSnapshot>,
+    /// Whether outgoing messages are still sent as PreKeySignalMessages because the peer has not
+    /// yet responded.
+    pub has_unacknowledged_pre_key_message: bool,
+}
+
+/// A summary of the sending chain of a [SessionStateSnapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SenderChainSnapshot {
+    pub ratchet_key_fingerprint: String,
+    /// The counter of the next message to be sent.
+    pub chain_index: Option<u32>,
+}
+
+/// A summary of one receiving chain of a [SessionStateSnapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiverChainSnapshot {
+    pub ratchet_key_fingerprint: String,
+    /// The counter of the next message expected on this chain.
+    pub chain_index: Option<u32>,
+    /// How many keys are saved for messages skipped over on this chain.
+    pub skipped_message_keys: usize,
+}
+
+impl fmt::Display for SessionStateSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "v{} base_key={} reg_ids={}->{} prev_counter={}",
+            self.version,
+            self.base_key_fingerprint,
+            self.local_registration_id,
+            self.remote_registration_id,
+            self.previous_counter,
+        )?;
+        if self.has_unacknowledged_pre_key_message {
+            write!(f, " unacknowledged")?;
+        }
+        match &self.sender_chain {
+            Some(chain) => write!(
+                f,
+                "\n  sender {} index={:?}",
+                chain.ratchet_key_fingerprint, chain.chain_index
+            )?,
+            None => write!(f, "\n  no sender chain")?,
+        }
+        for chain in &self.receiver_chains {
+            write!(
+                f,
+                "\n  receiver {} index={:?} skipped={}",
+                chain.ratchet_key_fingerprint, chain.chain_index, chain.skipped_message_keys
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SessionSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.current {
+            Some(state) => write!(f, "current: {}", state)?,
+            None => write!(f, "current: none")?,
+        }
+        for (i, state) in self.archived.iter().enumerate() {
+            write!(f, "\narchived[{}]: {}", i, state)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct SessionRecord {
     current_session: Option<SessionState>,
@@ -565,31 +779,80 @@ impl SessionRecord {
         &mut self,
         old_session: usize,
         updated_session: SessionState,
+        policy: &SessionPolicy,
     ) {
         self.previous_sessions.remove(old_session);
-        self.promote_state(updated_session)
+        self.promote_state(updated_session, policy)
     }
 
-    pub(crate) fn promote_state(&mut self, new_state: SessionState) {
-        self.archive_current_state_inner();
+    pub(crate) fn promote_state(&mut self, new_state: SessionState, policy: &SessionPolicy) {
+        self.archive_current_state_inner(policy);
         self.current_session = Some(new_state);
     }
 
     // A non-fallible version of archive_current_state.
-    fn archive_current_state_inner(&mut self) {
+    fn archive_current_state_inner(&mut self, policy: &SessionPolicy) {
         if let Some(current_session) = self.current_session.take() {
-            if self.previous_sessions.len() >= consts::ARCHIVED_STATES_MAX_LENGTH {
-                self.previous_sessions.pop();
-            }
             self.previous_sessions
                 .insert(0, current_session.session.encode_to_vec());
+            self.previous_sessions
+                .truncate(policy.archived_states_max_length);
         } else {
             log::info!("Skipping archive, current session state is fresh",);
         }
     }
 
+    /// Summarizes the current and archived states of this record.
+    pub fn snapshot(&self) -> Result<SessionSnapshot, SignalProtocolError> {
+        let current = self
+            .current_session
+            .as_ref()
+            .map(SessionState::snapshot)
+            .transpose()?;
+        let archived = self
+            .previous_session_states()
+            .map(|state| state?.snapshot())
+            .collect::<Result<_, _>>()?;
+        Ok(SessionSnapshot { current, archived })
+    }
+
+    /// Moves the current session state into the archived states, keeping at most the default
+    /// number of archived states.
+    ///
+    /// Use [`archive_current_state_with_policy`](Self::archive_current_state_with_policy) to
+    /// keep a different number.
     pub fn archive_current_state(&mut self) -> Result<(), SignalProtocolError> {
-        self.archive_current_state_inner();
+        self.archive_current_state_with_policy(&SessionPolicy::default())
+    }
+
+    /// Moves the current session state into the archived states, keeping at most
+    /// `policy.archived_states_max_length` of them.
+    pub fn archive_current_state_with_policy(
+        &mut self,
+        policy: &SessionPolicy,
+    ) -> Result<(), SignalProtocolError> {
+        self.archive_current_state_inner(policy);
+        Ok(())
+    }
+
+    /// Discards state that exceeds the limits of `policy`.
+    ///
+    /// This trims archived states, receiver chains and skipped message keys, including keys that
+    /// have [expired](SessionPolicy::max_skipped_key_age).
+    pub fn apply_policy(&mut self, policy: &SessionPolicy) -> Result<(), SignalProtocolError> {
+        let now = SystemTime::now();
+        if let Some(current_session) = &mut self.current_session {
+            current_session.apply_policy(policy, now);
+        }
+        self.previous_sessions
+            .truncate(policy.archived_states_max_length);
+        for previous in &mut self.previous_sessions {
+            let mut state: SessionState = SessionStructure::decode(&previous[..])
+                .map_err(|_| InvalidSessionError("failed to decode previous session protobuf"))?
+                .into();
+            state.apply_policy(policy, now);
+            *previous = state.session.encode_to_vec();
+        }
         Ok(())
     }
 
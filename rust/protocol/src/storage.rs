@@ -4,17 +4,36 @@
 //
 
 //! Interfaces in [traits] and reference implementations in [inmem] for various mutable stores.
+//!
+//! [EncryptedRecordStore] encrypts records before passing them to any [SendRecordStore], and the
+//! `sqlite` feature adds persistent implementations backed by a SQLite database.
 
 #![warn(missing_docs)]
 
+mod encrypted;
+pub(crate) mod flavor;
 mod inmem;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod traits;
 
+pub use encrypted::{EncryptedRecordStore, RecordCipher};
 pub use inmem::{
-    InMemIdentityKeyStore, InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore,
-    InMemSignalProtocolStore, InMemSignedPreKeyStore,
+    InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemPreKeyStore, InMemRecordStore,
+    InMemResendLogStore, InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore,
+    InMemSignedPreKeyStore,
 };
+#[cfg(feature = "sqlite")]
+pub use sqlite::{
+    SqliteIdentityKeyStore, SqliteKyberPreKeyStore, SqlitePreKeyStore, SqliteResendLogStore,
+    SqliteSenderKeyStore, SqliteSessionStore, SqliteSignalProtocolStore, SqliteSignedPreKeyStore,
+};
+pub(crate) use traits::in_transaction;
 pub use traits::{
-    Context, Direction, IdentityKeyStore, PreKeyStore, ProtocolStore, SenderKeyStore, SessionStore,
-    SignedPreKeyStore,
+    Context, Direction, IdentityChange, IdentityKeyStore, KyberPreKeyStore, PreKeyStore,
+    ProtocolStore, ProtocolStoreTransaction, RecordKind, RecordStore, ResendLogStore,
+    SendIdentityKeyStore, SendKyberPreKeyStore, SendPreKeyStore, SendProtocolStore,
+    SendProtocolStoreTransaction, SendRecordStore, SendResendLogStore, SendSenderKeyStore,
+    SendSessionStore, SendSignedPreKeyStore, SenderKeyStore, SessionStore, SignedPreKeyStore,
+    VerifiedStatus,
 };
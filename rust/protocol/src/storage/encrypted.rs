@@ -0,0 +1,611 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Encryption of records at rest, on top of any [SendRecordStore].
+//!
+//! Session, pre-key, and sender key records contain private keys, so stores that persist them
+//! usually need to encrypt them first. [EncryptedRecordStore] does this for any store that
+//! implements [SendRecordStore], so that the same encryption is used on every platform.
+
+use crate::policy::is_created_before;
+use crate::storage::{in_transaction, traits, RecordKind, SendRecordStore};
+use crate::{
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, ProtocolAddress, Result,
+    SenderKeyRecord, SessionRecord, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+};
+
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+use aes_gcm_siv::{Aes256GcmSiv, Key, Nonce};
+use async_trait::async_trait;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng};
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use uuid::Uuid;
+
+const RECORD_CIPHER_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 1 + KEY_ID_LEN + NONCE_LEN;
+
+/// Encrypts and decrypts serialized records with AES-256-GCM-SIV.
+///
+/// Each key has an id, which is stored alongside every record encrypted with it, so that records
+/// encrypted with an older key can still be read after the key is rotated. Each record is bound to
+/// its [RecordKind] and id, so a record cannot be passed off as a different one.
+///
+/// An encrypted record is laid out as `version || key_id || nonce || ciphertext || tag`, and is
+/// authenticated along with `version || key_id || kind || id`.
+#[derive(Clone)]
+pub struct RecordCipher {
+    key_id: u32,
+    key: [u8; 32],
+    previous_keys: Vec<(u32, [u8; 32])>,
+}
+
+impl RecordCipher {
+    /// Create a cipher that encrypts records with `key`, identified by `key_id`.
+    pub fn new(key_id: u32, key: [u8; 32]) -> Self {
+        Self {
+            key_id,
+            key,
+            previous_keys: Vec::new(),
+        }
+    }
+
+    /// Also decrypt records that were encrypted with the older `key`, identified by `key_id`.
+    pub fn with_previous_key(mut self, key_id: u32, key: [u8; 32]) -> Self {
+        self.previous_keys.push((key_id, key));
+        self
+    }
+
+    /// The id of the key used to encrypt new records.
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Encrypt `record`, which is stored under `kind` and `id`.
+    pub fn encrypt<R: Rng + CryptoRng>(
+        &self,
+        kind: RecordKind,
+        id: &[u8],
+        record: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        csprng.fill_bytes(&mut nonce);
+
+        let mut result = Vec::with_capacity(HEADER_LEN + record.len() + TAG_LEN);
+        result.push(RECORD_CIPHER_VERSION);
+        result.extend_from_slice(&self.key_id.to_be_bytes());
+        result.extend_from_slice(&nonce);
+
+        let ciphertext = Aes256GcmSiv::new(Key::from_slice(&self.key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: record,
+                    aad: &associated_data(self.key_id, kind, id),
+                },
+            )
+            .map_err(|_| {
+                SignalProtocolError::InvalidArgument("record too long to encrypt".to_string())
+            })?;
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    /// Decrypt `encrypted`, which was stored under `kind` and `id`.
+    pub fn decrypt(&self, kind: RecordKind, id: &[u8], encrypted: &[u8]) -> Result<Vec<u8>> {
+        let key_id = Self::encrypted_key_id(encrypted)?;
+        let key = if key_id == self.key_id {
+            &self.key
+        } else {
+            self.previous_keys
+                .iter()
+                .find(|(previous_id, _)| *previous_id == key_id)
+                .map(|(_, key)| key)
+                .ok_or_else(|| {
+                    SignalProtocolError::InvalidEncryptedRecord(format!(
+                        "unknown key id {}",
+                        key_id
+                    ))
+                })?
+        };
+
+        Aes256GcmSiv::new(Key::from_slice(key))
+            .decrypt(
+                Nonce::from_slice(&encrypted[1 + KEY_ID_LEN..HEADER_LEN]),
+                Payload {
+                    msg: &encrypted[HEADER_LEN..],
+                    aad: &associated_data(key_id, kind, id),
+                },
+            )
+            .map_err(|_| {
+                SignalProtocolError::InvalidEncryptedRecord("authentication failed".to_string())
+            })
+    }
+
+    /// Whether `encrypted` was encrypted with the current key.
+    fn is_current(&self, encrypted: &[u8]) -> Result<bool> {
+        Ok(Self::encrypted_key_id(encrypted)? == self.key_id)
+    }
+
+    fn encrypted_key_id(encrypted: &[u8]) -> Result<u32> {
+        if encrypted.len() < HEADER_LEN + TAG_LEN {
+            return Err(SignalProtocolError::InvalidEncryptedRecord(format!(
+                "too short ({} bytes)",
+                encrypted.len()
+            )));
+        }
+        if encrypted[0] != RECORD_CIPHER_VERSION {
+            return Err(SignalProtocolError::InvalidEncryptedRecord(format!(
+                "unknown version {}",
+                encrypted[0]
+            )));
+        }
+        Ok(u32::from_be_bytes(
+            encrypted[1..1 + KEY_ID_LEN]
+                .try_into()
+                .expect("correct length"),
+        ))
+    }
+
+    /// Encrypt new records with `key`, identified by `key_id`, keeping the current key for
+    /// decryption.
+    fn rotate(&mut self, key_id: u32, key: [u8; 32]) -> Result<()> {
+        if key_id == self.key_id || self.previous_keys.iter().any(|(id, _)| *id == key_id) {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "record key id {} is already in use",
+                key_id
+            )));
+        }
+        let previous_key = std::mem::replace(&mut self.key, key);
+        let previous_key_id = std::mem::replace(&mut self.key_id, key_id);
+        self.previous_keys.push((previous_key_id, previous_key));
+        Ok(())
+    }
+}
+
+fn associated_data(key_id: u32, kind: RecordKind, id: &[u8]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(1 + KEY_ID_LEN + 1 + id.len());
+    ad.push(RECORD_CIPHER_VERSION);
+    ad.extend_from_slice(&key_id.to_be_bytes());
+    ad.push(kind as u8);
+    ad.extend_from_slice(id);
+    ad
+}
+
+fn address_id(address: &ProtocolAddress) -> Vec<u8> {
+    let mut id = u32::from(address.device_id()).to_be_bytes().to_vec();
+    id.extend_from_slice(address.name().as_bytes());
+    id
+}
+
+fn sender_key_id(sender: &ProtocolAddress, distribution_id: Uuid) -> Vec<u8> {
+    let mut id = distribution_id.as_bytes().to_vec();
+    id.extend(address_id(sender));
+    id
+}
+
+/// Implements the record store interfaces on top of a [SendRecordStore], encrypting every record
+/// with a [RecordCipher] before it is stored.
+///
+/// Identities are not kept here, since they contain no secrets besides the local identity key.
+///
+/// Clones share the cipher, so a key rotated through one is used by all of them.
+#[derive(Clone)]
+pub struct EncryptedRecordStore<S> {
+    inner: S,
+    cipher: Arc<RwLock<RecordCipher>>,
+}
+
+impl<S: SendRecordStore> EncryptedRecordStore<S> {
+    /// Wrap `inner`, encrypting records with `cipher`.
+    pub fn new(inner: S, cipher: RecordCipher) -> Self {
+        Self {
+            inner,
+            cipher: Arc::new(RwLock::new(cipher)),
+        }
+    }
+
+    /// The underlying store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The underlying store, mutably.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the underlying store.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Switch to `key`, identified by `key_id`, re-encrypting every stored record with it.
+    ///
+    /// The records are re-encrypted in a single transaction. If that fails, the store is left as
+    /// it was, still using the old key.
+    ///
+    /// The old key is still used to decrypt records until [forget_previous_keys] is called, since
+    /// clones of this store may have written records with it while they were being re-encrypted,
+    /// and clones that do not share the underlying store were not re-encrypted at all.
+    ///
+    /// [forget_previous_keys]: Self::forget_previous_keys
+    pub async fn rotate_key(&mut self, key_id: u32, key: [u8; 32]) -> Result<()> {
+        let mut cipher = self.cipher().clone();
+        cipher.rotate(key_id, key)?;
+        in_transaction!([self.inner], {
+            for kind in RecordKind::ALL {
+                for id in self.inner.record_ids(kind).await? {
+                    let encrypted = match self.inner.load_record(kind, &id).await? {
+                        Some(encrypted) => encrypted,
+                        None => continue,
+                    };
+                    if cipher.is_current(&encrypted)? {
+                        continue;
+                    }
+                    let record = cipher.decrypt(kind, &id, &encrypted)?;
+                    let encrypted = cipher.encrypt(kind, &id, &record, &mut OsRng)?;
+                    self.inner.store_record(kind, &id, &encrypted).await?;
+                }
+            }
+        })?;
+        *self.cipher.write().expect("cipher lock poisoned") = cipher;
+        Ok(())
+    }
+
+    /// Stop decrypting records with keys replaced by [rotate_key](Self::rotate_key), here and in
+    /// every clone.
+    ///
+    /// Only call this once no record can still be using an old key.
+    pub fn forget_previous_keys(&self) {
+        self.cipher
+            .write()
+            .expect("cipher lock poisoned")
+            .previous_keys
+            .clear();
+    }
+
+    fn cipher(&self) -> RwLockReadGuard<'_, RecordCipher> {
+        self.cipher.read().expect("cipher lock poisoned")
+    }
+
+    /// Set the entry for `id` to the value of `record`, as a last-resort pre-key.
+    ///
+    /// Unlike keys saved with [traits::KyberPreKeyStore::save_kyber_pre_key], last-resort keys are
+    /// kept when they are used.
+    pub async fn save_last_resort_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        let id = u32::from(id).to_be_bytes();
+        self.store(RecordKind::LastResortKyberPreKey, &id, &record.serialize()?)
+            .await?;
+        self.inner.remove_record(RecordKind::KyberPreKey, &id).await
+    }
+
+    async fn load(&self, kind: RecordKind, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .load_record(kind, id)
+            .await?
+            .map(|encrypted| self.cipher().decrypt(kind, id, &encrypted))
+            .transpose()
+    }
+
+    /// Returns the ids of the pre-keys of `kind` whose records, as parsed by `timestamp`, were
+    /// created before `cutoff` (see [is_created_before]).
+    async fn ids_created_before(
+        &self,
+        kind: RecordKind,
+        cutoff: u64,
+        timestamp: impl Fn(&[u8]) -> Result<u64> + Send,
+    ) -> Result<Vec<u32>> {
+        let mut ids = vec![];
+        for id in self.inner.record_ids(kind).await? {
+            let record = match self.load(kind, &id).await? {
+                Some(record) => record,
+                None => continue,
+            };
+            if is_created_before(timestamp(&record)?, cutoff) {
+                let id: [u8; 4] = id.as_slice().try_into().map_err(|_| {
+                    SignalProtocolError::InvalidEncryptedRecord(format!(
+                        "pre-key id has {} bytes",
+                        id.len()
+                    ))
+                })?;
+                ids.push(u32::from_be_bytes(id));
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn store(&mut self, kind: RecordKind, id: &[u8], record: &[u8]) -> Result<()> {
+        let encrypted = self.cipher().encrypt(kind, id, record, &mut OsRng)?;
+        self.inner.store_record(kind, id, &encrypted).await
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendProtocolStoreTransaction for EncryptedRecordStore<S> {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.inner.begin_transaction().await
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.inner.commit_transaction().await
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.inner.rollback_transaction().await
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendPreKeyStore for EncryptedRecordStore<S> {
+    async fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
+        let record = self
+            .load(RecordKind::PreKey, &u32::from(id).to_be_bytes())
+            .await?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.store(
+            RecordKind::PreKey,
+            &u32::from(id).to_be_bytes(),
+            &record.serialize()?,
+        )
+        .await
+    }
+
+    async fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
+        self.inner
+            .remove_record(RecordKind::PreKey, &u32::from(id).to_be_bytes())
+            .await
+    }
+
+    async fn pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<PreKeyId>> {
+        let ids = self
+            .ids_created_before(RecordKind::PreKey, timestamp, |record| {
+                PreKeyRecord::deserialize(record)?.timestamp()
+            })
+            .await?;
+        Ok(ids.into_iter().map(PreKeyId::from).collect())
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendSignedPreKeyStore for EncryptedRecordStore<S> {
+    async fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
+        let record = self
+            .load(RecordKind::SignedPreKey, &u32::from(id).to_be_bytes())
+            .await?
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::SignedPreKey,
+            &u32::from(id).to_be_bytes(),
+            &record.serialize()?,
+        )
+        .await
+    }
+
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        let ids = self
+            .ids_created_before(RecordKind::SignedPreKey, timestamp, |record| {
+                SignedPreKeyRecord::deserialize(record)?.timestamp()
+            })
+            .await?;
+        Ok(ids.into_iter().map(SignedPreKeyId::from).collect())
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId) -> Result<()> {
+        self.inner
+            .remove_record(RecordKind::SignedPreKey, &u32::from(id).to_be_bytes())
+            .await
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendKyberPreKeyStore for EncryptedRecordStore<S> {
+    async fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        let id = u32::from(id).to_be_bytes();
+        let record = match self.load(RecordKind::KyberPreKey, &id).await? {
+            Some(record) => record,
+            None => self
+                .load(RecordKind::LastResortKyberPreKey, &id)
+                .await?
+                .ok_or(SignalProtocolError::InvalidKyberPreKeyId)?,
+        };
+        KyberPreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        let id = u32::from(id).to_be_bytes();
+        self.store(RecordKind::KyberPreKey, &id, &record.serialize()?)
+            .await?;
+        self.inner
+            .remove_record(RecordKind::LastResortKyberPreKey, &id)
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, id: KyberPreKeyId) -> Result<()> {
+        // Last-resort keys are stored separately, so they are unaffected.
+        self.inner
+            .remove_record(RecordKind::KyberPreKey, &u32::from(id).to_be_bytes())
+            .await
+    }
+
+    async fn kyber_pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<KyberPreKeyId>> {
+        let mut ids = vec![];
+        for kind in [RecordKind::KyberPreKey, RecordKind::LastResortKyberPreKey] {
+            ids.extend(
+                self.ids_created_before(kind, timestamp, |record| {
+                    KyberPreKeyRecord::deserialize(record)?.timestamp()
+                })
+                .await?
+                .into_iter()
+                .map(KyberPreKeyId::from),
+            );
+        }
+        Ok(ids)
+    }
+
+    async fn remove_kyber_pre_key(&mut self, id: KyberPreKeyId) -> Result<()> {
+        let id = u32::from(id).to_be_bytes();
+        self.inner
+            .remove_record(RecordKind::KyberPreKey, &id)
+            .await?;
+        self.inner
+            .remove_record(RecordKind::LastResortKyberPreKey, &id)
+            .await
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendSessionStore for EncryptedRecordStore<S> {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        self.load(RecordKind::Session, &address_id(address))
+            .await?
+            .map(|record| SessionRecord::deserialize(&record))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::Session,
+            &address_id(address),
+            &record.serialize()?,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<S: SendRecordStore> traits::SendSenderKeyStore for EncryptedRecordStore<S> {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::SenderKey,
+            &sender_key_id(sender, distribution_id),
+            &record.serialize()?,
+        )
+        .await
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>> {
+        self.load(
+            RecordKind::SenderKey,
+            &sender_key_id(sender, distribution_id),
+        )
+        .await?
+        .map(|record| SenderKeyRecord::deserialize(&record))
+        .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_cipher_round_trip() {
+        let cipher = RecordCipher::new(1, [1; 32]);
+        let encrypted = cipher
+            .encrypt(RecordKind::PreKey, b"id", b"record", &mut OsRng)
+            .expect("can encrypt");
+        assert_eq!(encrypted.len(), HEADER_LEN + b"record".len() + TAG_LEN);
+        assert_eq!(
+            cipher
+                .decrypt(RecordKind::PreKey, b"id", &encrypted)
+                .expect("can decrypt"),
+            b"record"
+        );
+
+        // Records are bound to their kind and id.
+        assert!(matches!(
+            cipher.decrypt(RecordKind::SignedPreKey, b"id", &encrypted),
+            Err(SignalProtocolError::InvalidEncryptedRecord(_))
+        ));
+        assert!(matches!(
+            cipher.decrypt(RecordKind::PreKey, b"other", &encrypted),
+            Err(SignalProtocolError::InvalidEncryptedRecord(_))
+        ));
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().expect("not empty") ^= 1;
+        assert!(matches!(
+            cipher.decrypt(RecordKind::PreKey, b"id", &tampered),
+            Err(SignalProtocolError::InvalidEncryptedRecord(_))
+        ));
+        assert!(matches!(
+            cipher.decrypt(RecordKind::PreKey, b"id", &encrypted[..HEADER_LEN]),
+            Err(SignalProtocolError::InvalidEncryptedRecord(_))
+        ));
+    }
+
+    #[test]
+    fn test_record_cipher_rotation() {
+        let mut cipher = RecordCipher::new(1, [1; 32]);
+        let old = cipher
+            .encrypt(RecordKind::Session, b"id", b"record", &mut OsRng)
+            .expect("can encrypt");
+
+        cipher.rotate(2, [2; 32]).expect("new key id");
+        assert!(!cipher.is_current(&old).expect("valid"));
+        assert_eq!(
+            cipher
+                .decrypt(RecordKind::Session, b"id", &old)
+                .expect("can decrypt"),
+            b"record"
+        );
+        assert!(matches!(
+            cipher.rotate(1, [3; 32]),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        let new_only = RecordCipher::new(2, [2; 32]);
+        assert!(matches!(
+            new_only.decrypt(RecordKind::Session, b"id", &old),
+            Err(SignalProtocolError::InvalidEncryptedRecord(_))
+        ));
+        let with_previous = new_only.with_previous_key(1, [1; 32]);
+        assert!(with_previous
+            .decrypt(RecordKind::Session, b"id", &old)
+            .is_ok());
+    }
+}
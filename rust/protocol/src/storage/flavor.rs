@@ -0,0 +1,214 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Lets the library's async functions be written once for both sets of store traits.
+//!
+//! The functions at the crate root take stores implementing the traits in [super::traits] and
+//! pass a [Context] to each store method, while those in [crate::send] take the `Send` variants
+//! and return futures that are [Send]. Both are thin wrappers around a single implementation that
+//! is generic over a [Flavor], which names the store types, context, and futures to use.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use uuid::Uuid;
+
+use super::traits::*;
+use crate::address::ProtocolAddress;
+use crate::error::Result;
+use crate::retry::ResendLogEntry;
+use crate::sender_keys::SenderKeyRecord;
+use crate::state::{
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId,
+    SignedPreKeyRecord,
+};
+use crate::{IdentityKey, IdentityKeyPair};
+
+/// The store types, context, and futures used by one instantiation of the library's functions.
+pub(crate) trait Flavor: Sized {
+    /// The context passed to every store method.
+    type Context: Copy;
+    /// The future returned by every store method.
+    type Future<'a, T: 'a>: Future<Output = Result<T>> + 'a;
+
+    type IdentityKeyStore<'s>: ?Sized + IdentityKeyStoreFor<Self> + 's;
+    type PreKeyStore<'s>: ?Sized + PreKeyStoreFor<Self> + 's;
+    type SignedPreKeyStore<'s>: ?Sized + SignedPreKeyStoreFor<Self> + 's;
+    type KyberPreKeyStore<'s>: ?Sized + KyberPreKeyStoreFor<Self> + 's;
+    type SessionStore<'s>: ?Sized + SessionStoreFor<Self> + 's;
+    type SenderKeyStore<'s>: ?Sized + SenderKeyStoreFor<Self> + 's;
+    type ResendLogStore<'s>: ?Sized + ResendLogStoreFor<Self> + 's;
+}
+
+/// The store traits in [super::traits], which take a [Context].
+pub(crate) enum Local {}
+
+/// The `Send` store traits, whose futures are [Send].
+pub(crate) enum Threaded {}
+
+type LocalFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + 'a>>;
+type ThreadedFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+impl Flavor for Local {
+    type Context = Context;
+    type Future<'a, T: 'a> = LocalFuture<'a, T>;
+
+    type IdentityKeyStore<'s> = dyn IdentityKeyStore + 's;
+    type PreKeyStore<'s> = dyn PreKeyStore + 's;
+    type SignedPreKeyStore<'s> = dyn SignedPreKeyStore + 's;
+    type KyberPreKeyStore<'s> = dyn KyberPreKeyStore + 's;
+    type SessionStore<'s> = dyn SessionStore + 's;
+    type SenderKeyStore<'s> = dyn SenderKeyStore + 's;
+    type ResendLogStore<'s> = dyn ResendLogStore + 's;
+}
+
+impl Flavor for Threaded {
+    type Context = ();
+    type Future<'a, T: 'a> = ThreadedFuture<'a, T>;
+
+    type IdentityKeyStore<'s> = dyn SendIdentityKeyStore + 's;
+    type PreKeyStore<'s> = dyn SendPreKeyStore + 's;
+    type SignedPreKeyStore<'s> = dyn SendSignedPreKeyStore + 's;
+    type KyberPreKeyStore<'s> = dyn SendKyberPreKeyStore + 's;
+    type SessionStore<'s> = dyn SendSessionStore + 's;
+    type SenderKeyStore<'s> = dyn SendSenderKeyStore + 's;
+    type ResendLogStore<'s> = dyn SendResendLogStore + 's;
+}
+
+/// Declares one method of a `*For` trait, or forwards it to the matching store trait.
+macro_rules! flavored_method {
+    (decl { fn $name:ident<$lt:lifetime>(&$l:lifetime self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l self, $($arg: $ty,)* ctx: F::Context) -> F::Future<$lt, $ret>;
+    };
+    (decl { fn $name:ident<$lt:lifetime>(&$l:lifetime mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l mut self, $($arg: $ty,)* ctx: F::Context) -> F::Future<$lt, $ret>;
+    };
+    (Local $trait:ident { fn $name:ident<$lt:lifetime>(&$l:lifetime self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l self, $($arg: $ty,)* ctx: Context) -> LocalFuture<$lt, $ret> {
+            $trait::$name(self, $($arg,)* ctx)
+        }
+    };
+    (Local $trait:ident { fn $name:ident<$lt:lifetime>(&$l:lifetime mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l mut self, $($arg: $ty,)* ctx: Context) -> LocalFuture<$lt, $ret> {
+            $trait::$name(self, $($arg,)* ctx)
+        }
+    };
+    (Threaded $trait:ident { fn $name:ident<$lt:lifetime>(&$l:lifetime self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l self, $($arg: $ty,)* _ctx: ()) -> ThreadedFuture<$lt, $ret> {
+            $trait::$name(self, $($arg),*)
+        }
+    };
+    (Threaded $trait:ident { fn $name:ident<$lt:lifetime>(&$l:lifetime mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty }) => {
+        fn $name<$lt>(&$l mut self, $($arg: $ty,)* _ctx: ()) -> ThreadedFuture<$lt, $ret> {
+            $trait::$name(self, $($arg),*)
+        }
+    };
+}
+
+/// Defines a trait over [Flavor]s for a pair of store traits, and implements it for both.
+macro_rules! flavored_store {
+    ($for_trait:ident $(: $super:ident)? => $local:ident, $threaded:ident { $($method:tt)* }) => {
+        pub(crate) trait $for_trait<F: Flavor> $(: $super<F>)? {
+            $(flavored_method!(decl $method);)*
+        }
+
+        impl $for_trait<Local> for dyn $local + '_ {
+            $(flavored_method!(Local $local $method);)*
+        }
+
+        impl $for_trait<Threaded> for dyn $threaded + '_ {
+            $(flavored_method!(Threaded $threaded $method);)*
+        }
+    };
+}
+
+pub(crate) trait ProtocolStoreTransactionFor<F: Flavor> {
+    flavored_method!(decl { fn begin_transaction<'a>(&'a mut self) -> () });
+    flavored_method!(decl { fn commit_transaction<'a>(&'a mut self) -> () });
+    flavored_method!(decl { fn rollback_transaction<'a>(&'a mut self) -> () });
+}
+
+/// Implements [ProtocolStoreTransactionFor] for each pair of store trait objects.
+macro_rules! transaction_for {
+    ($($local:ident, $threaded:ident;)*) => {
+        $(
+            impl ProtocolStoreTransactionFor<Local> for dyn $local + '_ {
+                flavored_method!(Local ProtocolStoreTransaction {
+                    fn begin_transaction<'a>(&'a mut self) -> ()
+                });
+                flavored_method!(Local ProtocolStoreTransaction {
+                    fn commit_transaction<'a>(&'a mut self) -> ()
+                });
+                flavored_method!(Local ProtocolStoreTransaction {
+                    fn rollback_transaction<'a>(&'a mut self) -> ()
+                });
+            }
+
+            impl ProtocolStoreTransactionFor<Threaded> for dyn $threaded + '_ {
+                flavored_method!(Threaded SendProtocolStoreTransaction {
+                    fn begin_transaction<'a>(&'a mut self) -> ()
+                });
+                flavored_method!(Threaded SendProtocolStoreTransaction {
+                    fn commit_transaction<'a>(&'a mut self) -> ()
+                });
+                flavored_method!(Threaded SendProtocolStoreTransaction {
+                    fn rollback_transaction<'a>(&'a mut self) -> ()
+                });
+            }
+        )*
+    };
+}
+
+transaction_for! {
+    IdentityKeyStore, SendIdentityKeyStore;
+    PreKeyStore, SendPreKeyStore;
+    KyberPreKeyStore, SendKyberPreKeyStore;
+    SessionStore, SendSessionStore;
+    SenderKeyStore, SendSenderKeyStore;
+    ResendLogStore, SendResendLogStore;
+}
+
+flavored_store!(IdentityKeyStoreFor: ProtocolStoreTransactionFor => IdentityKeyStore, SendIdentityKeyStore {
+    { fn get_identity_key_pair<'a>(&'a self) -> IdentityKeyPair }
+    { fn get_local_registration_id<'a>(&'a self) -> u32 }
+    { fn save_identity<'a>(&'a mut self, address: &'a ProtocolAddress, identity: &'a IdentityKey) -> IdentityChange }
+    { fn is_trusted_identity<'a>(&'a self, address: &'a ProtocolAddress, identity: &'a IdentityKey, direction: Direction) -> bool }
+    { fn get_identity<'a>(&'a self, address: &'a ProtocolAddress) -> Option<IdentityKey> }
+    { fn get_verified_status<'a>(&'a self, address: &'a ProtocolAddress) -> VerifiedStatus }
+    { fn set_verified_status<'a>(&'a mut self, address: &'a ProtocolAddress, identity: &'a IdentityKey, status: VerifiedStatus) -> bool }
+});
+
+flavored_store!(PreKeyStoreFor: ProtocolStoreTransactionFor => PreKeyStore, SendPreKeyStore {
+    { fn get_pre_key<'a>(&'a self, prekey_id: PreKeyId) -> PreKeyRecord }
+    { fn save_pre_key<'a>(&'a mut self, prekey_id: PreKeyId, record: &'a PreKeyRecord) -> () }
+    { fn remove_pre_key<'a>(&'a mut self, prekey_id: PreKeyId) -> () }
+});
+
+flavored_store!(SignedPreKeyStoreFor => SignedPreKeyStore, SendSignedPreKeyStore {
+    { fn get_signed_pre_key<'a>(&'a self, signed_prekey_id: SignedPreKeyId) -> SignedPreKeyRecord }
+    { fn save_signed_pre_key<'a>(&'a mut self, signed_prekey_id: SignedPreKeyId, record: &'a SignedPreKeyRecord) -> () }
+});
+
+flavored_store!(KyberPreKeyStoreFor: ProtocolStoreTransactionFor => KyberPreKeyStore, SendKyberPreKeyStore {
+    { fn get_kyber_pre_key<'a>(&'a self, kyber_prekey_id: KyberPreKeyId) -> KyberPreKeyRecord }
+    { fn save_kyber_pre_key<'a>(&'a mut self, kyber_prekey_id: KyberPreKeyId, record: &'a KyberPreKeyRecord) -> () }
+    { fn mark_kyber_pre_key_used<'a>(&'a mut self, kyber_prekey_id: KyberPreKeyId) -> () }
+});
+
+flavored_store!(SessionStoreFor: ProtocolStoreTransactionFor => SessionStore, SendSessionStore {
+    { fn load_session<'a>(&'a self, address: &'a ProtocolAddress) -> Option<SessionRecord> }
+    { fn store_session<'a>(&'a mut self, address: &'a ProtocolAddress, record: &'a SessionRecord) -> () }
+});
+
+flavored_store!(SenderKeyStoreFor: ProtocolStoreTransactionFor => SenderKeyStore, SendSenderKeyStore {
+    { fn store_sender_key<'a>(&'a mut self, sender: &'a ProtocolAddress, distribution_id: Uuid, record: &'a SenderKeyRecord) -> () }
+    { fn load_sender_key<'a>(&'a mut self, sender: &'a ProtocolAddress, distribution_id: Uuid) -> Option<SenderKeyRecord> }
+});
+
+flavored_store!(ResendLogStoreFor: ProtocolStoreTransactionFor => ResendLogStore, SendResendLogStore {
+    { fn save_resend_entry<'a>(&'a mut self, entry: &'a ResendLogEntry) -> () }
+    { fn load_resend_entry<'a>(&'a self, recipient: &'a ProtocolAddress, timestamp: u64) -> Option<ResendLogEntry> }
+    { fn remove_resend_entries_before<'a>(&'a mut self, timestamp: u64) -> () }
+});
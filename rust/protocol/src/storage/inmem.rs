@@ -7,23 +7,105 @@
 //!
 //! These implementations are purely in-memory, and therefore most likely useful for testing.
 
-use crate::storage::{traits, Context};
+use crate::policy::is_created_before;
+use crate::storage::{traits, RecordKind};
 use crate::{
-    IdentityKey, IdentityKeyPair, PreKeyId, PreKeyRecord, ProtocolAddress, Result, SenderKeyRecord,
-    SessionRecord, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+    IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
+    ProtocolAddress, ResendLogEntry, Result, SenderKeyRecord, SessionPolicy, SessionRecord,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
 };
 
 use async_trait::async_trait;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use uuid::Uuid;
 
+/// Transaction bookkeeping shared by the in-memory stores.
+///
+/// `T` holds whatever parts of a store can be changed by writes.
+#[derive(Clone)]
+struct InMemTransaction<T> {
+    snapshot: Option<T>,
+    writes_until_failure: Option<usize>,
+}
+
+impl<T> InMemTransaction<T> {
+    fn new() -> Self {
+        Self {
+            snapshot: None,
+            writes_until_failure: None,
+        }
+    }
+
+    fn begin(&mut self, snapshot: impl FnOnce() -> T) {
+        // Joining a transaction that is already in progress keeps the original snapshot.
+        if self.snapshot.is_none() {
+            self.snapshot = Some(snapshot());
+        }
+    }
+
+    fn commit(&mut self) {
+        self.snapshot = None;
+    }
+
+    /// Returns the state to restore, if a transaction was in progress.
+    fn rollback(&mut self) -> Option<T> {
+        self.snapshot.take()
+    }
+
+    fn check_write(&mut self, method: &'static str) -> Result<()> {
+        match &mut self.writes_until_failure {
+            None => Ok(()),
+            Some(0) => Err(SignalProtocolError::InvalidState(
+                method,
+                "simulated write failure".to_string(),
+            )),
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Adds `fail_after_writes` to stores that keep an [InMemTransaction] in `self.transaction`.
+macro_rules! impl_fail_after_writes {
+    ($($store:ty),+ $(,)?) => {
+        $(
+            impl $store {
+                /// Make every write to this store fail once `writes` more writes have succeeded,
+                /// or stop failing writes if `writes` is `None`.
+                ///
+                /// This simulates a storage failure partway through an operation.
+                pub fn fail_after_writes(&mut self, writes: Option<usize>) {
+                    self.transaction.writes_until_failure = writes;
+                }
+            }
+        )+
+    };
+}
+
+impl_fail_after_writes!(
+    InMemIdentityKeyStore,
+    InMemPreKeyStore,
+    InMemKyberPreKeyStore,
+    InMemSessionStore,
+    InMemSenderKeyStore,
+    InMemRecordStore,
+);
+
 /// Reference implementation of [traits::IdentityKeyStore].
 #[derive(Clone)]
 pub struct InMemIdentityKeyStore {
     key_pair: IdentityKeyPair,
     registration_id: u32,
     known_keys: HashMap<ProtocolAddress, IdentityKey>,
+    verified_statuses: HashMap<ProtocolAddress, traits::VerifiedStatus>,
+    #[allow(clippy::type_complexity)]
+    transaction: InMemTransaction<(
+        HashMap<ProtocolAddress, IdentityKey>,
+        HashMap<ProtocolAddress, traits::VerifiedStatus>,
+    )>,
 }
 
 impl InMemIdentityKeyStore {
@@ -36,22 +118,48 @@ impl InMemIdentityKeyStore {
             key_pair,
             registration_id,
             known_keys: HashMap::new(),
+            verified_statuses: HashMap::new(),
+            transaction: InMemTransaction::new(),
         }
     }
 
-    /// Clear the mapping of known keys.
+    /// Clear the mapping of known keys and their verified statuses.
     pub fn reset(&mut self) {
         self.known_keys.clear();
+        self.verified_statuses.clear();
     }
 }
 
-#[async_trait(?Send)]
-impl traits::IdentityKeyStore for InMemIdentityKeyStore {
-    async fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair> {
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemIdentityKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let (known_keys, verified_statuses) = (&self.known_keys, &self.verified_statuses);
+        self.transaction
+            .begin(|| (known_keys.clone(), verified_statuses.clone()));
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some((known_keys, verified_statuses)) = self.transaction.rollback() {
+            self.known_keys = known_keys;
+            self.verified_statuses = verified_statuses;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl traits::SendIdentityKeyStore for InMemIdentityKeyStore {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
         Ok(self.key_pair)
     }
 
-    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32> {
+    async fn get_local_registration_id(&self) -> Result<u32> {
         Ok(self.registration_id)
     }
 
@@ -59,21 +167,22 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
-        _ctx: Context,
-    ) -> Result<bool> {
-        match self.known_keys.get(address) {
-            None => {
-                self.known_keys.insert(address.clone(), *identity);
-                Ok(false) // new key
-            }
-            Some(k) if k == identity => {
-                Ok(false) // same key
-            }
-            Some(_k) => {
-                self.known_keys.insert(address.clone(), *identity);
-                Ok(true) // overwrite
-            }
-        }
+    ) -> Result<traits::IdentityChange> {
+        self.transaction.check_write("save_identity")?;
+        let change = match self.known_keys.get(address) {
+            None => traits::IdentityChange::New,
+            Some(k) if k == identity => return Ok(traits::IdentityChange::Unchanged),
+            Some(_k) => match self.verified_statuses.remove(address) {
+                Some(traits::VerifiedStatus::Verified) => {
+                    self.verified_statuses
+                        .insert(address.clone(), traits::VerifiedStatus::Unverified);
+                    traits::IdentityChange::ReplacedVerified
+                }
+                _ => traits::IdentityChange::ReplacedUnverified,
+            },
+        };
+        self.known_keys.insert(address.clone(), *identity);
+        Ok(change)
     }
 
     async fn is_trusted_identity(
@@ -81,7 +190,6 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
         address: &ProtocolAddress,
         identity: &IdentityKey,
         _direction: traits::Direction,
-        _ctx: Context,
     ) -> Result<bool> {
         match self.known_keys.get(address) {
             None => {
@@ -91,22 +199,44 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
         }
     }
 
-    async fn get_identity(
-        &self,
-        address: &ProtocolAddress,
-        _ctx: Context,
-    ) -> Result<Option<IdentityKey>> {
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
         match self.known_keys.get(address) {
             None => Ok(None),
             Some(k) => Ok(Some(k.to_owned())),
         }
     }
+
+    async fn get_verified_status(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<traits::VerifiedStatus> {
+        Ok(self
+            .verified_statuses
+            .get(address)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: traits::VerifiedStatus,
+    ) -> Result<bool> {
+        if self.known_keys.get(address) != Some(identity) {
+            return Ok(false);
+        }
+        self.transaction.check_write("set_verified_status")?;
+        self.verified_statuses.insert(address.clone(), status);
+        Ok(true)
+    }
 }
 
 /// Reference implementation of [traits::PreKeyStore].
 #[derive(Clone)]
 pub struct InMemPreKeyStore {
     pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+    transaction: InMemTransaction<HashMap<PreKeyId, PreKeyRecord>>,
 }
 
 impl InMemPreKeyStore {
@@ -114,7 +244,29 @@ impl InMemPreKeyStore {
     pub fn new() -> Self {
         Self {
             pre_keys: HashMap::new(),
+            transaction: InMemTransaction::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemPreKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let pre_keys = &self.pre_keys;
+        self.transaction.begin(|| pre_keys.clone());
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some(pre_keys) = self.transaction.rollback() {
+            self.pre_keys = pre_keys;
         }
+        Ok(())
     }
 }
 
@@ -124,9 +276,9 @@ impl Default for InMemPreKeyStore {
     }
 }
 
-#[async_trait(?Send)]
-impl traits::PreKeyStore for InMemPreKeyStore {
-    async fn get_pre_key(&self, id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord> {
+#[async_trait]
+impl traits::SendPreKeyStore for InMemPreKeyStore {
+    async fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
         Ok(self
             .pre_keys
             .get(&id)
@@ -134,22 +286,29 @@ impl traits::PreKeyStore for InMemPreKeyStore {
             .clone())
     }
 
-    async fn save_pre_key(
-        &mut self,
-        id: PreKeyId,
-        record: &PreKeyRecord,
-        _ctx: Context,
-    ) -> Result<()> {
+    async fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.transaction.check_write("save_pre_key")?;
         // This overwrites old values, which matches Java behavior, but is it correct?
         self.pre_keys.insert(id, record.to_owned());
         Ok(())
     }
 
-    async fn remove_pre_key(&mut self, id: PreKeyId, _ctx: Context) -> Result<()> {
+    async fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
+        self.transaction.check_write("remove_pre_key")?;
         // If id does not exist this silently does nothing
         self.pre_keys.remove(&id);
         Ok(())
     }
+
+    async fn pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<PreKeyId>> {
+        let mut ids = vec![];
+        for (id, record) in &self.pre_keys {
+            if is_created_before(record.timestamp()?, timestamp) {
+                ids.push(*id);
+            }
+        }
+        Ok(ids)
+    }
 }
 
 /// Reference implementation of [traits::SignedPreKeyStore].
@@ -173,13 +332,9 @@ impl Default for InMemSignedPreKeyStore {
     }
 }
 
-#[async_trait(?Send)]
-impl traits::SignedPreKeyStore for InMemSignedPreKeyStore {
-    async fn get_signed_pre_key(
-        &self,
-        id: SignedPreKeyId,
-        _ctx: Context,
-    ) -> Result<SignedPreKeyRecord> {
+#[async_trait]
+impl traits::SendSignedPreKeyStore for InMemSignedPreKeyStore {
+    async fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
         Ok(self
             .signed_pre_keys
             .get(&id)
@@ -191,18 +346,149 @@ impl traits::SignedPreKeyStore for InMemSignedPreKeyStore {
         &mut self,
         id: SignedPreKeyId,
         record: &SignedPreKeyRecord,
-        _ctx: Context,
     ) -> Result<()> {
         // This overwrites old values, which matches Java behavior, but is it correct?
         self.signed_pre_keys.insert(id, record.to_owned());
         Ok(())
     }
+
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        let mut ids = vec![];
+        for (id, record) in &self.signed_pre_keys {
+            if is_created_before(record.timestamp()?, timestamp) {
+                ids.push(*id);
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId) -> Result<()> {
+        self.signed_pre_keys.remove(&id);
+        Ok(())
+    }
+}
+
+/// Reference implementation of [traits::KyberPreKeyStore].
+#[derive(Clone)]
+pub struct InMemKyberPreKeyStore {
+    kyber_pre_keys: HashMap<KyberPreKeyId, KyberPreKeyRecord>,
+    last_resort_ids: HashSet<KyberPreKeyId>,
+    #[allow(clippy::type_complexity)]
+    transaction: InMemTransaction<(
+        HashMap<KyberPreKeyId, KyberPreKeyRecord>,
+        HashSet<KyberPreKeyId>,
+    )>,
+}
+
+impl InMemKyberPreKeyStore {
+    /// Create an empty Kyber pre-key store.
+    pub fn new() -> Self {
+        Self {
+            kyber_pre_keys: HashMap::new(),
+            last_resort_ids: HashSet::new(),
+            transaction: InMemTransaction::new(),
+        }
+    }
+
+    /// Set the entry for `id` to the value of `record`, as a last-resort pre-key.
+    ///
+    /// Unlike keys saved with [traits::KyberPreKeyStore::save_kyber_pre_key], last-resort keys are
+    /// kept when they are used.
+    pub fn save_last_resort_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) {
+        self.kyber_pre_keys.insert(id, record.to_owned());
+        self.last_resort_ids.insert(id);
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemKyberPreKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let (kyber_pre_keys, last_resort_ids) = (&self.kyber_pre_keys, &self.last_resort_ids);
+        self.transaction
+            .begin(|| (kyber_pre_keys.clone(), last_resort_ids.clone()));
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some((kyber_pre_keys, last_resort_ids)) = self.transaction.rollback() {
+            self.kyber_pre_keys = kyber_pre_keys;
+            self.last_resort_ids = last_resort_ids;
+        }
+        Ok(())
+    }
+}
+
+impl Default for InMemKyberPreKeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl traits::SendKyberPreKeyStore for InMemKyberPreKeyStore {
+    async fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        Ok(self
+            .kyber_pre_keys
+            .get(&id)
+            .ok_or(SignalProtocolError::InvalidKyberPreKeyId)?
+            .clone())
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.transaction.check_write("save_kyber_pre_key")?;
+        self.kyber_pre_keys.insert(id, record.to_owned());
+        self.last_resort_ids.remove(&id);
+        Ok(())
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.transaction.check_write("mark_kyber_pre_key_used")?;
+        if !self.last_resort_ids.contains(&id) {
+            self.kyber_pre_keys.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn kyber_pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<KyberPreKeyId>> {
+        let mut ids = vec![];
+        for (id, record) in &self.kyber_pre_keys {
+            if is_created_before(record.timestamp()?, timestamp) {
+                ids.push(*id);
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn remove_kyber_pre_key(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.transaction.check_write("remove_kyber_pre_key")?;
+        self.kyber_pre_keys.remove(&id);
+        self.last_resort_ids.remove(&id);
+        Ok(())
+    }
 }
 
 /// Reference implementation of [traits::SessionStore].
 #[derive(Clone)]
 pub struct InMemSessionStore {
     sessions: HashMap<ProtocolAddress, SessionRecord>,
+    policy: Option<SessionPolicy>,
+    transaction: InMemTransaction<HashMap<ProtocolAddress, SessionRecord>>,
 }
 
 impl InMemSessionStore {
@@ -210,6 +496,19 @@ impl InMemSessionStore {
     pub fn new() -> Self {
         Self {
             sessions: HashMap::new(),
+            policy: None,
+            transaction: InMemTransaction::new(),
+        }
+    }
+
+    /// Create an empty session store that applies `policy` to every record it stores.
+    ///
+    /// See [SessionRecord::apply_policy].
+    pub fn with_policy(policy: SessionPolicy) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            policy: Some(policy),
+            transaction: InMemTransaction::new(),
         }
     }
 
@@ -233,19 +532,36 @@ impl InMemSessionStore {
     }
 }
 
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemSessionStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let sessions = &self.sessions;
+        self.transaction.begin(|| sessions.clone());
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some(sessions) = self.transaction.rollback() {
+            self.sessions = sessions;
+        }
+        Ok(())
+    }
+}
+
 impl Default for InMemSessionStore {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[async_trait(?Send)]
-impl traits::SessionStore for InMemSessionStore {
-    async fn load_session(
-        &self,
-        address: &ProtocolAddress,
-        _ctx: Context,
-    ) -> Result<Option<SessionRecord>> {
+#[async_trait]
+impl traits::SendSessionStore for InMemSessionStore {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
         match self.sessions.get(address) {
             None => Ok(None),
             Some(s) => Ok(Some(s.clone())),
@@ -256,9 +572,13 @@ impl traits::SessionStore for InMemSessionStore {
         &mut self,
         address: &ProtocolAddress,
         record: &SessionRecord,
-        _ctx: Context,
     ) -> Result<()> {
-        self.sessions.insert(address.clone(), record.clone());
+        self.transaction.check_write("store_session")?;
+        let mut record = record.clone();
+        if let Some(policy) = &self.policy {
+            record.apply_policy(policy)?;
+        }
+        self.sessions.insert(address.clone(), record);
         Ok(())
     }
 }
@@ -269,6 +589,9 @@ pub struct InMemSenderKeyStore {
     // We use Cow keys in order to store owned values but compare to referenced ones.
     // See https://users.rust-lang.org/t/hashmap-with-tuple-keys/12711/6.
     keys: HashMap<(Cow<'static, ProtocolAddress>, Uuid), SenderKeyRecord>,
+    policy: Option<SessionPolicy>,
+    #[allow(clippy::type_complexity)]
+    transaction: InMemTransaction<HashMap<(Cow<'static, ProtocolAddress>, Uuid), SenderKeyRecord>>,
 }
 
 impl InMemSenderKeyStore {
@@ -276,29 +599,65 @@ impl InMemSenderKeyStore {
     pub fn new() -> Self {
         Self {
             keys: HashMap::new(),
+            policy: None,
+            transaction: InMemTransaction::new(),
+        }
+    }
+
+    /// Create an empty sender key store that applies `policy` to every record it stores.
+    ///
+    /// See [SenderKeyRecord::apply_policy].
+    pub fn with_policy(policy: SessionPolicy) -> Self {
+        Self {
+            keys: HashMap::new(),
+            policy: Some(policy),
+            transaction: InMemTransaction::new(),
         }
     }
 }
 
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemSenderKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let keys = &self.keys;
+        self.transaction.begin(|| keys.clone());
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some(keys) = self.transaction.rollback() {
+            self.keys = keys;
+        }
+        Ok(())
+    }
+}
+
 impl Default for InMemSenderKeyStore {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[async_trait(?Send)]
-impl traits::SenderKeyStore for InMemSenderKeyStore {
+#[async_trait]
+impl traits::SendSenderKeyStore for InMemSenderKeyStore {
     async fn store_sender_key(
         &mut self,
         sender: &ProtocolAddress,
         distribution_id: Uuid,
         record: &SenderKeyRecord,
-        _ctx: Context,
     ) -> Result<()> {
-        self.keys.insert(
-            (Cow::Owned(sender.clone()), distribution_id),
-            record.clone(),
-        );
+        self.transaction.check_write("store_sender_key")?;
+        let mut record = record.clone();
+        if let Some(policy) = &self.policy {
+            record.apply_policy(policy);
+        }
+        self.keys
+            .insert((Cow::Owned(sender.clone()), distribution_id), record);
         Ok(())
     }
 
@@ -306,7 +665,6 @@ impl traits::SenderKeyStore for InMemSenderKeyStore {
         &mut self,
         sender: &ProtocolAddress,
         distribution_id: Uuid,
-        _ctx: Context,
     ) -> Result<Option<SenderKeyRecord>> {
         Ok(self
             .keys
@@ -315,6 +673,151 @@ impl traits::SenderKeyStore for InMemSenderKeyStore {
     }
 }
 
+/// Reference implementation of [traits::ResendLogStore].
+#[derive(Clone)]
+pub struct InMemResendLogStore {
+    entries: BTreeMap<(u64, ProtocolAddress), ResendLogEntry>,
+    transaction: InMemTransaction<BTreeMap<(u64, ProtocolAddress), ResendLogEntry>>,
+}
+
+impl InMemResendLogStore {
+    /// Create an empty resend log.
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            transaction: InMemTransaction::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemResendLogStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let entries = &self.entries;
+        self.transaction.begin(|| entries.clone());
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some(entries) = self.transaction.rollback() {
+            self.entries = entries;
+        }
+        Ok(())
+    }
+}
+
+impl Default for InMemResendLogStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl traits::SendResendLogStore for InMemResendLogStore {
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry) -> Result<()> {
+        self.transaction.check_write("save_resend_entry")?;
+        for recipient in &entry.recipients {
+            self.entries
+                .insert((entry.timestamp, recipient.clone()), entry.clone());
+        }
+        Ok(())
+    }
+
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+    ) -> Result<Option<ResendLogEntry>> {
+        Ok(self.entries.get(&(timestamp, recipient.clone())).cloned())
+    }
+
+    async fn remove_resend_entries_before(&mut self, timestamp: u64) -> Result<()> {
+        self.transaction
+            .check_write("remove_resend_entries_before")?;
+        self.entries
+            .retain(|(entry_timestamp, _), _| *entry_timestamp >= timestamp);
+        Ok(())
+    }
+}
+
+/// Reference implementation of [traits::RecordStore].
+#[derive(Clone)]
+pub struct InMemRecordStore {
+    records: HashMap<(RecordKind, Vec<u8>), Vec<u8>>,
+    #[allow(clippy::type_complexity)]
+    transaction: InMemTransaction<HashMap<(RecordKind, Vec<u8>), Vec<u8>>>,
+}
+
+impl InMemRecordStore {
+    /// Create an empty record store.
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+            transaction: InMemTransaction::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemRecordStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        let records = &self.records;
+        self.transaction.begin(|| records.clone());
+        Ok(())
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.transaction.commit();
+        Ok(())
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        if let Some(records) = self.transaction.rollback() {
+            self.records = records;
+        }
+        Ok(())
+    }
+}
+
+impl Default for InMemRecordStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl traits::SendRecordStore for InMemRecordStore {
+    async fn load_record(&self, kind: RecordKind, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.records.get(&(kind, id.to_vec())).cloned())
+    }
+
+    async fn store_record(&mut self, kind: RecordKind, id: &[u8], record: &[u8]) -> Result<()> {
+        self.transaction.check_write("store_record")?;
+        self.records.insert((kind, id.to_vec()), record.to_vec());
+        Ok(())
+    }
+
+    async fn remove_record(&mut self, kind: RecordKind, id: &[u8]) -> Result<()> {
+        self.transaction.check_write("remove_record")?;
+        self.records.remove(&(kind, id.to_vec()));
+        Ok(())
+    }
+
+    async fn record_ids(&self, kind: RecordKind) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .records
+            .keys()
+            .filter(|(record_kind, _)| *record_kind == kind)
+            .map(|(_, id)| id.clone())
+            .collect())
+    }
+}
+
 /// Reference implementation of [traits::ProtocolStore].
 #[allow(missing_docs)]
 #[derive(Clone)]
@@ -322,8 +825,10 @@ pub struct InMemSignalProtocolStore {
     pub session_store: InMemSessionStore,
     pub pre_key_store: InMemPreKeyStore,
     pub signed_pre_key_store: InMemSignedPreKeyStore,
+    pub kyber_pre_key_store: InMemKyberPreKeyStore,
     pub identity_store: InMemIdentityKeyStore,
     pub sender_key_store: InMemSenderKeyStore,
+    pub resend_log_store: InMemResendLogStore,
 }
 
 impl InMemSignalProtocolStore {
@@ -334,31 +839,30 @@ impl InMemSignalProtocolStore {
             session_store: InMemSessionStore::new(),
             pre_key_store: InMemPreKeyStore::new(),
             signed_pre_key_store: InMemSignedPreKeyStore::new(),
+            kyber_pre_key_store: InMemKyberPreKeyStore::new(),
             identity_store: InMemIdentityKeyStore::new(key_pair, registration_id),
             sender_key_store: InMemSenderKeyStore::new(),
+            resend_log_store: InMemResendLogStore::new(),
         })
     }
 }
 
-#[async_trait(?Send)]
-impl traits::IdentityKeyStore for InMemSignalProtocolStore {
-    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
-        self.identity_store.get_identity_key_pair(ctx).await
+#[async_trait]
+impl traits::SendIdentityKeyStore for InMemSignalProtocolStore {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
+        self.identity_store.get_identity_key_pair().await
     }
 
-    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
-        self.identity_store.get_local_registration_id(ctx).await
+    async fn get_local_registration_id(&self) -> Result<u32> {
+        self.identity_store.get_local_registration_id().await
     }
 
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
-        ctx: Context,
-    ) -> Result<bool> {
-        self.identity_store
-            .save_identity(address, identity, ctx)
-            .await
+    ) -> Result<traits::IdentityChange> {
+        self.identity_store.save_identity(address, identity).await
     }
 
     async fn is_trusted_identity(
@@ -366,95 +870,142 @@ impl traits::IdentityKeyStore for InMemSignalProtocolStore {
         address: &ProtocolAddress,
         identity: &IdentityKey,
         direction: traits::Direction,
-        ctx: Context,
     ) -> Result<bool> {
         self.identity_store
-            .is_trusted_identity(address, identity, direction, ctx)
+            .is_trusted_identity(address, identity, direction)
             .await
     }
 
-    async fn get_identity(
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
+        self.identity_store.get_identity(address).await
+    }
+
+    async fn get_verified_status(
         &self,
         address: &ProtocolAddress,
-        ctx: Context,
-    ) -> Result<Option<IdentityKey>> {
-        self.identity_store.get_identity(address, ctx).await
+    ) -> Result<traits::VerifiedStatus> {
+        self.identity_store.get_verified_status(address).await
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: traits::VerifiedStatus,
+    ) -> Result<bool> {
+        self.identity_store
+            .set_verified_status(address, identity, status)
+            .await
     }
 }
 
-#[async_trait(?Send)]
-impl traits::PreKeyStore for InMemSignalProtocolStore {
-    async fn get_pre_key(&self, id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
-        self.pre_key_store.get_pre_key(id, ctx).await
+#[async_trait]
+impl traits::SendPreKeyStore for InMemSignalProtocolStore {
+    async fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
+        self.pre_key_store.get_pre_key(id).await
     }
 
-    async fn save_pre_key(
-        &mut self,
-        id: PreKeyId,
-        record: &PreKeyRecord,
-        ctx: Context,
-    ) -> Result<()> {
-        self.pre_key_store.save_pre_key(id, record, ctx).await
+    async fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.pre_key_store.save_pre_key(id, record).await
     }
 
-    async fn remove_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
-        self.pre_key_store.remove_pre_key(id, ctx).await
+    async fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
+        self.pre_key_store.remove_pre_key(id).await
+    }
+
+    async fn pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<PreKeyId>> {
+        self.pre_key_store
+            .pre_key_ids_created_before(timestamp)
+            .await
     }
 }
 
-#[async_trait(?Send)]
-impl traits::SignedPreKeyStore for InMemSignalProtocolStore {
-    async fn get_signed_pre_key(
-        &self,
-        id: SignedPreKeyId,
-        ctx: Context,
-    ) -> Result<SignedPreKeyRecord> {
-        self.signed_pre_key_store.get_signed_pre_key(id, ctx).await
+#[async_trait]
+impl traits::SendSignedPreKeyStore for InMemSignalProtocolStore {
+    async fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
+        self.signed_pre_key_store.get_signed_pre_key(id).await
     }
 
     async fn save_signed_pre_key(
         &mut self,
         id: SignedPreKeyId,
         record: &SignedPreKeyRecord,
-        ctx: Context,
     ) -> Result<()> {
         self.signed_pre_key_store
-            .save_signed_pre_key(id, record, ctx)
+            .save_signed_pre_key(id, record)
             .await
     }
-}
 
-#[async_trait(?Send)]
-impl traits::SessionStore for InMemSignalProtocolStore {
-    async fn load_session(
+    async fn signed_pre_key_ids_created_before(
         &self,
-        address: &ProtocolAddress,
-        ctx: Context,
-    ) -> Result<Option<SessionRecord>> {
-        self.session_store.load_session(address, ctx).await
+        timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        self.signed_pre_key_store
+            .signed_pre_key_ids_created_before(timestamp)
+            .await
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId) -> Result<()> {
+        self.signed_pre_key_store.remove_signed_pre_key(id).await
+    }
+}
+
+#[async_trait]
+impl traits::SendKyberPreKeyStore for InMemSignalProtocolStore {
+    async fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        self.kyber_pre_key_store.get_kyber_pre_key(id).await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.kyber_pre_key_store
+            .save_kyber_pre_key(id, record)
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.kyber_pre_key_store.mark_kyber_pre_key_used(id).await
+    }
+
+    async fn kyber_pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<KyberPreKeyId>> {
+        self.kyber_pre_key_store
+            .kyber_pre_key_ids_created_before(timestamp)
+            .await
+    }
+
+    async fn remove_kyber_pre_key(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.kyber_pre_key_store.remove_kyber_pre_key(id).await
+    }
+}
+
+#[async_trait]
+impl traits::SendSessionStore for InMemSignalProtocolStore {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        self.session_store.load_session(address).await
     }
 
     async fn store_session(
         &mut self,
         address: &ProtocolAddress,
         record: &SessionRecord,
-        ctx: Context,
     ) -> Result<()> {
-        self.session_store.store_session(address, record, ctx).await
+        self.session_store.store_session(address, record).await
     }
 }
 
-#[async_trait(?Send)]
-impl traits::SenderKeyStore for InMemSignalProtocolStore {
+#[async_trait]
+impl traits::SendSenderKeyStore for InMemSignalProtocolStore {
     async fn store_sender_key(
         &mut self,
         sender: &ProtocolAddress,
         distribution_id: Uuid,
         record: &SenderKeyRecord,
-        ctx: Context,
     ) -> Result<()> {
         self.sender_key_store
-            .store_sender_key(sender, distribution_id, record, ctx)
+            .store_sender_key(sender, distribution_id, record)
             .await
     }
 
@@ -462,12 +1013,64 @@ impl traits::SenderKeyStore for InMemSignalProtocolStore {
         &mut self,
         sender: &ProtocolAddress,
         distribution_id: Uuid,
-        ctx: Context,
     ) -> Result<Option<SenderKeyRecord>> {
         self.sender_key_store
-            .load_sender_key(sender, distribution_id, ctx)
+            .load_sender_key(sender, distribution_id)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendResendLogStore for InMemSignalProtocolStore {
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry) -> Result<()> {
+        self.resend_log_store.save_resend_entry(entry).await
+    }
+
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+    ) -> Result<Option<ResendLogEntry>> {
+        self.resend_log_store
+            .load_resend_entry(recipient, timestamp)
             .await
     }
+
+    async fn remove_resend_entries_before(&mut self, timestamp: u64) -> Result<()> {
+        self.resend_log_store
+            .remove_resend_entries_before(timestamp)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for InMemSignalProtocolStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.session_store.begin_transaction().await?;
+        self.pre_key_store.begin_transaction().await?;
+        self.kyber_pre_key_store.begin_transaction().await?;
+        self.identity_store.begin_transaction().await?;
+        self.sender_key_store.begin_transaction().await?;
+        self.resend_log_store.begin_transaction().await
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.session_store.commit_transaction().await?;
+        self.pre_key_store.commit_transaction().await?;
+        self.kyber_pre_key_store.commit_transaction().await?;
+        self.identity_store.commit_transaction().await?;
+        self.sender_key_store.commit_transaction().await?;
+        self.resend_log_store.commit_transaction().await
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.session_store.rollback_transaction().await?;
+        self.pre_key_store.rollback_transaction().await?;
+        self.kyber_pre_key_store.rollback_transaction().await?;
+        self.identity_store.rollback_transaction().await?;
+        self.sender_key_store.rollback_transaction().await?;
+        self.resend_log_store.rollback_transaction().await
+    }
 }
 
-impl traits::ProtocolStore for InMemSignalProtocolStore {}
+impl traits::SendProtocolStore for InMemSignalProtocolStore {}
@@ -0,0 +1,1403 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Implementations for stores defined in [super::traits], backed by a SQLite database.
+//!
+//! All of the stores in a [SqliteSignalProtocolStore] share a single connection, so a transaction
+//! begun through any of them covers writes made through all of them.
+//!
+//! Nothing is encrypted before it is written: records contain private keys, and the resend log
+//! contains message plaintext. Callers are responsible for encrypting the database itself.
+
+use crate::policy::is_created_before;
+use crate::proto::storage as storage_proto;
+use crate::storage::traits;
+use crate::{
+    IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
+    ProtocolAddress, ResendLogEntry, Result, SenderKeyRecord, SessionRecord, SignalProtocolError,
+    SignedPreKeyId, SignedPreKeyRecord,
+};
+
+use async_trait::async_trait;
+use prost::Message;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Schema changes, applied in order to bring a database up to date.
+///
+/// The number of migrations already applied is kept in SQLite's `user_version` pragma. Existing
+/// entries must never be changed; add a new entry instead.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE local_identity (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        key_pair BLOB NOT NULL,
+        registration_id INTEGER NOT NULL
+    );
+    CREATE TABLE identities (
+        name TEXT NOT NULL,
+        device_id INTEGER NOT NULL,
+        identity_key BLOB NOT NULL,
+        PRIMARY KEY (name, device_id)
+    );
+    CREATE TABLE pre_keys (
+        id INTEGER PRIMARY KEY,
+        record BLOB NOT NULL
+    );
+    CREATE TABLE signed_pre_keys (
+        id INTEGER PRIMARY KEY,
+        record BLOB NOT NULL
+    );
+    CREATE TABLE kyber_pre_keys (
+        id INTEGER PRIMARY KEY,
+        record BLOB NOT NULL,
+        last_resort INTEGER NOT NULL
+    );
+    CREATE TABLE sessions (
+        name TEXT NOT NULL,
+        device_id INTEGER NOT NULL,
+        record BLOB NOT NULL,
+        PRIMARY KEY (name, device_id)
+    );
+    CREATE TABLE sender_keys (
+        name TEXT NOT NULL,
+        device_id INTEGER NOT NULL,
+        distribution_id BLOB NOT NULL,
+        record BLOB NOT NULL,
+        PRIMARY KEY (name, device_id, distribution_id)
+    );
+    CREATE INDEX sender_keys_by_distribution_id ON sender_keys (distribution_id);
+",
+    "
+    ALTER TABLE identities ADD COLUMN verified_status INTEGER NOT NULL DEFAULT 0;
+",
+    "
+    CREATE TABLE resend_entries (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        entry BLOB NOT NULL
+    );
+    CREATE INDEX resend_entries_by_timestamp ON resend_entries (timestamp);
+    CREATE TABLE resend_recipients (
+        name TEXT NOT NULL,
+        device_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        entry_id INTEGER NOT NULL,
+        PRIMARY KEY (name, device_id, timestamp)
+    );
+    CREATE INDEX resend_recipients_by_entry_id ON resend_recipients (entry_id);
+",
+];
+
+// Kept as a message because rusqlite's errors are not UnwindSafe, which
+// SignalProtocolError::ApplicationCallbackError requires.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// SQLite error: {0}
+struct DatabaseError(String);
+
+fn database_error(method: &'static str) -> impl FnOnce(rusqlite::Error) -> SignalProtocolError {
+    move |e| {
+        SignalProtocolError::ApplicationCallbackError(
+            method,
+            Box::new(DatabaseError(e.to_string())),
+        )
+    }
+}
+
+fn verified_status_to_column(status: traits::VerifiedStatus) -> u8 {
+    match status {
+        traits::VerifiedStatus::Default => 0,
+        traits::VerifiedStatus::Verified => 1,
+        traits::VerifiedStatus::Unverified => 2,
+    }
+}
+
+fn verified_status_from_column(value: u8) -> Result<traits::VerifiedStatus> {
+    match value {
+        0 => Ok(traits::VerifiedStatus::Default),
+        1 => Ok(traits::VerifiedStatus::Verified),
+        2 => Ok(traits::VerifiedStatus::Unverified),
+        _ => Err(SignalProtocolError::InvalidState(
+            "get_verified_status",
+            format!("unknown verified status {}", value),
+        )),
+    }
+}
+
+/// The connection shared by every store opened from the same database.
+struct Database {
+    connection: Connection,
+    /// How many stores have joined the transaction in progress, if any.
+    transaction_depth: usize,
+    /// The [SharedDatabase::owner] of the stores in the transaction in progress, if any.
+    transaction_owner: u64,
+    /// The last owner handed out to a [SharedDatabase].
+    last_owner: u64,
+}
+
+/// A handle to the shared connection.
+///
+/// Handles with the same `owner` belong to the stores of one [SqliteSignalProtocolStore] and may
+/// join each other's transactions. Every clone gets a new owner, so that independent users of the
+/// same connection cannot end up in each other's transactions.
+struct SharedDatabase {
+    database: Arc<Mutex<Database>>,
+    owner: u64,
+}
+
+impl Clone for SharedDatabase {
+    fn clone(&self) -> Self {
+        let mut db = self.lock_unchecked();
+        db.last_owner += 1;
+        Self {
+            database: self.database.clone(),
+            owner: db.last_owner,
+        }
+    }
+}
+
+impl SharedDatabase {
+    fn new(connection: Connection) -> Result<Self> {
+        migrate(&connection)?;
+        Ok(Self {
+            database: Arc::new(Mutex::new(Database {
+                connection,
+                transaction_depth: 0,
+                transaction_owner: 0,
+                last_owner: 0,
+            })),
+            owner: 0,
+        })
+    }
+
+    /// Returns another handle with the same owner, for a store that joins this one's transactions.
+    fn share(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            owner: self.owner,
+        }
+    }
+
+    fn lock_unchecked(&self) -> MutexGuard<'_, Database> {
+        self.database.lock().expect("database lock poisoned")
+    }
+
+    /// Locks the connection for use by `method`, which fails if it is in the middle of a
+    /// transaction begun by another owner.
+    fn lock(&self, method: &'static str) -> Result<MutexGuard<'_, Database>> {
+        let db = self.lock_unchecked();
+        if db.transaction_depth > 0 && db.transaction_owner != self.owner {
+            return Err(SignalProtocolError::InvalidState(
+                method,
+                "the database is in another store's transaction".to_string(),
+            ));
+        }
+        Ok(db)
+    }
+
+    fn begin_transaction(&self) -> Result<()> {
+        let mut db = self.lock("begin_transaction")?;
+        // Later participants join the transaction begun by the first.
+        if db.transaction_depth == 0 {
+            db.connection
+                .execute_batch("BEGIN IMMEDIATE")
+                .map_err(database_error("begin_transaction"))?;
+            db.transaction_owner = self.owner;
+        }
+        db.transaction_depth += 1;
+        Ok(())
+    }
+
+    /// Whether `db` is in a transaction that stores with this handle's owner have joined.
+    fn in_own_transaction(&self, db: &Database) -> bool {
+        db.transaction_depth > 0 && db.transaction_owner == self.owner
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        let mut db = self.lock_unchecked();
+        if !self.in_own_transaction(&db) {
+            // Already rolled back by another participant.
+            return Ok(());
+        }
+        if db.transaction_depth == 1 {
+            // If this fails the transaction stays open for the caller to roll back.
+            db.connection
+                .execute_batch("COMMIT")
+                .map_err(database_error("commit_transaction"))?;
+        }
+        db.transaction_depth -= 1;
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        let mut db = self.lock_unchecked();
+        if !self.in_own_transaction(&db) {
+            return Ok(());
+        }
+        db.transaction_depth = 0;
+        // SQLite may already have rolled back on its own, e.g. after a failed COMMIT.
+        if !db.connection.is_autocommit() {
+            db.connection
+                .execute_batch("ROLLBACK")
+                .map_err(database_error("rollback_transaction"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the pre-keys in `table` whose records, as parsed by `timestamp`, were
+    /// created before `cutoff` (see [is_created_before]).
+    ///
+    /// Creation times live inside the serialized records, so this has to read every row.
+    fn pre_key_ids_created_before(
+        &self,
+        table: &str,
+        cutoff: u64,
+        timestamp: impl Fn(&[u8]) -> Result<u64>,
+        method: &'static str,
+    ) -> Result<Vec<u32>> {
+        let db = self.lock(method)?;
+        let mut statement = db
+            .connection
+            .prepare(&format!("SELECT id, record FROM {}", table))
+            .map_err(database_error(method))?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(database_error(method))?;
+        let mut ids = vec![];
+        for row in rows {
+            let (id, record) = row.map_err(database_error(method))?;
+            if is_created_before(timestamp(&record)?, cutoff) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+fn migrate(connection: &Connection) -> Result<()> {
+    let version: usize = connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(database_error("migrate"))?;
+    if version > MIGRATIONS.len() {
+        return Err(SignalProtocolError::InvalidState(
+            "migrate",
+            format!(
+                "database schema version {} is newer than the latest known version {}",
+                version,
+                MIGRATIONS.len()
+            ),
+        ));
+    }
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection
+            .unchecked_transaction()
+            .map_err(database_error("migrate"))?;
+        transaction
+            .execute_batch(migration)
+            .map_err(database_error("migrate"))?;
+        transaction
+            .pragma_update(None, "user_version", applied + 1)
+            .map_err(database_error("migrate"))?;
+        transaction.commit().map_err(database_error("migrate"))?;
+    }
+    Ok(())
+}
+
+/// SQLite implementation of [traits::IdentityKeyStore].
+#[derive(Clone)]
+pub struct SqliteIdentityKeyStore {
+    db: SharedDatabase,
+    key_pair: IdentityKeyPair,
+    registration_id: u32,
+}
+
+impl SqliteIdentityKeyStore {
+    fn save_local_identity(
+        db: &SharedDatabase,
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+    ) -> Result<Self> {
+        db.lock("save_local_identity")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO local_identity (id, key_pair, registration_id)
+                 VALUES (0, ?1, ?2)",
+                params![&key_pair.serialize()[..], registration_id],
+            )
+            .map_err(database_error("save_local_identity"))?;
+        Ok(Self {
+            db: db.share(),
+            key_pair,
+            registration_id,
+        })
+    }
+
+    fn load_local_identity(db: &SharedDatabase) -> Result<Self> {
+        let (key_pair, registration_id): (Vec<u8>, u32) = db
+            .lock("load_local_identity")?
+            .connection
+            .query_row(
+                "SELECT key_pair, registration_id FROM local_identity WHERE id = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(database_error("load_local_identity"))?
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "load_local_identity",
+                    "database has no local identity".to_string(),
+                )
+            })?;
+        Ok(Self {
+            db: db.share(),
+            key_pair: IdentityKeyPair::try_from(&key_pair[..])?,
+            registration_id,
+        })
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteIdentityKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendIdentityKeyStore for SqliteIdentityKeyStore {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
+        Ok(self.key_pair)
+    }
+
+    async fn get_local_registration_id(&self) -> Result<u32> {
+        Ok(self.registration_id)
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<traits::IdentityChange> {
+        let (change, status) = match self.get_identity(address).await? {
+            None => (traits::IdentityChange::New, traits::VerifiedStatus::Default),
+            Some(k) if k == *identity => return Ok(traits::IdentityChange::Unchanged),
+            Some(_k) => match self.get_verified_status(address).await? {
+                traits::VerifiedStatus::Verified => (
+                    traits::IdentityChange::ReplacedVerified,
+                    traits::VerifiedStatus::Unverified,
+                ),
+                _ => (
+                    traits::IdentityChange::ReplacedUnverified,
+                    traits::VerifiedStatus::Default,
+                ),
+            },
+        };
+        self.db
+            .lock("save_identity")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO identities (name, device_id, identity_key, verified_status)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    address.name(),
+                    u32::from(address.device_id()),
+                    &identity.serialize()[..],
+                    verified_status_to_column(status)
+                ],
+            )
+            .map_err(database_error("save_identity"))?;
+        Ok(change)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _direction: traits::Direction,
+    ) -> Result<bool> {
+        match self.get_identity(address).await? {
+            None => {
+                Ok(true) // first use
+            }
+            Some(k) => Ok(k == *identity),
+        }
+    }
+
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
+        let identity: Option<Vec<u8>> = self
+            .db
+            .lock("get_identity")?
+            .connection
+            .query_row(
+                "SELECT identity_key FROM identities WHERE name = ?1 AND device_id = ?2",
+                params![address.name(), u32::from(address.device_id())],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("get_identity"))?;
+        identity
+            .map(|identity| IdentityKey::try_from(&identity[..]))
+            .transpose()
+    }
+
+    async fn get_verified_status(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<traits::VerifiedStatus> {
+        let status: Option<u8> = self
+            .db
+            .lock("get_verified_status")?
+            .connection
+            .query_row(
+                "SELECT verified_status FROM identities WHERE name = ?1 AND device_id = ?2",
+                params![address.name(), u32::from(address.device_id())],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("get_verified_status"))?;
+        status.map_or(
+            Ok(traits::VerifiedStatus::Default),
+            verified_status_from_column,
+        )
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: traits::VerifiedStatus,
+    ) -> Result<bool> {
+        let updated = self
+            .db
+            .lock("set_verified_status")?
+            .connection
+            .execute(
+                "UPDATE identities SET verified_status = ?1
+                 WHERE name = ?2 AND device_id = ?3 AND identity_key = ?4",
+                params![
+                    verified_status_to_column(status),
+                    address.name(),
+                    u32::from(address.device_id()),
+                    &identity.serialize()[..]
+                ],
+            )
+            .map_err(database_error("set_verified_status"))?;
+        Ok(updated > 0)
+    }
+}
+
+/// SQLite implementation of [traits::PreKeyStore].
+#[derive(Clone)]
+pub struct SqlitePreKeyStore {
+    db: SharedDatabase,
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqlitePreKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendPreKeyStore for SqlitePreKeyStore {
+    async fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
+        let record: Vec<u8> = self
+            .db
+            .lock("get_pre_key")?
+            .connection
+            .query_row(
+                "SELECT record FROM pre_keys WHERE id = ?1",
+                params![u32::from(id)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("get_pre_key"))?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.db
+            .lock("save_pre_key")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO pre_keys (id, record) VALUES (?1, ?2)",
+                params![u32::from(id), record.serialize()?],
+            )
+            .map_err(database_error("save_pre_key"))?;
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
+        self.db
+            .lock("remove_pre_key")?
+            .connection
+            .execute("DELETE FROM pre_keys WHERE id = ?1", params![u32::from(id)])
+            .map_err(database_error("remove_pre_key"))?;
+        Ok(())
+    }
+
+    async fn pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<PreKeyId>> {
+        let ids = self.db.pre_key_ids_created_before(
+            "pre_keys",
+            timestamp,
+            |record| PreKeyRecord::deserialize(record)?.timestamp(),
+            "pre_key_ids_created_before",
+        )?;
+        Ok(ids.into_iter().map(PreKeyId::from).collect())
+    }
+}
+
+/// SQLite implementation of [traits::SignedPreKeyStore].
+#[derive(Clone)]
+pub struct SqliteSignedPreKeyStore {
+    db: SharedDatabase,
+}
+
+#[async_trait]
+impl traits::SendSignedPreKeyStore for SqliteSignedPreKeyStore {
+    async fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
+        let record: Vec<u8> = self
+            .db
+            .lock("get_signed_pre_key")?
+            .connection
+            .query_row(
+                "SELECT record FROM signed_pre_keys WHERE id = ?1",
+                params![u32::from(id)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("get_signed_pre_key"))?
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()> {
+        self.db
+            .lock("save_signed_pre_key")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO signed_pre_keys (id, record) VALUES (?1, ?2)",
+                params![u32::from(id), record.serialize()?],
+            )
+            .map_err(database_error("save_signed_pre_key"))?;
+        Ok(())
+    }
+
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        let ids = self.db.pre_key_ids_created_before(
+            "signed_pre_keys",
+            timestamp,
+            |record| SignedPreKeyRecord::deserialize(record)?.timestamp(),
+            "signed_pre_key_ids_created_before",
+        )?;
+        Ok(ids.into_iter().map(SignedPreKeyId::from).collect())
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId) -> Result<()> {
+        self.db
+            .lock("remove_signed_pre_key")?
+            .connection
+            .execute(
+                "DELETE FROM signed_pre_keys WHERE id = ?1",
+                params![u32::from(id)],
+            )
+            .map_err(database_error("remove_signed_pre_key"))?;
+        Ok(())
+    }
+}
+
+/// SQLite implementation of [traits::KyberPreKeyStore].
+#[derive(Clone)]
+pub struct SqliteKyberPreKeyStore {
+    db: SharedDatabase,
+}
+
+impl SqliteKyberPreKeyStore {
+    fn insert(
+        &self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        last_resort: bool,
+    ) -> Result<()> {
+        self.db
+            .lock("save_kyber_pre_key")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO kyber_pre_keys (id, record, last_resort)
+                 VALUES (?1, ?2, ?3)",
+                params![u32::from(id), record.serialize()?, last_resort],
+            )
+            .map_err(database_error("save_kyber_pre_key"))?;
+        Ok(())
+    }
+
+    /// Set the entry for `id` to the value of `record`, as a last-resort pre-key.
+    ///
+    /// Unlike keys saved with [traits::KyberPreKeyStore::save_kyber_pre_key], last-resort keys are
+    /// kept when they are used.
+    pub fn save_last_resort_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.insert(id, record, true)
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteKyberPreKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendKyberPreKeyStore for SqliteKyberPreKeyStore {
+    async fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        let record: Vec<u8> = self
+            .db
+            .lock("get_kyber_pre_key")?
+            .connection
+            .query_row(
+                "SELECT record FROM kyber_pre_keys WHERE id = ?1",
+                params![u32::from(id)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("get_kyber_pre_key"))?
+            .ok_or(SignalProtocolError::InvalidKyberPreKeyId)?;
+        KyberPreKeyRecord::deserialize(&record)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.insert(id, record, false)
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.db
+            .lock("mark_kyber_pre_key_used")?
+            .connection
+            .execute(
+                "DELETE FROM kyber_pre_keys WHERE id = ?1 AND NOT last_resort",
+                params![u32::from(id)],
+            )
+            .map_err(database_error("mark_kyber_pre_key_used"))?;
+        Ok(())
+    }
+
+    async fn kyber_pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<KyberPreKeyId>> {
+        let ids = self.db.pre_key_ids_created_before(
+            "kyber_pre_keys",
+            timestamp,
+            |record| KyberPreKeyRecord::deserialize(record)?.timestamp(),
+            "kyber_pre_key_ids_created_before",
+        )?;
+        Ok(ids.into_iter().map(KyberPreKeyId::from).collect())
+    }
+
+    async fn remove_kyber_pre_key(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.db
+            .lock("remove_kyber_pre_key")?
+            .connection
+            .execute(
+                "DELETE FROM kyber_pre_keys WHERE id = ?1",
+                params![u32::from(id)],
+            )
+            .map_err(database_error("remove_kyber_pre_key"))?;
+        Ok(())
+    }
+}
+
+/// SQLite implementation of [traits::SessionStore].
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    db: SharedDatabase,
+}
+
+impl SqliteSessionStore {
+    /// Bulk version of [`SessionStore::load_session`].
+    ///
+    /// Useful for [crate::sealed_sender_multi_recipient_encrypt].
+    ///
+    /// [`SessionStore::load_session`]: crate::SessionStore::load_session
+    pub fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+    ) -> Result<Vec<SessionRecord>> {
+        let db = self.db.lock("load_existing_sessions")?;
+        let mut statement = db
+            .connection
+            .prepare_cached("SELECT record FROM sessions WHERE name = ?1 AND device_id = ?2")
+            .map_err(database_error("load_existing_sessions"))?;
+        addresses
+            .iter()
+            .map(|&address| {
+                let record: Vec<u8> = statement
+                    .query_row(
+                        params![address.name(), u32::from(address.device_id())],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(database_error("load_existing_sessions"))?
+                    .ok_or_else(|| SignalProtocolError::SessionNotFound(address.clone()))?;
+                SessionRecord::deserialize(&record)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteSessionStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendSessionStore for SqliteSessionStore {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        let record: Option<Vec<u8>> = self
+            .db
+            .lock("load_session")?
+            .connection
+            .query_row(
+                "SELECT record FROM sessions WHERE name = ?1 AND device_id = ?2",
+                params![address.name(), u32::from(address.device_id())],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("load_session"))?;
+        record
+            .map(|record| SessionRecord::deserialize(&record))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<()> {
+        self.db
+            .lock("store_session")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO sessions (name, device_id, record) VALUES (?1, ?2, ?3)",
+                params![
+                    address.name(),
+                    u32::from(address.device_id()),
+                    record.serialize()?
+                ],
+            )
+            .map_err(database_error("store_session"))?;
+        Ok(())
+    }
+}
+
+/// SQLite implementation of [traits::SenderKeyStore].
+#[derive(Clone)]
+pub struct SqliteSenderKeyStore {
+    db: SharedDatabase,
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteSenderKeyStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendSenderKeyStore for SqliteSenderKeyStore {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<()> {
+        self.db
+            .lock("store_sender_key")?
+            .connection
+            .execute(
+                "INSERT OR REPLACE INTO sender_keys (name, device_id, distribution_id, record)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    sender.name(),
+                    u32::from(sender.device_id()),
+                    &distribution_id.as_bytes()[..],
+                    record.serialize()?
+                ],
+            )
+            .map_err(database_error("store_sender_key"))?;
+        Ok(())
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>> {
+        let record: Option<Vec<u8>> = self
+            .db
+            .lock("load_sender_key")?
+            .connection
+            .query_row(
+                "SELECT record FROM sender_keys
+                 WHERE name = ?1 AND device_id = ?2 AND distribution_id = ?3",
+                params![
+                    sender.name(),
+                    u32::from(sender.device_id()),
+                    &distribution_id.as_bytes()[..]
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("load_sender_key"))?;
+        record
+            .map(|record| SenderKeyRecord::deserialize(&record))
+            .transpose()
+    }
+}
+
+fn serialize_resend_entry(entry: &ResendLogEntry) -> Vec<u8> {
+    storage_proto::ResendLogEntryStructure {
+        timestamp: entry.timestamp,
+        content: entry.content.clone(),
+        distribution_id: entry
+            .distribution_id
+            .map_or_else(Vec::new, |id| id.as_bytes().to_vec()),
+        recipients: entry
+            .recipients
+            .iter()
+            .map(
+                |recipient| storage_proto::sender_key_state_structure::Recipient {
+                    name: recipient.name().to_owned(),
+                    device_id: recipient.device_id().into(),
+                },
+            )
+            .collect(),
+    }
+    .encode_to_vec()
+}
+
+fn deserialize_resend_entry(bytes: &[u8]) -> Result<ResendLogEntry> {
+    let entry = storage_proto::ResendLogEntryStructure::decode(bytes)
+        .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+    let distribution_id = match entry.distribution_id.len() {
+        0 => None,
+        _ => Some(
+            Uuid::from_slice(&entry.distribution_id)
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?,
+        ),
+    };
+    Ok(ResendLogEntry {
+        timestamp: entry.timestamp,
+        content: entry.content,
+        distribution_id,
+        recipients: entry
+            .recipients
+            .into_iter()
+            .map(|recipient| ProtocolAddress::new(recipient.name, recipient.device_id.into()))
+            .collect(),
+    })
+}
+
+/// SQLite implementation of [traits::ResendLogStore].
+///
+/// Each entry is stored once, and indexed by each of its recipients.
+///
+/// Entries are stored as they are, including the plaintext of each message. Callers must encrypt
+/// the database (for example with SQLCipher) if that needs protecting at rest.
+#[derive(Clone)]
+pub struct SqliteResendLogStore {
+    db: SharedDatabase,
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteResendLogStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+#[async_trait]
+impl traits::SendResendLogStore for SqliteResendLogStore {
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry) -> Result<()> {
+        let mut db = self.db.lock("save_resend_entry")?;
+        // A savepoint rather than a transaction, since one may already be in progress.
+        let transaction = db
+            .connection
+            .savepoint()
+            .map_err(database_error("save_resend_entry"))?;
+        transaction
+            .execute(
+                "INSERT INTO resend_entries (timestamp, entry) VALUES (?1, ?2)",
+                params![entry.timestamp, serialize_resend_entry(entry)],
+            )
+            .map_err(database_error("save_resend_entry"))?;
+        let entry_id = transaction.last_insert_rowid();
+        for recipient in &entry.recipients {
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO resend_recipients (name, device_id, timestamp, entry_id)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        recipient.name(),
+                        u32::from(recipient.device_id()),
+                        entry.timestamp,
+                        entry_id
+                    ],
+                )
+                .map_err(database_error("save_resend_entry"))?;
+        }
+        // Drop entries that no longer have any recipient.
+        transaction
+            .execute(
+                "DELETE FROM resend_entries WHERE timestamp = ?1
+                 AND id NOT IN (SELECT entry_id FROM resend_recipients WHERE timestamp = ?1)",
+                params![entry.timestamp],
+            )
+            .map_err(database_error("save_resend_entry"))?;
+        transaction
+            .commit()
+            .map_err(database_error("save_resend_entry"))?;
+        Ok(())
+    }
+
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+    ) -> Result<Option<ResendLogEntry>> {
+        let entry: Option<Vec<u8>> = self
+            .db
+            .lock("load_resend_entry")?
+            .connection
+            .query_row(
+                "SELECT resend_entries.entry FROM resend_recipients
+                 JOIN resend_entries ON resend_entries.id = resend_recipients.entry_id
+                 WHERE name = ?1 AND device_id = ?2 AND resend_recipients.timestamp = ?3",
+                params![
+                    recipient.name(),
+                    u32::from(recipient.device_id()),
+                    timestamp
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(database_error("load_resend_entry"))?;
+        entry
+            .map(|entry| deserialize_resend_entry(&entry))
+            .transpose()
+    }
+
+    async fn remove_resend_entries_before(&mut self, timestamp: u64) -> Result<()> {
+        let mut db = self.db.lock("remove_resend_entries_before")?;
+        let transaction = db
+            .connection
+            .savepoint()
+            .map_err(database_error("remove_resend_entries_before"))?;
+        for table in ["resend_recipients", "resend_entries"] {
+            transaction
+                .execute(
+                    &format!("DELETE FROM {} WHERE timestamp < ?1", table),
+                    params![timestamp],
+                )
+                .map_err(database_error("remove_resend_entries_before"))?;
+        }
+        transaction
+            .commit()
+            .map_err(database_error("remove_resend_entries_before"))
+    }
+}
+
+/// SQLite implementation of [traits::ProtocolStore].
+///
+/// Cloning the store, or any of its parts, shares the underlying connection. The parts of one
+/// store can join each other's transactions, but while one is in progress any other clone's use of
+/// the database fails rather than becoming part of it.
+#[allow(missing_docs)]
+pub struct SqliteSignalProtocolStore {
+    db: SharedDatabase,
+    pub session_store: SqliteSessionStore,
+    pub pre_key_store: SqlitePreKeyStore,
+    pub signed_pre_key_store: SqliteSignedPreKeyStore,
+    pub kyber_pre_key_store: SqliteKyberPreKeyStore,
+    pub identity_store: SqliteIdentityKeyStore,
+    pub sender_key_store: SqliteSenderKeyStore,
+    pub resend_log_store: SqliteResendLogStore,
+}
+
+impl SqliteSignalProtocolStore {
+    /// Open the database at `path`, creating it if necessary, and save the given identity
+    /// `key_pair` along with the separate randomly chosen `registration_id` in it.
+    ///
+    /// Any identity previously saved in the database is replaced.
+    pub fn new(
+        path: impl AsRef<Path>,
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+    ) -> Result<Self> {
+        let connection = Connection::open(path).map_err(database_error("new"))?;
+        let db = SharedDatabase::new(connection)?;
+        let identity_store =
+            SqliteIdentityKeyStore::save_local_identity(&db, key_pair, registration_id)?;
+        Ok(Self::with_identity_store(db, identity_store))
+    }
+
+    /// Like [`new`](Self::new), but with a database that only exists as long as the store.
+    pub fn new_in_memory(key_pair: IdentityKeyPair, registration_id: u32) -> Result<Self> {
+        let connection = Connection::open_in_memory().map_err(database_error("new_in_memory"))?;
+        let db = SharedDatabase::new(connection)?;
+        let identity_store =
+            SqliteIdentityKeyStore::save_local_identity(&db, key_pair, registration_id)?;
+        Ok(Self::with_identity_store(db, identity_store))
+    }
+
+    /// Open the database at `path`, which must already hold an identity saved by
+    /// [`new`](Self::new).
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path).map_err(database_error("open"))?;
+        let db = SharedDatabase::new(connection)?;
+        let identity_store = SqliteIdentityKeyStore::load_local_identity(&db)?;
+        Ok(Self::with_identity_store(db, identity_store))
+    }
+
+    fn with_identity_store(db: SharedDatabase, identity_store: SqliteIdentityKeyStore) -> Self {
+        Self {
+            session_store: SqliteSessionStore { db: db.share() },
+            pre_key_store: SqlitePreKeyStore { db: db.share() },
+            signed_pre_key_store: SqliteSignedPreKeyStore { db: db.share() },
+            kyber_pre_key_store: SqliteKyberPreKeyStore { db: db.share() },
+            sender_key_store: SqliteSenderKeyStore { db: db.share() },
+            resend_log_store: SqliteResendLogStore { db: db.share() },
+            identity_store,
+            db,
+        }
+    }
+}
+
+impl Clone for SqliteSignalProtocolStore {
+    fn clone(&self) -> Self {
+        let db = self.db.clone();
+        let identity_store = SqliteIdentityKeyStore {
+            db: db.share(),
+            key_pair: self.identity_store.key_pair,
+            registration_id: self.identity_store.registration_id,
+        };
+        Self::with_identity_store(db, identity_store)
+    }
+}
+
+#[async_trait]
+impl traits::SendIdentityKeyStore for SqliteSignalProtocolStore {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
+        self.identity_store.get_identity_key_pair().await
+    }
+
+    async fn get_local_registration_id(&self) -> Result<u32> {
+        self.identity_store.get_local_registration_id().await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<traits::IdentityChange> {
+        self.identity_store.save_identity(address, identity).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: traits::Direction,
+    ) -> Result<bool> {
+        self.identity_store
+            .is_trusted_identity(address, identity, direction)
+            .await
+    }
+
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
+        self.identity_store.get_identity(address).await
+    }
+
+    async fn get_verified_status(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<traits::VerifiedStatus> {
+        self.identity_store.get_verified_status(address).await
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: traits::VerifiedStatus,
+    ) -> Result<bool> {
+        self.identity_store
+            .set_verified_status(address, identity, status)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendPreKeyStore for SqliteSignalProtocolStore {
+    async fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
+        self.pre_key_store.get_pre_key(id).await
+    }
+
+    async fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.pre_key_store.save_pre_key(id, record).await
+    }
+
+    async fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
+        self.pre_key_store.remove_pre_key(id).await
+    }
+
+    async fn pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<PreKeyId>> {
+        self.pre_key_store
+            .pre_key_ids_created_before(timestamp)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendSignedPreKeyStore for SqliteSignalProtocolStore {
+    async fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
+        self.signed_pre_key_store.get_signed_pre_key(id).await
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()> {
+        self.signed_pre_key_store
+            .save_signed_pre_key(id, record)
+            .await
+    }
+
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        self.signed_pre_key_store
+            .signed_pre_key_ids_created_before(timestamp)
+            .await
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId) -> Result<()> {
+        self.signed_pre_key_store.remove_signed_pre_key(id).await
+    }
+}
+
+#[async_trait]
+impl traits::SendKyberPreKeyStore for SqliteSignalProtocolStore {
+    async fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        self.kyber_pre_key_store.get_kyber_pre_key(id).await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.kyber_pre_key_store
+            .save_kyber_pre_key(id, record)
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.kyber_pre_key_store.mark_kyber_pre_key_used(id).await
+    }
+
+    async fn kyber_pre_key_ids_created_before(&self, timestamp: u64) -> Result<Vec<KyberPreKeyId>> {
+        self.kyber_pre_key_store
+            .kyber_pre_key_ids_created_before(timestamp)
+            .await
+    }
+
+    async fn remove_kyber_pre_key(&mut self, id: KyberPreKeyId) -> Result<()> {
+        self.kyber_pre_key_store.remove_kyber_pre_key(id).await
+    }
+}
+
+#[async_trait]
+impl traits::SendSessionStore for SqliteSignalProtocolStore {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        self.session_store.load_session(address).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<()> {
+        self.session_store.store_session(address, record).await
+    }
+}
+
+#[async_trait]
+impl traits::SendSenderKeyStore for SqliteSignalProtocolStore {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<()> {
+        self.sender_key_store
+            .store_sender_key(sender, distribution_id, record)
+            .await
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>> {
+        self.sender_key_store
+            .load_sender_key(sender, distribution_id)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendResendLogStore for SqliteSignalProtocolStore {
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry) -> Result<()> {
+        self.resend_log_store.save_resend_entry(entry).await
+    }
+
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+    ) -> Result<Option<ResendLogEntry>> {
+        self.resend_log_store
+            .load_resend_entry(recipient, timestamp)
+            .await
+    }
+
+    async fn remove_resend_entries_before(&mut self, timestamp: u64) -> Result<()> {
+        self.resend_log_store
+            .remove_resend_entries_before(timestamp)
+            .await
+    }
+}
+
+#[async_trait]
+impl traits::SendProtocolStoreTransaction for SqliteSignalProtocolStore {
+    async fn begin_transaction(&mut self) -> Result<()> {
+        self.db.begin_transaction()
+    }
+
+    async fn commit_transaction(&mut self) -> Result<()> {
+        self.db.commit_transaction()
+    }
+
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        self.db.rollback_transaction()
+    }
+}
+
+impl traits::SendProtocolStore for SqliteSignalProtocolStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_version(connection: &Connection) -> usize {
+        connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .expect("can read version")
+    }
+
+    #[test]
+    fn test_migrations_applied_once() {
+        let connection = Connection::open_in_memory().expect("can open");
+        migrate(&connection).expect("can migrate");
+        assert_eq!(schema_version(&connection), MIGRATIONS.len());
+        migrate(&connection).expect("can migrate again");
+        assert_eq!(schema_version(&connection), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_newer_schema_rejected() {
+        let connection = Connection::open_in_memory().expect("can open");
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .expect("can set version");
+        assert!(matches!(
+            migrate(&connection),
+            Err(SignalProtocolError::InvalidState("migrate", _))
+        ));
+    }
+}
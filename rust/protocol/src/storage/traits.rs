@@ -9,17 +9,32 @@ use async_trait::async_trait;
 use uuid::Uuid;
 
 use crate::address::ProtocolAddress;
-use crate::error::Result;
+use crate::error::{Result, SignalProtocolError};
+use crate::retry::ResendLogEntry;
 use crate::sender_keys::SenderKeyRecord;
-use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
+use crate::state::{
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId,
+    SignedPreKeyRecord,
+};
 use crate::{IdentityKey, IdentityKeyPair};
 
 /// Handle to FFI-provided context object.
 ///
 /// This object is not manipulated in Rust, but is instead passed back to each FFI
 /// method invocation. This argument should just be [None] for all clients of the Rust-only API.
+///
+/// Stores that do not need a context, and that can be used from any thread, can implement the
+/// traits in [crate::send] instead, which have no context argument.
 pub type Context = Option<*mut std::ffi::c_void>;
 
+/// The error returned by the default implementations of optional store methods.
+fn unsupported<T>(method: &'static str) -> Result<T> {
+    Err(SignalProtocolError::InvalidState(
+        method,
+        "not supported by this store".to_string(),
+    ))
+}
+
 // TODO: consider moving this enum into utils.rs?
 /// Each Signal message can be considered to have exactly two participants, a sender and receiver.
 ///
@@ -33,13 +48,137 @@ pub enum Direction {
     Receiving,
 }
 
+/// What [IdentityKeyStore::save_identity] did with the identity it was given.
+///
+/// Clients typically show a "safety number changed" notice for either of the `Replaced` variants.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IdentityChange {
+    /// No identity was known for the address before.
+    New,
+    /// The identity was already the one known for the address.
+    Unchanged,
+    /// A different identity, which the user had not verified, was replaced.
+    ReplacedUnverified,
+    /// A different identity, which the user had marked as [VerifiedStatus::Verified], was
+    /// replaced.
+    ReplacedVerified,
+}
+
+impl IdentityChange {
+    /// Whether a different identity was known for the address before.
+    pub fn is_replaced(self) -> bool {
+        matches!(self, Self::ReplacedUnverified | Self::ReplacedVerified)
+    }
+}
+
+/// Whether the user has checked an identity out of band, e.g. by comparing safety numbers.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum VerifiedStatus {
+    /// The user has not verified this identity.
+    Default,
+    /// The user has verified this identity.
+    Verified,
+    /// The user verified a previous identity for this address, which has since been replaced.
+    Unverified,
+}
+
+impl Default for VerifiedStatus {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+/// Interface for grouping the writes of a single operation so that they are persisted together.
+///
+/// Operations that update more than one store, such as [crate::message_decrypt], begin a
+/// transaction on every store they may write to, make their changes, and then commit each
+/// transaction in turn. If anything fails before the first commit, every one of those stores is
+/// rolled back instead.
+///
+/// The commits themselves are not atomic across separate stores: if one store's commit fails after
+/// another's has succeeded, the first store keeps its changes. An operation is only
+/// all-or-nothing for stores backed by the same database. Those will have [begin_transaction]
+/// called once per store; they should join a transaction that is already in progress rather than
+/// starting a new one, and only persist the changes once the outermost transaction is committed.
+///
+/// The default implementations do nothing, which is appropriate for stores that persist each write
+/// on its own.
+///
+/// [begin_transaction]: Self::begin_transaction
+#[async_trait(?Send)]
+pub trait ProtocolStoreTransaction {
+    /// Start grouping writes to this store.
+    async fn begin_transaction(&mut self, _ctx: Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Persist all writes made since [begin_transaction](Self::begin_transaction).
+    async fn commit_transaction(&mut self, _ctx: Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discard all writes made since [begin_transaction](Self::begin_transaction).
+    ///
+    /// This may be called when no transaction is in progress, for example if beginning the
+    /// transaction failed, or if it was already committed; it should do nothing in that case.
+    async fn rollback_transaction(&mut self, _ctx: Context) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Evaluates `$body` inside a transaction on each of the given stores.
+///
+/// `$body` may use `?`. If beginning a transaction, `$body`, or committing fails, every store is
+/// rolled back and the error is returned. Rolling back does nothing for stores that were already
+/// committed, so this is only atomic for stores that share one underlying transaction; see
+/// [ProtocolStoreTransaction].
+macro_rules! in_transaction {
+    ($ctx:expr, [$($store:expr),+ $(,)?], $body:expr) => {{
+        let ctx = $ctx;
+        let result: $crate::error::Result<_> = async {
+            $($store.begin_transaction(ctx).await?;)+
+            let value = $body;
+            $($store.commit_transaction(ctx).await?;)+
+            Ok(value)
+        }
+        .await;
+        if result.is_err() {
+            $(
+                if let Err(e) = $store.rollback_transaction(ctx).await {
+                    log::error!("failed to roll back store transaction: {}", e);
+                }
+            )+
+        }
+        result
+    }};
+    // For stores implementing the `Send` traits, which take no context.
+    ([$($store:expr),+ $(,)?], $body:expr) => {{
+        let result: $crate::error::Result<_> = async {
+            $($store.begin_transaction().await?;)+
+            let value = $body;
+            $($store.commit_transaction().await?;)+
+            Ok(value)
+        }
+        .await;
+        if result.is_err() {
+            $(
+                if let Err(e) = $store.rollback_transaction().await {
+                    log::error!("failed to roll back store transaction: {}", e);
+                }
+            )+
+        }
+        result
+    }};
+}
+pub(crate) use in_transaction;
+
 /// Interface defining the identity store, which may be in-memory, on-disk, etc.
 ///
 /// Signal clients usually use the identity store in a [TOFU] manner, but this is not required.
 ///
 /// [TOFU]: https://en.wikipedia.org/wiki/Trust_on_first_use
 #[async_trait(?Send)]
-pub trait IdentityKeyStore {
+pub trait IdentityKeyStore: ProtocolStoreTransaction {
     /// Return the single specific identity the store is assumed to represent, with private key.
     async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair>;
 
@@ -53,17 +192,18 @@ pub trait IdentityKeyStore {
     /// be regenerated.
     async fn get_local_registration_id(&self, ctx: Context) -> Result<u32>;
 
-    // TODO: make this into an enum instead of a bool!
     /// Record an identity into the store. The identity is then considered "trusted".
     ///
-    /// The return value represents whether an existing identity was replaced (`Ok(true)`). If it is
-    /// new or hasn't changed, the return value should be `Ok(false)`.
+    /// The return value says whether the identity is new, unchanged, or replaced a different one.
+    /// When an identity is replaced, its [VerifiedStatus] should be reset: to
+    /// [VerifiedStatus::Unverified] if the old identity was [VerifiedStatus::Verified], and to
+    /// [VerifiedStatus::Default] otherwise.
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
         ctx: Context,
-    ) -> Result<bool>;
+    ) -> Result<IdentityChange>;
 
     /// Return whether an identity is trusted for the role specified by `direction`.
     async fn is_trusted_identity(
@@ -80,11 +220,33 @@ pub trait IdentityKeyStore {
         address: &ProtocolAddress,
         ctx: Context,
     ) -> Result<Option<IdentityKey>>;
+
+    /// Return whether the user has verified the identity known for `address`.
+    ///
+    /// Addresses with no known identity are [VerifiedStatus::Default].
+    async fn get_verified_status(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<VerifiedStatus>;
+
+    /// Record whether the user has verified `identity` for `address`.
+    ///
+    /// This does nothing unless `identity` is the identity currently known for `address`, so that
+    /// a verification is never applied to a key the user did not see. Returns whether the status
+    /// was recorded.
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: VerifiedStatus,
+        ctx: Context,
+    ) -> Result<bool>;
 }
 
 /// Interface for storing pre-keys downloaded from a server.
 #[async_trait(?Send)]
-pub trait PreKeyStore {
+pub trait PreKeyStore: ProtocolStoreTransaction {
     /// Look up the pre-key corresponding to `prekey_id`.
     async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord>;
 
@@ -98,6 +260,20 @@ pub trait PreKeyStore {
 
     /// Remove the entry for `prekey_id`.
     async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()>;
+
+    /// Returns the ids of pre-keys created before `timestamp` (in milliseconds since the epoch),
+    /// so that keys which were never used can be pruned.
+    ///
+    /// Keys saved without a creation time (a timestamp of 0) are never included, matching the age
+    /// limits in [SessionPolicy](crate::SessionPolicy). Stores that cannot answer this return an
+    /// error.
+    async fn pre_key_ids_created_before(
+        &self,
+        _timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<PreKeyId>> {
+        unsupported("pre_key_ids_created_before")
+    }
 }
 
 /// Interface for storing signed pre-keys downloaded from a server.
@@ -117,6 +293,85 @@ pub trait SignedPreKeyStore {
         record: &SignedPreKeyRecord,
         ctx: Context,
     ) -> Result<()>;
+
+    /// Returns the ids of signed pre-keys created before `timestamp` (in milliseconds since the
+    /// epoch), except for those saved without a creation time.
+    ///
+    /// This may include the signed pre-key currently published to the server; callers should
+    /// exclude it before removing the rest. Stores that cannot answer this return an error.
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        _timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        unsupported("signed_pre_key_ids_created_before")
+    }
+
+    /// Remove the entry for `signed_prekey_id`, if any.
+    ///
+    /// Stores that cannot remove signed pre-keys return an error.
+    async fn remove_signed_pre_key(
+        &mut self,
+        _signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        unsupported("remove_signed_pre_key")
+    }
+}
+
+/// Interface for storing signed Kyber pre-keys downloaded from a server.
+///
+/// A Kyber pre-key is either "one-time", to be used for a single session, or "last-resort", to be
+/// used whenever no one-time key is available. It is up to the store to remember which is which.
+#[async_trait(?Send)]
+pub trait KyberPreKeyStore: ProtocolStoreTransaction {
+    /// Look up the signed Kyber pre-key corresponding to `kyber_prekey_id`.
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<KyberPreKeyRecord>;
+
+    /// Set the entry for `kyber_prekey_id` to the value of `record`.
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()>;
+
+    /// Mark the entry for `kyber_prekey_id` as "used".
+    ///
+    /// One-time pre-keys should be removed at this point; last-resort pre-keys should be kept.
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<()>;
+
+    /// Returns the ids of Kyber pre-keys created before `timestamp` (in milliseconds since the
+    /// epoch), including last-resort keys, except for those saved without a creation time.
+    ///
+    /// This may include the last-resort key currently published to the server; callers should
+    /// exclude it before removing the rest. Stores that cannot answer this return an error.
+    async fn kyber_pre_key_ids_created_before(
+        &self,
+        _timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<KyberPreKeyId>> {
+        unsupported("kyber_pre_key_ids_created_before")
+    }
+
+    /// Remove the entry for `kyber_prekey_id`, if any, whether or not it is a last-resort key.
+    ///
+    /// Stores that cannot remove Kyber pre-keys return an error.
+    async fn remove_kyber_pre_key(
+        &mut self,
+        _kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        unsupported("remove_kyber_pre_key")
+    }
 }
 
 /// Interface for a Signal client instance to store a session associated with another particular
@@ -127,7 +382,7 @@ pub trait SignedPreKeyStore {
 ///
 /// [Double Ratchet]: https://signal.org/docs/specifications/doubleratchet/
 #[async_trait(?Send)]
-pub trait SessionStore {
+pub trait SessionStore: ProtocolStoreTransaction {
     /// Look up the session corresponding to `address`.
     async fn load_session(
         &self,
@@ -146,7 +401,7 @@ pub trait SessionStore {
 
 /// Interface for storing sender key records, allowing multiple keys per user.
 #[async_trait(?Send)]
-pub trait SenderKeyStore {
+pub trait SenderKeyStore: ProtocolStoreTransaction {
     /// Assign `record` to the entry for `(sender, distribution_id)`.
     async fn store_sender_key(
         &mut self,
@@ -166,5 +421,586 @@ pub trait SenderKeyStore {
     ) -> Result<Option<SenderKeyRecord>>;
 }
 
+/// Interface for keeping the messages we send, so that they can be resent after a
+/// [DecryptionErrorMessage](crate::DecryptionErrorMessage).
+///
+/// Entries are identified by recipient and timestamp, since timestamps are only unique among the
+/// messages sent to one recipient.
+///
+/// Entries hold message plaintext, so stores that persist them should encrypt them at rest.
+#[async_trait(?Send)]
+pub trait ResendLogStore: ProtocolStoreTransaction {
+    /// Record `entry` for each of its recipients, replacing any entry for the same recipient and
+    /// timestamp.
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry, ctx: Context) -> Result<()>;
+
+    /// Look up the entry for the message sent to `recipient` at `timestamp`.
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+        ctx: Context,
+    ) -> Result<Option<ResendLogEntry>>;
+
+    /// Remove every entry for a message sent before `timestamp`.
+    async fn remove_resend_entries_before(&mut self, timestamp: u64, ctx: Context) -> Result<()>;
+}
+
+/// The kinds of record kept in a [RecordStore].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// A [SessionRecord], identified by the remote address.
+    Session = 1,
+    /// A [PreKeyRecord], identified by its id.
+    PreKey = 2,
+    /// A [SignedPreKeyRecord], identified by its id.
+    SignedPreKey = 3,
+    /// A one-time [KyberPreKeyRecord], identified by its id.
+    KyberPreKey = 4,
+    /// A last-resort [KyberPreKeyRecord], identified by its id.
+    LastResortKyberPreKey = 5,
+    /// A [SenderKeyRecord], identified by the sender's address and the distribution id.
+    SenderKey = 6,
+}
+
+impl RecordKind {
+    /// Every kind of record, in the order of their discriminants.
+    pub const ALL: [RecordKind; 6] = [
+        RecordKind::Session,
+        RecordKind::PreKey,
+        RecordKind::SignedPreKey,
+        RecordKind::KyberPreKey,
+        RecordKind::LastResortKyberPreKey,
+        RecordKind::SenderKey,
+    ];
+}
+
+/// Interface for storing records as opaque bytes.
+///
+/// Implementing this instead of the typed store interfaces lets a store be wrapped in a
+/// [crate::EncryptedRecordStore], which encrypts each record before it reaches this store. Each
+/// record is identified by its [RecordKind] together with an id that is unique within that kind.
+#[async_trait(?Send)]
+pub trait RecordStore: ProtocolStoreTransaction {
+    /// Look up the record stored under `kind` and `id`.
+    async fn load_record(
+        &self,
+        kind: RecordKind,
+        id: &[u8],
+        ctx: Context,
+    ) -> Result<Option<Vec<u8>>>;
+
+    /// Set the record stored under `kind` and `id` to `record`, replacing any existing one.
+    async fn store_record(
+        &mut self,
+        kind: RecordKind,
+        id: &[u8],
+        record: &[u8],
+        ctx: Context,
+    ) -> Result<()>;
+
+    /// Remove the record stored under `kind` and `id`, if there is one.
+    async fn remove_record(&mut self, kind: RecordKind, id: &[u8], ctx: Context) -> Result<()>;
+
+    /// List the ids of every record stored under `kind`.
+    async fn record_ids(&self, kind: RecordKind, ctx: Context) -> Result<Vec<Vec<u8>>>;
+}
+
 /// Mixes in all the store interfaces defined in this module.
-pub trait ProtocolStore: SessionStore + PreKeyStore + SignedPreKeyStore + IdentityKeyStore {}
+pub trait ProtocolStore:
+    SessionStore + PreKeyStore + SignedPreKeyStore + KyberPreKeyStore + IdentityKeyStore
+{
+}
+
+/// Like [ProtocolStoreTransaction], for stores that can be used from any thread.
+///
+/// This and the other `Send` store traits mirror the traits above without the [Context]
+/// argument. Every type implementing one of them also implements its counterpart, so these stores
+/// can be used with the functions at the crate root as well as with those in [crate::send], whose
+/// futures are [Send].
+#[async_trait]
+pub trait SendProtocolStoreTransaction: Send + Sync {
+    /// See [ProtocolStoreTransaction::begin_transaction].
+    async fn begin_transaction(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// See [ProtocolStoreTransaction::commit_transaction].
+    async fn commit_transaction(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// See [ProtocolStoreTransaction::rollback_transaction].
+    async fn rollback_transaction(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Like [IdentityKeyStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendIdentityKeyStore: SendProtocolStoreTransaction {
+    /// See [IdentityKeyStore::get_identity_key_pair].
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair>;
+
+    /// See [IdentityKeyStore::get_local_registration_id].
+    async fn get_local_registration_id(&self) -> Result<u32>;
+
+    /// See [IdentityKeyStore::save_identity].
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<IdentityChange>;
+
+    /// See [IdentityKeyStore::is_trusted_identity].
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+    ) -> Result<bool>;
+
+    /// See [IdentityKeyStore::get_identity].
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>>;
+
+    /// See [IdentityKeyStore::get_verified_status].
+    async fn get_verified_status(&self, address: &ProtocolAddress) -> Result<VerifiedStatus>;
+
+    /// See [IdentityKeyStore::set_verified_status].
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: VerifiedStatus,
+    ) -> Result<bool>;
+}
+
+/// Like [PreKeyStore], for stores that can be used from any thread.
+// `Send + Sync` are repeated so that the default methods below stay object-safe.
+#[async_trait]
+pub trait SendPreKeyStore: SendProtocolStoreTransaction + Send + Sync {
+    /// See [PreKeyStore::get_pre_key].
+    async fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord>;
+
+    /// See [PreKeyStore::save_pre_key].
+    async fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord) -> Result<()>;
+
+    /// See [PreKeyStore::remove_pre_key].
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<()>;
+
+    /// See [PreKeyStore::pre_key_ids_created_before].
+    async fn pre_key_ids_created_before(&self, _timestamp: u64) -> Result<Vec<PreKeyId>> {
+        unsupported("pre_key_ids_created_before")
+    }
+}
+
+/// Like [SignedPreKeyStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendSignedPreKeyStore: Send + Sync {
+    /// See [SignedPreKeyStore::get_signed_pre_key].
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+    ) -> Result<SignedPreKeyRecord>;
+
+    /// See [SignedPreKeyStore::save_signed_pre_key].
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()>;
+
+    /// See [SignedPreKeyStore::signed_pre_key_ids_created_before].
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        _timestamp: u64,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        unsupported("signed_pre_key_ids_created_before")
+    }
+
+    /// See [SignedPreKeyStore::remove_signed_pre_key].
+    async fn remove_signed_pre_key(&mut self, _signed_prekey_id: SignedPreKeyId) -> Result<()> {
+        unsupported("remove_signed_pre_key")
+    }
+}
+
+/// Like [KyberPreKeyStore], for stores that can be used from any thread.
+// `Send + Sync` are repeated so that the default methods below stay object-safe.
+#[async_trait]
+pub trait SendKyberPreKeyStore: SendProtocolStoreTransaction + Send + Sync {
+    /// See [KyberPreKeyStore::get_kyber_pre_key].
+    async fn get_kyber_pre_key(&self, kyber_prekey_id: KyberPreKeyId) -> Result<KyberPreKeyRecord>;
+
+    /// See [KyberPreKeyStore::save_kyber_pre_key].
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()>;
+
+    /// See [KyberPreKeyStore::mark_kyber_pre_key_used].
+    async fn mark_kyber_pre_key_used(&mut self, kyber_prekey_id: KyberPreKeyId) -> Result<()>;
+
+    /// See [KyberPreKeyStore::kyber_pre_key_ids_created_before].
+    async fn kyber_pre_key_ids_created_before(
+        &self,
+        _timestamp: u64,
+    ) -> Result<Vec<KyberPreKeyId>> {
+        unsupported("kyber_pre_key_ids_created_before")
+    }
+
+    /// See [KyberPreKeyStore::remove_kyber_pre_key].
+    async fn remove_kyber_pre_key(&mut self, _kyber_prekey_id: KyberPreKeyId) -> Result<()> {
+        unsupported("remove_kyber_pre_key")
+    }
+}
+
+/// Like [SessionStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendSessionStore: SendProtocolStoreTransaction {
+    /// See [SessionStore::load_session].
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>>;
+
+    /// See [SessionStore::store_session].
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<()>;
+}
+
+/// Like [SenderKeyStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendSenderKeyStore: SendProtocolStoreTransaction {
+    /// See [SenderKeyStore::store_sender_key].
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<()>;
+
+    /// See [SenderKeyStore::load_sender_key].
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>>;
+}
+
+/// Like [ResendLogStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendResendLogStore: SendProtocolStoreTransaction {
+    /// See [ResendLogStore::save_resend_entry].
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry) -> Result<()>;
+
+    /// See [ResendLogStore::load_resend_entry].
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+    ) -> Result<Option<ResendLogEntry>>;
+
+    /// See [ResendLogStore::remove_resend_entries_before].
+    async fn remove_resend_entries_before(&mut self, timestamp: u64) -> Result<()>;
+}
+
+/// Like [RecordStore], for stores that can be used from any thread.
+#[async_trait]
+pub trait SendRecordStore: SendProtocolStoreTransaction {
+    /// See [RecordStore::load_record].
+    async fn load_record(&self, kind: RecordKind, id: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// See [RecordStore::store_record].
+    async fn store_record(&mut self, kind: RecordKind, id: &[u8], record: &[u8]) -> Result<()>;
+
+    /// See [RecordStore::remove_record].
+    async fn remove_record(&mut self, kind: RecordKind, id: &[u8]) -> Result<()>;
+
+    /// See [RecordStore::record_ids].
+    async fn record_ids(&self, kind: RecordKind) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Like [ProtocolStore], for stores that can be used from any thread.
+pub trait SendProtocolStore:
+    SendSessionStore
+    + SendPreKeyStore
+    + SendSignedPreKeyStore
+    + SendKyberPreKeyStore
+    + SendIdentityKeyStore
+{
+}
+
+impl<T: SendProtocolStore + ?Sized> ProtocolStore for T {}
+
+#[async_trait(?Send)]
+impl<T: SendProtocolStoreTransaction + ?Sized> ProtocolStoreTransaction for T {
+    async fn begin_transaction(&mut self, _ctx: Context) -> Result<()> {
+        SendProtocolStoreTransaction::begin_transaction(self).await
+    }
+
+    async fn commit_transaction(&mut self, _ctx: Context) -> Result<()> {
+        SendProtocolStoreTransaction::commit_transaction(self).await
+    }
+
+    async fn rollback_transaction(&mut self, _ctx: Context) -> Result<()> {
+        SendProtocolStoreTransaction::rollback_transaction(self).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendIdentityKeyStore + ?Sized> IdentityKeyStore for T {
+    async fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair> {
+        SendIdentityKeyStore::get_identity_key_pair(self).await
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32> {
+        SendIdentityKeyStore::get_local_registration_id(self).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<IdentityChange> {
+        SendIdentityKeyStore::save_identity(self, address, identity).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool> {
+        SendIdentityKeyStore::is_trusted_identity(self, address, identity, direction).await
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        SendIdentityKeyStore::get_identity(self, address).await
+    }
+
+    async fn get_verified_status(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<VerifiedStatus> {
+        SendIdentityKeyStore::get_verified_status(self, address).await
+    }
+
+    async fn set_verified_status(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        status: VerifiedStatus,
+        _ctx: Context,
+    ) -> Result<bool> {
+        SendIdentityKeyStore::set_verified_status(self, address, identity, status).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendPreKeyStore + ?Sized> PreKeyStore for T {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord> {
+        SendPreKeyStore::get_pre_key(self, prekey_id).await
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendPreKeyStore::save_pre_key(self, prekey_id, record).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, _ctx: Context) -> Result<()> {
+        SendPreKeyStore::remove_pre_key(self, prekey_id).await
+    }
+
+    async fn pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<PreKeyId>> {
+        SendPreKeyStore::pre_key_ids_created_before(self, timestamp).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendSignedPreKeyStore + ?Sized> SignedPreKeyStore for T {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        SendSignedPreKeyStore::get_signed_pre_key(self, signed_prekey_id).await
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendSignedPreKeyStore::save_signed_pre_key(self, signed_prekey_id, record).await
+    }
+
+    async fn signed_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        SendSignedPreKeyStore::signed_pre_key_ids_created_before(self, timestamp).await
+    }
+
+    async fn remove_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendSignedPreKeyStore::remove_signed_pre_key(self, signed_prekey_id).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendKyberPreKeyStore + ?Sized> KyberPreKeyStore for T {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        SendKyberPreKeyStore::get_kyber_pre_key(self, kyber_prekey_id).await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendKyberPreKeyStore::save_kyber_pre_key(self, kyber_prekey_id, record).await
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendKyberPreKeyStore::mark_kyber_pre_key_used(self, kyber_prekey_id).await
+    }
+
+    async fn kyber_pre_key_ids_created_before(
+        &self,
+        timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Vec<KyberPreKeyId>> {
+        SendKyberPreKeyStore::kyber_pre_key_ids_created_before(self, timestamp).await
+    }
+
+    async fn remove_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendKyberPreKeyStore::remove_kyber_pre_key(self, kyber_prekey_id).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendSessionStore + ?Sized> SessionStore for T {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        SendSessionStore::load_session(self, address).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendSessionStore::store_session(self, address, record).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendSenderKeyStore + ?Sized> SenderKeyStore for T {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        SendSenderKeyStore::store_sender_key(self, sender, distribution_id, record).await
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        _ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        SendSenderKeyStore::load_sender_key(self, sender, distribution_id).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendResendLogStore + ?Sized> ResendLogStore for T {
+    async fn save_resend_entry(&mut self, entry: &ResendLogEntry, _ctx: Context) -> Result<()> {
+        SendResendLogStore::save_resend_entry(self, entry).await
+    }
+
+    async fn load_resend_entry(
+        &self,
+        recipient: &ProtocolAddress,
+        timestamp: u64,
+        _ctx: Context,
+    ) -> Result<Option<ResendLogEntry>> {
+        SendResendLogStore::load_resend_entry(self, recipient, timestamp).await
+    }
+
+    async fn remove_resend_entries_before(&mut self, timestamp: u64, _ctx: Context) -> Result<()> {
+        SendResendLogStore::remove_resend_entries_before(self, timestamp).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: SendRecordStore + ?Sized> RecordStore for T {
+    async fn load_record(
+        &self,
+        kind: RecordKind,
+        id: &[u8],
+        _ctx: Context,
+    ) -> Result<Option<Vec<u8>>> {
+        SendRecordStore::load_record(self, kind, id).await
+    }
+
+    async fn store_record(
+        &mut self,
+        kind: RecordKind,
+        id: &[u8],
+        record: &[u8],
+        _ctx: Context,
+    ) -> Result<()> {
+        SendRecordStore::store_record(self, kind, id, record).await
+    }
+
+    async fn remove_record(&mut self, kind: RecordKind, id: &[u8], _ctx: Context) -> Result<()> {
+        SendRecordStore::remove_record(self, kind, id).await
+    }
+
+    async fn record_ids(&self, kind: RecordKind, _ctx: Context) -> Result<Vec<Vec<u8>>> {
+        SendRecordStore::record_ids(self, kind).await
+    }
+}
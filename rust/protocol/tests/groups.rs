@@ -29,6 +29,7 @@ fn group_no_send_session() -> Result<(), SignalProtocolError> {
         &sender_address,
         distribution_id,
         "space camp?".as_bytes(),
+        &SessionPolicy::default(),
         &mut csprng,
         None,
     )
@@ -53,6 +54,8 @@ impl ContextUsingSenderKeyStore {
     }
 }
 
+impl ProtocolStoreTransaction for ContextUsingSenderKeyStore {}
+
 #[async_trait(?Send)]
 impl SenderKeyStore for ContextUsingSenderKeyStore {
     async fn store_sender_key(
@@ -139,6 +142,7 @@ fn group_no_recv_session() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "space camp?".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -148,6 +152,7 @@ fn group_no_recv_session() -> Result<(), SignalProtocolError> {
             alice_ciphertext.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await;
@@ -188,6 +193,7 @@ fn group_basic_encrypt_decrypt() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "space camp?".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -205,6 +211,7 @@ fn group_basic_encrypt_decrypt() -> Result<(), SignalProtocolError> {
             alice_ciphertext.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -220,6 +227,98 @@ fn group_basic_encrypt_decrypt() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn group_message_padding() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        process_sender_key_distribution_message(
+            &sender_address,
+            &SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let policy = SessionPolicy {
+            padding: Some(padding::PaddingScheme::Padme),
+            ..SessionPolicy::default()
+        };
+
+        let alice_ciphertext = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            &[b'x'; 1000],
+            &policy,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        // 1001 bytes are padded to 1024, then CBC adds a block.
+        assert_eq!(alice_ciphertext.ciphertext().len(), 1040);
+
+        let bob_plaintext = group_decrypt(
+            alice_ciphertext.serialized(),
+            &mut bob_store,
+            &sender_address,
+            &policy,
+            None,
+        )
+        .await?;
+        assert_eq!(bob_plaintext, [b'x'; 1000]);
+
+        let unpadded_ciphertext = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            b"space camp?",
+            &SessionPolicy::default(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let err = group_decrypt(
+            unpadded_ciphertext.serialized(),
+            &mut bob_store,
+            &sender_address,
+            &policy,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, SignalProtocolError::InvalidPadding));
+        // The rejected message's key is kept, so it can still be decrypted.
+        let bob_plaintext = group_decrypt(
+            unpadded_ciphertext.serialized(),
+            &mut bob_store,
+            &sender_address,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+        assert_eq!(bob_plaintext, b"space camp?");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn group_sealed_sender() -> Result<(), SignalProtocolError> {
     async {
@@ -324,6 +423,7 @@ fn group_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_uuid_address,
             distribution_id,
             "space camp?".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -366,6 +466,7 @@ fn group_sealed_sender() -> Result<(), SignalProtocolError> {
             bob_usmc.contents()?,
             &mut bob_store,
             &alice_uuid_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -385,6 +486,7 @@ fn group_sealed_sender() -> Result<(), SignalProtocolError> {
             carol_usmc.contents()?,
             &mut carol_store,
             &alice_uuid_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -433,6 +535,7 @@ fn group_large_messages() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             &large_message,
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -450,6 +553,7 @@ fn group_large_messages() -> Result<(), SignalProtocolError> {
             alice_ciphertext.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -498,6 +602,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "swim camp".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -507,6 +612,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "robot camp".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -516,6 +622,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "ninja camp".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -525,6 +632,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             alice_ciphertext1.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -538,6 +646,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
                 alice_ciphertext1.serialized(),
                 &mut bob_store,
                 &sender_address,
+                &SessionPolicy::default(),
                 None
             )
             .await,
@@ -548,6 +657,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             alice_ciphertext3.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -560,6 +670,7 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
             alice_ciphertext2.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -603,6 +714,7 @@ fn group_late_join() -> Result<(), SignalProtocolError> {
                 &sender_address,
                 distribution_id,
                 format!("nefarious plotting {}/100", i).as_bytes(),
+                &SessionPolicy::default(),
                 &mut csprng,
                 None,
             )
@@ -623,6 +735,7 @@ fn group_late_join() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "welcome bob".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -632,6 +745,7 @@ fn group_late_join() -> Result<(), SignalProtocolError> {
             alice_ciphertext.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -686,6 +800,7 @@ fn group_out_of_order() -> Result<(), SignalProtocolError> {
                     &sender_address,
                     distribution_id,
                     format!("nefarious plotting {:02}/100", i).as_bytes(),
+                    &SessionPolicy::default(),
                     &mut csprng,
                     None,
                 )
@@ -703,6 +818,7 @@ fn group_out_of_order() -> Result<(), SignalProtocolError> {
                     ciphertext.serialized(),
                     &mut bob_store,
                     &sender_address,
+                    &SessionPolicy::default(),
                     None,
                 )
                 .await?,
@@ -762,6 +878,7 @@ fn group_too_far_in_the_future() -> Result<(), SignalProtocolError> {
                 &sender_address,
                 distribution_id,
                 format!("nefarious plotting {}", i).as_bytes(),
+                &SessionPolicy::default(),
                 &mut csprng,
                 None,
             )
@@ -773,6 +890,7 @@ fn group_too_far_in_the_future() -> Result<(), SignalProtocolError> {
             &sender_address,
             distribution_id,
             "you got the plan?".as_bytes(),
+            &SessionPolicy::default(),
             &mut csprng,
             None,
         )
@@ -782,6 +900,7 @@ fn group_too_far_in_the_future() -> Result<(), SignalProtocolError> {
             alice_ciphertext.serialized(),
             &mut bob_store,
             &sender_address,
+            &SessionPolicy::default(),
             None
         )
         .await
@@ -833,6 +952,7 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
                     &sender_address,
                     distribution_id,
                     "too many messages".as_bytes(),
+                    &SessionPolicy::default(),
                     &mut csprng,
                     None,
                 )
@@ -844,7 +964,14 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
 
         assert_eq!(
             String::from_utf8(
-                group_decrypt(&ciphertexts[1000], &mut bob_store, &sender_address, None,).await?
+                group_decrypt(
+                    &ciphertexts[1000],
+                    &mut bob_store,
+                    &sender_address,
+                    &SessionPolicy::default(),
+                    None,
+                )
+                .await?
             )
             .expect("valid utf8"),
             "too many messages"
@@ -855,6 +982,7 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
                     &ciphertexts[ciphertexts.len() - 1],
                     &mut bob_store,
                     &sender_address,
+                    &SessionPolicy::default(),
                     None,
                 )
                 .await?
@@ -862,12 +990,531 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
             .expect("valid utf8"),
             "too many messages"
         );
+        assert!(group_decrypt(
+            &ciphertexts[0],
+            &mut bob_store,
+            &sender_address,
+            &SessionPolicy::default(),
+            None
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_skipped_key_expiry() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let recv_distribution_message =
+            SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?;
+
+        process_sender_key_distribution_message(
+            &sender_address,
+            &recv_distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let mut ciphertexts = Vec::with_capacity(3);
+
+        for _ in 0..ciphertexts.capacity() {
+            ciphertexts.push(
+                group_encrypt(
+                    &mut alice_store,
+                    &sender_address,
+                    distribution_id,
+                    "short-lived".as_bytes(),
+                    &SessionPolicy::default(),
+                    &mut csprng,
+                    None,
+                )
+                .await?
+                .serialized()
+                .to_vec(),
+            );
+        }
+
+        let policy = SessionPolicy {
+            max_skipped_key_age: Some(std::time::Duration::ZERO),
+            ..SessionPolicy::default()
+        };
+
+        // Skips the keys for the first two messages...
+        group_decrypt(
+            &ciphertexts[2],
+            &mut bob_store,
+            &sender_address,
+            &policy,
+            None,
+        )
+        .await?;
+        // ...which have already expired by the time the next message arrives.
+        assert!(matches!(
+            group_decrypt(
+                &ciphertexts[0],
+                &mut bob_store,
+                &sender_address,
+                &policy,
+                None
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(3, 0))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_sender_key_rotation_policy() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+
+        create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message_limit = SessionPolicy {
+            max_sender_key_messages: Some(2),
+            ..SessionPolicy::default()
+        };
+        for _ in 0..2 {
+            group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "limited".as_bytes(),
+                &message_limit,
+                &mut csprng,
+                None,
+            )
+            .await?;
+        }
+        assert!(matches!(
+            group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "one too many".as_bytes(),
+                &message_limit,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SenderKeyRotationRequired {
+                reason: SenderKeyRotationReason::MessageLimit,
+                ..
+            })
+        ));
+
+        let age_limit = SessionPolicy {
+            max_sender_key_age: Some(std::time::Duration::ZERO),
+            ..SessionPolicy::default()
+        };
+        assert!(matches!(
+            group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "too old".as_bytes(),
+                &age_limit,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SenderKeyRotationRequired {
+                reason: SenderKeyRotationReason::Expired,
+                ..
+            })
+        ));
+
+        // A new sender key starts counting from zero again.
+        rotate_sender_key(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            "fresh".as_bytes(),
+            &message_limit,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_member_removal_requires_rotation() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14159999112".to_owned(), 1.into());
+        let carol_address = ProtocolAddress::new("+14159999113".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let mut carol_store = test_in_memory_protocol_store()?;
+
+        let members = [bob_address.clone(), carol_address.clone()];
+        assert_eq!(
+            members_missing_sender_key(
+                &sender_address,
+                distribution_id,
+                &members,
+                &mut alice_store,
+                None
+            )
+            .await?,
+            members
+        );
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        for store in [&mut bob_store, &mut carol_store] {
+            process_sender_key_distribution_message(
+                &sender_address,
+                &sent_distribution_message,
+                store,
+                None,
+            )
+            .await?;
+        }
+        mark_sender_key_distributed(
+            &sender_address,
+            distribution_id,
+            &[bob_address.clone()],
+            &mut alice_store,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            members_missing_sender_key(
+                &sender_address,
+                distribution_id,
+                &members,
+                &mut alice_store,
+                None
+            )
+            .await?,
+            vec![carol_address.clone()]
+        );
+        mark_sender_key_distributed(
+            &sender_address,
+            distribution_id,
+            &[carol_address.clone()],
+            &mut alice_store,
+            None,
+        )
+        .await?;
+
+        // Removing someone who never had the key does not require rotation.
+        let dave_address = ProtocolAddress::new("+14159999114".to_owned(), 1.into());
         assert!(
-            group_decrypt(&ciphertexts[0], &mut bob_store, &sender_address, None)
-                .await
-                .is_err()
+            !remove_sender_key_recipients(
+                &sender_address,
+                distribution_id,
+                &[dave_address],
+                &mut alice_store,
+                None
+            )
+            .await?
         );
 
+        assert!(
+            remove_sender_key_recipients(
+                &sender_address,
+                distribution_id,
+                &[carol_address.clone()],
+                &mut alice_store,
+                None
+            )
+            .await?
+        );
+        assert!(matches!(
+            group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "carol is gone".as_bytes(),
+                &SessionPolicy::default(),
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SenderKeyRotationRequired {
+                reason: SenderKeyRotationReason::MemberRemoved,
+                ..
+            })
+        ));
+
+        let rotated_distribution_message = rotate_sender_key(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_ne!(
+            rotated_distribution_message.chain_id()?,
+            sent_distribution_message.chain_id()?
+        );
+        assert_eq!(
+            members_missing_sender_key(
+                &sender_address,
+                distribution_id,
+                &[bob_address.clone()],
+                &mut alice_store,
+                None
+            )
+            .await?,
+            vec![bob_address.clone()]
+        );
+
+        process_sender_key_distribution_message(
+            &sender_address,
+            &rotated_distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+        mark_sender_key_distributed(
+            &sender_address,
+            distribution_id,
+            &[bob_address],
+            &mut alice_store,
+            None,
+        )
+        .await?;
+
+        let alice_ciphertext = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            "carol is gone".as_bytes(),
+            &SessionPolicy::default(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let bob_plaintext = group_decrypt(
+            alice_ciphertext.serialized(),
+            &mut bob_store,
+            &sender_address,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(bob_plaintext).expect("valid utf8"),
+            "carol is gone"
+        );
+
+        assert!(matches!(
+            group_decrypt(
+                alice_ciphertext.serialized(),
+                &mut carol_store,
+                &sender_address,
+                &SessionPolicy::default(),
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::NoSenderKeyState { .. })
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_member_removal_without_tracking_requires_rotation() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14159999112".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+
+        // The key may have been handed out without mark_sender_key_distributed.
+        create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert!(
+            remove_sender_key_recipients(
+                &sender_address,
+                distribution_id,
+                &[bob_address],
+                &mut alice_store,
+                None
+            )
+            .await?
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_decryption_error_resends_sender_key() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14159999112".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let timestamp = 1_700_000_000_000;
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+
+        create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        mark_sender_key_distributed(
+            &sender_address,
+            distribution_id,
+            &[bob_address.clone()],
+            &mut alice_store,
+            None,
+        )
+        .await?;
+
+        let alice_ciphertext = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            b"space camp?",
+            &SessionPolicy::default(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let entry = ResendLogEntry {
+            timestamp,
+            content: b"space camp?".to_vec(),
+            distribution_id: Some(distribution_id),
+            recipients: vec![bob_address.clone()],
+        };
+        alice_store.save_resend_entry(&entry, None).await?;
+
+        let error_message = DecryptionErrorMessage::for_original(
+            alice_ciphertext.serialized(),
+            CiphertextMessageType::SenderKey,
+            timestamp,
+            1,
+        )?;
+        let plan = process_decryption_error_message(
+            &error_message,
+            &bob_address,
+            &sender_address,
+            &mut alice_store.session_store,
+            &mut alice_store.sender_key_store,
+            &alice_store.resend_log_store,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+        assert_eq!(
+            plan,
+            RetryPlan {
+                session_archived: false,
+                sender_key_distribution_id: Some(distribution_id),
+                resend: Some(entry),
+            }
+        );
+
+        // Bob has to be sent the sender key again, but it does not have to be rotated.
+        assert_eq!(
+            members_missing_sender_key(
+                &sender_address,
+                distribution_id,
+                &[bob_address.clone()],
+                &mut alice_store,
+                None
+            )
+            .await?,
+            vec![bob_address]
+        );
+        group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            b"space camp?",
+            &SessionPolicy::default(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+
         Ok(())
     }
     .now_or_never()
@@ -0,0 +1,282 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use futures_util::FutureExt;
+use libsignal_protocol::key_transparency::*;
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const ALICE_UUID: &str = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+const BOB_UUID: &str = "796abedb-ca4e-4f18-8803-1fde5b921f9f";
+
+fn aci(uuid: &str) -> ServiceId {
+    Aci::from(Uuid::parse_str(uuid).expect("valid UUID")).into()
+}
+
+/// A tree head timestamp, `seconds` before now.
+fn seconds_ago(seconds: u64) -> u64 {
+    (SystemTime::now() - Duration::from_secs(seconds))
+        .duration_since(UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_millis() as u64
+}
+
+fn assert_verification_failed<T: std::fmt::Debug>(result: Result<T, SignalProtocolError>) {
+    assert!(
+        matches!(
+            result,
+            Err(SignalProtocolError::KeyTransparencyVerificationFailed(_))
+        ),
+        "unexpected result {:?}",
+        result
+    );
+}
+
+#[test]
+fn key_transparency_search() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        let bob_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        let other_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        let mut log = InMemKeyTransparencyLog::new(&mut csprng)?;
+        log.update(aci(ALICE_UUID), alice_key, seconds_ago(30), &mut csprng)?;
+        log.update(aci(BOB_UUID), bob_key, seconds_ago(20), &mut csprng)?;
+
+        let mut client = KeyTransparencyClient::new(log.config());
+        assert_eq!(client.search(aci(ALICE_UUID), &log).await?, Some(alice_key));
+        assert_eq!(client.tree_head(), Some(log.tree_head()));
+        assert_eq!(client.verified_key(aci(ALICE_UUID)), Some(&alice_key));
+        assert_eq!(client.verified_key(aci(BOB_UUID)), None);
+
+        let alice_address = ProtocolAddress::from_service_id(aci(ALICE_UUID), 1.into());
+        assert_eq!(
+            client.check_identity(&alice_address, &alice_key),
+            Some(true)
+        );
+        assert_eq!(
+            client.check_identity(&alice_address, &other_key),
+            Some(false)
+        );
+
+        // Addresses that were never searched for have nothing to check against.
+        let bob_address = ProtocolAddress::from_service_id(aci(BOB_UUID), 1.into());
+        assert_eq!(client.check_identity(&bob_address, &other_key), None);
+
+        // A newer entry replaces the old one, and the tree head moves forward.
+        log.update(aci(ALICE_UUID), other_key, seconds_ago(10), &mut csprng)?;
+        assert_eq!(client.search(aci(ALICE_UUID), &log).await?, Some(other_key));
+        assert_eq!(client.tree_head().map(|head| head.tree_size), Some(3));
+        assert_eq!(
+            client.check_identity(&alice_address, &other_key),
+            Some(true)
+        );
+
+        let unknown = aci("00000000-0000-4000-8000-000000000000");
+        assert_eq!(client.search(unknown, &log).await?, None);
+        assert_eq!(client.tree_head().map(|head| head.tree_size), Some(3));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn key_transparency_rejects_tampered_responses() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        let bob_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        let mut log = InMemKeyTransparencyLog::new(&mut csprng)?;
+        log.update(aci(ALICE_UUID), alice_key, seconds_ago(30), &mut csprng)?;
+        log.update(aci(BOB_UUID), bob_key, seconds_ago(20), &mut csprng)?;
+        let config = log.config();
+
+        let response = log.search(aci(ALICE_UUID), None).await?.expect("present");
+        verify_search(&config, aci(ALICE_UUID), None, &response, SystemTime::now())?;
+
+        // The response is only valid for the service ID that was searched for.
+        assert_verification_failed(verify_search(
+            &config,
+            aci(BOB_UUID),
+            None,
+            &response,
+            SystemTime::now(),
+        ));
+
+        let mut tampered = response.clone();
+        tampered.identity_key = bob_key;
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            None,
+            &tampered,
+            SystemTime::now(),
+        ));
+
+        let mut tampered = response.clone();
+        tampered.position = 1;
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            None,
+            &tampered,
+            SystemTime::now(),
+        ));
+
+        let mut tampered = response.clone();
+        tampered.tree_head.timestamp += 1;
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            None,
+            &tampered,
+            SystemTime::now(),
+        ));
+
+        let mut tampered = response.clone();
+        tampered.vrf_proof[0] ^= 1;
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            None,
+            &tampered,
+            SystemTime::now(),
+        ));
+
+        // A log with different keys is not trusted.
+        let other_log = InMemKeyTransparencyLog::new(&mut csprng)?;
+        assert_verification_failed(verify_search(
+            &other_log.config(),
+            aci(ALICE_UUID),
+            None,
+            &response,
+            SystemTime::now(),
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn key_transparency_rejects_rewritten_history() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        let mallory_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        let signature_key = KeyPair::generate(&mut csprng);
+        let vrf_key = KeyPair::generate(&mut csprng);
+        let mut log = InMemKeyTransparencyLog::with_keys(signature_key, vrf_key, &mut csprng)?;
+        log.update(aci(ALICE_UUID), alice_key, seconds_ago(30), &mut csprng)?;
+        log.update(aci(BOB_UUID), alice_key, seconds_ago(20), &mut csprng)?;
+
+        let mut client = KeyTransparencyClient::new(log.config());
+        client.search(aci(ALICE_UUID), &log).await?;
+        let seen = client.tree_head().expect("verified").clone();
+
+        // The same log operator presents a fork with a different key for Alice.
+        let mut fork = InMemKeyTransparencyLog::with_keys(signature_key, vrf_key, &mut csprng)?;
+        fork.update(aci(ALICE_UUID), mallory_key, seconds_ago(30), &mut csprng)?;
+        fork.update(aci(BOB_UUID), alice_key, seconds_ago(20), &mut csprng)?;
+        fork.update(aci(ALICE_UUID), mallory_key, seconds_ago(10), &mut csprng)?;
+        assert_verification_failed(client.search(aci(ALICE_UUID), &fork).await);
+        assert_eq!(client.tree_head(), Some(&seen));
+        assert_eq!(client.verified_key(aci(ALICE_UUID)), Some(&alice_key));
+
+        // A client restored from the tree head it saw before also rejects the fork.
+        let mut restored = KeyTransparencyClient::new(log.config()).with_tree_head(seen.clone());
+        assert_verification_failed(restored.search(aci(ALICE_UUID), &fork).await);
+
+        // Nor can the log go back to a smaller tree.
+        let mut rolled_back =
+            InMemKeyTransparencyLog::with_keys(signature_key, vrf_key, &mut csprng)?;
+        rolled_back.update(aci(ALICE_UUID), alice_key, seconds_ago(5), &mut csprng)?;
+        let response = rolled_back
+            .search(aci(ALICE_UUID), None)
+            .await?
+            .expect("present");
+        assert_verification_failed(verify_search(
+            &log.config(),
+            aci(ALICE_UUID),
+            Some(&seen),
+            &response,
+            SystemTime::now(),
+        ));
+
+        // The honest log can still extend what was seen.
+        log.update(aci(ALICE_UUID), alice_key, seconds_ago(10), &mut csprng)?;
+        assert_eq!(client.search(aci(ALICE_UUID), &log).await?, Some(alice_key));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn key_transparency_rejects_stale_tree_heads() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        let signature_key = KeyPair::generate(&mut csprng);
+        let vrf_key = KeyPair::generate(&mut csprng);
+        let mut log = InMemKeyTransparencyLog::with_keys(signature_key, vrf_key, &mut csprng)?;
+        log.update(aci(ALICE_UUID), alice_key, seconds_ago(60), &mut csprng)?;
+        let config = log.config();
+        let response = log.search(aci(ALICE_UUID), None).await?.expect("present");
+
+        let now = SystemTime::now();
+        let max_age = KeyTransparencyConfig::DEFAULT_MAX_TREE_HEAD_AGE;
+        let seen = verify_search(&config, aci(ALICE_UUID), None, &response, now)?;
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            None,
+            &response,
+            now + max_age,
+        ));
+        let unlimited = KeyTransparencyConfig {
+            max_tree_head_age: None,
+            ..config
+        };
+        verify_search(&unlimited, aci(ALICE_UUID), None, &response, now + max_age)?;
+
+        // The same tree, signed again before the tree head that was seen, is consistent with it
+        // but still rejected.
+        let mut replayed = InMemKeyTransparencyLog::with_keys(signature_key, vrf_key, &mut csprng)?;
+        replayed.update(aci(ALICE_UUID), alice_key, seconds_ago(120), &mut csprng)?;
+        let response = replayed
+            .search(aci(ALICE_UUID), Some(1))
+            .await?
+            .expect("present");
+        assert_eq!(response.tree_head.root, seen.root);
+        assert_verification_failed(verify_search(
+            &config,
+            aci(ALICE_UUID),
+            Some(&seen),
+            &response,
+            now,
+        ));
+
+        // A client that has already seen a newer tree head rejects it too.
+        let mut client = KeyTransparencyClient::new(config).with_tree_head(seen.clone());
+        assert_verification_failed(client.search(aci(ALICE_UUID), &replayed).await);
+        assert_eq!(client.tree_head(), Some(&seen));
+        assert_eq!(client.search(aci(ALICE_UUID), &log).await?, Some(alice_key));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
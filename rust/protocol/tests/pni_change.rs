@@ -0,0 +1,219 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+mod support;
+
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use std::time::SystemTime;
+use support::*;
+
+fn build_bundle(aci_identity: &IdentityKeyPair) -> Result<PniChangeBundle, SignalProtocolError> {
+    PniChangeBundleBuilder::new(*aci_identity)
+        .with_device(1.into())
+        .with_device(2.into())
+        .with_device(3.into())
+        .with_device(2.into())
+        .build(SystemTime::now(), &mut OsRng)
+}
+
+#[test]
+fn pni_change_bundle_per_device() -> Result<(), SignalProtocolError> {
+    let aci_identity = IdentityKeyPair::generate(&mut OsRng);
+    let bundle = build_bundle(&aci_identity)?;
+
+    let device_ids: Vec<DeviceId> = bundle.devices().iter().map(|d| d.device_id()).collect();
+    assert_eq!(device_ids, [1.into(), 2.into(), 3.into()]);
+
+    let new_identity = bundle.identity_key_pair().identity_key();
+    assert!(new_identity
+        .verify_alternate_identity(aci_identity.identity_key(), bundle.pni_signature())?);
+    assert!(aci_identity
+        .identity_key()
+        .verify_alternate_identity(new_identity, bundle.aci_signature())?);
+
+    for device in bundle.devices() {
+        assert!(device.registration_id() >= 1 && device.registration_id() <= 0x3FFF);
+        let upload = device.signed_pre_key_upload()?;
+        assert!(new_identity
+            .public_key()
+            .verify_signature(&upload.public_key.serialize(), &upload.signature)?);
+        let kyber_upload = device.last_resort_kyber_pre_key_upload()?;
+        assert!(new_identity.public_key().verify_signature(
+            &kyber_upload.public_key.serialize(),
+            &kyber_upload.signature
+        )?);
+    }
+
+    // Each device gets its own signed pre-key.
+    let public_keys: Vec<PublicKey> = bundle
+        .devices()
+        .iter()
+        .map(|d| d.signed_pre_key().public_key())
+        .collect::<Result<_, _>>()?;
+    assert_ne!(public_keys[0], public_keys[1]);
+    assert_ne!(public_keys[1], public_keys[2]);
+    let kyber_public_keys: Vec<Box<[u8]>> = bundle
+        .devices()
+        .iter()
+        .map(|d| Ok(d.last_resort_kyber_pre_key().public_key()?.serialize()))
+        .collect::<Result<_, SignalProtocolError>>()?;
+    assert_ne!(kyber_public_keys[0], kyber_public_keys[1]);
+    assert_ne!(kyber_public_keys[1], kyber_public_keys[2]);
+
+    let existing_identity = IdentityKeyPair::generate(&mut OsRng);
+    let bundle = PniChangeBundleBuilder::new(aci_identity)
+        .with_identity_key_pair(existing_identity)
+        .with_device(1.into())
+        .build(SystemTime::now(), &mut OsRng)?;
+    assert_eq!(
+        bundle.identity_key_pair().identity_key(),
+        existing_identity.identity_key()
+    );
+
+    assert!(matches!(
+        PniChangeBundleBuilder::new(aci_identity).build(SystemTime::now(), &mut OsRng),
+        Err(SignalProtocolError::InvalidArgument(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn pni_change_linked_device_verification() -> Result<(), SignalProtocolError> {
+    let aci_identity = IdentityKeyPair::generate(&mut OsRng);
+    let bundle = build_bundle(&aci_identity)?;
+    assert!(bundle.device_bundle(4.into()).is_none());
+
+    let serialized = bundle
+        .device_bundle(2.into())
+        .expect("present")
+        .serialize()?;
+    let received = PniChangeDeviceBundle::deserialize(&serialized)?;
+    received.verify(aci_identity.identity_key(), 2.into())?;
+    assert_eq!(received.device().device_id(), 2.into());
+    assert_eq!(
+        received.identity_key_pair().serialize(),
+        bundle.identity_key_pair().serialize()
+    );
+    assert_eq!(received.pni_signature(), bundle.pni_signature());
+    assert_eq!(received.aci_signature(), bundle.aci_signature());
+
+    assert!(matches!(
+        received.verify(aci_identity.identity_key(), 3.into()),
+        Err(SignalProtocolError::InvalidArgument(_))
+    ));
+
+    let other_aci_identity = IdentityKeyPair::generate(&mut OsRng);
+    assert!(matches!(
+        received.verify(other_aci_identity.identity_key(), 2.into()),
+        Err(SignalProtocolError::SignatureValidationFailed)
+    ));
+
+    // Corrupting any of the signatures is caught.
+    let pni_signature = received.pni_signature().to_vec();
+    let aci_signature = received.aci_signature().to_vec();
+    let signed_pre_key_signature = received.device().signed_pre_key().signature()?;
+    let kyber_pre_key_signature = received.device().last_resort_kyber_pre_key().signature()?;
+    for signature in [
+        pni_signature,
+        aci_signature,
+        signed_pre_key_signature,
+        kyber_pre_key_signature,
+    ] {
+        let start = serialized
+            .windows(signature.len())
+            .position(|window| window == &signature[..])
+            .expect("present");
+        let mut corrupted = serialized.clone();
+        corrupted[start] ^= 1;
+        assert!(matches!(
+            PniChangeDeviceBundle::deserialize(&corrupted)?
+                .verify(aci_identity.identity_key(), 2.into()),
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+    }
+
+    assert!(PniChangeDeviceBundle::deserialize(&serialized[..serialized.len() - 1]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn pni_change_linked_device_receives_messages() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let aci_identity = IdentityKeyPair::generate(&mut csprng);
+        let bundle = build_bundle(&aci_identity)?;
+
+        let received = PniChangeDeviceBundle::deserialize(
+            &bundle
+                .device_bundle(2.into())
+                .expect("present")
+                .serialize()?,
+        )?;
+        received.verify(aci_identity.identity_key(), 2.into())?;
+
+        let device = received.device();
+        let mut linked_store =
+            InMemSignalProtocolStore::new(*received.identity_key_pair(), device.registration_id())?;
+        let signed_pre_key = device.signed_pre_key();
+        linked_store
+            .save_signed_pre_key(signed_pre_key.id()?, signed_pre_key, None)
+            .await?;
+        let kyber_pre_key = device.last_resort_kyber_pre_key();
+        linked_store
+            .save_kyber_pre_key(kyber_pre_key.id()?, kyber_pre_key, None)
+            .await?;
+
+        // The server hands out the uploaded keys to someone starting a session.
+        let upload = device.signed_pre_key_upload()?;
+        let kyber_upload = device.last_resort_kyber_pre_key_upload()?;
+        let pre_key_bundle = PreKeyBundle::new(
+            device.registration_id(),
+            device.device_id(),
+            None,
+            upload.id,
+            upload.public_key,
+            upload.signature,
+            *received.identity_key_pair().identity_key(),
+        )?
+        .with_kyber_pre_key(
+            kyber_upload.id,
+            kyber_upload.public_key,
+            kyber_upload.signature,
+        );
+
+        let linked_address = ProtocolAddress::new("+14151111111".to_owned(), device.device_id());
+        let sender_address = ProtocolAddress::new("+14152222222".to_owned(), 1.into());
+        let mut sender_store = test_in_memory_protocol_store()?;
+        process_prekey_bundle(
+            &linked_address,
+            &mut sender_store.session_store,
+            &mut sender_store.identity_store,
+            &pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message = encrypt(&mut sender_store, &linked_address, "hello").await?;
+        match &message {
+            CiphertextMessage::PreKeySignalMessage(message) => {
+                assert!(message.kyber_payload().is_some())
+            }
+            _ => panic!("expected a pre-key message"),
+        }
+        assert_eq!(
+            decrypt(&mut linked_store, &sender_address, &message).await?,
+            b"hello"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
@@ -0,0 +1,337 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+mod support;
+
+use async_trait::async_trait;
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use support::*;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn start_time() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+}
+
+async fn refresh(
+    manager: &mut PreKeyManager,
+    store: &mut InMemSignalProtocolStore,
+    now: SystemTime,
+) -> Result<PreKeyUpload, SignalProtocolError> {
+    let identity_key_pair = store.get_identity_key_pair(None).await?;
+    manager
+        .refresh(
+            &identity_key_pair,
+            &mut store.pre_key_store,
+            &mut store.signed_pre_key_store,
+            now,
+            &mut OsRng,
+            None,
+        )
+        .await
+}
+
+fn assert_consecutive(ids: &[PreKeyId]) {
+    for pair in ids.windows(2) {
+        let (previous, next) = (u32::from(pair[0]), u32::from(pair[1]));
+        assert_eq!(next, previous % MAX_PRE_KEY_ID + 1);
+    }
+}
+
+#[test]
+fn replenishes_one_time_pre_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = test_in_memory_protocol_store()?;
+        let config = PreKeyManagerConfig {
+            batch_size: 5,
+            minimum_pre_keys: 3,
+            ..PreKeyManagerConfig::default()
+        };
+        let mut manager = PreKeyManager::new(config, PreKeyManagerState::new(&mut OsRng));
+
+        let upload = refresh(&mut manager, &mut store, start_time()).await?;
+        assert_eq!(upload.pre_keys.len(), 5);
+        assert!(upload.signed_pre_key.is_some());
+        let first_ids: Vec<PreKeyId> = upload.pre_keys.iter().map(|(id, _)| *id).collect();
+        assert_consecutive(&first_ids);
+        let start_timestamp = start_time()
+            .duration_since(UNIX_EPOCH)
+            .expect("valid time")
+            .as_millis() as u64;
+        for (id, public_key) in &upload.pre_keys {
+            let record = store.get_pre_key(*id, None).await?;
+            assert_eq!(record.public_key()?, *public_key);
+            assert_eq!(record.timestamp()?, start_timestamp);
+        }
+
+        // Nothing to do until keys are used up.
+        let upload = refresh(&mut manager, &mut store, start_time()).await?;
+        assert!(upload.is_empty());
+
+        store.remove_pre_key(first_ids[0], None).await?;
+        store.remove_pre_key(first_ids[1], None).await?;
+        let upload = refresh(&mut manager, &mut store, start_time()).await?;
+        assert!(upload.is_empty());
+        assert_eq!(
+            manager
+                .remaining_pre_keys(&store.pre_key_store, None)
+                .await?,
+            3
+        );
+
+        store.remove_pre_key(first_ids[2], None).await?;
+        let upload = refresh(&mut manager, &mut store, start_time()).await?;
+        assert_eq!(upload.pre_keys.len(), 5);
+        assert!(upload.signed_pre_key.is_none());
+
+        // Ids keep counting up from the previous batch.
+        let mut all_ids = first_ids;
+        all_ids.extend(upload.pre_keys.iter().map(|(id, _)| *id));
+        assert_consecutive(&all_ids);
+        assert_eq!(manager.state().pre_key_ids(), all_ids[3..].to_vec());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn rotates_signed_pre_keys() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = test_in_memory_protocol_store()?;
+        let config = PreKeyManagerConfig {
+            signed_pre_key_rotation_interval: DAY,
+            signed_pre_key_grace_period: 3 * DAY,
+            ..PreKeyManagerConfig::default()
+        };
+        let mut manager = PreKeyManager::new(config, PreKeyManagerState::new(&mut OsRng));
+        let mut signed_pre_key_ids = vec![];
+
+        for day in 0..3 {
+            let upload = refresh(&mut manager, &mut store, start_time() + day * DAY).await?;
+            let signed_pre_key = upload.signed_pre_key.expect("rotated");
+            assert!(upload.expired_signed_pre_key_ids.is_empty());
+
+            let record = store.get_signed_pre_key(signed_pre_key.id, None).await?;
+            assert_eq!(record.public_key()?, signed_pre_key.public_key);
+            assert_eq!(record.signature()?, signed_pre_key.signature);
+            assert!(store
+                .get_identity_key_pair(None)
+                .await?
+                .public_key()
+                .verify_signature(
+                    &signed_pre_key.public_key.serialize(),
+                    &signed_pre_key.signature
+                )?);
+
+            // Not due again until a full interval has passed.
+            let upload =
+                refresh(&mut manager, &mut store, start_time() + day * DAY + DAY / 2).await?;
+            assert!(upload.signed_pre_key.is_none());
+
+            signed_pre_key_ids.push(signed_pre_key.id);
+        }
+
+        assert_eq!(
+            manager.state().signed_pre_key_id(),
+            Some(signed_pre_key_ids[2])
+        );
+        assert_eq!(
+            manager.state().old_signed_pre_key_ids(),
+            signed_pre_key_ids[..2].to_vec()
+        );
+
+        // The first key was replaced on day 1, so its grace period ends on day 4.
+        let upload = refresh(&mut manager, &mut store, start_time() + 4 * DAY).await?;
+        assert!(upload.signed_pre_key.is_some());
+        assert_eq!(
+            upload.expired_signed_pre_key_ids,
+            vec![signed_pre_key_ids[0]]
+        );
+        assert_eq!(
+            manager.state().old_signed_pre_key_ids(),
+            signed_pre_key_ids[1..].to_vec()
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn upload_can_start_session() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let mut manager = PreKeyManager::new(
+            PreKeyManagerConfig::default(),
+            PreKeyManagerState::new(&mut csprng),
+        );
+
+        let upload = refresh(&mut manager, &mut bob_store, start_time()).await?;
+        let signed_pre_key = upload.signed_pre_key.expect("generated");
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(),
+            Some(upload.pre_keys[0]),
+            signed_pre_key.id,
+            signed_pre_key.public_key,
+            signed_pre_key.signature,
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+        let ptext = decrypt(&mut bob_store, &alice_address, &message).await?;
+        assert_eq!(ptext, b"hi bob");
+
+        // The used key is no longer counted.
+        assert_eq!(
+            manager
+                .remaining_pre_keys(&bob_store.pre_key_store, None)
+                .await?,
+            upload.pre_keys.len() - 1
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+/// A signed pre-key store that fails every write.
+struct ReadOnlySignedPreKeyStore;
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for ReadOnlySignedPreKeyStore {
+    async fn get_signed_pre_key(
+        &self,
+        _id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidSignedPreKeyId)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        _id: SignedPreKeyId,
+        _record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "save_signed_pre_key",
+            "read-only".to_string(),
+        ))
+    }
+}
+
+#[test]
+fn failed_refresh_changes_nothing() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = test_in_memory_protocol_store()?;
+        let identity_key_pair = store.get_identity_key_pair(None).await?;
+        let mut manager = PreKeyManager::new(
+            PreKeyManagerConfig::default(),
+            PreKeyManagerState::new(&mut OsRng),
+        );
+        let before = manager.state().serialize()?;
+
+        // The one-time pre-keys are saved, but the signed pre-key is not.
+        assert!(manager
+            .refresh(
+                &identity_key_pair,
+                &mut store.pre_key_store,
+                &mut ReadOnlySignedPreKeyStore,
+                start_time(),
+                &mut OsRng,
+                None,
+            )
+            .await
+            .is_err());
+        assert_eq!(manager.state().serialize()?, before);
+
+        // Trying again tracks exactly the keys it uploads.
+        let upload = refresh(&mut manager, &mut store, start_time()).await?;
+        assert!(upload.signed_pre_key.is_some());
+        assert_eq!(
+            manager.state().pre_key_ids(),
+            upload
+                .pre_keys
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn state_round_trip() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = test_in_memory_protocol_store()?;
+        let config = PreKeyManagerConfig {
+            batch_size: 3,
+            minimum_pre_keys: 1,
+            ..PreKeyManagerConfig::default()
+        };
+        let mut manager = PreKeyManager::new(config.clone(), PreKeyManagerState::new(&mut OsRng));
+        let first = refresh(&mut manager, &mut store, start_time()).await?;
+
+        let state = PreKeyManagerState::deserialize(&manager.state().serialize()?)?;
+        assert_eq!(
+            state.pre_key_ids(),
+            first.pre_keys.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            state.signed_pre_key_id(),
+            first.signed_pre_key.map(|key| key.id)
+        );
+
+        let mut manager = PreKeyManager::new(config, state);
+        for (id, _) in &first.pre_keys {
+            store.remove_pre_key(*id, None).await?;
+        }
+        let second = refresh(&mut manager, &mut store, start_time()).await?;
+        assert!(second.signed_pre_key.is_none());
+
+        let ids: Vec<PreKeyId> = first
+            .pre_keys
+            .iter()
+            .chain(&second.pre_keys)
+            .map(|(id, _)| *id)
+            .collect();
+        assert_consecutive(&ids);
+
+        assert!(matches!(
+            PreKeyManagerState::deserialize(&[0xff]),
+            Err(SignalProtocolError::InvalidProtobufEncoding)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
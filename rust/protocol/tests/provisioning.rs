@@ -0,0 +1,204 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use rand::Rng;
+use uuid::Uuid;
+
+fn provision_message() -> ProvisionMessage {
+    let mut csprng = OsRng;
+    ProvisionMessage {
+        aci_identity_key_pair: IdentityKeyPair::generate(&mut csprng),
+        pni_identity_key_pair: Some(IdentityKeyPair::generate(&mut csprng)),
+        aci: Aci::from(Uuid::from_u128(0x9d0652a3_dcc3_4d11_975f_74d61598733f)),
+        pni: Some(Pni::from(Uuid::from_u128(
+            0x796abedb_ca4e_4f18_8803_1fde5b921f9f,
+        ))),
+        number: Some("+14151111111".to_owned()),
+        provisioning_code: "123456".to_owned(),
+        user_agent: Some("OWI".to_owned()),
+        profile_key: csprng.gen(),
+        read_receipts: true,
+        provisioning_version: Some(1),
+    }
+}
+
+fn assert_same_key_pair(a: &IdentityKeyPair, b: &IdentityKeyPair) {
+    assert_eq!(a.serialize(), b.serialize());
+}
+
+#[test]
+fn provision_message_round_trip() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let message = provision_message();
+
+    let new_device = ProvisioningCipher::generate(&mut csprng);
+    let envelope =
+        ProvisioningCipher::encrypt(new_device.public_key(), &message.serialize(), &mut csprng)?;
+    let received = ProvisionMessage::deserialize(&new_device.decrypt(&envelope)?)?;
+
+    assert_same_key_pair(
+        &received.aci_identity_key_pair,
+        &message.aci_identity_key_pair,
+    );
+    assert_same_key_pair(
+        &received.pni_identity_key_pair.expect("present"),
+        &message.pni_identity_key_pair.expect("present"),
+    );
+    assert_eq!(received.aci, message.aci);
+    assert_eq!(received.pni, message.pni);
+    assert_eq!(received.number, message.number);
+    assert_eq!(received.provisioning_code, message.provisioning_code);
+    assert_eq!(received.user_agent, message.user_agent);
+    assert_eq!(received.profile_key, message.profile_key);
+    assert_eq!(received.read_receipts, message.read_receipts);
+    assert_eq!(received.provisioning_version, message.provisioning_version);
+
+    // Optional fields can be left out.
+    let minimal = ProvisionMessage {
+        pni_identity_key_pair: None,
+        pni: None,
+        number: None,
+        user_agent: None,
+        read_receipts: false,
+        provisioning_version: None,
+        ..message
+    };
+    let received = ProvisionMessage::deserialize(&minimal.serialize())?;
+    assert!(received.pni_identity_key_pair.is_none());
+    assert_eq!(received.pni, None);
+    assert_eq!(received.number, None);
+    assert!(!received.read_receipts);
+
+    assert!(ProvisionMessage::deserialize(b"").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn provisioning_cipher_round_trip() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let new_device = ProvisioningCipher::new(KeyPair::generate(&mut csprng));
+
+    for len in [0, 1, 15, 16, 17, 1000] {
+        let plaintext: Vec<u8> = (0..len).map(|_| csprng.gen()).collect();
+        let envelope =
+            ProvisioningCipher::encrypt(new_device.public_key(), &plaintext, &mut csprng)?;
+        assert_eq!(new_device.decrypt(&envelope)?, plaintext);
+
+        // Each envelope uses a new ephemeral key and IV.
+        let again = ProvisioningCipher::encrypt(new_device.public_key(), &plaintext, &mut csprng)?;
+        assert_ne!(envelope, again);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn provisioning_cipher_rejects_bad_envelopes() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let new_device = ProvisioningCipher::generate(&mut csprng);
+    let plaintext = b"link me";
+    let envelope = ProvisioningCipher::encrypt(new_device.public_key(), plaintext, &mut csprng)?;
+
+    // Only the intended device can decrypt it.
+    let other_device = ProvisioningCipher::generate(&mut csprng);
+    assert!(matches!(
+        other_device.decrypt(&envelope),
+        Err(SignalProtocolError::InvalidProvisioningMessage(_))
+    ));
+
+    // The body is the last field of the envelope, so flipping any of its trailing bytes breaks
+    // the MAC, the ciphertext, or the version.
+    for i in 1..=(1 + 16 + 16 + 32) {
+        let mut corrupted = envelope.clone();
+        let index = corrupted.len() - i;
+        corrupted[index] ^= 1;
+        assert!(
+            matches!(
+                new_device.decrypt(&corrupted),
+                Err(SignalProtocolError::InvalidProvisioningMessage(_))
+            ),
+            "corrupting byte {} was not detected",
+            index
+        );
+    }
+
+    let mut truncated = envelope.clone();
+    truncated.truncate(envelope.len() - 1);
+    assert!(new_device.decrypt(&truncated).is_err());
+    assert!(new_device.decrypt(b"").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn provisioning_cipher_known_answer() -> Result<(), SignalProtocolError> {
+    // Built outside this crate, following ProvisioningCipher in the Signal Android app: ephemeral
+    // key 05e29d75..., IV 10 11 .. 1f, and a ProvisionMessage encoded field by field.
+    const ENVELOPE: &str =
+        "0a2105e29d7521911498b837ed692d12a81587898e0ac3f6208eac1069bca82fb6b63312d102011011121314\
+         15161718191a1b1c1d1e1fa19daeac39cd0049cc50d75bfc5c60d1d983a1fea3da77d4087e44f5ae6e03706c\
+         c64d927dac28c0fb9c1eeaaea5152634a4a3ae907068825e440882efb544d8a3e3aad5213c927ae8782586fc\
+         1f4f94b2be1beae937314509a15d6cfabbdae6bdb3f5460f36845e0c981af08ed9071f161d5a4fe0fde68c9d\
+         41970490ac6bf86555698848284e9cfe714b97f671351c694b1f1d8fa659984fca28158ea476828bde60d0db\
+         87bc32cacd104642793ec3718199ad30ea5deca621311df6eb497642bf8389136ba1de9bcc91ff50a27bcab3\
+         28d7a4bd6e3ac911058b916e71950ca96ebfc061b65061aa886cb42702a75f79e03c6d354f174f012ac96524\
+         6372698868e9ea6b8ba99e1355768c8a3906316bad3b34dfbf47459fa07d9e78b9b309f91c10a52bd4739c1a\
+         4434fc66d18e869953facf331a3310eda065d6d82a4a2f";
+
+    let new_device = ProvisioningCipher::new(KeyPair::from_public_and_private(
+        &hex::decode("059ed57da36c28ca0f95f37ebe05fa4ff83cde6fa70ca44bb64d8016729e56ed16")
+            .expect("valid hex"),
+        &hex::decode("58eb5d403b2518fa365c20e116546ed4e7ac2aeb6b96aa8fab33b3c04e62bf54")
+            .expect("valid hex"),
+    )?);
+    let envelope = hex::decode(ENVELOPE).expect("valid hex");
+    let message = ProvisionMessage::deserialize(&new_device.decrypt(&envelope)?)?;
+
+    assert_eq!(
+        hex::encode(message.aci_identity_key_pair.identity_key().serialize()),
+        "0522dd192a4c79b767d2df7cbe45cf740470cdbc238bf2e50a97d9a225142ea160"
+    );
+    assert_eq!(
+        hex::encode(message.aci_identity_key_pair.private_key().serialize()),
+        "68ef8c25a4c86cfb5da5b1aefdd34af3df4258932d86605e9d59341d804bad63"
+    );
+    let pni_identity_key_pair = message.pni_identity_key_pair.expect("present");
+    assert_eq!(
+        hex::encode(pni_identity_key_pair.identity_key().serialize()),
+        "0529dca6546582274c486bc256edb1830bf00fffb23049fef064e309837b486d4b"
+    );
+    assert_eq!(
+        hex::encode(pni_identity_key_pair.private_key().serialize()),
+        "d0e356f8fa3864b28dee653e8cb272f0d77a74489dde0208de16be19767e7375"
+    );
+    assert_eq!(
+        message.aci,
+        Aci::from(Uuid::from_u128(0x9d0652a3_dcc3_4d11_975f_74d61598733f))
+    );
+    assert_eq!(
+        message.pni,
+        Some(Pni::from(Uuid::from_u128(
+            0x796abedb_ca4e_4f18_8803_1fde5b921f9f
+        )))
+    );
+    assert_eq!(message.number.as_deref(), Some("+14151111111"));
+    assert_eq!(message.provisioning_code, "123456");
+    assert_eq!(message.user_agent.as_deref(), Some("OWI"));
+    assert_eq!(
+        message.profile_key.to_vec(),
+        (0x20..0x40).collect::<Vec<u8>>()
+    );
+    assert!(message.read_receipts);
+    assert_eq!(message.provisioning_version, Some(1));
+
+    // Any other key fails to decrypt it.
+    let other_device = ProvisioningCipher::generate(&mut OsRng);
+    assert!(other_device.decrypt(&envelope).is_err());
+
+    Ok(())
+}
@@ -191,6 +191,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_ptext,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
             &mut rng,
         )
@@ -207,6 +208,8 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -224,6 +227,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_ptext,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
             &mut rng,
         )
@@ -240,6 +244,8 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await;
@@ -262,6 +268,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_ptext,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
             &mut rng,
         )
@@ -280,6 +287,8 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await;
@@ -377,6 +386,7 @@ fn test_sender_key_in_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_uuid_address,
             distribution_id,
             "swim camp".as_bytes(),
+            &SessionPolicy::default(),
             &mut rng,
             None,
         )
@@ -411,6 +421,7 @@ fn test_sender_key_in_sealed_sender() -> Result<(), SignalProtocolError> {
             bob_usmc.contents()?,
             &mut bob_store,
             &alice_uuid_address,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -484,6 +495,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &bob_uuid_address,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -509,6 +521,14 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
         )
         .await?;
 
+        // Recipients that are all ACIs are identified by bare UUIDs.
+        assert_eq!(alice_ctext[0], 0x22);
+        assert_eq!(alice_ctext[1], 1);
+        assert_eq!(
+            alice_ctext[2..18],
+            *Uuid::parse_str(&bob_uuid).expect("valid").as_bytes()
+        );
+
         let [bob_ctext] = <[_; 1]>::try_from(sealed_sender_multi_recipient_fan_out(&alice_ctext)?)
             .expect("only one recipient");
 
@@ -523,6 +543,8 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -538,6 +560,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &bob_uuid_address,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -577,6 +600,8 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await;
@@ -598,6 +623,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &bob_uuid_address,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -639,6 +665,8 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.session_store,
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             None,
         )
         .await;
@@ -659,6 +687,150 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn test_sealed_sender_multi_recipient_encrypt_with_service_ids() -> Result<(), SignalProtocolError>
+{
+    async {
+        let mut rng = OsRng;
+
+        let alice_device_id: DeviceId = 23.into();
+        let bob_device_id: DeviceId = 42.into();
+
+        let alice_aci = Aci::from(Uuid::from_u128(0x9d0652a3_dcc3_4d11_975f_74d61598733f));
+        let bob_pni = Pni::from(Uuid::from_u128(0x796abedb_ca4e_4f18_8803_1fde5b921f9f));
+
+        let bob_pni_address = ProtocolAddress::from_service_id(bob_pni.into(), bob_device_id);
+        assert_eq!(
+            bob_pni_address.name(),
+            "PNI:796abedb-ca4e-4f18-8803-1fde5b921f9f"
+        );
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let alice_pubkey = *alice_store.get_identity_key_pair(None).await?.public_key();
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut rng).await?;
+
+        process_prekey_bundle(
+            &bob_pni_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut rng,
+            None,
+        )
+        .await?;
+
+        let trust_root = KeyPair::generate(&mut rng);
+        let server_key = KeyPair::generate(&mut rng);
+
+        let server_cert =
+            ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+
+        let expires = 1605722925;
+
+        let sender_cert = SenderCertificate::new(
+            alice_aci.service_id_string(),
+            None,
+            alice_pubkey,
+            alice_device_id,
+            expires,
+            server_cert,
+            &server_key.private_key,
+            &mut rng,
+        )?;
+        assert_eq!(sender_cert.sender_aci()?, alice_aci);
+
+        let alice_ptext = vec![1, 2, 3, 23, 99];
+        let alice_message = message_encrypt(
+            &alice_ptext,
+            &bob_pni_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+
+        let alice_usmc = UnidentifiedSenderMessageContent::new(
+            alice_message.message_type(),
+            sender_cert,
+            alice_message.serialize().to_vec(),
+            ContentHint::Default,
+            None,
+        )?;
+
+        let recipients = [&bob_pni_address];
+        let alice_ctext = sealed_sender_multi_recipient_encrypt(
+            &recipients,
+            &alice_store
+                .session_store
+                .load_existing_sessions(&recipients)?,
+            &alice_usmc,
+            &mut alice_store.identity_store,
+            None,
+            &mut rng,
+        )
+        .await?;
+
+        // version || count || ServiceId || ...
+        assert_eq!(alice_ctext[0], 0x23);
+        assert_eq!(alice_ctext[1], 1);
+        assert_eq!(
+            alice_ctext[2..19],
+            ServiceId::from(bob_pni).service_id_fixed_width_binary()
+        );
+
+        let [bob_ctext] = <[_; 1]>::try_from(sealed_sender_multi_recipient_fan_out(&alice_ctext)?)
+            .expect("only one recipient");
+        assert_eq!(bob_ctext[0], 0x22);
+
+        let bob_ptext = sealed_sender_decrypt(
+            &bob_ctext,
+            &trust_root.public_key,
+            expires - 1,
+            None,
+            bob_pni.service_id_string(),
+            bob_device_id,
+            &mut bob_store.identity_store,
+            &mut bob_store.session_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+
+        assert_eq!(bob_ptext.message, alice_ptext);
+        assert_eq!(bob_ptext.sender_aci()?, alice_aci);
+        assert_eq!(bob_ptext.device_id, alice_device_id);
+
+        // Recipients must be identified by service IDs.
+        let bob_e164_address = ProtocolAddress::new("+14151114444".to_owned(), bob_device_id);
+        let recipients = [&bob_e164_address];
+        assert!(matches!(
+            sealed_sender_multi_recipient_encrypt(
+                &recipients,
+                &alice_store
+                    .session_store
+                    .load_existing_sessions(&[&bob_pni_address])?,
+                &alice_usmc,
+                &mut alice_store.identity_store,
+                None,
+                &mut rng,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn test_sealed_sender_multi_recipient_encrypt_with_archived_session(
 ) -> Result<(), SignalProtocolError> {
@@ -717,6 +889,7 @@ fn test_sealed_sender_multi_recipient_encrypt_with_archived_session(
             &bob_uuid_address,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -823,6 +996,7 @@ fn test_sealed_sender_multi_recipient_encrypt_with_bad_registration_id(
             &bob_uuid_address,
             &mut alice_store.session_store,
             &mut alice_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -901,6 +1075,7 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_uuid_address,
             &mut bob_store.session_store,
             &mut bob_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -912,6 +1087,8 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut alice_store.identity_store,
             &mut alice_store.pre_key_store,
             &mut alice_store.signed_pre_key_store,
+            &mut alice_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
             &mut rng,
             None,
         )
@@ -924,6 +1101,7 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
             &alice_uuid_address,
             &mut bob_store.session_store,
             &mut bob_store.identity_store,
+            &SessionPolicy::default(),
             None,
         )
         .await?;
@@ -1002,3 +1180,147 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
     .now_or_never()
     .expect("sync")
 }
+
+#[test]
+fn test_sealed_sender_v2_sent_message_parsing() -> Result<(), SignalProtocolError> {
+    async {
+        let mut rng = OsRng;
+
+        let alice_aci = Aci::from(Uuid::from_u128(0x9d0652a3_dcc3_4d11_975f_74d61598733f));
+        let bob_aci = Aci::from(Uuid::from_u128(0x796abedb_ca4e_4f18_8803_1fde5b921f9f));
+        let carol_pni = Pni::from(Uuid::from_u128(0x38381c3b_2606_4ca7_9c67_b5e5c0d5f4e8));
+
+        let bob_address = ProtocolAddress::from_service_id(bob_aci.into(), 1.into());
+        let carol_address = ProtocolAddress::from_service_id(carol_pni.into(), 7.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let mut carol_store = support::test_in_memory_protocol_store()?;
+
+        for (address, store) in [
+            (&bob_address, &mut bob_store),
+            (&carol_address, &mut carol_store),
+        ] {
+            let pre_key_bundle = create_pre_key_bundle(store, &mut rng).await?;
+            process_prekey_bundle(
+                address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &pre_key_bundle,
+                &mut rng,
+                None,
+            )
+            .await?;
+        }
+
+        let trust_root = KeyPair::generate(&mut rng);
+        let server_key = KeyPair::generate(&mut rng);
+        let server_cert =
+            ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+        let sender_cert = SenderCertificate::new(
+            alice_aci.service_id_string(),
+            None,
+            *alice_store.get_identity_key_pair(None).await?.public_key(),
+            23.into(),
+            1605722925,
+            server_cert,
+            &server_key.private_key,
+            &mut rng,
+        )?;
+
+        // The content is not decrypted here, so it does not need to be a real message.
+        let alice_usmc = UnidentifiedSenderMessageContent::new(
+            CiphertextMessageType::Plaintext,
+            sender_cert,
+            vec![1, 2, 3],
+            ContentHint::Default,
+            None,
+        )?;
+
+        let recipients = [&bob_address, &carol_address];
+        let alice_ctext = sealed_sender_multi_recipient_encrypt(
+            &recipients,
+            &alice_store
+                .session_store
+                .load_existing_sessions(&recipients)?,
+            &alice_usmc,
+            &mut alice_store.identity_store,
+            None,
+            &mut rng,
+        )
+        .await?;
+
+        let message = SealedSenderV2SentMessage::parse(&alice_ctext)?;
+        assert_eq!(message.version, 0x23);
+        let table: Vec<(ServiceId, DeviceId, u16)> = message
+            .recipients
+            .iter()
+            .map(|recipient| {
+                (
+                    recipient.service_id,
+                    recipient.device_id,
+                    recipient.registration_id,
+                )
+            })
+            .collect();
+        assert_eq!(
+            table,
+            vec![
+                (bob_aci.into(), 1.into(), 5),
+                (carol_pni.into(), 7.into(), 5)
+            ]
+        );
+        assert_eq!(
+            &alice_ctext[message.range_for_shared_bytes()],
+            message.shared_bytes()
+        );
+
+        let fanned_out = sealed_sender_multi_recipient_fan_out(&alice_ctext)?;
+        for (recipient, expected) in message.recipients.iter().zip(&fanned_out) {
+            assert_eq!(&message.received_message_for_recipient(recipient), expected);
+            assert_eq!(
+                &message.received_message_parts_for_recipient(recipient)[1],
+                &recipient.key_material()
+            );
+        }
+
+        let carol_usmc = sealed_sender_decrypt_to_usmc(
+            &message.received_message_for_recipient(&message.recipients[1]),
+            &mut carol_store.identity_store,
+            None,
+        )
+        .await?;
+        assert_eq!(carol_usmc.contents()?, alice_usmc.contents()?);
+
+        // Dropping a recipient leaves the others untouched.
+        let without_carol = message.serialize_excluding(&[carol_pni.into()]);
+        let reparsed = SealedSenderV2SentMessage::parse(&without_carol)?;
+        assert_eq!(reparsed.recipients.len(), 1);
+        assert_eq!(reparsed.recipients[0].service_id, bob_aci.into());
+        assert_eq!(
+            reparsed.received_message_for_recipient(&reparsed.recipients[0]),
+            fanned_out[0]
+        );
+
+        // Version 0x22 identifies recipients by bare (ACI) UUIDs.
+        let mut uuid_version = vec![0x22, 1];
+        uuid_version.extend_from_slice(bob_aci.service_id_binary().as_slice());
+        uuid_version.extend_from_slice(&without_carol[2 + ServiceId::FIXED_WIDTH_BINARY_LEN..]);
+        let reparsed = SealedSenderV2SentMessage::parse(&uuid_version)?;
+        assert_eq!(reparsed.version, 0x22);
+        assert_eq!(reparsed.recipients[0].service_id, bob_aci.into());
+        assert_eq!(reparsed.recipients[0].device_id, 1.into());
+        assert_eq!(reparsed.serialize_excluding(&[]), uuid_version);
+
+        assert!(matches!(
+            SealedSenderV2SentMessage::parse(
+                &alice_ctext[..message.range_for_shared_bytes().start]
+            ),
+            Err(SignalProtocolError::InvalidProtobufEncoding)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
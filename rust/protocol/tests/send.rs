@@ -0,0 +1,243 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+mod support;
+
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use std::convert::TryFrom;
+use std::future::Future;
+use support::*;
+use uuid::Uuid;
+
+/// Polls `future` to completion on a different thread than the one that created it.
+fn run_on_other_thread<F>(future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    std::thread::scope(|s| {
+        s.spawn(|| future.now_or_never().expect("sync"))
+            .join()
+            .expect("no panic")
+    })
+}
+
+async fn send_encrypt(
+    store: &mut InMemSignalProtocolStore,
+    remote_address: &ProtocolAddress,
+    msg: &str,
+) -> Result<CiphertextMessage, SignalProtocolError> {
+    send::message_encrypt(
+        msg.as_bytes(),
+        remote_address,
+        &mut store.session_store,
+        &mut store.identity_store,
+        &SessionPolicy::default(),
+    )
+    .await
+}
+
+async fn send_decrypt(
+    store: &mut InMemSignalProtocolStore,
+    remote_address: &ProtocolAddress,
+    msg: &CiphertextMessage,
+) -> Result<Vec<u8>, SignalProtocolError> {
+    send::message_decrypt(
+        msg,
+        remote_address,
+        &mut store.session_store,
+        &mut store.identity_store,
+        &mut store.pre_key_store,
+        &mut store.signed_pre_key_store,
+        &mut store.kyber_pre_key_store,
+        &SessionPolicy::default(),
+        &mut OsRng,
+    )
+    .await
+}
+
+#[test]
+fn session_on_other_thread() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+    let mut alice_store = test_in_memory_protocol_store()?;
+    let mut bob_store = test_in_memory_protocol_store()?;
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)
+        .now_or_never()
+        .expect("sync")?;
+
+    run_on_other_thread(async {
+        send::process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+        )
+        .await?;
+
+        let outgoing_message = send_encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+        let ptext = send_decrypt(&mut bob_store, &alice_address, &outgoing_message).await?;
+        assert_eq!(String::from_utf8(ptext).expect("valid utf8"), "hi bob");
+
+        let reply = send_encrypt(&mut bob_store, &alice_address, "hi alice").await?;
+        let ptext = send_decrypt(&mut alice_store, &bob_address, &reply).await?;
+        assert_eq!(String::from_utf8(ptext).expect("valid utf8"), "hi alice");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn group_on_other_thread() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+    let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+    let mut alice_store = test_in_memory_protocol_store()?;
+    let mut bob_store = test_in_memory_protocol_store()?;
+
+    run_on_other_thread(async {
+        let sent_distribution_message = send::create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store.sender_key_store,
+            &mut csprng,
+        )
+        .await?;
+
+        let recv_distribution_message =
+            SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?;
+
+        send::process_sender_key_distribution_message(
+            &sender_address,
+            &recv_distribution_message,
+            &mut bob_store.sender_key_store,
+        )
+        .await?;
+
+        let alice_ciphertext = send::group_encrypt(
+            &mut alice_store.sender_key_store,
+            &sender_address,
+            distribution_id,
+            "space camp?".as_bytes(),
+            &SessionPolicy::default(),
+            &mut csprng,
+        )
+        .await?;
+
+        let bob_plaintext = send::group_decrypt(
+            alice_ciphertext.serialized(),
+            &mut bob_store.sender_key_store,
+            &sender_address,
+            &SessionPolicy::default(),
+        )
+        .await?;
+
+        assert_eq!(
+            String::from_utf8(bob_plaintext).expect("valid utf8"),
+            "space camp?"
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn sealed_sender_on_other_thread() -> Result<(), SignalProtocolError> {
+    let mut rng = OsRng;
+
+    let alice_device_id: DeviceId = 23.into();
+    let bob_device_id: DeviceId = 42.into();
+
+    let alice_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string();
+    let bob_uuid = "796abedb-ca4e-4f18-8803-1fde5b921f9f".to_string();
+
+    let bob_uuid_address = ProtocolAddress::new(bob_uuid.clone(), bob_device_id);
+
+    let mut alice_store = test_in_memory_protocol_store()?;
+    let mut bob_store = test_in_memory_protocol_store()?;
+
+    let alice_pubkey = *alice_store
+        .identity_store
+        .get_identity_key_pair(None)
+        .now_or_never()
+        .expect("sync")?
+        .public_key();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut rng)
+        .now_or_never()
+        .expect("sync")?;
+
+    let trust_root = KeyPair::generate(&mut rng);
+    let server_key = KeyPair::generate(&mut rng);
+
+    let server_cert =
+        ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+
+    let expires = 1605722925;
+
+    let sender_cert = SenderCertificate::new(
+        alice_uuid.clone(),
+        None,
+        alice_pubkey,
+        alice_device_id,
+        expires,
+        server_cert,
+        &server_key.private_key,
+        &mut rng,
+    )?;
+
+    run_on_other_thread(async {
+        send::process_prekey_bundle(
+            &bob_uuid_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut rng,
+        )
+        .await?;
+
+        let alice_ptext = vec![1, 2, 3, 23, 99];
+        let alice_ctext = send::sealed_sender_encrypt(
+            &bob_uuid_address,
+            &sender_cert,
+            &alice_ptext,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &SessionPolicy::default(),
+            &mut rng,
+        )
+        .await?;
+
+        let bob_ptext = send::sealed_sender_decrypt(
+            &alice_ctext,
+            &trust_root.public_key,
+            expires - 1,
+            None,
+            bob_uuid,
+            bob_device_id,
+            &mut bob_store.identity_store,
+            &mut bob_store.session_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &SessionPolicy::default(),
+        )
+        .await?;
+
+        assert_eq!(bob_ptext.message, alice_ptext);
+        assert_eq!(bob_ptext.sender_uuid, alice_uuid);
+
+        Ok(())
+    })
+}
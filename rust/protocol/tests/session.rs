@@ -9,6 +9,7 @@ use futures_util::FutureExt;
 use libsignal_protocol::*;
 use rand::rngs::OsRng;
 use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use support::*;
 
 #[test]
@@ -204,7 +205,7 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
             SignalProtocolError::UntrustedIdentity(a) if a == alice_address
         ));
 
-        assert!(
+        assert_eq!(
             bob_store
                 .save_identity(
                     &alice_address,
@@ -214,7 +215,8 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
                         .identity_key(),
                     None,
                 )
-                .await?
+                .await?,
+            IdentityChange::ReplacedUnverified
         );
 
         let decrypted = decrypt(&mut bob_store, &alice_address, &outgoing_message).await?;
@@ -255,8 +257,7 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
 }
 
 #[test]
-#[ignore = "slow to run locally"]
-fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
+fn test_basic_prekey_v4() -> Result<(), SignalProtocolError> {
     async {
         let mut csprng = OsRng;
 
@@ -268,16 +269,19 @@ fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
 
         let bob_pre_key_pair = KeyPair::generate(&mut csprng);
         let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
-        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
-        let bob_signed_pre_key_signature = bob_store
-            .get_identity_key_pair(None)
-            .await?
+        let bob_identity_key_pair = bob_store.get_identity_key_pair(None).await?;
+        let bob_signed_pre_key_signature = bob_identity_key_pair
             .private_key()
-            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+            .calculate_signature(&bob_signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+        let bob_kyber_pre_key_signature = bob_identity_key_pair
+            .private_key()
+            .calculate_signature(&bob_kyber_pre_key_pair.public_key.serialize(), &mut csprng)?;
 
         let pre_key_id = 31337;
         let signed_pre_key_id = 22;
+        let kyber_pre_key_id = 8888;
 
         let bob_pre_key_bundle = PreKeyBundle::new(
             bob_store.get_local_registration_id(None).await?,
@@ -286,8 +290,13 @@ fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
             signed_pre_key_id.into(),                               // signed pre key id
             bob_signed_pre_key_pair.public_key,
             bob_signed_pre_key_signature.to_vec(),
-            *bob_store.get_identity_key_pair(None).await?.identity_key(),
-        )?;
+            *bob_identity_key_pair.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            kyber_pre_key_id.into(),
+            bob_kyber_pre_key_pair.public_key.clone(),
+            bob_kyber_pre_key_signature.to_vec(),
+        );
 
         process_prekey_bundle(
             &bob_address,
@@ -299,6 +308,32 @@ fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
         )
         .await?;
 
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .session_version()?,
+            4
+        );
+
+        let original_message = "L'homme est condamné à être libre";
+
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
+
+        assert_eq!(
+            outgoing_message.message_type(),
+            CiphertextMessageType::PreKey
+        );
+
+        let incoming_message = PreKeySignalMessage::try_from(outgoing_message.serialize())?;
+        assert_eq!(incoming_message.message_version(), 4);
+        assert_eq!(
+            incoming_message.kyber_pre_key_id(),
+            Some(kyber_pre_key_id.into())
+        );
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(incoming_message);
+
         bob_store
             .save_pre_key(
                 pre_key_id.into(),
@@ -318,197 +353,62 @@ fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
                 None,
             )
             .await?;
-
-        // Same as library consts.rs
-        pub const MAX_FORWARD_JUMPS: usize = 25_000;
-
-        for _i in 0..(MAX_FORWARD_JUMPS + 1) {
-            let _msg = encrypt(
-                &mut alice_store,
-                &bob_address,
-                "Yet another message for you",
-            )
-            .await?;
-        }
-
-        let too_far = encrypt(&mut alice_store, &bob_address, "Now you have gone too far").await?;
-
-        assert!(decrypt(&mut bob_store, &alice_address, &too_far)
-            .await
-            .is_err());
-        Ok(())
-    }
-    .now_or_never()
-    .expect("sync")
-}
-
-#[test]
-#[ignore = "slow to run locally"]
-fn chain_jump_over_limit_with_self() -> Result<(), SignalProtocolError> {
-    async {
-        let mut csprng = OsRng;
-
-        let device_id_1: DeviceId = 1.into();
-        let a1_address = ProtocolAddress::new("+14151111111".to_owned(), device_id_1);
-        let device_id_2: DeviceId = 2.into();
-        let a2_address = ProtocolAddress::new("+14151111111".to_owned(), device_id_2);
-
-        let mut a1_store = support::test_in_memory_protocol_store()?;
-        let mut a2_store = a1_store.clone(); // same key!
-
-        let a2_pre_key_pair = KeyPair::generate(&mut csprng);
-        let a2_signed_pre_key_pair = KeyPair::generate(&mut csprng);
-
-        let a2_signed_pre_key_public = a2_signed_pre_key_pair.public_key.serialize();
-        let a2_signed_pre_key_signature = a2_store
-            .get_identity_key_pair(None)
-            .await?
-            .private_key()
-            .calculate_signature(&a2_signed_pre_key_public, &mut csprng)?;
-
-        let pre_key_id = 31337;
-        let signed_pre_key_id = 22;
-
-        let a2_pre_key_bundle = PreKeyBundle::new(
-            a2_store.get_local_registration_id(None).await?,
-            1.into(),                                              // device id
-            Some((pre_key_id.into(), a2_pre_key_pair.public_key)), // pre key
-            signed_pre_key_id.into(),                              // signed pre key id
-            a2_signed_pre_key_pair.public_key,
-            a2_signed_pre_key_signature.to_vec(),
-            *a2_store.get_identity_key_pair(None).await?.identity_key(),
-        )?;
-
-        process_prekey_bundle(
-            &a2_address,
-            &mut a1_store.session_store,
-            &mut a1_store.identity_store,
-            &a2_pre_key_bundle,
-            &mut csprng,
-            None,
-        )
-        .await?;
-
-        a2_store
-            .save_pre_key(
-                pre_key_id.into(),
-                &PreKeyRecord::new(pre_key_id.into(), &a2_pre_key_pair),
-                None,
-            )
-            .await?;
-        a2_store
-            .save_signed_pre_key(
-                signed_pre_key_id.into(),
-                &SignedPreKeyRecord::new(
-                    signed_pre_key_id.into(),
+        bob_store
+            .save_kyber_pre_key(
+                kyber_pre_key_id.into(),
+                &KyberPreKeyRecord::new(
+                    kyber_pre_key_id.into(),
                     /*timestamp*/ 42,
-                    &a2_signed_pre_key_pair,
-                    &a2_signed_pre_key_signature,
+                    &bob_kyber_pre_key_pair,
+                    &bob_kyber_pre_key_signature,
                 ),
                 None,
             )
             .await?;
 
-        // Same as library consts.rs
-        pub const MAX_FORWARD_JUMPS: usize = 25_000;
-
-        for _i in 0..(MAX_FORWARD_JUMPS + 1) {
-            let _msg = encrypt(
-                &mut a1_store,
-                &a2_address,
-                "Yet another message for yourself",
-            )
-            .await?;
-        }
-
-        let too_far = encrypt(
-            &mut a1_store,
-            &a2_address,
-            "This is the song that never ends",
-        )
-        .await?;
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
 
-        let ptext = decrypt(&mut a2_store, &a1_address, &too_far).await?;
         assert_eq!(
-            String::from_utf8(ptext).unwrap(),
-            "This is the song that never ends"
+            String::from_utf8(ptext).expect("valid utf8"),
+            original_message
         );
 
-        Ok(())
-    }
-    .now_or_never()
-    .expect("sync")
-}
-
-#[test]
-fn test_bad_signed_pre_key_signature() -> Result<(), SignalProtocolError> {
-    async {
-        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
-
-        let mut alice_store = support::test_in_memory_protocol_store()?;
-        let bob_store = support::test_in_memory_protocol_store()?;
-
-        let mut csprng = OsRng;
-        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
-        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        // Both one-time pre-keys have been consumed.
+        assert!(matches!(
+            bob_store.get_pre_key(pre_key_id.into(), None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+        assert!(matches!(
+            bob_store
+                .get_kyber_pre_key(kyber_pre_key_id.into(), None)
+                .await,
+            Err(SignalProtocolError::InvalidKyberPreKeyId)
+        ));
 
-        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
-        let bob_signed_pre_key_signature = bob_store
-            .get_identity_key_pair(None)
+        let bobs_session_with_alice = bob_store
+            .load_session(&alice_address, None)
             .await?
-            .private_key()
-            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?
-            .to_vec();
-
-        let pre_key_id = 31337;
-        let signed_pre_key_id = 22;
-
-        for bit in 0..8 * bob_signed_pre_key_signature.len() {
-            let mut bad_signature = bob_signed_pre_key_signature.clone();
+            .expect("session found");
+        assert_eq!(bobs_session_with_alice.session_version()?, 4);
 
-            bad_signature[bit / 8] ^= 0x01u8 << (bit % 8);
+        let bobs_response = "Who watches the watchers?";
 
-            let bob_pre_key_bundle = PreKeyBundle::new(
-                bob_store.get_local_registration_id(None).await?,
-                1.into(),
-                Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
-                signed_pre_key_id.into(),
-                bob_signed_pre_key_pair.public_key,
-                bad_signature,
-                *bob_store.get_identity_key_pair(None).await?.identity_key(),
-            )?;
+        let bob_outgoing = encrypt(&mut bob_store, &alice_address, bobs_response).await?;
 
-            assert!(process_prekey_bundle(
-                &bob_address,
-                &mut alice_store.session_store,
-                &mut alice_store.identity_store,
-                &bob_pre_key_bundle,
-                &mut csprng,
-                None,
-            )
-            .await
-            .is_err());
-        }
+        assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
 
-        // Finally check that the non-corrupted signature is accepted:
+        let alice_decrypts = decrypt(&mut alice_store, &bob_address, &bob_outgoing).await?;
 
-        let bob_pre_key_bundle = PreKeyBundle::new(
-            bob_store.get_local_registration_id(None).await?,
-            1.into(),
-            Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
-            signed_pre_key_id.into(),
-            bob_signed_pre_key_pair.public_key,
-            bob_signed_pre_key_signature,
-            *bob_store.get_identity_key_pair(None).await?.identity_key(),
-        )?;
+        assert_eq!(
+            String::from_utf8(alice_decrypts).expect("valid utf8"),
+            bobs_response
+        );
 
-        process_prekey_bundle(
+        run_interaction(
+            &mut alice_store,
+            &alice_address,
+            &mut bob_store,
             &bob_address,
-            &mut alice_store.session_store,
-            &mut alice_store.identity_store,
-            &bob_pre_key_bundle,
-            &mut csprng,
-            None,
         )
         .await?;
 
@@ -518,30 +418,32 @@ fn test_bad_signed_pre_key_signature() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
-// testRepeatBundleMessageV2 cannot be represented
-
 #[test]
-fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
+fn test_basic_prekey_v5() -> Result<(), SignalProtocolError> {
     async {
+        let mut csprng = OsRng;
+
         let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
         let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
 
         let mut alice_store = support::test_in_memory_protocol_store()?;
         let mut bob_store = support::test_in_memory_protocol_store()?;
 
-        let mut csprng = OsRng;
         let bob_pre_key_pair = KeyPair::generate(&mut csprng);
         let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
-        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
-        let bob_signed_pre_key_signature = bob_store
-            .get_identity_key_pair(None)
-            .await?
+        let bob_identity_key_pair = bob_store.get_identity_key_pair(None).await?;
+        let bob_signed_pre_key_signature = bob_identity_key_pair
             .private_key()
-            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+            .calculate_signature(&bob_signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+        let bob_kyber_pre_key_signature = bob_identity_key_pair
+            .private_key()
+            .calculate_signature(&bob_kyber_pre_key_pair.public_key.serialize(), &mut csprng)?;
 
         let pre_key_id = 31337;
         let signed_pre_key_id = 22;
+        let kyber_pre_key_id = 8888;
 
         let bob_pre_key_bundle = PreKeyBundle::new(
             bob_store.get_local_registration_id(None).await?,
@@ -550,8 +452,14 @@ fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
             signed_pre_key_id.into(),                               // signed pre key id
             bob_signed_pre_key_pair.public_key,
             bob_signed_pre_key_signature.to_vec(),
-            *bob_store.get_identity_key_pair(None).await?.identity_key(),
-        )?;
+            *bob_identity_key_pair.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            kyber_pre_key_id.into(),
+            bob_kyber_pre_key_pair.public_key.clone(),
+            bob_kyber_pre_key_signature.to_vec(),
+        )
+        .with_aead_message_support();
 
         process_prekey_bundle(
             &bob_address,
@@ -563,36 +471,31 @@ fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
         )
         .await?;
 
-        assert!(alice_store
-            .load_session(&bob_address, None)
-            .await?
-            .is_some());
         assert_eq!(
             alice_store
                 .load_session(&bob_address, None)
                 .await?
                 .expect("session found")
                 .session_version()?,
-            3
+            5
         );
 
         let original_message = "L'homme est condamné à être libre";
 
-        let outgoing_message1 = encrypt(&mut alice_store, &bob_address, original_message).await?;
-        let outgoing_message2 = encrypt(&mut alice_store, &bob_address, original_message).await?;
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
 
         assert_eq!(
-            outgoing_message1.message_type(),
+            outgoing_message.message_type(),
             CiphertextMessageType::PreKey
         );
+
+        let incoming_message = PreKeySignalMessage::try_from(outgoing_message.serialize())?;
+        assert_eq!(incoming_message.message_version(), 5);
         assert_eq!(
-            outgoing_message2.message_type(),
-            CiphertextMessageType::PreKey
-        );
-
-        let incoming_message = CiphertextMessage::PreKeySignalMessage(
-            PreKeySignalMessage::try_from(outgoing_message1.serialize())?,
+            incoming_message.kyber_pre_key_id(),
+            Some(kyber_pre_key_id.into())
         );
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(incoming_message);
 
         bob_store
             .save_pre_key(
@@ -613,40 +516,65 @@ fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
                 None,
             )
             .await?;
+        bob_store
+            .save_kyber_pre_key(
+                kyber_pre_key_id.into(),
+                &KyberPreKeyRecord::new(
+                    kyber_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &bob_kyber_pre_key_pair,
+                    &bob_kyber_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
 
         let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
+
         assert_eq!(
             String::from_utf8(ptext).expect("valid utf8"),
             original_message
         );
 
-        let bob_outgoing = encrypt(&mut bob_store, &alice_address, original_message).await?;
-        assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
-        let alice_decrypts = decrypt(&mut alice_store, &bob_address, &bob_outgoing).await?;
-        assert_eq!(
-            String::from_utf8(alice_decrypts).expect("valid utf8"),
-            original_message
-        );
+        // Both one-time pre-keys have been consumed.
+        assert!(matches!(
+            bob_store.get_pre_key(pre_key_id.into(), None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+        assert!(matches!(
+            bob_store
+                .get_kyber_pre_key(kyber_pre_key_id.into(), None)
+                .await,
+            Err(SignalProtocolError::InvalidKyberPreKeyId)
+        ));
 
-        // The test
+        let bobs_session_with_alice = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(bobs_session_with_alice.session_version()?, 5);
 
-        let incoming_message2 = CiphertextMessage::PreKeySignalMessage(
-            PreKeySignalMessage::try_from(outgoing_message2.serialize())?,
-        );
+        let bobs_response = "Who watches the watchers?";
 
-        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message2).await?;
-        assert_eq!(
-            String::from_utf8(ptext).expect("valid utf8"),
-            original_message
-        );
+        let bob_outgoing = encrypt(&mut bob_store, &alice_address, bobs_response).await?;
+
+        assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
 
-        let bob_outgoing = encrypt(&mut bob_store, &alice_address, original_message).await?;
         let alice_decrypts = decrypt(&mut alice_store, &bob_address, &bob_outgoing).await?;
+
         assert_eq!(
             String::from_utf8(alice_decrypts).expect("valid utf8"),
-            original_message
+            bobs_response
         );
 
+        run_interaction(
+            &mut alice_store,
+            &alice_address,
+            &mut bob_store,
+            &bob_address,
+        )
+        .await?;
+
         Ok(())
     }
     .now_or_never()
@@ -654,56 +582,67 @@ fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
 }
 
 #[test]
-fn bad_message_bundle() -> Result<(), SignalProtocolError> {
+fn test_session_version_selection() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let alice_identity = IdentityKeyPair::generate(&mut csprng);
+    let bob_identity = IdentityKeyPair::generate(&mut csprng);
+    let bob_signed_pre_key = KeyPair::generate(&mut csprng);
+    let bob_kyber_pre_key = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+
+    let alice_params = || {
+        AliceSignalProtocolParameters::new(
+            alice_identity,
+            KeyPair::generate(&mut OsRng),
+            *bob_identity.identity_key(),
+            bob_signed_pre_key.public_key,
+            None,
+            bob_signed_pre_key.public_key,
+        )
+    };
+    let version = |params: AliceSignalProtocolParameters| {
+        initialize_alice_session_record(&params, &mut OsRng)?.session_version()
+    };
+
+    assert_eq!(version(alice_params())?, 3);
+    assert!(matches!(
+        version(alice_params().with_session_version(5)),
+        Err(SignalProtocolError::InvalidArgument(_))
+    ));
+
+    let with_kyber =
+        || alice_params().with_their_kyber_pre_key(bob_kyber_pre_key.public_key.clone());
+    assert_eq!(version(with_kyber())?, 5);
+    // Peers that do not support AEAD message encryption yet can still use PQXDH.
+    assert_eq!(version(with_kyber().with_session_version(4))?, 4);
+    assert!(matches!(
+        version(with_kyber().with_session_version(3)),
+        Err(SignalProtocolError::InvalidArgument(_))
+    ));
+
+    Ok(())
+}
+#[test]
+fn test_kyber_last_resort_pre_key() -> Result<(), SignalProtocolError> {
     async {
         let mut csprng = OsRng;
 
-        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
         let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
-
-        let mut alice_store = support::test_in_memory_protocol_store()?;
         let mut bob_store = support::test_in_memory_protocol_store()?;
 
-        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
         let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
-        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
-        let bob_signed_pre_key_signature = bob_store
-            .get_identity_key_pair(None)
-            .await?
+        let bob_identity_key_pair = bob_store.get_identity_key_pair(None).await?;
+        let bob_signed_pre_key_signature = bob_identity_key_pair
             .private_key()
-            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+            .calculate_signature(&bob_signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+        let bob_kyber_pre_key_signature = bob_identity_key_pair
+            .private_key()
+            .calculate_signature(&bob_kyber_pre_key_pair.public_key.serialize(), &mut csprng)?;
 
-        let pre_key_id = 31337;
         let signed_pre_key_id = 22;
+        let kyber_pre_key_id = 8888;
 
-        let bob_pre_key_bundle = PreKeyBundle::new(
-            bob_store.get_local_registration_id(None).await?,
-            1.into(), // device id
-            Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
-            signed_pre_key_id.into(), // signed pre key id
-            bob_signed_pre_key_pair.public_key,
-            bob_signed_pre_key_signature.to_vec(),
-            *bob_store.get_identity_key_pair(None).await?.identity_key(),
-        )?;
-
-        process_prekey_bundle(
-            &bob_address,
-            &mut alice_store.session_store,
-            &mut alice_store.identity_store,
-            &bob_pre_key_bundle,
-            &mut csprng,
-            None,
-        )
-        .await?;
-
-        bob_store
-            .save_pre_key(
-                pre_key_id.into(),
-                &PreKeyRecord::new(pre_key_id.into(), &bob_pre_key_pair),
-                None,
-            )
-            .await?;
         bob_store
             .save_signed_pre_key(
                 signed_pre_key_id.into(),
@@ -716,61 +655,124 @@ fn bad_message_bundle() -> Result<(), SignalProtocolError> {
                 None,
             )
             .await?;
+        bob_store
+            .kyber_pre_key_store
+            .save_last_resort_kyber_pre_key(
+                kyber_pre_key_id.into(),
+                &KyberPreKeyRecord::new(
+                    kyber_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &bob_kyber_pre_key_pair,
+                    &bob_kyber_pre_key_signature,
+                ),
+            );
 
-        assert!(alice_store
-            .load_session(&bob_address, None)
-            .await?
-            .is_some());
-        assert_eq!(
-            alice_store
-                .load_session(&bob_address, None)
-                .await?
-                .expect("session found")
-                .session_version()?,
-            3
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(), // device id
+            None,     // no one-time pre key
+            signed_pre_key_id.into(),
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature.to_vec(),
+            *bob_identity_key_pair.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            kyber_pre_key_id.into(),
+            bob_kyber_pre_key_pair.public_key.clone(),
+            bob_kyber_pre_key_signature.to_vec(),
         );
 
-        let original_message = "L'homme est condamné à être libre";
+        // Several senders can start sessions from the same last-resort key.
+        for (i, name) in ["+14151111111", "+14151111113"].iter().enumerate() {
+            let alice_address = ProtocolAddress::new(name.to_string(), 1.into());
+            let mut alice_store = support::test_in_memory_protocol_store()?;
 
-        assert!(bob_store.get_pre_key(pre_key_id.into(), None).await.is_ok());
-        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
 
-        assert_eq!(
-            outgoing_message.message_type(),
-            CiphertextMessageType::PreKey
-        );
+            let original_message = format!("message {}", i);
+            let outgoing_message =
+                encrypt(&mut alice_store, &bob_address, &original_message).await?;
+            let ptext = decrypt(&mut bob_store, &alice_address, &outgoing_message).await?;
+            assert_eq!(
+                String::from_utf8(ptext).expect("valid utf8"),
+                original_message
+            );
 
-        let outgoing_message = outgoing_message.serialize().to_vec();
+            bob_store
+                .get_kyber_pre_key(kyber_pre_key_id.into(), None)
+                .await
+                .expect("last-resort key is kept");
+        }
 
-        let mut corrupted_message: Vec<u8> = outgoing_message.clone();
-        corrupted_message[outgoing_message.len() - 10] ^= 1;
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
 
-        let incoming_message = CiphertextMessage::PreKeySignalMessage(
-            PreKeySignalMessage::try_from(corrupted_message.as_slice())?,
-        );
+#[test]
+fn test_bad_kyber_pre_key_signature() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
 
-        assert!(decrypt(&mut bob_store, &alice_address, &incoming_message)
-            .await
-            .is_err());
-        assert!(bob_store.get_pre_key(pre_key_id.into(), None).await.is_ok());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
 
-        let incoming_message = CiphertextMessage::PreKeySignalMessage(
-            PreKeySignalMessage::try_from(outgoing_message.as_slice())?,
-        );
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let bob_store = support::test_in_memory_protocol_store()?;
 
-        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
+        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
-        assert_eq!(
-            String::from_utf8(ptext).expect("valid utf8"),
-            original_message
+        let bob_identity_key_pair = bob_store.get_identity_key_pair(None).await?;
+        let bob_signed_pre_key_signature = bob_identity_key_pair
+            .private_key()
+            .calculate_signature(&bob_signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+        // Sign the Kyber pre-key with the wrong key.
+        let bad_kyber_pre_key_signature = alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&bob_kyber_pre_key_pair.public_key.serialize(), &mut csprng)?;
+
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(),
+            None,
+            22.into(),
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature.to_vec(),
+            *bob_identity_key_pair.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            8888.into(),
+            bob_kyber_pre_key_pair.public_key,
+            bad_kyber_pre_key_signature.to_vec(),
         );
+
         assert!(matches!(
-            bob_store
-                .get_pre_key(pre_key_id.into(), None)
-                .await
-                .unwrap_err(),
-            SignalProtocolError::InvalidPreKeyId
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SignatureValidationFailed)
         ));
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_none());
 
         Ok(())
     }
@@ -779,15 +781,18 @@ fn bad_message_bundle() -> Result<(), SignalProtocolError> {
 }
 
 #[test]
-fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
+#[ignore = "slow to run locally"]
+fn chain_jump_over_limit() -> Result<(), SignalProtocolError> {
     async {
+        let mut csprng = OsRng;
+
         let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
         let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
 
         let mut alice_store = support::test_in_memory_protocol_store()?;
         let mut bob_store = support::test_in_memory_protocol_store()?;
 
-        let mut csprng = OsRng;
+        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
         let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
 
         let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
@@ -797,13 +802,14 @@ fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
             .private_key()
             .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
 
+        let pre_key_id = 31337;
         let signed_pre_key_id = 22;
 
         let bob_pre_key_bundle = PreKeyBundle::new(
             bob_store.get_local_registration_id(None).await?,
-            1.into(),                 // device id
-            None,                     // no pre key
-            signed_pre_key_id.into(), // signed pre key id
+            1.into(),                                               // device id
+            Some((pre_key_id.into(), bob_pre_key_pair.public_key)), // pre key
+            signed_pre_key_id.into(),                               // signed pre key id
             bob_signed_pre_key_pair.public_key,
             bob_signed_pre_key_signature.to_vec(),
             *bob_store.get_identity_key_pair(None).await?.identity_key(),
@@ -819,28 +825,13 @@ fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
         )
         .await?;
 
-        assert_eq!(
-            alice_store
-                .load_session(&bob_address, None)
-                .await?
-                .expect("session found")
-                .session_version()?,
-            3
-        );
-
-        let original_message = "L'homme est condamné à être libre";
-
-        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
-
-        assert_eq!(
-            outgoing_message.message_type(),
-            CiphertextMessageType::PreKey
-        );
-
-        let incoming_message = CiphertextMessage::PreKeySignalMessage(
-            PreKeySignalMessage::try_from(outgoing_message.serialize())?,
-        );
-
+        bob_store
+            .save_pre_key(
+                pre_key_id.into(),
+                &PreKeyRecord::new(pre_key_id.into(), &bob_pre_key_pair),
+                None,
+            )
+            .await?;
         bob_store
             .save_signed_pre_key(
                 signed_pre_key_id.into(),
@@ -854,28 +845,1129 @@ fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
             )
             .await?;
 
-        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
-
-        assert_eq!(
-            String::from_utf8(ptext).expect("valid utf8"),
-            original_message
-        );
-
-        Ok(())
-    }
-    .now_or_never()
-    .expect("sync")
-}
+        // Same as library consts.rs
+        pub const MAX_FORWARD_JUMPS: usize = 25_000;
+
+        for _i in 0..(MAX_FORWARD_JUMPS + 1) {
+            let _msg = encrypt(
+                &mut alice_store,
+                &bob_address,
+                "Yet another message for you",
+            )
+            .await?;
+        }
+
+        let too_far = encrypt(&mut alice_store, &bob_address, "Now you have gone too far").await?;
+
+        assert!(decrypt(&mut bob_store, &alice_address, &too_far)
+            .await
+            .is_err());
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+#[ignore = "slow to run locally"]
+fn chain_jump_over_limit_with_self() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let device_id_1: DeviceId = 1.into();
+        let a1_address = ProtocolAddress::new("+14151111111".to_owned(), device_id_1);
+        let device_id_2: DeviceId = 2.into();
+        let a2_address = ProtocolAddress::new("+14151111111".to_owned(), device_id_2);
+
+        let mut a1_store = support::test_in_memory_protocol_store()?;
+        let mut a2_store = a1_store.clone(); // same key!
+
+        let a2_pre_key_pair = KeyPair::generate(&mut csprng);
+        let a2_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+        let a2_signed_pre_key_public = a2_signed_pre_key_pair.public_key.serialize();
+        let a2_signed_pre_key_signature = a2_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&a2_signed_pre_key_public, &mut csprng)?;
+
+        let pre_key_id = 31337;
+        let signed_pre_key_id = 22;
+
+        let a2_pre_key_bundle = PreKeyBundle::new(
+            a2_store.get_local_registration_id(None).await?,
+            1.into(),                                              // device id
+            Some((pre_key_id.into(), a2_pre_key_pair.public_key)), // pre key
+            signed_pre_key_id.into(),                              // signed pre key id
+            a2_signed_pre_key_pair.public_key,
+            a2_signed_pre_key_signature.to_vec(),
+            *a2_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &a2_address,
+            &mut a1_store.session_store,
+            &mut a1_store.identity_store,
+            &a2_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        a2_store
+            .save_pre_key(
+                pre_key_id.into(),
+                &PreKeyRecord::new(pre_key_id.into(), &a2_pre_key_pair),
+                None,
+            )
+            .await?;
+        a2_store
+            .save_signed_pre_key(
+                signed_pre_key_id.into(),
+                &SignedPreKeyRecord::new(
+                    signed_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &a2_signed_pre_key_pair,
+                    &a2_signed_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
+
+        // Same as library consts.rs
+        pub const MAX_FORWARD_JUMPS: usize = 25_000;
+
+        for _i in 0..(MAX_FORWARD_JUMPS + 1) {
+            let _msg = encrypt(
+                &mut a1_store,
+                &a2_address,
+                "Yet another message for yourself",
+            )
+            .await?;
+        }
+
+        let too_far = encrypt(
+            &mut a1_store,
+            &a2_address,
+            "This is the song that never ends",
+        )
+        .await?;
+
+        let ptext = decrypt(&mut a2_store, &a1_address, &too_far).await?;
+        assert_eq!(
+            String::from_utf8(ptext).unwrap(),
+            "This is the song that never ends"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_bad_signed_pre_key_signature() -> Result<(), SignalProtocolError> {
+    async {
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let bob_store = support::test_in_memory_protocol_store()?;
+
+        let mut csprng = OsRng;
+        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
+        let bob_signed_pre_key_signature = bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?
+            .to_vec();
+
+        let pre_key_id = 31337;
+        let signed_pre_key_id = 22;
+
+        for bit in 0..8 * bob_signed_pre_key_signature.len() {
+            let mut bad_signature = bob_signed_pre_key_signature.clone();
+
+            bad_signature[bit / 8] ^= 0x01u8 << (bit % 8);
+
+            let bob_pre_key_bundle = PreKeyBundle::new(
+                bob_store.get_local_registration_id(None).await?,
+                1.into(),
+                Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
+                signed_pre_key_id.into(),
+                bob_signed_pre_key_pair.public_key,
+                bad_signature,
+                *bob_store.get_identity_key_pair(None).await?.identity_key(),
+            )?;
+
+            assert!(process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await
+            .is_err());
+        }
+
+        // Finally check that the non-corrupted signature is accepted:
+
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(),
+            Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
+            signed_pre_key_id.into(),
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature,
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+// testRepeatBundleMessageV2 cannot be represented
+
+#[test]
+fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let mut csprng = OsRng;
+        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
+        let bob_signed_pre_key_signature = bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+
+        let pre_key_id = 31337;
+        let signed_pre_key_id = 22;
+
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(),                                               // device id
+            Some((pre_key_id.into(), bob_pre_key_pair.public_key)), // pre key
+            signed_pre_key_id.into(),                               // signed pre key id
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature.to_vec(),
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_some());
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .session_version()?,
+            3
+        );
+
+        let original_message = "L'homme est condamné à être libre";
+
+        let outgoing_message1 = encrypt(&mut alice_store, &bob_address, original_message).await?;
+        let outgoing_message2 = encrypt(&mut alice_store, &bob_address, original_message).await?;
+
+        assert_eq!(
+            outgoing_message1.message_type(),
+            CiphertextMessageType::PreKey
+        );
+        assert_eq!(
+            outgoing_message2.message_type(),
+            CiphertextMessageType::PreKey
+        );
+
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(outgoing_message1.serialize())?,
+        );
+
+        bob_store
+            .save_pre_key(
+                pre_key_id.into(),
+                &PreKeyRecord::new(pre_key_id.into(), &bob_pre_key_pair),
+                None,
+            )
+            .await?;
+        bob_store
+            .save_signed_pre_key(
+                signed_pre_key_id.into(),
+                &SignedPreKeyRecord::new(
+                    signed_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &bob_signed_pre_key_pair,
+                    &bob_signed_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
+
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
+        assert_eq!(
+            String::from_utf8(ptext).expect("valid utf8"),
+            original_message
+        );
+
+        let bob_outgoing = encrypt(&mut bob_store, &alice_address, original_message).await?;
+        assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
+        let alice_decrypts = decrypt(&mut alice_store, &bob_address, &bob_outgoing).await?;
+        assert_eq!(
+            String::from_utf8(alice_decrypts).expect("valid utf8"),
+            original_message
+        );
+
+        // The test
+
+        let incoming_message2 = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(outgoing_message2.serialize())?,
+        );
+
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message2).await?;
+        assert_eq!(
+            String::from_utf8(ptext).expect("valid utf8"),
+            original_message
+        );
+
+        let bob_outgoing = encrypt(&mut bob_store, &alice_address, original_message).await?;
+        let alice_decrypts = decrypt(&mut alice_store, &bob_address, &bob_outgoing).await?;
+        assert_eq!(
+            String::from_utf8(alice_decrypts).expect("valid utf8"),
+            original_message
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn bad_message_bundle() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_pair = KeyPair::generate(&mut csprng);
+        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
+        let bob_signed_pre_key_signature = bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+
+        let pre_key_id = 31337;
+        let signed_pre_key_id = 22;
+
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(), // device id
+            Some((pre_key_id.into(), bob_pre_key_pair.public_key)),
+            signed_pre_key_id.into(), // signed pre key id
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature.to_vec(),
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        bob_store
+            .save_pre_key(
+                pre_key_id.into(),
+                &PreKeyRecord::new(pre_key_id.into(), &bob_pre_key_pair),
+                None,
+            )
+            .await?;
+        bob_store
+            .save_signed_pre_key(
+                signed_pre_key_id.into(),
+                &SignedPreKeyRecord::new(
+                    signed_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &bob_signed_pre_key_pair,
+                    &bob_signed_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
+
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_some());
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .session_version()?,
+            3
+        );
+
+        let original_message = "L'homme est condamné à être libre";
+
+        assert!(bob_store.get_pre_key(pre_key_id.into(), None).await.is_ok());
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
+
+        assert_eq!(
+            outgoing_message.message_type(),
+            CiphertextMessageType::PreKey
+        );
+
+        let outgoing_message = outgoing_message.serialize().to_vec();
+
+        let mut corrupted_message: Vec<u8> = outgoing_message.clone();
+        corrupted_message[outgoing_message.len() - 10] ^= 1;
+
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(corrupted_message.as_slice())?,
+        );
+
+        assert!(decrypt(&mut bob_store, &alice_address, &incoming_message)
+            .await
+            .is_err());
+        assert!(bob_store.get_pre_key(pre_key_id.into(), None).await.is_ok());
+
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(outgoing_message.as_slice())?,
+        );
+
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
+
+        assert_eq!(
+            String::from_utf8(ptext).expect("valid utf8"),
+            original_message
+        );
+        assert!(matches!(
+            bob_store
+                .get_pre_key(pre_key_id.into(), None)
+                .await
+                .unwrap_err(),
+            SignalProtocolError::InvalidPreKeyId
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let mut csprng = OsRng;
+        let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+        let bob_signed_pre_key_public = bob_signed_pre_key_pair.public_key.serialize();
+        let bob_signed_pre_key_signature = bob_store
+            .get_identity_key_pair(None)
+            .await?
+            .private_key()
+            .calculate_signature(&bob_signed_pre_key_public, &mut csprng)?;
+
+        let signed_pre_key_id = 22;
+
+        let bob_pre_key_bundle = PreKeyBundle::new(
+            bob_store.get_local_registration_id(None).await?,
+            1.into(),                 // device id
+            None,                     // no pre key
+            signed_pre_key_id.into(), // signed pre key id
+            bob_signed_pre_key_pair.public_key,
+            bob_signed_pre_key_signature.to_vec(),
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        assert_eq!(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found")
+                .session_version()?,
+            3
+        );
+
+        let original_message = "L'homme est condamné à être libre";
+
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message).await?;
+
+        assert_eq!(
+            outgoing_message.message_type(),
+            CiphertextMessageType::PreKey
+        );
+
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(outgoing_message.serialize())?,
+        );
+
+        bob_store
+            .save_signed_pre_key(
+                signed_pre_key_id.into(),
+                &SignedPreKeyRecord::new(
+                    signed_pre_key_id.into(),
+                    /*timestamp*/ 42,
+                    &bob_signed_pre_key_pair,
+                    &bob_signed_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
+
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message).await?;
+
+        assert_eq!(
+            String::from_utf8(ptext).expect("valid utf8"),
+            original_message
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn basic_session_v3() -> Result<(), SignalProtocolError> {
+    let (alice_session, bob_session) = initialize_sessions_v3()?;
+    run_session_interaction(alice_session, bob_session)?;
+    Ok(())
+}
+
+#[test]
+fn message_key_limits() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        const MAX_MESSAGE_KEYS: usize = 2000; // same value as in library
+        const TOO_MANY_MESSAGES: usize = MAX_MESSAGE_KEYS + 300;
+
+        let mut inflight = Vec::with_capacity(TOO_MANY_MESSAGES);
+
+        for i in 0..TOO_MANY_MESSAGES {
+            inflight
+                .push(encrypt(&mut alice_store, &bob_address, &format!("It's over {}", i)).await?);
+        }
+
+        assert_eq!(
+            String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[1000]).await?)
+                .expect("valid utf8"),
+            "It's over 1000"
+        );
+        assert_eq!(
+            String::from_utf8(
+                decrypt(
+                    &mut bob_store,
+                    &alice_address,
+                    &inflight[TOO_MANY_MESSAGES - 1]
+                )
+                .await?
+            )
+            .expect("valid utf8"),
+            format!("It's over {}", TOO_MANY_MESSAGES - 1)
+        );
+
+        let err = decrypt(&mut bob_store, &alice_address, &inflight[5])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SignalProtocolError::DuplicatedMessage(2300, 5)
+        ));
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_key_limits_with_policy() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let policy = SessionPolicy {
+            max_forward_jumps: 50,
+            max_message_keys: 10,
+            ..SessionPolicy::default()
+        };
+
+        let mut inflight = Vec::with_capacity(100);
+        for i in 0..inflight.capacity() {
+            inflight
+                .push(encrypt(&mut alice_store, &bob_address, &format!("It's over {}", i)).await?);
+        }
+
+        let err = decrypt_with_policy(&mut bob_store, &alice_address, &inflight[99], &policy)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SignalProtocolError::InvalidMessage(CiphertextMessageType::Whisper, _)
+        ));
+
+        assert_eq!(
+            String::from_utf8(
+                decrypt_with_policy(&mut bob_store, &alice_address, &inflight[40], &policy).await?
+            )
+            .expect("valid utf8"),
+            "It's over 40"
+        );
+        assert_eq!(
+            String::from_utf8(
+                decrypt_with_policy(&mut bob_store, &alice_address, &inflight[30], &policy).await?
+            )
+            .expect("valid utf8"),
+            "It's over 30"
+        );
+
+        let err = decrypt_with_policy(&mut bob_store, &alice_address, &inflight[29], &policy)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SignalProtocolError::DuplicatedMessage(41, 29)
+        ));
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
 
 #[test]
-fn basic_session_v3() -> Result<(), SignalProtocolError> {
-    let (alice_session, bob_session) = initialize_sessions_v3()?;
-    run_session_interaction(alice_session, bob_session)?;
-    Ok(())
+fn encrypt_for_all_devices_reports_unusable_sessions() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_name = "+14151111112";
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_device_1 = support::test_in_memory_protocol_store()?;
+        let mut bob_device_2 = support::test_in_memory_protocol_store()?;
+        let mut bob_device_4 =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 0x4000)?;
+        let mut bob_device_5 = support::test_in_memory_protocol_store()?;
+        let mut bob_device_6 = support::test_in_memory_protocol_store()?;
+        let mut bob_device_7 =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 0)?;
+        let mut bob_device_8 = support::test_in_memory_protocol_store()?;
+
+        for (device_id, bob_store) in [
+            (1, &mut bob_device_1),
+            (2, &mut bob_device_2),
+            (4, &mut bob_device_4),
+            (5, &mut bob_device_5),
+            (6, &mut bob_device_6),
+            (7, &mut bob_device_7),
+            (8, &mut bob_device_8),
+        ] {
+            let bundle = create_pre_key_bundle(bob_store, &mut csprng).await?;
+            process_prekey_bundle(
+                &ProtocolAddress::new(bob_name.to_owned(), device_id.into()),
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+        }
+
+        // Device 5's session has been archived, e.g. after the session was reset.
+        let device_5_address = ProtocolAddress::new(bob_name.to_owned(), 5.into());
+        let mut record = alice_store
+            .load_session(&device_5_address, None)
+            .await?
+            .expect("session exists");
+        record.archive_current_state()?;
+        alice_store
+            .store_session(&device_5_address, &record, None)
+            .await?;
+
+        // Device 8 has changed its identity key since the session was set up.
+        let device_8_address = ProtocolAddress::new(bob_name.to_owned(), 8.into());
+        alice_store
+            .save_identity(
+                &device_8_address,
+                IdentityKeyPair::generate(&mut csprng).identity_key(),
+                None,
+            )
+            .await?;
+
+        let mut devices = vec![];
+        for (device_id, bob_store) in [
+            (1, &bob_device_1),
+            (2, &bob_device_2),
+            (4, &bob_device_4),
+            (5, &bob_device_5),
+            (7, &bob_device_7),
+            (8, &bob_device_8),
+        ] {
+            devices.push((
+                DeviceId::from(device_id),
+                bob_store.get_local_registration_id(None).await?,
+            ));
+        }
+        // Device 3 has never been sent to, and device 6 has re-registered.
+        devices.push((3.into(), 0x1234));
+        devices.push((
+            6.into(),
+            bob_device_6.get_local_registration_id(None).await? ^ 1,
+        ));
+        // Duplicates are ignored.
+        devices.push(devices[0]);
+
+        let result = encrypt_for_all_devices(
+            b"hi bob",
+            bob_name,
+            &devices,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await;
+
+        assert_eq!(result.missing_sessions, [5, 3].map(DeviceId::from));
+        assert_eq!(result.stale_registration_ids, [4, 7, 6].map(DeviceId::from));
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].0, DeviceId::from(8));
+        assert!(matches!(
+            result.errors[0].1,
+            SignalProtocolError::UntrustedIdentity(_)
+        ));
+        assert_eq!(result.messages.len(), 2);
+        for (message, bob_store) in result
+            .messages
+            .iter()
+            .zip([&mut bob_device_1, &mut bob_device_2])
+        {
+            assert_eq!(
+                message.registration_id,
+                bob_store.get_local_registration_id(None).await?
+            );
+            assert_eq!(
+                decrypt(bob_store, &alice_address, &message.message).await?,
+                b"hi bob"
+            );
+        }
+        assert_eq!(
+            result
+                .messages
+                .iter()
+                .map(|message| message.device_id)
+                .collect::<Vec<_>>(),
+            [1, 2].map(DeviceId::from)
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn signed_pre_key_max_age() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+
+        // Re-save Bob's signed pre-key as created a week ago.
+        let signed_pre_key_id = bob_pre_key_bundle.signed_pre_key_id()?;
+        let record = bob_store
+            .get_signed_pre_key(signed_pre_key_id, None)
+            .await?;
+        let week_ago = (SystemTime::now() - 7 * day)
+            .duration_since(UNIX_EPOCH)
+            .expect("valid time")
+            .as_millis() as u64;
+        let record = SignedPreKeyRecord::new(
+            signed_pre_key_id,
+            week_ago,
+            &record.key_pair()?,
+            &record.signature()?,
+        );
+        bob_store
+            .save_signed_pre_key(signed_pre_key_id, &record, None)
+            .await?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+
+        let policy = SessionPolicy {
+            max_signed_pre_key_age: Some(2 * day),
+            ..SessionPolicy::default()
+        };
+        let err = decrypt_with_policy(&mut bob_store, &alice_address, &message, &policy)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SignalProtocolError::SignedPreKeyExpired(id) if id == signed_pre_key_id
+        ));
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+
+        // Nothing was consumed, so a more lenient policy still accepts the message.
+        let policy = SessionPolicy {
+            max_signed_pre_key_age: Some(8 * day),
+            ..SessionPolicy::default()
+        };
+        assert_eq!(
+            decrypt_with_policy(&mut bob_store, &alice_address, &message, &policy).await?,
+            b"hi bob"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn skipped_message_key_expiry() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let mut inflight = Vec::with_capacity(3);
+        for i in 0..inflight.capacity() {
+            inflight
+                .push(encrypt(&mut alice_store, &bob_address, &format!("Message {}", i)).await?);
+        }
+
+        let policy = SessionPolicy {
+            max_skipped_key_age: Some(Duration::ZERO),
+            ..SessionPolicy::default()
+        };
+
+        decrypt_with_policy(&mut bob_store, &alice_address, &inflight[2], &policy).await?;
+
+        // With the default policy, the skipped key would still be available.
+        let snapshot = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .snapshot()?;
+        assert_eq!(
+            snapshot.current.expect("current session").receiver_chains[0].skipped_message_keys,
+            2
+        );
+
+        let err = decrypt_with_policy(&mut bob_store, &alice_address, &inflight[0], &policy)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SignalProtocolError::DuplicatedMessage(3, 0)));
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn message_padding() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let policy = SessionPolicy {
+            padding: Some(padding::PaddingScheme::SIGNAL_BLOCKS),
+            ..SessionPolicy::default()
+        };
+
+        let mut encrypt_padded = |msg: &'static [u8]| {
+            message_encrypt(
+                msg,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &policy,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+        };
+        let short = encrypt_padded(b"hi")?;
+        let long = encrypt_padded(&[0x80; 200])?;
+
+        // Both are padded to whole blocks before CBC adds a block of its own.
+        for (message, expected_len) in [(&short, 176), (&long, 336)] {
+            match message {
+                CiphertextMessage::SignalMessage(m) => assert_eq!(m.body().len(), expected_len),
+                _ => panic!("unexpected message type"),
+            }
+        }
+
+        assert_eq!(
+            decrypt_with_policy(&mut bob_store, &alice_address, &short, &policy).await?,
+            b"hi"
+        );
+        assert_eq!(
+            decrypt_with_policy(&mut bob_store, &alice_address, &long, &policy).await?,
+            [0x80; 200]
+        );
+
+        // Unpadded messages are rejected by a recipient expecting padding...
+        let unpadded = encrypt(&mut alice_store, &bob_address, "no padding").await?;
+        let err = decrypt_with_policy(&mut bob_store, &alice_address, &unpadded, &policy)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SignalProtocolError::InvalidPadding));
+        // The rejected message's keys are kept, so it can still be decrypted.
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &unpadded).await?,
+            b"no padding"
+        );
+
+        // ...and a recipient not expecting padding sees it as part of the message.
+        let padded = message_encrypt(
+            b"padded",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &policy,
+            None,
+        )
+        .await?;
+        let ptext = decrypt(&mut bob_store, &alice_address, &padded).await?;
+        assert_eq!(ptext.len(), 160);
+        assert_eq!(&ptext[..7], b"padded\x80");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn decryption_error_archives_session() -> Result<(), SignalProtocolError> {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+        let timestamp = 1_700_000_000_000;
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record, None)
+            .await?;
+
+        let message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+        let entry = ResendLogEntry {
+            timestamp,
+            content: b"hi bob".to_vec(),
+            distribution_id: None,
+            recipients: vec![bob_address.clone()],
+        };
+        alice_store.save_resend_entry(&entry, None).await?;
+
+        let error_message = DecryptionErrorMessage::for_original(
+            message.serialize(),
+            message.message_type(),
+            timestamp,
+            1,
+        )?;
+
+        let mut process = |error_message: &DecryptionErrorMessage| {
+            process_decryption_error_message(
+                error_message,
+                &bob_address,
+                &alice_address,
+                &mut alice_store.session_store,
+                &mut alice_store.sender_key_store,
+                &alice_store.resend_log_store,
+                &SessionPolicy::default(),
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+        };
+
+        // Errors for messages sent by our other devices are ignored.
+        let other_device = DecryptionErrorMessage::for_original(
+            message.serialize(),
+            message.message_type(),
+            timestamp,
+            2,
+        )?;
+        assert_eq!(process(&other_device)?, RetryPlan::default());
+
+        assert_eq!(
+            process(&error_message)?,
+            RetryPlan {
+                session_archived: true,
+                sender_key_distribution_id: None,
+                resend: Some(entry),
+            }
+        );
+
+        // The session has already been replaced, so a repeated error changes nothing more.
+        assert!(!process(&error_message)?.session_archived);
+
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(!record.has_current_session_state());
+
+        // Entries are only kept until they are removed.
+        alice_store
+            .remove_resend_entries_before(timestamp + 1, None)
+            .await?;
+        assert_eq!(
+            alice_store
+                .load_resend_entry(&bob_address, timestamp, None)
+                .await?,
+            None
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
 }
 
 #[test]
-fn message_key_limits() -> Result<(), SignalProtocolError> {
+fn end_session_archives_both_sides() -> Result<(), SignalProtocolError> {
     async {
         let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
 
@@ -892,40 +1984,352 @@ fn message_key_limits() -> Result<(), SignalProtocolError> {
             .store_session(&alice_address, &bob_session_record, None)
             .await?;
 
-        const MAX_MESSAGE_KEYS: usize = 2000; // same value as in library
-        const TOO_MANY_MESSAGES: usize = MAX_MESSAGE_KEYS + 300;
+        let in_flight = encrypt(&mut alice_store, &bob_address, "before the end").await?;
 
-        let mut inflight = Vec::with_capacity(TOO_MANY_MESSAGES);
+        let timestamp = now_millis();
+        let content = end_session(
+            &bob_address,
+            &mut alice_store.session_store,
+            timestamp,
+            &SessionPolicy::default(),
+            None,
+        )
+        .await?;
+        assert!(matches!(
+            encrypt(&mut alice_store, &bob_address, "after the end").await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
 
-        for i in 0..TOO_MANY_MESSAGES {
-            inflight
-                .push(encrypt(&mut alice_store, &bob_address, &format!("It's over {}", i)).await?);
-        }
+        let received = PlaintextContent::try_from(content.serialized())?;
+        let message = received
+            .end_session_message()?
+            .expect("contains an EndSessionMessage");
+        assert_eq!(message.timestamp(), timestamp);
+        assert!(
+            process_end_session_message(
+                &message,
+                &alice_address,
+                &mut bob_store,
+                &SessionPolicy::default(),
+                None,
+            )
+            .await?
+        );
+        assert!(matches!(
+            encrypt(&mut bob_store, &alice_address, "after the end").await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+        // Processing the message again has nothing left to drop.
+        assert!(
+            !process_end_session_message(
+                &message,
+                &alice_address,
+                &mut bob_store,
+                &SessionPolicy::default(),
+                None,
+            )
+            .await?
+        );
 
+        // Messages sent before the session ended can still be decrypted.
         assert_eq!(
-            String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[1000]).await?)
-                .expect("valid utf8"),
-            "It's over 1000"
+            decrypt(&mut bob_store, &alice_address, &in_flight).await?,
+            b"before the end"
         );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_millis() as u64
+}
+
+#[test]
+fn end_session_message_only_ends_its_session() -> Result<(), SignalProtocolError> {
+    async {
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let start_sessions =
+            |alice_store: &mut InMemSignalProtocolStore,
+             bob_store: &mut InMemSignalProtocolStore| {
+                let (alice_session_record, bob_session_record) = initialize_sessions_v3()?;
+                alice_store
+                    .store_session(&bob_address, &alice_session_record, None)
+                    .now_or_never()
+                    .expect("sync")?;
+                bob_store
+                    .store_session(&alice_address, &bob_session_record, None)
+                    .now_or_never()
+                    .expect("sync")
+            };
+        let end = |alice_store: &mut InMemSignalProtocolStore, timestamp: u64| {
+            end_session(
+                &bob_address,
+                &mut alice_store.session_store,
+                timestamp,
+                &SessionPolicy::default(),
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .and_then(|content| {
+                Ok(PlaintextContent::try_from(content.serialized())?
+                    .end_session_message()?
+                    .expect("contains an EndSessionMessage"))
+            })
+        };
+        let process = |bob_store: &mut InMemSignalProtocolStore, message: &EndSessionMessage| {
+            process_end_session_message(
+                message,
+                &alice_address,
+                bob_store,
+                &SessionPolicy::default(),
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+        };
+
+        start_sessions(&mut alice_store, &mut bob_store)?;
+        let message = end(&mut alice_store, now_millis())?;
+
+        // A forged message is rejected...
+        let mut forged = message.serialized().to_vec();
+        *forged.last_mut().expect("not empty") ^= 1;
+        assert!(matches!(
+            process(&mut bob_store, &EndSessionMessage::try_from(&forged[..])?),
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Plaintext,
+                _
+            ))
+        ));
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found")
+            .has_current_session_state());
+
+        // ...while the real one ends the session.
+        assert!(process(&mut bob_store, &message)?);
+
+        // Replaying it against a later session leaves that session alone.
+        start_sessions(&mut alice_store, &mut bob_store)?;
+        assert!(matches!(
+            process(&mut bob_store, &message),
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Plaintext,
+                _
+            ))
+        ));
+        let bob_message = encrypt(&mut bob_store, &alice_address, "still here").await?;
         assert_eq!(
-            String::from_utf8(
-                decrypt(
-                    &mut bob_store,
-                    &alice_address,
-                    &inflight[TOO_MANY_MESSAGES - 1]
+            decrypt(&mut alice_store, &bob_address, &bob_message).await?,
+            b"still here"
+        );
+
+        // The timestamp is not checked against the session, since the clocks may disagree.
+        let earlier = end(&mut alice_store, now_millis() - 60_000)?;
+        assert!(process(&mut bob_store, &earlier)?);
+
+        // Without a current session there is nothing to end.
+        assert!(matches!(
+            end(&mut alice_store, now_millis()),
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn archived_states_policy() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let policy = SessionPolicy {
+            archived_states_max_length: 2,
+            ..SessionPolicy::default()
+        };
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let mut alice_limited_store = InMemSessionStore::with_policy(policy.clone());
+        let mut alice_policy_store = InMemSessionStore::new();
+
+        for _ in 0..5 {
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+            for session_store in [
+                &mut alice_store.session_store as &mut dyn SessionStore,
+                &mut alice_limited_store,
+            ] {
+                process_prekey_bundle(
+                    &bob_address,
+                    session_store,
+                    &mut alice_store.identity_store,
+                    &bob_pre_key_bundle,
+                    &mut csprng,
+                    None,
                 )
+                .await?;
+            }
+            process_prekey_bundle_with_policy(
+                &bob_address,
+                &mut alice_policy_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                &policy,
+                None,
+            )
+            .await?;
+        }
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.snapshot()?.archived.len(), 4);
+
+        record.apply_policy(&policy)?;
+        assert_eq!(record.snapshot()?.archived.len(), 2);
+        assert_eq!(
+            record.snapshot()?.current,
+            alice_store
+                .load_session(&bob_address, None)
                 .await?
+                .expect("session found")
+                .snapshot()?
+                .current
+        );
+
+        let limited_record = alice_limited_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(limited_record.snapshot()?.archived.len(), 2);
+
+        let policy_record = alice_policy_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(policy_record.snapshot()?.archived.len(), 2);
+
+        end_session(
+            &bob_address,
+            &mut alice_policy_store,
+            now_millis(),
+            &policy,
+            None,
+        )
+        .await?;
+        let ended_record = alice_policy_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert!(ended_record.snapshot()?.current.is_none());
+        assert_eq!(ended_record.snapshot()?.archived.len(), 2);
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn store_failure_rolls_back_decrypt() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        let bob_pre_key_id = bob_pre_key_bundle
+            .pre_key_id()?
+            .expect("has one-time pre-key");
+
+        // A failure while setting up the session leaves nothing behind.
+        alice_store.session_store.fail_after_writes(Some(0));
+        assert!(matches!(
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
             )
-            .expect("valid utf8"),
-            format!("It's over {}", TOO_MANY_MESSAGES - 1)
+            .await,
+            Err(SignalProtocolError::InvalidState("store_session", _))
+        ));
+        assert!(alice_store
+            .get_identity(&bob_address, None)
+            .await?
+            .is_none());
+
+        alice_store.session_store.fail_after_writes(None);
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+        assert_eq!(
+            outgoing_message.message_type(),
+            CiphertextMessageType::PreKey
         );
 
-        let err = decrypt(&mut bob_store, &alice_address, &inflight[5])
-            .await
-            .unwrap_err();
+        // The session and identity are saved before the pre-key is removed.
+        bob_store.pre_key_store.fail_after_writes(Some(0));
         assert!(matches!(
-            err,
-            SignalProtocolError::DuplicatedMessage(2300, 5)
+            decrypt(&mut bob_store, &alice_address, &outgoing_message).await,
+            Err(SignalProtocolError::InvalidState("remove_pre_key", _))
+        ));
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+        assert!(bob_store
+            .get_identity(&alice_address, None)
+            .await?
+            .is_none());
+        bob_store.get_pre_key(bob_pre_key_id, None).await?;
+
+        bob_store.pre_key_store.fail_after_writes(None);
+        let ptext = decrypt(&mut bob_store, &alice_address, &outgoing_message).await?;
+        assert_eq!(String::from_utf8(ptext).expect("valid utf8"), "hi bob");
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_some());
+        assert!(bob_store
+            .get_identity(&alice_address, None)
+            .await?
+            .is_some());
+        assert!(matches!(
+            bob_store.get_pre_key(bob_pre_key_id, None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
         ));
         Ok(())
     }
@@ -933,6 +2337,107 @@ fn message_key_limits() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn test_session_snapshot() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let alice_snapshot = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .snapshot()?;
+        assert!(alice_snapshot.archived.is_empty());
+        let alice_state = alice_snapshot.current.expect("current state");
+        assert_eq!(alice_state.version, 3);
+        assert!(alice_state.has_unacknowledged_pre_key_message);
+        assert_eq!(
+            alice_state
+                .sender_chain
+                .as_ref()
+                .expect("sender chain")
+                .chain_index,
+            Some(0)
+        );
+        assert_eq!(alice_state.receiver_chains.len(), 1);
+
+        let messages = [
+            encrypt(&mut alice_store, &bob_address, "one").await?,
+            encrypt(&mut alice_store, &bob_address, "two").await?,
+            encrypt(&mut alice_store, &bob_address, "three").await?,
+        ];
+        // Deliver out of order, skipping the second message.
+        decrypt(&mut bob_store, &alice_address, &messages[0]).await?;
+        decrypt(&mut bob_store, &alice_address, &messages[2]).await?;
+
+        let mut bob_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        let bob_state = bob_record.snapshot()?.current.expect("current state");
+        assert_eq!(bob_state.version, 3);
+        assert_eq!(
+            bob_state.base_key_fingerprint,
+            alice_state.base_key_fingerprint
+        );
+        assert!(!bob_state.has_unacknowledged_pre_key_message);
+        assert_eq!(bob_state.receiver_chains.len(), 1);
+        assert_eq!(bob_state.receiver_chains[0].chain_index, Some(3));
+        assert_eq!(bob_state.receiver_chains[0].skipped_message_keys, 1);
+        assert_eq!(
+            bob_state.receiver_chains[0].ratchet_key_fingerprint,
+            alice_state
+                .sender_chain
+                .as_ref()
+                .expect("sender chain")
+                .ratchet_key_fingerprint
+        );
+
+        let bob_reply = encrypt(&mut bob_store, &alice_address, "ack").await?;
+        decrypt(&mut alice_store, &bob_address, &bob_reply).await?;
+        let alice_state = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .snapshot()?
+            .current
+            .expect("current state");
+        assert!(!alice_state.has_unacknowledged_pre_key_message);
+        assert_eq!(alice_state.receiver_chains.len(), 2);
+
+        let bob_state = bob_record.snapshot()?.current.expect("current state");
+        bob_record.archive_current_state()?;
+        let bob_snapshot = bob_record.snapshot()?;
+        assert_eq!(bob_snapshot.current, None);
+        assert_eq!(bob_snapshot.archived, vec![bob_state]);
+        assert!(bob_snapshot
+            .to_string()
+            .starts_with("current: none\narchived[0]: v3 "));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[allow(clippy::needless_range_loop)]
 fn run_session_interaction(
     alice_session: SessionRecord,
@@ -0,0 +1,1115 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Tests that every store implementation must pass, run against each implementation.
+
+mod support;
+
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use rand::rngs::OsRng;
+use std::convert::TryFrom;
+use support::*;
+use uuid::Uuid;
+
+/// The stores making up a combined store, borrowed separately.
+struct Stores<'a> {
+    session: &'a mut dyn SessionStore,
+    identity: &'a mut dyn IdentityKeyStore,
+    pre_key: &'a mut dyn PreKeyStore,
+    signed_pre_key: &'a mut dyn SignedPreKeyStore,
+    kyber_pre_key: &'a mut dyn KyberPreKeyStore,
+}
+
+trait TestStore:
+    ProtocolStore + SenderKeyStore + ResendLogStore + ProtocolStoreTransaction + Sized
+{
+    fn create() -> Result<Self, SignalProtocolError>;
+    fn stores(&mut self) -> Stores<'_>;
+}
+
+impl TestStore for InMemSignalProtocolStore {
+    fn create() -> Result<Self, SignalProtocolError> {
+        test_in_memory_protocol_store()
+    }
+
+    fn stores(&mut self) -> Stores<'_> {
+        Stores {
+            session: &mut self.session_store,
+            identity: &mut self.identity_store,
+            pre_key: &mut self.pre_key_store,
+            signed_pre_key: &mut self.signed_pre_key_store,
+            kyber_pre_key: &mut self.kyber_pre_key_store,
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl TestStore for SqliteSignalProtocolStore {
+    fn create() -> Result<Self, SignalProtocolError> {
+        SqliteSignalProtocolStore::new_in_memory(IdentityKeyPair::generate(&mut OsRng), 5)
+    }
+
+    fn stores(&mut self) -> Stores<'_> {
+        Stores {
+            session: &mut self.session_store,
+            identity: &mut self.identity_store,
+            pre_key: &mut self.pre_key_store,
+            signed_pre_key: &mut self.signed_pre_key_store,
+            kyber_pre_key: &mut self.kyber_pre_key_store,
+        }
+    }
+}
+
+async fn encrypt_with<S: TestStore>(
+    store: &mut S,
+    remote_address: &ProtocolAddress,
+    msg: &str,
+) -> Result<CiphertextMessage, SignalProtocolError> {
+    let stores = store.stores();
+    message_encrypt(
+        msg.as_bytes(),
+        remote_address,
+        stores.session,
+        stores.identity,
+        &SessionPolicy::default(),
+        None,
+    )
+    .await
+}
+
+async fn decrypt_with<S: TestStore>(
+    store: &mut S,
+    remote_address: &ProtocolAddress,
+    msg: &CiphertextMessage,
+) -> Result<String, SignalProtocolError> {
+    let stores = store.stores();
+    let ptext = message_decrypt(
+        msg,
+        remote_address,
+        stores.session,
+        stores.identity,
+        stores.pre_key,
+        stores.signed_pre_key,
+        stores.kyber_pre_key,
+        &SessionPolicy::default(),
+        &mut OsRng,
+        None,
+    )
+    .await?;
+    Ok(String::from_utf8(ptext).expect("valid utf8"))
+}
+
+fn identity_store<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let other_address = ProtocolAddress::new("+14151111111".to_owned(), 2.into());
+        let key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let new_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+
+        assert_eq!(store.get_local_registration_id(None).await?, 5);
+
+        assert_eq!(store.get_identity(&address, None).await?, None);
+        assert!(
+            store
+                .is_trusted_identity(&address, &key, Direction::Receiving, None)
+                .await?
+        );
+
+        assert_eq!(
+            store.save_identity(&address, &key, None).await?,
+            IdentityChange::New
+        );
+        assert_eq!(
+            store.save_identity(&address, &key, None).await?,
+            IdentityChange::Unchanged
+        );
+        assert_eq!(store.get_identity(&address, None).await?, Some(key));
+        assert_eq!(store.get_identity(&other_address, None).await?, None);
+        assert!(
+            !store
+                .is_trusted_identity(&address, &new_key, Direction::Sending, None)
+                .await?
+        );
+
+        assert_eq!(
+            store.save_identity(&address, &new_key, None).await?,
+            IdentityChange::ReplacedUnverified
+        );
+        assert_eq!(store.get_identity(&address, None).await?, Some(new_key));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn verified_status<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let new_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+
+        // Only the identity currently known for an address can be verified.
+        assert!(
+            !store
+                .set_verified_status(&address, &key, VerifiedStatus::Verified, None)
+                .await?
+        );
+        assert_eq!(
+            store.get_verified_status(&address, None).await?,
+            VerifiedStatus::Default
+        );
+        store.save_identity(&address, &key, None).await?;
+        assert!(
+            !store
+                .set_verified_status(&address, &new_key, VerifiedStatus::Verified, None)
+                .await?
+        );
+        assert!(
+            store
+                .set_verified_status(&address, &key, VerifiedStatus::Verified, None)
+                .await?
+        );
+        assert_eq!(
+            store.get_verified_status(&address, None).await?,
+            VerifiedStatus::Verified
+        );
+
+        // Saving the same identity again keeps it verified.
+        assert_eq!(
+            store.save_identity(&address, &key, None).await?,
+            IdentityChange::Unchanged
+        );
+        assert_eq!(
+            store.get_verified_status(&address, None).await?,
+            VerifiedStatus::Verified
+        );
+
+        // Replacing a verified identity leaves the new one unverified...
+        assert_eq!(
+            store.save_identity(&address, &new_key, None).await?,
+            IdentityChange::ReplacedVerified
+        );
+        assert_eq!(
+            store.get_verified_status(&address, None).await?,
+            VerifiedStatus::Unverified
+        );
+
+        // ...and replacing an identity that was never verified resets it to the default.
+        assert_eq!(
+            store.save_identity(&address, &key, None).await?,
+            IdentityChange::ReplacedUnverified
+        );
+        assert_eq!(
+            store.get_verified_status(&address, None).await?,
+            VerifiedStatus::Default
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn pre_key_stores<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let key_pair = KeyPair::generate(&mut OsRng);
+
+        assert!(matches!(
+            store.get_pre_key(1.into(), None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+        let record = PreKeyRecord::new(1.into(), &key_pair);
+        store.save_pre_key(1.into(), &record, None).await?;
+        assert_eq!(
+            store.get_pre_key(1.into(), None).await?.serialize()?,
+            record.serialize()?
+        );
+        store.remove_pre_key(1.into(), None).await?;
+        assert!(matches!(
+            store.get_pre_key(1.into(), None).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+
+        assert!(matches!(
+            store.get_signed_pre_key(2.into(), None).await,
+            Err(SignalProtocolError::InvalidSignedPreKeyId)
+        ));
+        let record = SignedPreKeyRecord::new(2.into(), 42, &key_pair, &[0; 64]);
+        store.save_signed_pre_key(2.into(), &record, None).await?;
+        assert_eq!(
+            store
+                .get_signed_pre_key(2.into(), None)
+                .await?
+                .serialize()?,
+            record.serialize()?
+        );
+
+        let kyber_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+        assert!(matches!(
+            store.get_kyber_pre_key(3.into(), None).await,
+            Err(SignalProtocolError::InvalidKyberPreKeyId)
+        ));
+        let record = KyberPreKeyRecord::new(3.into(), 42, &kyber_key_pair, &[0; 64]);
+        store.save_kyber_pre_key(3.into(), &record, None).await?;
+        assert_eq!(
+            store.get_kyber_pre_key(3.into(), None).await?.serialize()?,
+            record.serialize()?
+        );
+        store.mark_kyber_pre_key_used(3.into(), None).await?;
+        assert!(matches!(
+            store.get_kyber_pre_key(3.into(), None).await,
+            Err(SignalProtocolError::InvalidKyberPreKeyId)
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+/// Saves pre-keys with creation times either side of 200, for testing pruning queries.
+async fn save_timestamped_pre_keys<S>(store: &mut S) -> Result<(), SignalProtocolError>
+where
+    S: PreKeyStore + SignedPreKeyStore + KyberPreKeyStore,
+{
+    let key_pair = KeyPair::generate(&mut OsRng);
+    let kyber_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+
+    // Saved without a creation time.
+    store
+        .save_pre_key(1.into(), &PreKeyRecord::new(1.into(), &key_pair), None)
+        .await?;
+    for (id, timestamp) in [(2, 100), (3, 300)] {
+        let record = PreKeyRecord::new(id.into(), &key_pair).with_timestamp(timestamp);
+        store.save_pre_key(id.into(), &record, None).await?;
+
+        let record = SignedPreKeyRecord::new(id.into(), timestamp, &key_pair, &[0; 64]);
+        store.save_signed_pre_key(id.into(), &record, None).await?;
+
+        let record = KyberPreKeyRecord::new(id.into(), timestamp, &kyber_key_pair, &[0; 64]);
+        store.save_kyber_pre_key(id.into(), &record, None).await?;
+    }
+    Ok(())
+}
+
+async fn check_pruning_queries<S>(store: &mut S) -> Result<(), SignalProtocolError>
+where
+    S: PreKeyStore + SignedPreKeyStore + KyberPreKeyStore,
+{
+    save_timestamped_pre_keys(store).await?;
+
+    // Keys without a creation time are never reported as old.
+    assert_eq!(
+        sorted_ids(store.pre_key_ids_created_before(200, None).await?),
+        [2]
+    );
+    assert_eq!(
+        sorted_ids(store.signed_pre_key_ids_created_before(200, None).await?),
+        [2]
+    );
+    assert_eq!(
+        sorted_ids(store.kyber_pre_key_ids_created_before(200, None).await?),
+        [2]
+    );
+
+    store.remove_signed_pre_key(2.into(), None).await?;
+    store.remove_kyber_pre_key(2.into(), None).await?;
+    assert!(store.get_signed_pre_key(2.into(), None).await.is_err());
+    assert!(store.get_kyber_pre_key(2.into(), None).await.is_err());
+    assert!(store.get_signed_pre_key(3.into(), None).await.is_ok());
+    assert!(store.get_kyber_pre_key(3.into(), None).await.is_ok());
+    Ok(())
+}
+
+fn sorted_ids<T: Into<u32>>(ids: Vec<T>) -> Vec<u32> {
+    let mut ids: Vec<u32> = ids.into_iter().map(Into::into).collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn pruning_queries<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async { check_pruning_queries(&mut S::create()?).await }
+        .now_or_never()
+        .expect("sync")
+}
+
+fn session_store<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let other_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let (record, _) = initialize_sessions_v3()?;
+
+        assert!(store.load_session(&address, None).await?.is_none());
+        store.store_session(&address, &record, None).await?;
+        let loaded = store
+            .load_session(&address, None)
+            .await?
+            .expect("session stored");
+        assert_eq!(loaded.serialize()?, record.serialize()?);
+        assert!(store.load_session(&other_address, None).await?.is_none());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn sender_key_store<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let sender = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let other_sender = ProtocolAddress::new("+14159999111".to_owned(), 2.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let other_distribution_id = Uuid::from_u128(0xd2d2d2d2_7000_11eb_b32a_33b8a8a487a6);
+
+        create_sender_key_distribution_message(
+            &sender,
+            distribution_id,
+            &mut store,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+
+        let record = store
+            .load_sender_key(&sender, distribution_id, None)
+            .await?
+            .expect("sender key created");
+        assert!(store
+            .load_sender_key(&sender, other_distribution_id, None)
+            .await?
+            .is_none());
+        assert!(store
+            .load_sender_key(&other_sender, distribution_id, None)
+            .await?
+            .is_none());
+
+        store
+            .store_sender_key(&other_sender, other_distribution_id, &record, None)
+            .await?;
+        let loaded = store
+            .load_sender_key(&other_sender, other_distribution_id, None)
+            .await?
+            .expect("sender key stored");
+        assert_eq!(loaded.serialize()?, record.serialize()?);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn resend_log_store<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let alice_2 = ProtocolAddress::new("+14151111111".to_owned(), 2.into());
+        let bob = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let timestamp = 1_700_000_000_000;
+
+        let group_message = ResendLogEntry {
+            timestamp,
+            content: b"hi all".to_vec(),
+            distribution_id: Some(Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6)),
+            recipients: vec![alice.clone(), alice_2.clone()],
+        };
+        // A different message that happens to have been sent at the same time.
+        let direct_message = ResendLogEntry {
+            timestamp,
+            content: b"hi bob".to_vec(),
+            distribution_id: None,
+            recipients: vec![bob.clone()],
+        };
+        let later_message = ResendLogEntry {
+            timestamp: timestamp + 1,
+            ..direct_message.clone()
+        };
+        for entry in [&group_message, &direct_message, &later_message] {
+            store.save_resend_entry(entry, None).await?;
+        }
+
+        for (recipient, expected) in [
+            (&alice, &group_message),
+            (&alice_2, &group_message),
+            (&bob, &direct_message),
+        ] {
+            assert_eq!(
+                store.load_resend_entry(recipient, timestamp, None).await?,
+                Some(expected.clone())
+            );
+        }
+        assert_eq!(
+            store.load_resend_entry(&bob, timestamp + 1, None).await?,
+            Some(later_message.clone())
+        );
+        assert_eq!(
+            store.load_resend_entry(&alice, timestamp + 1, None).await?,
+            None
+        );
+
+        // Saving again for a recipient replaces its entry.
+        let resent = ResendLogEntry {
+            content: b"hi again".to_vec(),
+            recipients: vec![alice_2.clone()],
+            ..group_message.clone()
+        };
+        store.save_resend_entry(&resent, None).await?;
+        assert_eq!(
+            store.load_resend_entry(&alice_2, timestamp, None).await?,
+            Some(resent)
+        );
+        assert_eq!(
+            store.load_resend_entry(&alice, timestamp, None).await?,
+            Some(group_message)
+        );
+
+        store
+            .remove_resend_entries_before(timestamp + 1, None)
+            .await?;
+        for recipient in [&alice, &alice_2, &bob] {
+            assert_eq!(
+                store.load_resend_entry(recipient, timestamp, None).await?,
+                None
+            );
+        }
+        assert_eq!(
+            store.load_resend_entry(&bob, timestamp + 1, None).await?,
+            Some(later_message)
+        );
+
+        // Entries saved in a transaction that is rolled back are forgotten.
+        store.begin_transaction(None).await?;
+        store.save_resend_entry(&direct_message, None).await?;
+        store.rollback_transaction(None).await?;
+        assert_eq!(store.load_resend_entry(&bob, timestamp, None).await?, None);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn session_round_trip<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = S::create()?;
+        let mut bob_store = S::create()?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+        let bob_pre_key_id = bob_pre_key_bundle.pre_key_id()?.expect("has pre key");
+
+        let stores = alice_store.stores();
+        process_prekey_bundle(
+            &bob_address,
+            stores.session,
+            stores.identity,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message = encrypt_with(&mut alice_store, &bob_address, "hi bob").await?;
+        assert_eq!(message.message_type(), CiphertextMessageType::PreKey);
+        assert_eq!(
+            decrypt_with(&mut bob_store, &alice_address, &message).await?,
+            "hi bob"
+        );
+        assert!(bob_store.get_pre_key(bob_pre_key_id, None).await.is_err());
+
+        let reply = encrypt_with(&mut bob_store, &alice_address, "hi alice").await?;
+        assert_eq!(reply.message_type(), CiphertextMessageType::Whisper);
+        assert_eq!(
+            decrypt_with(&mut alice_store, &bob_address, &reply).await?,
+            "hi alice"
+        );
+
+        let message = encrypt_with(&mut alice_store, &bob_address, "bye bob").await?;
+        assert_eq!(message.message_type(), CiphertextMessageType::Whisper);
+        assert_eq!(
+            decrypt_with(&mut bob_store, &alice_address, &message).await?,
+            "bye bob"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn group_round_trip<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = S::create()?;
+        let mut bob_store = S::create()?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let recv_distribution_message =
+            SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?;
+        process_sender_key_distribution_message(
+            &sender_address,
+            &recv_distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        for message in ["space camp?", "see you there"] {
+            let ciphertext = group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                message.as_bytes(),
+                &SessionPolicy::default(),
+                &mut csprng,
+                None,
+            )
+            .await?;
+            let plaintext = group_decrypt(
+                ciphertext.serialized(),
+                &mut bob_store,
+                &sender_address,
+                &SessionPolicy::default(),
+                None,
+            )
+            .await?;
+            assert_eq!(String::from_utf8(plaintext).expect("valid utf8"), message);
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+fn transaction_rollback<S: TestStore>() -> Result<(), SignalProtocolError> {
+    async {
+        let mut store = S::create()?;
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let record = PreKeyRecord::new(1.into(), &KeyPair::generate(&mut OsRng));
+
+        store.begin_transaction(None).await?;
+        store.save_pre_key(1.into(), &record, None).await?;
+        store.save_identity(&address, &key, None).await?;
+        store.rollback_transaction(None).await?;
+
+        assert!(store.get_pre_key(1.into(), None).await.is_err());
+        assert_eq!(store.get_identity(&address, None).await?, None);
+
+        store.begin_transaction(None).await?;
+        store.save_pre_key(1.into(), &record, None).await?;
+        store.save_identity(&address, &key, None).await?;
+        store.commit_transaction(None).await?;
+
+        // Rolling back after a commit has no effect.
+        store.rollback_transaction(None).await?;
+        assert!(store.get_pre_key(1.into(), None).await.is_ok());
+        assert_eq!(store.get_identity(&address, None).await?, Some(key));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+macro_rules! store_tests {
+    ($store:ty) => {
+        #[test]
+        fn pruning_queries() -> Result<(), SignalProtocolError> {
+            super::pruning_queries::<$store>()
+        }
+
+        #[test]
+        fn identity_store() -> Result<(), SignalProtocolError> {
+            super::identity_store::<$store>()
+        }
+
+        #[test]
+        fn verified_status() -> Result<(), SignalProtocolError> {
+            super::verified_status::<$store>()
+        }
+
+        #[test]
+        fn pre_key_stores() -> Result<(), SignalProtocolError> {
+            super::pre_key_stores::<$store>()
+        }
+
+        #[test]
+        fn session_store() -> Result<(), SignalProtocolError> {
+            super::session_store::<$store>()
+        }
+
+        #[test]
+        fn sender_key_store() -> Result<(), SignalProtocolError> {
+            super::sender_key_store::<$store>()
+        }
+
+        #[test]
+        fn resend_log_store() -> Result<(), SignalProtocolError> {
+            super::resend_log_store::<$store>()
+        }
+
+        #[test]
+        fn session_round_trip() -> Result<(), SignalProtocolError> {
+            super::session_round_trip::<$store>()
+        }
+
+        #[test]
+        fn group_round_trip() -> Result<(), SignalProtocolError> {
+            super::group_round_trip::<$store>()
+        }
+
+        #[test]
+        fn transaction_rollback() -> Result<(), SignalProtocolError> {
+            super::transaction_rollback::<$store>()
+        }
+    };
+}
+
+mod in_memory {
+    use super::*;
+
+    store_tests!(InMemSignalProtocolStore);
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    store_tests!(SqliteSignalProtocolStore);
+
+    /// A database path that is deleted when dropped.
+    struct TempDatabase(PathBuf);
+
+    impl TempDatabase {
+        fn new() -> Self {
+            let name = format!("libsignal-protocol-test-{}.db", rand::random::<u64>());
+            Self(std::env::temp_dir().join(name))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reopen_keeps_state() -> Result<(), SignalProtocolError> {
+        async {
+            let mut csprng = OsRng;
+            let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+            let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+            let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+            let database = TempDatabase::new();
+            let alice_identity = IdentityKeyPair::generate(&mut csprng);
+            let mut bob_store = test_in_memory_protocol_store()?;
+
+            {
+                let mut alice_store =
+                    SqliteSignalProtocolStore::new(database.path(), alice_identity, 23)?;
+                let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+                process_prekey_bundle(
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    &bob_pre_key_bundle,
+                    &mut csprng,
+                    None,
+                )
+                .await?;
+                create_sender_key_distribution_message(
+                    &alice_address,
+                    distribution_id,
+                    &mut alice_store,
+                    &mut csprng,
+                    None,
+                )
+                .await?;
+            }
+
+            let mut alice_store = SqliteSignalProtocolStore::open(database.path())?;
+            assert_eq!(
+                alice_store.get_identity_key_pair(None).await?.serialize(),
+                alice_identity.serialize()
+            );
+            assert_eq!(alice_store.get_local_registration_id(None).await?, 23);
+            assert!(alice_store
+                .load_sender_key(&alice_address, distribution_id, None)
+                .await?
+                .is_some());
+
+            let message = encrypt_with(&mut alice_store, &bob_address, "hi bob").await?;
+            assert_eq!(
+                decrypt(&mut bob_store, &alice_address, &message).await?,
+                b"hi bob"
+            );
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn open_requires_identity() {
+        let database = TempDatabase::new();
+        assert!(matches!(
+            SqliteSignalProtocolStore::open(database.path()),
+            Err(SignalProtocolError::InvalidState("load_local_identity", _))
+        ));
+    }
+
+    #[test]
+    fn last_resort_kyber_pre_key_is_kept() -> Result<(), SignalProtocolError> {
+        async {
+            let mut store = SqliteSignalProtocolStore::create()?;
+            let key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+            let record = KyberPreKeyRecord::new(1.into(), 42, &key_pair, &[0; 64]);
+
+            store
+                .kyber_pre_key_store
+                .save_last_resort_kyber_pre_key(1.into(), &record)?;
+            store.mark_kyber_pre_key_used(1.into(), None).await?;
+            assert!(store.get_kyber_pre_key(1.into(), None).await.is_ok());
+
+            // Saving it again as an ordinary key makes it one-time.
+            store.save_kyber_pre_key(1.into(), &record, None).await?;
+            store.mark_kyber_pre_key_used(1.into(), None).await?;
+            assert!(store.get_kyber_pre_key(1.into(), None).await.is_err());
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn transaction_spans_stores() -> Result<(), SignalProtocolError> {
+        async {
+            let mut store = SqliteSignalProtocolStore::create()?;
+            let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+            let (record, _) = initialize_sessions_v3()?;
+            let pre_key = PreKeyRecord::new(1.into(), &KeyPair::generate(&mut OsRng));
+
+            // Both stores join one transaction, which only commits once both have committed.
+            store.session_store.begin_transaction(None).await?;
+            store.pre_key_store.begin_transaction(None).await?;
+            store
+                .session_store
+                .store_session(&address, &record, None)
+                .await?;
+            store.save_pre_key(1.into(), &pre_key, None).await?;
+            store.session_store.commit_transaction(None).await?;
+            store.pre_key_store.rollback_transaction(None).await?;
+            store.session_store.rollback_transaction(None).await?;
+
+            assert!(store.load_session(&address, None).await?.is_none());
+            assert!(store.get_pre_key(1.into(), None).await.is_err());
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn clones_do_not_share_transactions() -> Result<(), SignalProtocolError> {
+        async {
+            let mut store = SqliteSignalProtocolStore::create()?;
+            let mut other = store.clone();
+            let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+            let (record, _) = initialize_sessions_v3()?;
+
+            // A clone can neither join nor write into another clone's transaction.
+            store.begin_transaction(None).await?;
+            assert!(other.begin_transaction(None).await.is_err());
+            assert!(other.store_session(&address, &record, None).await.is_err());
+            assert!(other.commit_transaction(None).await.is_ok());
+            store.rollback_transaction(None).await?;
+
+            other.store_session(&address, &record, None).await?;
+            assert!(store.load_session(&address, None).await?.is_some());
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
+
+mod encrypted {
+    use super::*;
+
+    const KEY: [u8; 32] = [1; 32];
+    const NEW_KEY: [u8; 32] = [2; 32];
+
+    fn encrypted_store() -> EncryptedRecordStore<InMemRecordStore> {
+        EncryptedRecordStore::new(InMemRecordStore::new(), RecordCipher::new(1, KEY))
+    }
+
+    #[test]
+    fn session_round_trip() -> Result<(), SignalProtocolError> {
+        async {
+            let mut csprng = OsRng;
+            let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+            let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+            let mut alice_store = test_in_memory_protocol_store()?;
+            let mut bob_store = test_in_memory_protocol_store()?;
+            let mut bob_sessions = encrypted_store();
+            let mut bob_pre_keys = encrypted_store();
+            let mut bob_signed_pre_keys = encrypted_store();
+            let mut bob_kyber_pre_keys = encrypted_store();
+
+            let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng).await?;
+            let pre_key_id = bob_pre_key_bundle.pre_key_id()?.expect("has pre key");
+            let signed_pre_key_id = bob_pre_key_bundle.signed_pre_key_id()?;
+            bob_pre_keys
+                .save_pre_key(
+                    pre_key_id,
+                    &bob_store.get_pre_key(pre_key_id, None).await?,
+                    None,
+                )
+                .await?;
+            bob_signed_pre_keys
+                .save_signed_pre_key(
+                    signed_pre_key_id,
+                    &bob_store
+                        .get_signed_pre_key(signed_pre_key_id, None)
+                        .await?,
+                    None,
+                )
+                .await?;
+
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+
+            let message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+            let ptext = message_decrypt(
+                &message,
+                &alice_address,
+                &mut bob_sessions,
+                &mut bob_store.identity_store,
+                &mut bob_pre_keys,
+                &mut bob_signed_pre_keys,
+                &mut bob_kyber_pre_keys,
+                &SessionPolicy::default(),
+                &mut csprng,
+                None,
+            )
+            .await?;
+            assert_eq!(ptext, b"hi bob");
+
+            // The one-time pre-key was used up.
+            assert!(bob_pre_keys
+                .inner()
+                .record_ids(RecordKind::PreKey, None)
+                .await?
+                .is_empty());
+
+            // The session is only stored encrypted.
+            let session = bob_sessions
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session stored")
+                .serialize()?;
+            let ids = bob_sessions
+                .inner()
+                .record_ids(RecordKind::Session, None)
+                .await?;
+            assert_eq!(ids.len(), 1);
+            let stored = bob_sessions
+                .inner()
+                .load_record(RecordKind::Session, &ids[0], None)
+                .await?
+                .expect("present");
+            assert!(!stored.windows(session.len()).any(|w| w == session));
+            assert!(matches!(
+                RecordCipher::new(1, NEW_KEY).decrypt(RecordKind::Session, &ids[0], &stored),
+                Err(SignalProtocolError::InvalidEncryptedRecord(_))
+            ));
+
+            let reply = message_encrypt(
+                b"hi alice",
+                &alice_address,
+                &mut bob_sessions,
+                &mut bob_store.identity_store,
+                &SessionPolicy::default(),
+                None,
+            )
+            .await?;
+            assert_eq!(
+                decrypt(&mut alice_store, &bob_address, &reply).await?,
+                b"hi alice"
+            );
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn rotate_key() -> Result<(), SignalProtocolError> {
+        async {
+            let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+            let (session, _) = initialize_sessions_v3()?;
+            let pre_key = PreKeyRecord::new(7.into(), &KeyPair::generate(&mut OsRng));
+
+            let mut store = encrypted_store();
+            store.store_session(&address, &session, None).await?;
+            store.save_pre_key(7.into(), &pre_key, None).await?;
+
+            assert!(matches!(
+                store.rotate_key(1, NEW_KEY).await,
+                Err(SignalProtocolError::InvalidArgument(_))
+            ));
+            store.rotate_key(2, NEW_KEY).await?;
+
+            // Only the new key is needed from now on.
+            let store =
+                EncryptedRecordStore::new(store.into_inner(), RecordCipher::new(2, NEW_KEY));
+            assert_eq!(
+                store
+                    .load_session(&address, None)
+                    .await?
+                    .expect("present")
+                    .serialize()?,
+                session.serialize()?
+            );
+            assert_eq!(
+                store.get_pre_key(7.into(), None).await?.serialize()?,
+                pre_key.serialize()?
+            );
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn pruning_queries() -> Result<(), SignalProtocolError> {
+        async { check_pruning_queries(&mut encrypted_store()).await }
+            .now_or_never()
+            .expect("sync")
+    }
+
+    #[test]
+    fn clones_share_rotated_key() -> Result<(), SignalProtocolError> {
+        async {
+            let pre_key = PreKeyRecord::new(7.into(), &KeyPair::generate(&mut OsRng));
+
+            let mut store = encrypted_store();
+            let mut clone = store.clone();
+            clone.save_pre_key(7.into(), &pre_key, None).await?;
+            store.rotate_key(2, NEW_KEY).await?;
+
+            // The clone's own record was not re-encrypted, but can still be read...
+            assert_eq!(
+                clone.get_pre_key(7.into(), None).await?.serialize()?,
+                pre_key.serialize()?
+            );
+            // ...and the clone now writes with the new key.
+            clone.save_pre_key(8.into(), &pre_key, None).await?;
+            clone.remove_pre_key(7.into(), None).await?;
+            clone.forget_previous_keys();
+            let clone =
+                EncryptedRecordStore::new(clone.into_inner(), RecordCipher::new(2, NEW_KEY));
+            clone.get_pre_key(8.into(), None).await?;
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn failed_rotation_keeps_old_key() -> Result<(), SignalProtocolError> {
+        async {
+            let mut store = encrypted_store();
+            for id in 1..=3 {
+                let record = PreKeyRecord::new(id.into(), &KeyPair::generate(&mut OsRng));
+                store.save_pre_key(id.into(), &record, None).await?;
+            }
+
+            store.inner_mut().fail_after_writes(Some(1));
+            assert!(store.rotate_key(2, NEW_KEY).await.is_err());
+            store.inner_mut().fail_after_writes(None);
+
+            // Nothing was re-encrypted, and new records still use the old key.
+            let record = PreKeyRecord::new(4.into(), &KeyPair::generate(&mut OsRng));
+            store.save_pre_key(4.into(), &record, None).await?;
+            let store = EncryptedRecordStore::new(store.into_inner(), RecordCipher::new(1, KEY));
+            for id in 1..=4 {
+                store.get_pre_key(id.into(), None).await?;
+            }
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn last_resort_kyber_pre_key_is_kept() -> Result<(), SignalProtocolError> {
+        async {
+            let mut store = encrypted_store();
+            let key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+            let record = KyberPreKeyRecord::new(1.into(), 42, &key_pair, &[0; 64]);
+
+            store
+                .save_last_resort_kyber_pre_key(1.into(), &record)
+                .await?;
+            store.mark_kyber_pre_key_used(1.into(), None).await?;
+            assert!(store.get_kyber_pre_key(1.into(), None).await.is_ok());
+
+            store.save_kyber_pre_key(1.into(), &record, None).await?;
+            store.mark_kyber_pre_key_used(1.into(), None).await?;
+            assert!(matches!(
+                store.get_kyber_pre_key(1.into(), None).await,
+                Err(SignalProtocolError::InvalidKyberPreKeyId)
+            ));
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
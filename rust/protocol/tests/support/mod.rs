@@ -26,6 +26,7 @@ pub async fn encrypt(
         remote_address,
         &mut store.session_store,
         &mut store.identity_store,
+        &SessionPolicy::default(),
         None,
     )
     .await
@@ -36,6 +37,16 @@ pub async fn decrypt(
     store: &mut InMemSignalProtocolStore,
     remote_address: &ProtocolAddress,
     msg: &CiphertextMessage,
+) -> Result<Vec<u8>, SignalProtocolError> {
+    decrypt_with_policy(store, remote_address, msg, &SessionPolicy::default()).await
+}
+
+#[allow(dead_code)]
+pub async fn decrypt_with_policy(
+    store: &mut InMemSignalProtocolStore,
+    remote_address: &ProtocolAddress,
+    msg: &CiphertextMessage,
+    policy: &SessionPolicy,
 ) -> Result<Vec<u8>, SignalProtocolError> {
     let mut csprng = OsRng;
     message_decrypt(
@@ -45,6 +56,8 @@ pub async fn decrypt(
         &mut store.identity_store,
         &mut store.pre_key_store,
         &mut store.signed_pre_key_store,
+        &mut store.kyber_pre_key_store,
+        policy,
         &mut csprng,
         None,
     )
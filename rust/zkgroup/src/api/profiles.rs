@@ -5,6 +5,7 @@
 
 pub mod expiring_profile_key_credential;
 pub mod expiring_profile_key_credential_response;
+pub mod profile_cipher;
 pub mod profile_key;
 pub mod profile_key_commitment;
 pub mod profile_key_credential_presentation;
@@ -14,6 +15,7 @@ pub mod profile_key_version;
 
 pub use expiring_profile_key_credential::ExpiringProfileKeyCredential;
 pub use expiring_profile_key_credential_response::ExpiringProfileKeyCredentialResponse;
+pub use profile_cipher::ProfileCipher;
 pub use profile_key::ProfileKey;
 pub use profile_key_commitment::ProfileKeyCommitment;
 pub use profile_key_credential_presentation::{
@@ -0,0 +1,806 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Encryption for the fields of a profile, with keys derived from its [ProfileKey].
+//!
+//! Each field is padded to one of a fixed set of lengths for its type before it is encrypted with
+//! AES-256-GCM, so the ciphertext reveals only a rough size. Decryption accepts only the smallest
+//! padded length that fits and zero padding, so there is exactly one encoding of each value.
+//!
+//! Clients that predate this format encrypted every field with the profile key itself as the
+//! AES-256-GCM key, in the same `nonce || ciphertext || tag` layout, and checked padding less
+//! strictly. Decryption falls back to that format, so profiles written by those clients can still
+//! be read. Those clients can't read the new format, so until every client that reads a profile
+//! can decrypt it, the profile should be written with the `encrypt_*_legacy` methods instead.
+
+use crate::api::profiles::ProfileKey;
+use crate::common::constants::*;
+use crate::common::errors::*;
+use crate::common::sho::*;
+use crate::common::simple_types::*;
+use signal_crypto::{padded_attachment_size, Aes256GcmDecryption, Aes256GcmEncryption};
+
+const NAME_PADDED_LENGTHS: &[usize] = &[53, 257];
+const ABOUT_PADDED_LENGTHS: &[usize] = &[128, 254, 512];
+const ABOUT_EMOJI_PADDED_LENGTHS: &[usize] = &[32];
+const PAYMENT_ADDRESS_PADDED_LENGTHS: &[usize] = &[554];
+const LENGTH_PREFIX_LEN: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ProfileField {
+    Name,
+    About,
+    AboutEmoji,
+    PaymentAddress,
+    Avatar,
+}
+
+impl ProfileField {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Self::Name => b"Name",
+            Self::About => b"About",
+            Self::AboutEmoji => b"AboutEmoji",
+            Self::PaymentAddress => b"PaymentAddress",
+            Self::Avatar => b"Avatar",
+        }
+    }
+
+    /// The length an encoded field of `content_len` bytes is padded to, or `None` if it is too
+    /// long for this field.
+    fn padded_len(self, content_len: usize) -> Option<usize> {
+        let padded_lengths = match self {
+            Self::Name => NAME_PADDED_LENGTHS,
+            Self::About => ABOUT_PADDED_LENGTHS,
+            Self::AboutEmoji => ABOUT_EMOJI_PADDED_LENGTHS,
+            Self::PaymentAddress => PAYMENT_ADDRESS_PADDED_LENGTHS,
+            Self::Avatar => return padded_attachment_size(content_len as u64).try_into().ok(),
+        };
+        padded_lengths
+            .iter()
+            .copied()
+            .find(|&padded_len| padded_len >= content_len)
+    }
+}
+
+fn encode_text(text: &str) -> Result<&[u8], ProfileFieldEncodingFailure> {
+    if text.contains('\0') {
+        return Err(ProfileFieldEncodingFailure);
+    }
+    Ok(text.as_bytes())
+}
+
+fn encode_name(
+    given_name: &str,
+    family_name: Option<&str>,
+) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+    let mut content = encode_text(given_name)?.to_vec();
+    if let Some(family_name) = family_name.filter(|name| !name.is_empty()) {
+        content.push(0);
+        content.extend_from_slice(encode_text(family_name)?);
+    }
+    Ok(content)
+}
+
+fn encode_with_length(bytes: &[u8]) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+    let len: u32 = bytes
+        .len()
+        .try_into()
+        .map_err(|_| ProfileFieldEncodingFailure)?;
+    let mut content = Vec::with_capacity(LENGTH_PREFIX_LEN + bytes.len());
+    content.extend_from_slice(&len.to_le_bytes());
+    content.extend_from_slice(bytes);
+    Ok(content)
+}
+
+/// How a field was encrypted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FieldFormat {
+    /// With the field's own key, and padded strictly.
+    Current,
+    /// With the profile key itself, as clients did before [ProfileCipher].
+    ///
+    /// Those clients stripped trailing zeros from text, and only checked that a length prefix was
+    /// in bounds. Avatars had no length prefix.
+    Legacy,
+}
+
+/// Strip the zero padding from text.
+///
+/// Only names contain a zero byte, as the separator between the given and family names.
+fn decode_text(
+    field: ProfileField,
+    mut padded: Vec<u8>,
+    format: FieldFormat,
+) -> Result<String, ZkGroupVerificationFailure> {
+    let content_len = padded
+        .iter()
+        .rposition(|&b| b != 0)
+        .map_or(0, |last| last + 1);
+    let max_separators = match field {
+        ProfileField::Name => 1,
+        _ => 0,
+    };
+    if format == FieldFormat::Current
+        && (field.padded_len(content_len) != Some(padded.len())
+            || padded[..content_len].iter().filter(|&&b| b == 0).count() > max_separators)
+    {
+        return Err(ZkGroupVerificationFailure);
+    }
+    padded.truncate(content_len);
+    String::from_utf8(padded).map_err(|_| ZkGroupVerificationFailure)
+}
+
+fn decode_with_length(
+    field: ProfileField,
+    mut padded: Vec<u8>,
+    format: FieldFormat,
+) -> Result<Vec<u8>, ZkGroupVerificationFailure> {
+    if padded.len() < LENGTH_PREFIX_LEN {
+        return Err(ZkGroupVerificationFailure);
+    }
+    let len = u32::from_le_bytes(
+        padded[..LENGTH_PREFIX_LEN]
+            .try_into()
+            .expect("correct size"),
+    ) as usize;
+    let content_len = LENGTH_PREFIX_LEN
+        .checked_add(len)
+        .ok_or(ZkGroupVerificationFailure)?;
+    if content_len > padded.len()
+        || format == FieldFormat::Current
+            && (field.padded_len(content_len) != Some(padded.len())
+                || padded[content_len..].iter().any(|&b| b != 0))
+    {
+        return Err(ZkGroupVerificationFailure);
+    }
+    padded.truncate(content_len);
+    padded.drain(..LENGTH_PREFIX_LEN);
+    Ok(padded)
+}
+
+/// Decrypt `nonce || ciphertext || tag` with AES-256-GCM, or return `None` if it doesn't
+/// authenticate under `key`.
+fn aes_gcm_decrypt(key: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, ciphertext) = ciphertext.split_at(AESGCM_NONCE_LEN);
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - AESGCM_TAG_LEN);
+
+    let mut cipher = Aes256GcmDecryption::new(key, nonce, &[]).ok()?;
+    let mut padded = ciphertext.to_vec();
+    cipher.decrypt(&mut padded).ok()?;
+    cipher.verify_tag(tag).ok()?;
+    Some(padded)
+}
+
+/// Encrypts and decrypts the fields of a profile.
+///
+/// Each field is encrypted with its own key derived from the profile key, and the result is laid
+/// out as `nonce || ciphertext || tag`. The `encrypt_*_legacy` methods encrypt with the profile key
+/// itself instead, for clients that predate this format.
+#[derive(Copy, Clone)]
+pub struct ProfileCipher {
+    profile_key: ProfileKey,
+}
+
+impl ProfileCipher {
+    pub fn new(profile_key: ProfileKey) -> Self {
+        Self { profile_key }
+    }
+
+    fn field_key(&self, field: ProfileField) -> AesKeyBytes {
+        let mut sho = Sho::new(
+            b"Signal_ZKGroup_20230601_ProfileKey_ProfileCipher_DeriveFieldKey",
+            &self.profile_key.bytes,
+        );
+        sho.absorb_and_ratchet(field.label());
+        sho.squeeze(AES_KEY_LEN).try_into().expect("correct size")
+    }
+
+    fn key(&self, field: ProfileField, format: FieldFormat) -> AesKeyBytes {
+        match format {
+            FieldFormat::Current => self.field_key(field),
+            FieldFormat::Legacy => self.profile_key.bytes,
+        }
+    }
+
+    fn encrypt(
+        &self,
+        field: ProfileField,
+        format: FieldFormat,
+        randomness: RandomnessBytes,
+        content: &[u8],
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        let padded_len = field
+            .padded_len(content.len())
+            .ok_or(ProfileFieldEncodingFailure)?;
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(content);
+        padded.resize(padded_len, 0);
+        Ok(self.encrypt_padded(field, format, randomness, padded))
+    }
+
+    fn encrypt_padded(
+        &self,
+        field: ProfileField,
+        format: FieldFormat,
+        randomness: RandomnessBytes,
+        mut padded: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut sho = Sho::new(
+            b"Signal_ZKGroup_20230601_Random_ProfileCipher_Encrypt",
+            &randomness,
+        );
+        let nonce = sho.squeeze(AESGCM_NONCE_LEN);
+
+        let mut cipher = Aes256GcmEncryption::new(&self.key(field, format), &nonce, &[])
+            .expect("valid key and nonce");
+        cipher.encrypt(&mut padded).expect("can encrypt");
+        let tag = cipher.compute_tag().expect("can compute tag");
+
+        let mut ciphertext = nonce;
+        ciphertext.reserve(padded.len() + AESGCM_TAG_LEN);
+        ciphertext.extend_from_slice(&padded);
+        ciphertext.extend_from_slice(&tag);
+        ciphertext
+    }
+
+    /// Decrypt `ciphertext`, returning the padded plaintext and the format it was encrypted in.
+    fn decrypt(
+        &self,
+        field: ProfileField,
+        ciphertext: &[u8],
+    ) -> Result<(Vec<u8>, FieldFormat), ZkGroupVerificationFailure> {
+        if ciphertext.len() < AESGCM_NONCE_LEN + AESGCM_TAG_LEN {
+            return Err(ZkGroupVerificationFailure);
+        }
+        if let Some(padded) = aes_gcm_decrypt(&self.field_key(field), ciphertext) {
+            return Ok((padded, FieldFormat::Current));
+        }
+        aes_gcm_decrypt(&self.profile_key.bytes, ciphertext)
+            .map(|padded| (padded, FieldFormat::Legacy))
+            .ok_or(ZkGroupVerificationFailure)
+    }
+
+    /// Encrypt a name, padded to 53 or 257 bytes.
+    ///
+    /// An empty family name is the same as none.
+    pub fn encrypt_name(
+        &self,
+        randomness: RandomnessBytes,
+        given_name: &str,
+        family_name: Option<&str>,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::Name,
+            FieldFormat::Current,
+            randomness,
+            &encode_name(given_name, family_name)?,
+        )
+    }
+
+    /// Encrypt a name in the format of clients that predate [ProfileCipher].
+    pub fn encrypt_name_legacy(
+        &self,
+        randomness: RandomnessBytes,
+        given_name: &str,
+        family_name: Option<&str>,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::Name,
+            FieldFormat::Legacy,
+            randomness,
+            &encode_name(given_name, family_name)?,
+        )
+    }
+
+    /// Decrypt a name, returning the given name and the family name, if there is one.
+    pub fn decrypt_name(
+        &self,
+        ciphertext: &[u8],
+    ) -> Result<(String, Option<String>), ZkGroupVerificationFailure> {
+        let (padded, format) = self.decrypt(ProfileField::Name, ciphertext)?;
+        let name = decode_text(ProfileField::Name, padded, format)?;
+        Ok(match name.split_once('\0') {
+            Some((given_name, family_name)) => {
+                (given_name.to_string(), Some(family_name.to_string()))
+            }
+            None => (name, None),
+        })
+    }
+
+    /// Encrypt an "about" text, padded to 128, 254, or 512 bytes.
+    pub fn encrypt_about(
+        &self,
+        randomness: RandomnessBytes,
+        about: &str,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::About,
+            FieldFormat::Current,
+            randomness,
+            encode_text(about)?,
+        )
+    }
+
+    /// Encrypt an "about" text in the format of clients that predate [ProfileCipher].
+    pub fn encrypt_about_legacy(
+        &self,
+        randomness: RandomnessBytes,
+        about: &str,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::About,
+            FieldFormat::Legacy,
+            randomness,
+            encode_text(about)?,
+        )
+    }
+
+    pub fn decrypt_about(&self, ciphertext: &[u8]) -> Result<String, ZkGroupVerificationFailure> {
+        let (padded, format) = self.decrypt(ProfileField::About, ciphertext)?;
+        decode_text(ProfileField::About, padded, format)
+    }
+
+    /// Encrypt the emoji shown with the "about" text, padded to 32 bytes.
+    pub fn encrypt_about_emoji(
+        &self,
+        randomness: RandomnessBytes,
+        emoji: &str,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::AboutEmoji,
+            FieldFormat::Current,
+            randomness,
+            encode_text(emoji)?,
+        )
+    }
+
+    /// Encrypt the "about" emoji in the format of clients that predate [ProfileCipher].
+    pub fn encrypt_about_emoji_legacy(
+        &self,
+        randomness: RandomnessBytes,
+        emoji: &str,
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::AboutEmoji,
+            FieldFormat::Legacy,
+            randomness,
+            encode_text(emoji)?,
+        )
+    }
+
+    pub fn decrypt_about_emoji(
+        &self,
+        ciphertext: &[u8],
+    ) -> Result<String, ZkGroupVerificationFailure> {
+        let (padded, format) = self.decrypt(ProfileField::AboutEmoji, ciphertext)?;
+        decode_text(ProfileField::AboutEmoji, padded, format)
+    }
+
+    /// Encrypt a serialized payment address, prefixed with its length and padded to 554 bytes.
+    pub fn encrypt_payment_address(
+        &self,
+        randomness: RandomnessBytes,
+        payment_address: &[u8],
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::PaymentAddress,
+            FieldFormat::Current,
+            randomness,
+            &encode_with_length(payment_address)?,
+        )
+    }
+
+    /// Encrypt a payment address in the format of clients that predate [ProfileCipher].
+    pub fn encrypt_payment_address_legacy(
+        &self,
+        randomness: RandomnessBytes,
+        payment_address: &[u8],
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::PaymentAddress,
+            FieldFormat::Legacy,
+            randomness,
+            &encode_with_length(payment_address)?,
+        )
+    }
+
+    pub fn decrypt_payment_address(
+        &self,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, ZkGroupVerificationFailure> {
+        let (padded, format) = self.decrypt(ProfileField::PaymentAddress, ciphertext)?;
+        decode_with_length(ProfileField::PaymentAddress, padded, format)
+    }
+
+    /// Encrypt an avatar image, prefixed with its length and padded to the same size buckets as
+    /// attachments (see [signal_crypto::padded_attachment_size]).
+    pub fn encrypt_avatar(
+        &self,
+        randomness: RandomnessBytes,
+        avatar: &[u8],
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::Avatar,
+            FieldFormat::Current,
+            randomness,
+            &encode_with_length(avatar)?,
+        )
+    }
+
+    /// Encrypt an avatar image in the format of clients that predate [ProfileCipher].
+    ///
+    /// These avatars have no length prefix, so they decrypt with their zero padding.
+    pub fn encrypt_avatar_legacy(
+        &self,
+        randomness: RandomnessBytes,
+        avatar: &[u8],
+    ) -> Result<Vec<u8>, ProfileFieldEncodingFailure> {
+        self.encrypt(
+            ProfileField::Avatar,
+            FieldFormat::Legacy,
+            randomness,
+            avatar,
+        )
+    }
+
+    /// Decrypt an avatar image.
+    ///
+    /// Avatars encrypted before [ProfileCipher] have no length prefix, so they are returned with
+    /// their zero padding, which image decoders ignore.
+    pub fn decrypt_avatar(&self, ciphertext: &[u8]) -> Result<Vec<u8>, ZkGroupVerificationFailure> {
+        match self.decrypt(ProfileField::Avatar, ciphertext)? {
+            (padded, FieldFormat::Current) => {
+                decode_with_length(ProfileField::Avatar, padded, FieldFormat::Current)
+            }
+            (padded, FieldFormat::Legacy) => Ok(padded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cipher() -> ProfileCipher {
+        ProfileCipher::new(ProfileKey::create(TEST_ARRAY_32))
+    }
+
+    const OVERHEAD: usize = AESGCM_NONCE_LEN + AESGCM_TAG_LEN;
+
+    #[test]
+    fn name_round_trip() {
+        let cipher = test_cipher();
+
+        for (given_name, family_name, padded_len) in [
+            ("Alice", Some("Smith"), 53),
+            ("Alice", None, 53),
+            ("", None, 53),
+            ("", Some("Smith"), 53),
+            ("Ålice 🦊", Some("Smith-Jones"), 53),
+            (&"a".repeat(52)[..], None, 53),
+            (&"a".repeat(52)[..], Some("b"), 257),
+            (&"a".repeat(257)[..], None, 257),
+        ] {
+            let ciphertext = cipher
+                .encrypt_name(TEST_ARRAY_32_1, given_name, family_name)
+                .unwrap();
+            assert_eq!(ciphertext.len(), OVERHEAD + padded_len);
+            let (decrypted_given_name, decrypted_family_name) =
+                cipher.decrypt_name(&ciphertext).unwrap();
+            assert_eq!(decrypted_given_name, given_name);
+            assert_eq!(decrypted_family_name.as_deref(), family_name);
+        }
+
+        // An empty family name is encoded the same way as none.
+        assert_eq!(
+            cipher
+                .encrypt_name(TEST_ARRAY_32_1, "Alice", Some(""))
+                .unwrap(),
+            cipher.encrypt_name(TEST_ARRAY_32_1, "Alice", None).unwrap()
+        );
+
+        assert!(cipher
+            .encrypt_name(TEST_ARRAY_32_1, &"a".repeat(258), None)
+            .is_err());
+        assert!(cipher
+            .encrypt_name(TEST_ARRAY_32_1, &"a".repeat(200), Some(&"b".repeat(57)))
+            .is_err());
+        assert!(cipher
+            .encrypt_name(TEST_ARRAY_32_1, "Al\0ice", None)
+            .is_err());
+        assert!(cipher
+            .encrypt_name(TEST_ARRAY_32_1, "Alice", Some("Sm\0ith"))
+            .is_err());
+    }
+
+    #[test]
+    fn text_fields_round_trip() {
+        let cipher = test_cipher();
+
+        for (about, padded_len) in [
+            ("", 128),
+            ("Hello", 128),
+            (&"a".repeat(128)[..], 128),
+            (&"a".repeat(129)[..], 254),
+            (&"a".repeat(255)[..], 512),
+            (&"a".repeat(512)[..], 512),
+        ] {
+            let ciphertext = cipher.encrypt_about(TEST_ARRAY_32_1, about).unwrap();
+            assert_eq!(ciphertext.len(), OVERHEAD + padded_len);
+            assert_eq!(cipher.decrypt_about(&ciphertext).unwrap(), about);
+        }
+        assert!(cipher
+            .encrypt_about(TEST_ARRAY_32_1, &"a".repeat(513))
+            .is_err());
+        assert!(cipher.encrypt_about(TEST_ARRAY_32_1, "a\0b").is_err());
+
+        let ciphertext = cipher.encrypt_about_emoji(TEST_ARRAY_32_1, "🦊").unwrap();
+        assert_eq!(ciphertext.len(), OVERHEAD + 32);
+        assert_eq!(cipher.decrypt_about_emoji(&ciphertext).unwrap(), "🦊");
+        assert!(cipher
+            .encrypt_about_emoji(TEST_ARRAY_32_1, &"🦊".repeat(9))
+            .is_err());
+    }
+
+    #[test]
+    fn length_prefixed_fields_round_trip() {
+        let cipher = test_cipher();
+
+        for payment_address in [&[][..], &[0u8; 10][..], &[0xffu8; 550][..]] {
+            let ciphertext = cipher
+                .encrypt_payment_address(TEST_ARRAY_32_1, payment_address)
+                .unwrap();
+            assert_eq!(ciphertext.len(), OVERHEAD + 554);
+            assert_eq!(
+                cipher.decrypt_payment_address(&ciphertext).unwrap(),
+                payment_address
+            );
+        }
+        assert!(cipher
+            .encrypt_payment_address(TEST_ARRAY_32_1, &[0u8; 551])
+            .is_err());
+
+        for avatar_len in [0, 1, 537, 538, 10_000, 1_000_000] {
+            // Avatars can end in zeros, which the length prefix keeps.
+            let avatar: Vec<u8> = (0..avatar_len).map(|i| (i % 3) as u8).collect();
+            let ciphertext = cipher.encrypt_avatar(TEST_ARRAY_32_1, &avatar).unwrap();
+            assert_eq!(
+                ciphertext.len() as u64,
+                OVERHEAD as u64 + padded_attachment_size(4 + avatar_len as u64)
+            );
+            assert_eq!(cipher.decrypt_avatar(&ciphertext).unwrap(), avatar);
+        }
+    }
+
+    #[test]
+    fn field_keys_are_separate() {
+        let cipher = test_cipher();
+
+        // Each field has its own key, so a ciphertext for one field can't be passed off as
+        // another, even if its padding would be valid.
+        let mut padded_name = b"Alice".to_vec();
+        padded_name.resize(53, 0);
+        let as_name = cipher.encrypt_padded(
+            ProfileField::Name,
+            FieldFormat::Current,
+            TEST_ARRAY_32_1,
+            padded_name.clone(),
+        );
+        assert!(cipher.decrypt_name(&as_name).is_ok());
+        let as_about = cipher.encrypt_padded(
+            ProfileField::About,
+            FieldFormat::Current,
+            TEST_ARRAY_32_1,
+            padded_name,
+        );
+        assert!(cipher.decrypt_name(&as_about).is_err());
+
+        let about = cipher.encrypt_about_emoji(TEST_ARRAY_32_1, "🦊").unwrap();
+
+        let other_cipher = ProfileCipher::new(ProfileKey::create(TEST_ARRAY_32_2));
+        assert!(other_cipher.decrypt_about_emoji(&about).is_err());
+
+        // The nonce comes from the randomness.
+        let again = cipher.encrypt_about_emoji(TEST_ARRAY_32_1, "🦊").unwrap();
+        assert_eq!(about, again);
+        let different = cipher.encrypt_about_emoji(TEST_ARRAY_32_2, "🦊").unwrap();
+        assert_ne!(about, different);
+
+        let mut tampered = about;
+        tampered[AESGCM_NONCE_LEN] ^= 1;
+        assert!(cipher.decrypt_about_emoji(&tampered).is_err());
+        assert!(cipher.decrypt_about_emoji(&[0u8; OVERHEAD - 1]).is_err());
+    }
+
+    #[test]
+    fn strict_unpadding() {
+        let cipher = test_cipher();
+        let padded = |content: &[u8], padded_len: usize| {
+            let mut padded = content.to_vec();
+            padded.resize(padded_len, 0);
+            padded
+        };
+        let name = |content: &[u8], padded_len| {
+            cipher.encrypt_padded(
+                ProfileField::Name,
+                FieldFormat::Current,
+                TEST_ARRAY_32_1,
+                padded(content, padded_len),
+            )
+        };
+
+        assert!(cipher.decrypt_name(&name(b"Alice\0Smith", 53)).is_ok());
+        // Not the smallest padded length that fits.
+        assert!(cipher.decrypt_name(&name(b"Alice\0Smith", 257)).is_err());
+        // Not one of the padded lengths at all.
+        assert!(cipher.decrypt_name(&name(b"Alice\0Smith", 54)).is_err());
+        // More than one separator.
+        assert!(cipher.decrypt_name(&name(b"Alice\0\0Smith", 53)).is_err());
+        // Not UTF-8.
+        assert!(cipher.decrypt_name(&name(b"Alice\xff", 53)).is_err());
+
+        let about = cipher.encrypt_padded(
+            ProfileField::About,
+            FieldFormat::Current,
+            TEST_ARRAY_32_1,
+            padded(b"Hello\0world", 128),
+        );
+        assert!(cipher.decrypt_about(&about).is_err());
+
+        let payment_address = |content: &[u8]| {
+            cipher.encrypt_padded(
+                ProfileField::PaymentAddress,
+                FieldFormat::Current,
+                TEST_ARRAY_32_1,
+                padded(content, 554),
+            )
+        };
+        assert_eq!(
+            cipher
+                .decrypt_payment_address(&payment_address(b"\x02\0\0\0ab"))
+                .unwrap(),
+            b"ab"
+        );
+        // Nonzero padding.
+        assert!(cipher
+            .decrypt_payment_address(&payment_address(b"\x02\0\0\0abc"))
+            .is_err());
+        // Length longer than the padded plaintext.
+        assert!(cipher
+            .decrypt_payment_address(&payment_address(b"\x2b\x02\0\0"))
+            .is_err());
+        assert!(cipher
+            .decrypt_payment_address(&payment_address(b"\xff\xff\xff\xff"))
+            .is_err());
+
+        let avatar = cipher.encrypt_padded(
+            ProfileField::Avatar,
+            FieldFormat::Current,
+            TEST_ARRAY_32_1,
+            padded(b"\x02\0\0\0ab", 568),
+        );
+        assert!(cipher.decrypt_avatar(&avatar).is_err());
+    }
+
+    /// Encrypt `padded` the way clients did before [ProfileCipher], with the profile key itself.
+    fn legacy_encrypt(cipher: &ProfileCipher, nonce: &[u8], mut padded: Vec<u8>) -> Vec<u8> {
+        let mut aes = Aes256GcmEncryption::new(&cipher.profile_key.bytes, nonce, &[]).unwrap();
+        aes.encrypt(&mut padded).unwrap();
+        [nonce, &padded, &aes.compute_tag().unwrap()].concat()
+    }
+
+    #[test]
+    fn legacy_format() {
+        let cipher = test_cipher();
+        let nonce: Vec<u8> = (0x40..0x4c).collect();
+        let padded = |content: &[u8], padded_len: usize| {
+            let mut padded = content.to_vec();
+            padded.resize(padded_len, 0);
+            padded
+        };
+
+        // Computed outside this crate with an independent AES-256-GCM implementation, keyed with
+        // the profile key bytes, as older clients encrypt profiles.
+        let name = hex::decode("404142434445464748494a4ba3d5c740433cd46ea4b07f369b64135b79c6525cb7350f2377474ee59713eddb9808f3acdeb9df5421f40b6f910ddfdacb71aa3133905f6deee0cf7b248d7f6dce4b08a925").unwrap();
+        let emoji = hex::decode("404142434445464748494a4b122608a9263c8703cdc417369b64135b79c6525cb7350f2377474ee59713eddbf01a3ba63011458accd154d236973f90").unwrap();
+        assert_eq!(
+            cipher.decrypt_name(&name).unwrap(),
+            ("Alice".to_string(), Some("Smith".to_string()))
+        );
+        assert_eq!(cipher.decrypt_about_emoji(&emoji).unwrap(), "🦊");
+        assert_eq!(
+            legacy_encrypt(&cipher, &nonce, padded(b"Alice\0Smith", 53)),
+            name
+        );
+
+        // Padding is checked as loosely as older clients checked it.
+        let about = legacy_encrypt(&cipher, &nonce, padded(b"Hello", 200));
+        assert_eq!(cipher.decrypt_about(&about).unwrap(), "Hello");
+        let payment_address = legacy_encrypt(&cipher, &nonce, padded(b"\x02\0\0\0abc", 100));
+        assert_eq!(
+            cipher.decrypt_payment_address(&payment_address).unwrap(),
+            b"ab"
+        );
+        let payment_address = legacy_encrypt(&cipher, &nonce, padded(b"\x05\0\0\0ab", 6));
+        assert!(cipher.decrypt_payment_address(&payment_address).is_err());
+
+        // Avatars have no length prefix, so they come back padded.
+        let avatar = legacy_encrypt(&cipher, &nonce, padded(b"avatar", 16));
+        assert_eq!(
+            cipher.decrypt_avatar(&avatar).unwrap(),
+            padded(b"avatar", 16)
+        );
+
+        // The legacy encryption methods produce the same format.
+        let legacy_name = cipher
+            .encrypt_name_legacy(TEST_ARRAY_32_1, "Alice", Some("Smith"))
+            .unwrap();
+        assert_eq!(
+            legacy_name,
+            legacy_encrypt(
+                &cipher,
+                &legacy_name[..AESGCM_NONCE_LEN],
+                padded(b"Alice\0Smith", 53)
+            )
+        );
+        assert_eq!(
+            cipher.decrypt_name(&legacy_name).unwrap(),
+            ("Alice".to_string(), Some("Smith".to_string()))
+        );
+        let legacy_about = cipher
+            .encrypt_about_legacy(TEST_ARRAY_32_1, "Hello")
+            .unwrap();
+        assert_eq!(cipher.decrypt_about(&legacy_about).unwrap(), "Hello");
+        let legacy_emoji = cipher
+            .encrypt_about_emoji_legacy(TEST_ARRAY_32_1, "🦊")
+            .unwrap();
+        assert_eq!(cipher.decrypt_about_emoji(&legacy_emoji).unwrap(), "🦊");
+        let legacy_payment_address = cipher
+            .encrypt_payment_address_legacy(TEST_ARRAY_32_1, b"ab")
+            .unwrap();
+        assert_eq!(
+            legacy_payment_address,
+            legacy_encrypt(
+                &cipher,
+                &legacy_payment_address[..AESGCM_NONCE_LEN],
+                padded(b"\x02\0\0\0ab", 554)
+            )
+        );
+        let legacy_avatar = cipher
+            .encrypt_avatar_legacy(TEST_ARRAY_32_1, b"avatar")
+            .unwrap();
+        assert_eq!(
+            cipher.decrypt_avatar(&legacy_avatar).unwrap(),
+            padded(b"avatar", padded_attachment_size(6) as usize)
+        );
+
+        // Only the profile key decrypts them.
+        let other_cipher = ProfileCipher::new(ProfileKey::create(TEST_ARRAY_32_2));
+        assert!(other_cipher.decrypt_name(&name).is_err());
+        let mut tampered = emoji;
+        tampered[AESGCM_NONCE_LEN] ^= 1;
+        assert!(cipher.decrypt_about_emoji(&tampered).is_err());
+    }
+
+    #[test]
+    fn profile_cipher_regression_vectors() {
+        // Produced by this implementation, not by an independent one: these catch accidental
+        // changes to the format, but not mistakes that were there from the start.
+        let cipher = test_cipher();
+        assert_eq!(
+            hex::encode(
+                cipher
+                    .encrypt_name(TEST_ARRAY_32_1, "Alice", Some("Smith"))
+                    .unwrap()
+            ),
+            "52f7b3d5e2511d49326cbece4291dfa42592ebf1591196a332ce046146c2124049b538cc0a8acc16abbd259bd0d041d8fc94656814a32a60addb6d3cc838d15e7bb8b1639c735066c5963f47ad0da73803"
+        );
+        assert_eq!(
+            hex::encode(cipher.encrypt_about_emoji(TEST_ARRAY_32_1, "🦊").unwrap()),
+            "52f7b3d5e2511d49326cbecef21460a845e9eae7638e26a75ef93afa7552c8ff83c030aad47f959829ed9cb0b41a81f38219d76a716fa827d279e8ea"
+        );
+    }
+}
@@ -10,3 +10,7 @@ pub struct ZkGroupVerificationFailure;
 #[derive(Debug, displaydoc::Display)]
 /// Deserialization failure in zkgroup
 pub struct ZkGroupDeserializationFailure;
+
+#[derive(Debug, displaydoc::Display)]
+/// Profile field is too long or contains a zero byte
+pub struct ProfileFieldEncodingFailure;
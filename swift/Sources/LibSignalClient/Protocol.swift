@@ -50,6 +50,7 @@ public func signalDecryptPreKey(message: PreKeySignalMessage,
                                 identityStore: IdentityKeyStore,
                                 preKeyStore: PreKeyStore,
                                 signedPreKeyStore: SignedPreKeyStore,
+                                kyberPreKeyStore: KyberPreKeyStore,
                                 context: StoreContext) throws -> [UInt8] {
     return try withNativeHandles(message, address) { messageHandle, addressHandle in
         try context.withOpaquePointer { context in
@@ -57,8 +58,10 @@ public func signalDecryptPreKey(message: PreKeySignalMessage,
                 try withIdentityKeyStore(identityStore) { ffiIdentityStore in
                     try withPreKeyStore(preKeyStore) { ffiPreKeyStore in
                         try withSignedPreKeyStore(signedPreKeyStore) { ffiSignedPreKeyStore in
-                            try invokeFnReturningArray {
-                                signal_decrypt_pre_key_message($0, messageHandle, addressHandle, ffiSessionStore, ffiIdentityStore, ffiPreKeyStore, ffiSignedPreKeyStore, context)
+                            try withKyberPreKeyStore(kyberPreKeyStore) { ffiKyberPreKeyStore in
+                                try invokeFnReturningArray {
+                                    signal_decrypt_pre_key_message($0, messageHandle, addressHandle, ffiSessionStore, ffiIdentityStore, ffiPreKeyStore, ffiSignedPreKeyStore, ffiKyberPreKeyStore, context)
+                                }
                             }
                         }
                     }
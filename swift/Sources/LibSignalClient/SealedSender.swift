@@ -232,6 +232,7 @@ public func sealedSenderDecrypt<Bytes: ContiguousBytes>(message: Bytes,
                                                         identityStore: IdentityKeyStore,
                                                         preKeyStore: PreKeyStore,
                                                         signedPreKeyStore: SignedPreKeyStore,
+                                                        kyberPreKeyStore: KyberPreKeyStore,
                                                         context: StoreContext) throws -> SealedSenderResult {
     var senderE164: UnsafePointer<CChar>?
     var senderUUID: UnsafePointer<CChar>?
@@ -244,23 +245,26 @@ public func sealedSenderDecrypt<Bytes: ContiguousBytes>(message: Bytes,
                     try withIdentityKeyStore(identityStore) { ffiIdentityStore in
                         try withPreKeyStore(preKeyStore) { ffiPreKeyStore in
                             try withSignedPreKeyStore(signedPreKeyStore) { ffiSignedPreKeyStore in
-                                try invokeFnReturningArray {
-                                    signal_sealed_session_cipher_decrypt(
-                                        $0,
-                                        &senderE164,
-                                        &senderUUID,
-                                        &senderDeviceId,
-                                        messageBuffer,
-                                        trustRootHandle,
-                                        timestamp,
-                                        localAddress.e164,
-                                        localAddress.uuidString,
-                                        localAddress.deviceId,
-                                        ffiSessionStore,
-                                        ffiIdentityStore,
-                                        ffiPreKeyStore,
-                                        ffiSignedPreKeyStore,
-                                        context)
+                                try withKyberPreKeyStore(kyberPreKeyStore) { ffiKyberPreKeyStore in
+                                    try invokeFnReturningArray {
+                                        signal_sealed_session_cipher_decrypt(
+                                            $0,
+                                            &senderE164,
+                                            &senderUUID,
+                                            &senderDeviceId,
+                                            messageBuffer,
+                                            trustRootHandle,
+                                            timestamp,
+                                            localAddress.e164,
+                                            localAddress.uuidString,
+                                            localAddress.deviceId,
+                                            ffiSessionStore,
+                                            ffiIdentityStore,
+                                            ffiPreKeyStore,
+                                            ffiSignedPreKeyStore,
+                                            ffiKyberPreKeyStore,
+                                            context)
+                                    }
                                 }
                             }
                         }
@@ -31,7 +31,10 @@ public class PreKeyBundle: NativeHandleOwner {
                                                          signedPrekeyId,
                                                          signedPrekeyHandle,
                                                          $0,
-                                                         identityKeyHandle))
+                                                         identityKeyHandle,
+                                                         ~0,
+                                                         nil,
+                                                         SignalBorrowedBuffer()))
             }
 
         }
@@ -56,7 +59,78 @@ public class PreKeyBundle: NativeHandleOwner {
                                                          signedPrekeyId,
                                                          signedPrekeyHandle,
                                                          $0,
-                                                         identityKeyHandle))
+                                                         identityKeyHandle,
+                                                         ~0,
+                                                         nil,
+                                                         SignalBorrowedBuffer()))
+            }
+        }
+        self.init(owned: result!)
+    }
+
+    // with a prekey and a Kyber pre-key
+    public convenience init<Bytes: ContiguousBytes, KyberBytes: ContiguousBytes>(registrationId: UInt32,
+                                                                                 deviceId: UInt32,
+                                                                                 prekeyId: UInt32,
+                                                                                 prekey: PublicKey,
+                                                                                 signedPrekeyId: UInt32,
+                                                                                 signedPrekey: PublicKey,
+                                                                                 signedPrekeySignature: Bytes,
+                                                                                 identity identityKey: IdentityKey,
+                                                                                 kyberPrekeyId: UInt32,
+                                                                                 kyberPrekey: KEMPublicKey,
+                                                                                 kyberPrekeySignature: KyberBytes) throws {
+        var result: OpaquePointer?
+        try withNativeHandles(prekey, signedPrekey, identityKey.publicKey) { prekeyHandle, signedPrekeyHandle, identityKeyHandle in
+            try kyberPrekey.withNativeHandle { kyberPrekeyHandle in
+                try signedPrekeySignature.withUnsafeBorrowedBuffer { signedPrekeySignatureBuffer in
+                    try kyberPrekeySignature.withUnsafeBorrowedBuffer { kyberPrekeySignatureBuffer in
+                        try checkError(signal_pre_key_bundle_new(&result,
+                                                                 registrationId,
+                                                                 deviceId,
+                                                                 prekeyId,
+                                                                 prekeyHandle,
+                                                                 signedPrekeyId,
+                                                                 signedPrekeyHandle,
+                                                                 signedPrekeySignatureBuffer,
+                                                                 identityKeyHandle,
+                                                                 kyberPrekeyId,
+                                                                 kyberPrekeyHandle,
+                                                                 kyberPrekeySignatureBuffer))
+                    }
+                }
+            }
+        }
+        self.init(owned: result!)
+    }
+
+    // without a prekey, with a Kyber pre-key
+    public convenience init<Bytes: ContiguousBytes, KyberBytes: ContiguousBytes>(registrationId: UInt32,
+                                                                                 deviceId: UInt32,
+                                                                                 signedPrekeyId: UInt32,
+                                                                                 signedPrekey: PublicKey,
+                                                                                 signedPrekeySignature: Bytes,
+                                                                                 identity identityKey: IdentityKey,
+                                                                                 kyberPrekeyId: UInt32,
+                                                                                 kyberPrekey: KEMPublicKey,
+                                                                                 kyberPrekeySignature: KyberBytes) throws {
+        var result: OpaquePointer?
+        try withNativeHandles(signedPrekey, identityKey.publicKey, kyberPrekey) { signedPrekeyHandle, identityKeyHandle, kyberPrekeyHandle in
+            try signedPrekeySignature.withUnsafeBorrowedBuffer { signedPrekeySignatureBuffer in
+                try kyberPrekeySignature.withUnsafeBorrowedBuffer { kyberPrekeySignatureBuffer in
+                    try checkError(signal_pre_key_bundle_new(&result,
+                                                             registrationId,
+                                                             deviceId,
+                                                             ~0,
+                                                             nil,
+                                                             signedPrekeyId,
+                                                             signedPrekeyHandle,
+                                                             signedPrekeySignatureBuffer,
+                                                             identityKeyHandle,
+                                                             kyberPrekeyId,
+                                                             kyberPrekeyHandle,
+                                                             kyberPrekeySignatureBuffer))
+                }
             }
         }
         self.init(owned: result!)
@@ -148,4 +222,40 @@ public class PreKeyBundle: NativeHandleOwner {
             }
         }
     }
+
+    public var kyberPreKeyId: UInt32? {
+        let kyberPrekeyId = withNativeHandle { nativeHandle in
+            failOnError {
+                try invokeFnReturningInteger {
+                    signal_pre_key_bundle_get_kyber_pre_key_id($0, nativeHandle)
+                }
+            }
+        }
+
+        if kyberPrekeyId == ~0 {
+            return nil
+        } else {
+            return kyberPrekeyId
+        }
+    }
+
+    public var kyberPreKeyPublic: KEMPublicKey? {
+        return withNativeHandle { nativeHandle in
+            failOnError {
+                try invokeFnReturningOptionalNativeHandle {
+                    signal_pre_key_bundle_get_kyber_pre_key_public($0, nativeHandle)
+                }
+            }
+        }
+    }
+
+    public var kyberPreKeySignature: [UInt8] {
+        return withNativeHandle { nativeHandle in
+            failOnError {
+                try invokeFnReturningArray {
+                    signal_pre_key_bundle_get_kyber_pre_key_signature($0, nativeHandle)
+                }
+            }
+        }
+    }
 }
@@ -185,6 +185,14 @@ typedef struct SignalFingerprint SignalFingerprint;
 
 typedef struct SignalHsmEnclaveClient SignalHsmEnclaveClient;
 
+typedef struct SignalKyberKeyPair SignalKyberKeyPair;
+
+typedef struct SignalKyberPreKeyRecord SignalKyberPreKeyRecord;
+
+typedef struct SignalKyberPublicKey SignalKyberPublicKey;
+
+typedef struct SignalKyberSecretKey SignalKyberSecretKey;
+
 typedef struct SignalPinHash SignalPinHash;
 
 typedef struct SignalPlaintextContent SignalPlaintextContent;
@@ -293,6 +301,19 @@ typedef struct {
   SignalStoreSignedPreKey store_signed_pre_key;
 } SignalSignedPreKeyStore;
 
+typedef int (*SignalLoadKyberPreKey)(void *store_ctx, SignalKyberPreKeyRecord **recordp, uint32_t id, void *ctx);
+
+typedef int (*SignalStoreKyberPreKey)(void *store_ctx, uint32_t id, const SignalKyberPreKeyRecord *record, void *ctx);
+
+typedef int (*SignalMarkKyberPreKeyUsed)(void *store_ctx, uint32_t id, void *ctx);
+
+typedef struct {
+  void *ctx;
+  SignalLoadKyberPreKey load_kyber_pre_key;
+  SignalStoreKyberPreKey store_kyber_pre_key;
+  SignalMarkKyberPreKeyUsed mark_kyber_pre_key_used;
+} SignalKyberPreKeyStore;
+
 typedef bool (*SignalLogEnabledCallback)(const char *target, SignalLogLevel level);
 
 typedef void (*SignalLogCallback)(const char *target, SignalLogLevel level, const char *file, uint32_t line, const char *message);
@@ -366,6 +387,7 @@ SignalFfiError *signal_sealed_session_cipher_decrypt(SignalOwnedBuffer *out,
                                                      const SignalIdentityKeyStore *identity_store,
                                                      const SignalPreKeyStore *prekey_store,
                                                      const SignalSignedPreKeyStore *signed_prekey_store,
+                                                     const SignalKyberPreKeyStore *kyber_prekey_store,
                                                      void *ctx);
 
 void signal_init_logger(SignalLogLevel max_level, SignalFfiLogger logger);
@@ -440,6 +462,26 @@ SignalFfiError *signal_fingerprint_destroy(SignalFingerprint *p);
 
 SignalFfiError *signal_fingerprint_clone(SignalFingerprint **new_obj, const SignalFingerprint *obj);
 
+SignalFfiError *signal_kyber_key_pair_destroy(SignalKyberKeyPair *p);
+
+SignalFfiError *signal_kyber_key_pair_clone(SignalKyberKeyPair **new_obj,
+                                            const SignalKyberKeyPair *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_destroy(SignalKyberPreKeyRecord *p);
+
+SignalFfiError *signal_kyber_pre_key_record_clone(SignalKyberPreKeyRecord **new_obj,
+                                                  const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_public_key_destroy(SignalKyberPublicKey *p);
+
+SignalFfiError *signal_kyber_public_key_clone(SignalKyberPublicKey **new_obj,
+                                              const SignalKyberPublicKey *obj);
+
+SignalFfiError *signal_kyber_secret_key_destroy(SignalKyberSecretKey *p);
+
+SignalFfiError *signal_kyber_secret_key_clone(SignalKyberSecretKey **new_obj,
+                                              const SignalKyberSecretKey *obj);
+
 SignalFfiError *signal_plaintext_content_destroy(SignalPlaintextContent *p);
 
 SignalFfiError *signal_plaintext_content_clone(SignalPlaintextContent **new_obj,
@@ -624,6 +666,8 @@ SignalFfiError *signal_pre_key_signal_message_new(SignalPreKeySignalMessage **ou
                                                   uint32_t registration_id,
                                                   uint32_t pre_key_id,
                                                   uint32_t signed_pre_key_id,
+                                                  uint32_t kyber_pre_key_id,
+                                                  SignalBorrowedBuffer kyber_ciphertext,
                                                   const SignalPublicKey *base_key,
                                                   const SignalPublicKey *identity_key,
                                                   const SignalMessage *signal_message);
@@ -758,7 +802,10 @@ SignalFfiError *signal_pre_key_bundle_new(SignalPreKeyBundle **out,
                                           uint32_t signed_prekey_id,
                                           const SignalPublicKey *signed_prekey,
                                           SignalBorrowedBuffer signed_prekey_signature,
-                                          const SignalPublicKey *identity_key);
+                                          const SignalPublicKey *identity_key,
+                                          uint32_t kyber_prekey_id,
+                                          const SignalKyberPublicKey *kyber_prekey,
+                                          SignalBorrowedBuffer kyber_prekey_signature);
 
 SignalFfiError *signal_pre_key_bundle_get_identity_key(SignalPublicKey **out,
                                                        const SignalPreKeyBundle *p);
@@ -782,6 +829,15 @@ SignalFfiError *signal_pre_key_bundle_get_pre_key_public(SignalPublicKey **out,
 SignalFfiError *signal_pre_key_bundle_get_signed_pre_key_public(SignalPublicKey **out,
                                                                 const SignalPreKeyBundle *obj);
 
+SignalFfiError *signal_pre_key_bundle_get_kyber_pre_key_id(uint32_t *out,
+                                                           const SignalPreKeyBundle *obj);
+
+SignalFfiError *signal_pre_key_bundle_get_kyber_pre_key_public(SignalKyberPublicKey **out,
+                                                               const SignalPreKeyBundle *bundle);
+
+SignalFfiError *signal_pre_key_bundle_get_kyber_pre_key_signature(SignalOwnedBuffer *out,
+                                                                  const SignalPreKeyBundle *bundle);
+
 SignalFfiError *signal_signed_pre_key_record_deserialize(SignalSignedPreKeyRecord **out,
                                                          SignalBorrowedBuffer data);
 
@@ -810,6 +866,60 @@ SignalFfiError *signal_signed_pre_key_record_new(SignalSignedPreKeyRecord **out,
                                                  const SignalPrivateKey *priv_key,
                                                  SignalBorrowedBuffer signature);
 
+SignalFfiError *signal_kyber_pre_key_record_deserialize(SignalKyberPreKeyRecord **out,
+                                                        SignalBorrowedBuffer data);
+
+SignalFfiError *signal_kyber_pre_key_record_get_signature(SignalOwnedBuffer *out,
+                                                          const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_serialize(SignalOwnedBuffer *out,
+                                                      const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_get_id(uint32_t *out,
+                                                   const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_get_timestamp(uint64_t *out,
+                                                          const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_get_public_key(SignalKyberPublicKey **out,
+                                                           const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_get_secret_key(SignalKyberSecretKey **out,
+                                                           const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_get_key_pair(SignalKyberKeyPair **out,
+                                                         const SignalKyberPreKeyRecord *obj);
+
+SignalFfiError *signal_kyber_pre_key_record_new(SignalKyberPreKeyRecord **out,
+                                                uint32_t id,
+                                                uint64_t timestamp,
+                                                const SignalKyberKeyPair *key_pair,
+                                                SignalBorrowedBuffer signature);
+
+SignalFfiError *signal_kyber_key_pair_generate(SignalKyberKeyPair **out);
+
+SignalFfiError *signal_kyber_key_pair_get_public_key(SignalKyberPublicKey **out,
+                                                     const SignalKyberKeyPair *key_pair);
+
+SignalFfiError *signal_kyber_key_pair_get_secret_key(SignalKyberSecretKey **out,
+                                                     const SignalKyberKeyPair *key_pair);
+
+SignalFfiError *signal_kyber_public_key_deserialize(SignalKyberPublicKey **out,
+                                                    SignalBorrowedBuffer data);
+
+SignalFfiError *signal_kyber_public_key_serialize(SignalOwnedBuffer *out,
+                                                  const SignalKyberPublicKey *obj);
+
+SignalFfiError *signal_kyber_public_key_equals(bool *out,
+                                               const SignalKyberPublicKey *lhs,
+                                               const SignalKyberPublicKey *rhs);
+
+SignalFfiError *signal_kyber_secret_key_deserialize(SignalKyberSecretKey **out,
+                                                    SignalBorrowedBuffer data);
+
+SignalFfiError *signal_kyber_secret_key_serialize(SignalOwnedBuffer *out,
+                                                  const SignalKyberSecretKey *obj);
+
 SignalFfiError *signal_pre_key_record_deserialize(SignalPreKeyRecord **out,
                                                   SignalBorrowedBuffer data);
 
@@ -984,6 +1094,7 @@ SignalFfiError *signal_decrypt_pre_key_message(SignalOwnedBuffer *out,
                                                const SignalIdentityKeyStore *identity_key_store,
                                                const SignalPreKeyStore *prekey_store,
                                                const SignalSignedPreKeyStore *signed_prekey_store,
+                                               const SignalKyberPreKeyStore *kyber_prekey_store,
                                                void *ctx);
 
 SignalFfiError *signal_sealed_session_cipher_encrypt(SignalOwnedBuffer *out,
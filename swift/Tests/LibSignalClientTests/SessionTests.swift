@@ -85,6 +85,7 @@ class SessionTests: TestCaseBase {
                                                identityStore: bob_store,
                                                preKeyStore: bob_store,
                                                signedPreKeyStore: bob_store,
+                                               kyberPreKeyStore: bob_store,
                                                context: NullContext())
 
         XCTAssertEqual(ptext_a, ptext_b)
@@ -139,6 +140,7 @@ class SessionTests: TestCaseBase {
                                                      identityStore: bob_store,
                                                      preKeyStore: bob_store,
                                                      signedPreKeyStore: bob_store,
+                                                     kyberPreKeyStore: bob_store,
                                                      context: NullContext()),
                              "should fail to decrypt") { error in
             guard case BadStore.Error.badness = error else {
@@ -186,6 +188,7 @@ class SessionTests: TestCaseBase {
                                                 identityStore: bob_store,
                                                 preKeyStore: bob_store,
                                                 signedPreKeyStore: bob_store,
+                                                kyberPreKeyStore: bob_store,
                                                 context: NullContext())
 
         XCTAssertEqual(plaintext.message, message)
@@ -376,6 +379,7 @@ class SessionTests: TestCaseBase {
                                     identityStore: alice_store,
                                     preKeyStore: alice_store,
                                     signedPreKeyStore: alice_store,
+                                    kyberPreKeyStore: alice_store,
                                     context: NullContext())
 
         let bob_message = try signalEncrypt(message: Array("space camp".utf8),